@@ -0,0 +1,91 @@
+use std::convert::TryFrom;
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mqtt_rs::hex::{Property, PropertyItem, PropertyValue};
+use mqtt_rs::message::BaseMessage;
+use mqtt_rs::message::entity::PublishMessage;
+use mqtt_rs::message::v5::MqttMessageV5;
+use mqtt_rs::packet::{encode_publish, v5_unpacket};
+use mqtt_rs::subscript::SubscriptOption;
+use mqtt_rs::tools::protocol::{MqttDup, MqttProtocolLevel, MqttQos, MqttRetain};
+
+const SUBSCRIBERS: usize = 1000;
+
+///
+/// 从报文解码得到的消息, 与服务端收到的 PUBLISH 一致
+///
+fn message(size: usize) -> PublishMessage {
+    let properties = vec![
+        PropertyItem(Property::ContentType, PropertyValue::String("text/plain".to_owned())),
+        PropertyItem(Property::UserProperty, PropertyValue::Map("source".to_owned(), "bench".to_owned())),
+    ];
+    let msg = PublishMessage::new(MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable, "bench/fanout/topic".to_owned(), 1, vec![b'x'; size], Some(properties));
+    let data = MqttMessageV5::Publish(msg).to_vec().unwrap();
+    match v5_unpacket::publish(BaseMessage::try_from(data).unwrap()).unwrap() {
+        MqttMessageV5::Publish(msg) => msg,
+        _ => unreachable!()
+    }
+}
+
+///
+/// 修改前的消息: 保留原始报文 `Vec<u8>` 与 `String` 载荷
+///
+#[derive(Clone)]
+struct LegacyPublish {
+    msg: PublishMessage,
+    msg_body: String,
+    bytes: Option<Vec<u8>>,
+}
+
+impl LegacyPublish {
+    fn new(msg: &PublishMessage) -> LegacyPublish {
+        LegacyPublish {
+            msg_body: String::from_utf8(msg.msg_body.to_vec()).unwrap(),
+            bytes: msg.bytes.as_deref().map(<[u8]>::to_vec),
+            msg: PublishMessage { msg_body: Bytes::new(), bytes: None, ..msg.clone() },
+        }
+    }
+}
+
+///
+/// 修改前的转发路径: 每个订阅者复制一份消息, 包括原始报文与载荷, 然后重新编码整个报文
+///
+fn reencode(msg: &LegacyPublish, option: &SubscriptOption) -> usize {
+    (0..SUBSCRIBERS).map(|id| {
+        let LegacyPublish { msg, msg_body, bytes } = msg.clone();
+        black_box(bytes);
+        let mut msg = option.apply(&msg);
+        msg.message_id = id as u16 + 1;
+        let msg = PublishMessage { msg_body: Bytes::from(msg_body.into_bytes()), ..msg };
+        MqttMessageV5::Publish(msg).to_vec().unwrap().len()
+    }).sum()
+}
+
+///
+/// 所有订阅者共享载荷与编码缓存, 只编码固定报头、主题名与报文标识符
+///
+fn shared(msg: &PublishMessage, option: &SubscriptOption) -> usize {
+    let msg = msg.shared();
+    (0..SUBSCRIBERS).map(|id| {
+        let mut msg = option.apply(&msg);
+        msg.message_id = id as u16 + 1;
+        let (head, rest) = encode_publish(MqttProtocolLevel::Level5, &msg).unwrap();
+        head.len() + rest.len()
+    }).sum()
+}
+
+fn fanout(c: &mut Criterion) {
+    let option = SubscriptOption { qos: MqttQos::Qos1, ..SubscriptOption::default() };
+    let mut group = c.benchmark_group("fanout");
+    for size in [64, 4096] {
+        let msg = message(size);
+        let legacy = LegacyPublish::new(&msg);
+        group.throughput(Throughput::Elements(SUBSCRIBERS as u64));
+        group.bench_with_input(BenchmarkId::new("reencode", size), &legacy, |b, msg| b.iter(|| reencode(black_box(msg), &option)));
+        group.bench_with_input(BenchmarkId::new("shared", size), &msg, |b, msg| b.iter(|| shared(black_box(msg), &option)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
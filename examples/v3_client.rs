@@ -18,19 +18,18 @@ async fn start() {
         22222,
     );
     let builder = ConfigBuilder::default();
-    let mut client = MqttClient::new(builder.build().unwrap(), SocketAddr::from(socket));
+    let client = MqttClient::new(builder.build().unwrap(), SocketAddr::from(socket));
     let mut client = client.handle(handle_v3_message);
-    let rc = client.connect().await;
+    if let Some(mut receiver) = client.connect().await {
+        while let Some(payload) = receiver.recv().await {
+            println!("receive: {:?}", payload);
+        }
+    }
 }
 
 
 pub async fn handle_v3_message(session: ClientSession, v3_kind: Option<MqttMessageKind>) {
-    if let Some(MqttMessageKind::RequestV3(v3)) = v3_kind {
-        match v3 {
-            MqttMessageV3::Connack(_) => {
-                session.subscribe(session.session_id()).await;
-            }
-            _ => {}
-        }
+    if let Some(MqttMessageKind::RequestV3(MqttMessageV3::Connack(_))) = v3_kind {
+        session.subscribe(session.session_id()).await;
     }
 }
@@ -1,11 +1,10 @@
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::str::FromStr;
 use mqtt_rs::executor::v3_server::MqttServer;
-use mqtt_rs::message::entity::{ConnackMessage, DisconnectMessage, PubackMessage, PubrecMessage, UnsubackMessage, SubackMessage, PubrelMessage, PubcompMessage, PingrespMessage};
+use mqtt_rs::message::entity::{DisconnectMessage, UnsubackMessage, PingrespMessage};
 use mqtt_rs::message::MqttMessageKind;
 use mqtt_rs::message::v3::MqttMessageV3;
 use mqtt_rs::session::{MqttSession, ServerSession};
-use mqtt_rs::tools::protocol::MqttQos;
 
 #[tokio::main]
 async fn main() {
@@ -24,11 +23,12 @@ pub async fn handle_v3_message(session: ServerSession, v3_kind: Option<MqttMessa
     if let Some(v3) = v3_kind {
         match v3 {
             MqttMessageKind::RequestV3(ref msg) => {
-                let res_msg = handle_v3(&session, msg).await.expect("handle v3 message error");
-                if res_msg.is_disconnect() {
-                    session.exit().await;
-                } else {
-                    session.send(res_msg.to_vec().unwrap()).await
+                if let Some(res_msg) = handle_v3(&session, msg).await {
+                    if res_msg.is_disconnect() {
+                        session.exit().await;
+                    } else {
+                        session.send(res_msg.to_vec().unwrap()).await
+                    }
                 }
             }
             MqttMessageKind::RequestV3Vec(ref items) => {
@@ -38,7 +38,9 @@ pub async fn handle_v3_message(session: ServerSession, v3_kind: Option<MqttMessa
                         res.push(res_msg.to_vec().unwrap());
                     }
                 }
-                session.send(res.concat()).await
+                if !res.is_empty() {
+                    session.send(res.concat()).await
+                }
             }
             _ => {}
         };
@@ -47,26 +49,13 @@ pub async fn handle_v3_message(session: ServerSession, v3_kind: Option<MqttMessa
 
 async fn handle_v3(session: &ServerSession, kind: &MqttMessageV3) -> Option<MqttMessageV3> {
     match kind {
-        MqttMessageV3::Connect(_) => {
-            return Some(MqttMessageV3::Connack(ConnackMessage::default()));
-        }
-        MqttMessageV3::Subscribe(msg) => {
-            session.subscribe(&msg.topic).await;
-            let sm = SubackMessage::from(msg);
-            println!("{:?}", sm);
-            return Some(MqttMessageV3::Suback(sm));
-        }
+        // CONNACK, SUBACK and the QoS 1/2 acknowledgements are sent by the server itself
+        MqttMessageV3::Connect(_) | MqttMessageV3::Subscribe(_) => None,
         MqttMessageV3::Unsubscribe(msg) => Some(MqttMessageV3::Unsuback(UnsubackMessage::new(msg.message_id, None))),
         MqttMessageV3::Publish(msg) => {
             session.publish(msg).await;
-            match msg.qos {
-                MqttQos::Qos1 => return Some(MqttMessageV3::Puback(PubackMessage::new(msg.message_id))),
-                MqttQos::Qos2 => return Some(MqttMessageV3::Pubrec(PubrecMessage::new(msg.message_id))),
-                _ => return None
-            }
+            None
         }
-        MqttMessageV3::Pubrec(msg) => { Some(MqttMessageV3::Pubrel(PubrelMessage::from(msg))) }
-        MqttMessageV3::Pubrel(msg) => { Some(MqttMessageV3::Pubcomp(PubcompMessage::from(msg))) }
         MqttMessageV3::Pingreq(_) => { Some(MqttMessageV3::Pingresp(PingrespMessage::default())) }
         MqttMessageV3::Disconnect(_) => Some(MqttMessageV3::Disconnect(DisconnectMessage::default())),
         _ => None
@@ -0,0 +1,222 @@
+use std::io;
+use std::path::Path;
+use async_trait::async_trait;
+use crate::auth::{Action, Authorizer};
+
+///
+/// ACL 规则作用的对象
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Scope {
+    Any,
+    User(String),
+    Client(String),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    scope: Scope,
+    allow: bool,
+    actions: Vec<Action>,
+    filter: String,
+}
+
+///
+/// 文件 ACL, 每行一条指令, `#` 开头的行为注释:
+///
+/// ```text
+/// # 对所有客户端生效
+/// allow subscribe public/#
+/// # 之后的规则只对用户 alice 生效
+/// user alice
+/// allow all alice/#
+/// deny publish alice/readonly/#
+/// # 之后的规则只对客户端标识 sensor-1 生效
+/// client sensor-1
+/// allow publish sensors/%c/#
+/// # 回到对所有客户端生效
+/// any
+/// allow publish devices/%u/%c
+/// ```
+///
+/// 主题过滤器中的 `%c` 替换为客户端标识, `%u` 替换为用户名 (没有用户名时该规则不生效);
+/// 客户端标识或用户名包含 `+`、`#` 或 `/` 时替换后会扩大或改变主题层级, 该规则同样不生效.
+/// 任意一条拒绝规则匹配时拒绝, 否则任意一条允许规则匹配时允许, 都不匹配时拒绝
+///
+#[derive(Debug, Default, Clone)]
+pub struct AclAuthorizer {
+    rules: Vec<Rule>,
+}
+
+impl AclAuthorizer {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<AclAuthorizer> {
+        AclAuthorizer::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> io::Result<AclAuthorizer> {
+        let mut rules = vec![];
+        let mut scope = Scope::Any;
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid acl entry at line {}", index + 1));
+            let words = line.split_whitespace().collect::<Vec<&str>>();
+            match words.as_slice() {
+                ["any"] => scope = Scope::Any,
+                ["user", username] => scope = Scope::User(username.to_string()),
+                ["client", client_id] => scope = Scope::Client(client_id.to_string()),
+                [permission, action, filter] => {
+                    let allow = match *permission {
+                        "allow" => true,
+                        "deny" => false,
+                        _ => return Err(invalid())
+                    };
+                    let actions = match *action {
+                        "publish" => vec![Action::Publish],
+                        "subscribe" => vec![Action::Subscribe],
+                        "all" => vec![Action::Publish, Action::Subscribe],
+                        _ => return Err(invalid())
+                    };
+                    rules.push(Rule { scope: scope.clone(), allow, actions, filter: filter.to_string() });
+                }
+                _ => return Err(invalid())
+            }
+        }
+        Ok(AclAuthorizer { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn check(&self, client_id: &str, username: Option<&str>, action: Action, topic: &str) -> bool {
+        let filters = self.rules.iter()
+            .filter(|rule| rule.actions.contains(&action))
+            .filter(|rule| match &rule.scope {
+                Scope::Any => true,
+                Scope::User(name) => Some(name.as_str()) == username,
+                Scope::Client(id) => id == client_id,
+            })
+            .filter_map(|rule| substitute(&rule.filter, client_id, username).map(|filter| (rule.allow, filter)))
+            .collect::<Vec<(bool, String)>>();
+        if filters.iter().any(|(allow, filter)| !allow && overlaps(filter, topic)) {
+            return false;
+        }
+        filters.iter().any(|(allow, filter)| *allow && covers(filter, topic))
+    }
+}
+
+#[async_trait]
+impl Authorizer for AclAuthorizer {
+    async fn authorize(&self, client_id: &str, username: Option<&str>, action: Action, topic: &str) -> bool {
+        self.check(client_id, username, action, topic)
+    }
+}
+
+///
+/// 替换 `%c` 与 `%u`; 需要用户名但客户端没有提供, 或者替换的值不是单个普通主题层级时返回 `None`
+///
+fn substitute(filter: &str, client_id: &str, username: Option<&str>) -> Option<String> {
+    let plain = |value: &str| !value.contains(['+', '#', '/']);
+    if filter.contains("%c") && !plain(client_id) {
+        return None;
+    }
+    let filter = filter.replace("%c", client_id);
+    match username {
+        Some(username) if filter.contains("%u") && !plain(username) => None,
+        Some(username) => Some(filter.replace("%u", username)),
+        None if filter.contains("%u") => None,
+        None => Some(filter)
+    }
+}
+
+///
+/// ACL 过滤器是否包含主题 (主题名或订阅的主题过滤器) 的全部范围
+///
+fn covers(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(level)) if level != "#" => {}
+            (Some(f), Some(t)) if f == t && f != "+" => {}
+            (None, None) => return true,
+            _ => return false
+        }
+    }
+}
+
+///
+/// ACL 过滤器与主题是否存在交集
+///
+fn overlaps(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some("+"), Some(_)) | (Some(_), Some("+")) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acl_rules() {
+        let acl = AclAuthorizer::parse("\
+            allow subscribe public/#\n\
+            user alice\n\
+            allow all alice/#\n\
+            deny publish alice/readonly/#\n\
+            client sensor-1\n\
+            allow publish sensors/%c/#\n\
+            any\n\
+            allow publish devices/%u/%c\n").unwrap();
+        assert_eq!(acl.len(), 5);
+
+        assert!(acl.check("c1", None, Action::Subscribe, "public/news"));
+        assert!(!acl.check("c1", None, Action::Publish, "public/news"));
+        assert!(acl.check("c1", Some("alice"), Action::Publish, "alice/a"));
+        assert!(!acl.check("c1", Some("alice"), Action::Publish, "alice/readonly/a"));
+        assert!(acl.check("c1", Some("alice"), Action::Subscribe, "alice/+/b"));
+        assert!(!acl.check("c1", Some("bob"), Action::Subscribe, "alice/#"));
+        assert!(!acl.check("c1", Some("alice"), Action::Subscribe, "#"));
+        assert!(acl.check("sensor-1", None, Action::Publish, "sensors/sensor-1/temp"));
+        assert!(!acl.check("sensor-2", None, Action::Publish, "sensors/sensor-2/temp"));
+        assert!(acl.check("c1", Some("bob"), Action::Publish, "devices/bob/c1"));
+        assert!(!acl.check("c1", None, Action::Publish, "devices/%u/c1"));
+        assert!(AclAuthorizer::parse("allow read topic").is_err());
+
+        let acl = AclAuthorizer::parse("allow subscribe clients/%c/#\nallow publish users/%u").unwrap();
+        assert!(acl.check("c1", None, Action::Subscribe, "clients/c1/#"));
+        assert!(!acl.check("+", None, Action::Subscribe, "clients/+/#"));
+        assert!(!acl.check("+", None, Action::Subscribe, "clients/c1/status"));
+        assert!(!acl.check("#", None, Action::Subscribe, "clients/#"));
+        assert!(!acl.check("a/b", None, Action::Subscribe, "clients/a/b/status"));
+        assert!(!acl.check("c1", Some("x/y"), Action::Publish, "users/x/y"));
+    }
+
+    #[test]
+    fn filter_relations() {
+        assert!(covers("a/+/c", "a/b/c"));
+        assert!(covers("a/#", "a/+/c"));
+        assert!(!covers("a/+", "a/#"));
+        assert!(!covers("a/b", "a/+"));
+        assert!(overlaps("a/b", "a/+"));
+        assert!(overlaps("a/b/#", "#"));
+        assert!(!overlaps("a/b", "a/c"));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use async_trait::async_trait;
+use tokio_rustls::rustls::Certificate;
+use crate::hex::reason_code::{ReasonCodeV3, ReasonPhrases};
+
+pub mod password_file;
+pub mod acl;
+pub mod scram;
+
+pub use password_file::PasswordFileAuthenticator;
+pub use acl::AclAuthorizer;
+pub use scram::{ScramSha256Client, ScramSha256Server};
+
+///
+/// Unix 套接字对端进程的凭据
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+///
+/// CONNECT 报文中用于认证的信息, TLS 连接附带客户端证书, Unix 套接字连接附带对端进程凭据
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials<'a> {
+    pub client_id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub peer_addr: Option<SocketAddr>,
+    pub peer_certificate: Option<&'a Certificate>,
+    pub peer_credentials: Option<PeerCredentials>,
+}
+
+///
+/// 连接认证, 返回 `Err` 时以对应原因码回复 CONNACK 并断开连接
+///
+/// v3.1.1 连接的原因码会转换为 `ReasonCodeV3`, 也可以直接返回 `ReasonCodeV3::NotAuthorized.into()`
+///
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<(), ReasonPhrases>;
+}
+
+impl fmt::Debug for dyn Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authenticator")
+    }
+}
+
+///
+/// 增强认证交换中的一步
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthStep {
+    /// 继续认证, 以 AUTH 0x18 发送认证数据
+    Continue(Vec<u8>),
+    /// 认证成功, 可以附带最后的认证数据
+    Success(Option<Vec<u8>>),
+    Failure(ReasonPhrases),
+}
+
+///
+/// v5 增强认证的一次交换, 每收到一次对端的认证数据前进一步
+///
+/// 客户端以 `step(None)` 取得 CONNECT 或 AUTH 0x19 中的初始认证数据
+///
+#[async_trait]
+pub trait AuthExchange: Send + Sync {
+    async fn step(&mut self, data: Option<&[u8]>) -> AuthStep;
+}
+
+///
+/// v5 增强认证方法, 服务端与客户端共用, 每次认证 (包括重新认证) 开始一次新的交换
+///
+pub trait AuthMechanism: Send + Sync {
+    fn method(&self) -> &str;
+
+    fn start(&self) -> Box<dyn AuthExchange>;
+}
+
+impl fmt::Debug for dyn AuthMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AuthMechanism({})", self.method())
+    }
+}
+
+///
+/// 需要授权的操作, 订阅时检查的是订阅的主题过滤器
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Publish,
+    Subscribe,
+}
+
+///
+/// 主题授权, 拒绝的订阅回复 SUBACK 0x80 (v5 0x87), 拒绝的发布不会转发给订阅者
+///
+#[async_trait]
+pub trait Authorizer: Send + Sync {
+    async fn authorize(&self, client_id: &str, username: Option<&str>, action: Action, topic: &str) -> bool;
+}
+
+impl fmt::Debug for dyn Authorizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authorizer")
+    }
+}
+
+///
+/// 内存中的用户表, 密码以明文保存
+///
+#[derive(Debug, Default, Clone)]
+pub struct StaticAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl StaticAuthenticator {
+    pub fn new() -> StaticAuthenticator {
+        StaticAuthenticator::default()
+    }
+
+    pub fn user<S: Into<String>>(mut self, username: S, password: S) -> StaticAuthenticator {
+        self.users.insert(username.into(), password.into());
+        self
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticAuthenticator {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<(), ReasonPhrases> {
+        let username = credentials.username.ok_or(ReasonCodeV3::NotAuthorized)?;
+        match self.users.get(username) {
+            Some(password) if Some(password.as_str()) == credentials.password => Ok(()),
+            _ => Err(ReasonPhrases::BadUserNameOrPassword)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn credentials<'a>(username: Option<&'a str>, password: Option<&'a str>) -> Credentials<'a> {
+        Credentials { client_id: "auth-client", username, password, peer_addr: None, peer_certificate: None, peer_credentials: None }
+    }
+
+    #[tokio::test]
+    async fn static_users() {
+        let authenticator = StaticAuthenticator::new().user("admin", "secret");
+        assert_eq!(authenticator.authenticate(&credentials(Some("admin"), Some("secret"))).await, Ok(()));
+        assert_eq!(authenticator.authenticate(&credentials(Some("admin"), Some("wrong"))).await, Err(ReasonPhrases::BadUserNameOrPassword));
+        assert_eq!(authenticator.authenticate(&credentials(Some("guest"), Some("secret"))).await, Err(ReasonPhrases::BadUserNameOrPassword));
+        assert_eq!(authenticator.authenticate(&credentials(None, None)).await, Err(ReasonPhrases::NotAuthorized));
+    }
+}
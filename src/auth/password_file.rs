@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use async_trait::async_trait;
+use crate::auth::{Authenticator, Credentials};
+use crate::hex::reason_code::{ReasonCodeV3, ReasonPhrases};
+
+///
+/// 密码文件认证, 每行一个 `username:hash`, `#` 开头的行为注释
+///
+/// 支持 bcrypt (`$2a$` / `$2b$` / `$2y$`) 与 argon2 (`$argon2id$` 等 PHC 格式) 哈希
+///
+#[derive(Debug, Default, Clone)]
+pub struct PasswordFileAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl PasswordFileAuthenticator {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<PasswordFileAuthenticator> {
+        PasswordFileAuthenticator::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> io::Result<PasswordFileAuthenticator> {
+        let mut users = HashMap::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((username, hash)) if !username.is_empty() && hash.starts_with('$') => {
+                    users.insert(username.to_owned(), hash.to_owned());
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid password entry at line {}", index + 1)))
+            }
+        }
+        Ok(PasswordFileAuthenticator { users })
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+#[async_trait]
+impl Authenticator for PasswordFileAuthenticator {
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<(), ReasonPhrases> {
+        let username = credentials.username.ok_or(ReasonCodeV3::NotAuthorized)?;
+        let (hash, password) = match (self.users.get(username), credentials.password) {
+            (Some(hash), Some(password)) => (hash.clone(), password.to_owned()),
+            _ => return Err(ReasonPhrases::BadUserNameOrPassword)
+        };
+        // 哈希校验耗时较长, 放到阻塞线程池中执行
+        match tokio::task::spawn_blocking(move || verify(&password, &hash)).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ReasonPhrases::BadUserNameOrPassword),
+            Err(_) => Err(ReasonPhrases::ServerUnavailable)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::PasswordHasher;
+    use argon2::password_hash::SaltString;
+    use crate::auth::tests::credentials;
+
+    #[tokio::test]
+    async fn bcrypt_and_argon2() {
+        let bcrypt_hash = bcrypt::hash("bcrypt-secret", 4).unwrap();
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let argon2_hash = Argon2::default().hash_password(b"argon2-secret", &salt).unwrap().to_string();
+        let content = format!("# users\nalice:{}\n\nbob:{}\n", bcrypt_hash, argon2_hash);
+        let authenticator = PasswordFileAuthenticator::parse(&content).unwrap();
+        assert_eq!(authenticator.len(), 2);
+
+        assert_eq!(authenticator.authenticate(&credentials(Some("alice"), Some("bcrypt-secret"))).await, Ok(()));
+        assert_eq!(authenticator.authenticate(&credentials(Some("bob"), Some("argon2-secret"))).await, Ok(()));
+        assert_eq!(authenticator.authenticate(&credentials(Some("alice"), Some("argon2-secret"))).await, Err(ReasonPhrases::BadUserNameOrPassword));
+        assert_eq!(authenticator.authenticate(&credentials(Some("carol"), Some("secret"))).await, Err(ReasonPhrases::BadUserNameOrPassword));
+        assert!(PasswordFileAuthenticator::parse("alice").is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use crate::auth::{AuthExchange, AuthMechanism, AuthStep};
+use crate::hex::reason_code::ReasonPhrases;
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+const DEFAULT_ITERATIONS: u32 = 4096;
+
+/// 不使用通道绑定时的 GS2 头
+const GS2_HEADER: &str = "n,,";
+
+///
+/// 服务端保存的 SCRAM 凭据, 不包含明文密码
+///
+#[derive(Debug, Clone)]
+pub struct ScramCredentials {
+    salt: Vec<u8>,
+    iterations: u32,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    pub fn new(password: &str, salt: &[u8], iterations: u32) -> ScramCredentials {
+        let salted_password = salted_password(password, salt, iterations);
+        ScramCredentials {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: Sha256::digest(hmac(&salted_password, b"Client Key")).to_vec(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+}
+
+///
+/// SCRAM-SHA-256 服务端 (RFC 5802 / RFC 7677), 不支持通道绑定
+///
+#[derive(Debug, Default, Clone)]
+pub struct ScramSha256Server {
+    users: Arc<HashMap<String, ScramCredentials>>,
+}
+
+impl ScramSha256Server {
+    pub fn new() -> ScramSha256Server {
+        ScramSha256Server::default()
+    }
+
+    ///
+    /// 以随机盐值添加用户
+    ///
+    pub fn user<S: Into<String>>(self, username: S, password: &str) -> ScramSha256Server {
+        let mut salt = [0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        self.credentials(username, ScramCredentials::new(password, &salt, DEFAULT_ITERATIONS))
+    }
+
+    pub fn credentials<S: Into<String>>(mut self, username: S, credentials: ScramCredentials) -> ScramSha256Server {
+        Arc::make_mut(&mut self.users).insert(username.into(), credentials);
+        self
+    }
+}
+
+impl AuthMechanism for ScramSha256Server {
+    fn method(&self) -> &str {
+        SCRAM_SHA_256
+    }
+
+    fn start(&self) -> Box<dyn AuthExchange> {
+        Box::new(ServerExchange { users: self.users.clone(), nonce: nonce(), state: ServerState::ClientFirst })
+    }
+}
+
+enum ServerState {
+    ClientFirst,
+    ClientFinal { nonce: String, auth_message: String, credentials: ScramCredentials },
+    Done,
+}
+
+struct ServerExchange {
+    users: Arc<HashMap<String, ScramCredentials>>,
+    nonce: String,
+    state: ServerState,
+}
+
+impl ServerExchange {
+    fn client_first(&mut self, data: &str) -> AuthStep {
+        let bare = match data.strip_prefix(GS2_HEADER) {
+            Some(bare) => bare,
+            None => return AuthStep::Failure(ReasonPhrases::BadAuthenticationMethod)
+        };
+        let (username, client_nonce) = match (attribute(bare, 'n'), attribute(bare, 'r')) {
+            (Some(username), Some(client_nonce)) => (unescape(username), client_nonce),
+            _ => return AuthStep::Failure(ReasonPhrases::MalformedPacket)
+        };
+        let credentials = match self.users.get(&username) {
+            Some(credentials) => credentials.clone(),
+            None => return AuthStep::Failure(ReasonPhrases::BadUserNameOrPassword)
+        };
+        let nonce = format!("{}{}", client_nonce, self.nonce);
+        let server_first = format!("r={},s={},i={}", nonce, STANDARD.encode(&credentials.salt), credentials.iterations);
+        let auth_message = format!("{},{}", bare, server_first);
+        self.state = ServerState::ClientFinal { nonce, auth_message, credentials };
+        AuthStep::Continue(server_first.into_bytes())
+    }
+
+    fn client_final(nonce: &str, auth_message: &str, credentials: &ScramCredentials, data: &str) -> AuthStep {
+        let (without_proof, proof) = match data.rsplit_once(",p=") {
+            Some((without_proof, proof)) => (without_proof, proof),
+            None => return AuthStep::Failure(ReasonPhrases::MalformedPacket)
+        };
+        if attribute(without_proof, 'c') != Some(&STANDARD.encode(GS2_HEADER)) || attribute(without_proof, 'r') != Some(nonce) {
+            return AuthStep::Failure(ReasonPhrases::NotAuthorized);
+        }
+        let proof = match STANDARD.decode(proof) {
+            Ok(proof) if proof.len() == credentials.stored_key.len() => proof,
+            _ => return AuthStep::Failure(ReasonPhrases::MalformedPacket)
+        };
+        let auth_message = format!("{},{}", auth_message, without_proof);
+        let client_signature = hmac(&credentials.stored_key, auth_message.as_bytes());
+        let client_key = xor(&proof, &client_signature);
+        if Sha256::digest(client_key).as_slice() != credentials.stored_key.as_slice() {
+            return AuthStep::Failure(ReasonPhrases::BadUserNameOrPassword);
+        }
+        let server_signature = hmac(&credentials.server_key, auth_message.as_bytes());
+        AuthStep::Success(Some(format!("v={}", STANDARD.encode(server_signature)).into_bytes()))
+    }
+}
+
+#[async_trait]
+impl AuthExchange for ServerExchange {
+    async fn step(&mut self, data: Option<&[u8]>) -> AuthStep {
+        let data = match data.map(std::str::from_utf8) {
+            Some(Ok(data)) => data,
+            _ => return AuthStep::Failure(ReasonPhrases::MalformedPacket)
+        };
+        match std::mem::replace(&mut self.state, ServerState::Done) {
+            ServerState::ClientFirst => self.client_first(data),
+            ServerState::ClientFinal { nonce, auth_message, credentials } => {
+                ServerExchange::client_final(&nonce, &auth_message, &credentials, data)
+            }
+            ServerState::Done => AuthStep::Failure(ReasonPhrases::ProtocolError)
+        }
+    }
+}
+
+///
+/// SCRAM-SHA-256 客户端
+///
+#[derive(Debug, Clone)]
+pub struct ScramSha256Client {
+    username: String,
+    password: String,
+}
+
+impl ScramSha256Client {
+    pub fn new<S: Into<String>>(username: S, password: S) -> ScramSha256Client {
+        ScramSha256Client { username: username.into(), password: password.into() }
+    }
+
+    fn exchange(&self, nonce: String) -> ClientExchange {
+        ClientExchange { username: self.username.clone(), password: self.password.clone(), nonce, state: ClientState::Initial }
+    }
+}
+
+impl AuthMechanism for ScramSha256Client {
+    fn method(&self) -> &str {
+        SCRAM_SHA_256
+    }
+
+    fn start(&self) -> Box<dyn AuthExchange> {
+        Box::new(self.exchange(nonce()))
+    }
+}
+
+enum ClientState {
+    Initial,
+    ServerFirst { bare: String },
+    ServerFinal { signature: Vec<u8> },
+    Done,
+}
+
+struct ClientExchange {
+    username: String,
+    password: String,
+    nonce: String,
+    state: ClientState,
+}
+
+impl ClientExchange {
+    fn server_first(&mut self, bare: &str, data: &str) -> AuthStep {
+        let (nonce, salt, iterations) = match (attribute(data, 'r'), attribute(data, 's'), attribute(data, 'i')) {
+            (Some(nonce), Some(salt), Some(iterations)) => (nonce, salt, iterations),
+            _ => return AuthStep::Failure(ReasonPhrases::MalformedPacket)
+        };
+        let (salt, iterations) = match (STANDARD.decode(salt), iterations.parse::<u32>()) {
+            (Ok(salt), Ok(iterations)) if nonce.starts_with(&self.nonce) && iterations > 0 => (salt, iterations),
+            _ => return AuthStep::Failure(ReasonPhrases::NotAuthorized)
+        };
+        let salted_password = salted_password(&self.password, &salt, iterations);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let without_proof = format!("c={},r={}", STANDARD.encode(GS2_HEADER), nonce);
+        let auth_message = format!("{},{},{}", bare, data, without_proof);
+        let proof = xor(&client_key, &hmac(&stored_key, auth_message.as_bytes()));
+        let signature = hmac(&hmac(&salted_password, b"Server Key"), auth_message.as_bytes());
+        self.state = ClientState::ServerFinal { signature };
+        AuthStep::Continue(format!("{},p={}", without_proof, STANDARD.encode(proof)).into_bytes())
+    }
+}
+
+#[async_trait]
+impl AuthExchange for ClientExchange {
+    async fn step(&mut self, data: Option<&[u8]>) -> AuthStep {
+        let data = data.map(String::from_utf8_lossy);
+        match (std::mem::replace(&mut self.state, ClientState::Done), data) {
+            (ClientState::Initial, None) => {
+                let bare = format!("n={},r={}", escape(&self.username), self.nonce);
+                let client_first = format!("{}{}", GS2_HEADER, bare);
+                self.state = ClientState::ServerFirst { bare };
+                AuthStep::Continue(client_first.into_bytes())
+            }
+            (ClientState::ServerFirst { bare }, Some(data)) => self.server_first(&bare, &data),
+            (ClientState::ServerFinal { signature }, Some(data)) => {
+                match attribute(&data, 'v').map(|v| STANDARD.decode(v)) {
+                    Some(Ok(v)) if v == signature => AuthStep::Success(None),
+                    _ => AuthStep::Failure(ReasonPhrases::NotAuthorized)
+                }
+            }
+            _ => AuthStep::Failure(ReasonPhrases::ProtocolError)
+        }
+    }
+}
+
+fn salted_password(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut salted = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted);
+    salted
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+fn nonce() -> String {
+    let mut bytes = [0; 18];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    STANDARD.encode(bytes)
+}
+
+///
+/// 读取 `a=value` 形式的属性
+///
+fn attribute(message: &str, name: char) -> Option<&str> {
+    message.split(',').find_map(|item| {
+        item.strip_prefix(name).and_then(|item| item.strip_prefix('='))
+    })
+}
+
+fn escape(username: &str) -> String {
+    username.replace('=', "=3D").replace(',', "=2C")
+}
+
+fn unescape(username: &str) -> String {
+    username.replace("=2C", ",").replace("=3D", "=")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rfc7677_vector() {
+        let client = ScramSha256Client::new("user", "pencil");
+        let mut exchange = client.exchange("rOprNGfwEbeRWgbNEkqO".to_owned());
+        assert_eq!(exchange.step(None).await, AuthStep::Continue(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO".to_vec()));
+        let server_first = b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let client_final = b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+        assert_eq!(exchange.step(Some(server_first)).await, AuthStep::Continue(client_final.to_vec()));
+        let server_final = b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+        assert_eq!(exchange.step(Some(server_final)).await, AuthStep::Success(None));
+
+        let salt = STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let server = ScramSha256Server::new().credentials("user", ScramCredentials::new("pencil", &salt, 4096));
+        let mut exchange = ServerExchange { users: server.users.clone(), nonce: "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_owned(), state: ServerState::ClientFirst };
+        assert_eq!(exchange.step(Some(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO")).await, AuthStep::Continue(server_first.to_vec()));
+        assert_eq!(exchange.step(Some(client_final)).await, AuthStep::Success(Some(server_final.to_vec())));
+    }
+
+    async fn run(client: &ScramSha256Client, server: &ScramSha256Server) -> AuthStep {
+        let mut client = client.start();
+        let mut server = server.start();
+        let mut data = match client.step(None).await {
+            AuthStep::Continue(data) => data,
+            step => return step
+        };
+        loop {
+            match server.step(Some(&data)).await {
+                AuthStep::Continue(challenge) => match client.step(Some(&challenge)).await {
+                    AuthStep::Continue(response) => data = response,
+                    step => return step
+                },
+                AuthStep::Success(Some(last)) => return client.step(Some(&last)).await,
+                step => return step
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn client_and_server() {
+        let server = ScramSha256Server::new().user("alice", "secret").user("a,b=c", "escaped");
+        assert_eq!(run(&ScramSha256Client::new("alice", "secret"), &server).await, AuthStep::Success(None));
+        assert_eq!(run(&ScramSha256Client::new("a,b=c", "escaped"), &server).await, AuthStep::Success(None));
+        assert_eq!(run(&ScramSha256Client::new("alice", "wrong"), &server).await, AuthStep::Failure(ReasonPhrases::BadUserNameOrPassword));
+        assert_eq!(run(&ScramSha256Client::new("bob", "secret"), &server).await, AuthStep::Failure(ReasonPhrases::BadUserNameOrPassword));
+    }
+}
@@ -1,56 +1,275 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio::time::Instant;
+use crate::message::entity::PublishMessage;
 use crate::subscript::ClientID;
+use crate::tools::protocol::{MqttDup, MqttQos};
 
+///
+/// 持久会话离线期间最多保存的消息数量
+///
+pub const MAX_QUEUED_MESSAGES: usize = 1000;
+
+///
+/// 出站消息所处的确认阶段
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FrameState {
+    /// 已发送 PUBLISH, 等待 PUBACK (QoS 1) 或 PUBREC (QoS 2)
+    Publish,
+    /// 已收到 PUBREC 并发送 PUBREL, 等待 PUBCOMP
+    Pubrel,
+}
+
+///
+/// 单个客户端的飞行窗口: 出站 QoS 1/2 消息, 等待 PUBREL 的入站 QoS 2 报文标识符, 以及离线期间排队的消息
+///
+#[derive(Default)]
 pub struct ClientMessageFrames {
-    frames: HashMap<u16, MessageFrame>,
+    next_id: u16,
+    frames: Vec<MessageFrame>,
+    received: HashSet<u16>,
+    /// 等待 SUBACK / UNSUBACK 的报文标识符, 与出站消息共用同一个标识符空间
+    reserved: HashSet<u16>,
+    /// 连接中断期间记录的出站消息, 还没有发送过
+    unsent: HashSet<u16>,
+    queue: VecDeque<(ClientID, PublishMessage)>,
 }
 
 impl ClientMessageFrames {
     pub fn new() -> ClientMessageFrames {
-        ClientMessageFrames { frames: HashMap::default() }
+        ClientMessageFrames::default()
+    }
+
+    ///
+    /// 分配一个未被占用的报文标识符, 全部被占用时返回 `None`
+    ///
+    fn next_message_id(&mut self) -> Option<u16> {
+        if self.frames.len() + self.reserved.len() >= u16::MAX as usize {
+            return None;
+        }
+        loop {
+            self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+            if !self.reserved.contains(&self.next_id) && !self.frames.iter().any(|frame| frame.message_id == self.next_id) {
+                return Some(self.next_id);
+            }
+        }
+    }
+
+    ///
+    /// 为 SUBSCRIBE / UNSUBSCRIBE 分配报文标识符, 收到对应的确认前不会再分配给其他报文
+    ///
+    pub fn reserve(&mut self) -> Option<u16> {
+        let message_id = self.next_message_id()?;
+        self.reserved.insert(message_id);
+        Some(message_id)
+    }
+
+    ///
+    /// 收到 SUBACK / UNSUBACK, 释放报文标识符
+    ///
+    pub fn unreserve(&mut self, message_id: u16) -> bool {
+        self.reserved.remove(&message_id)
+    }
+
+    ///
+    /// 连接中断, 已发送的 SUBSCRIBE / UNSUBSCRIBE 不会再收到确认
+    ///
+    pub fn clear_reserved(&mut self) {
+        self.reserved.clear();
+    }
+
+    ///
+    /// 记录一条出站消息并分配报文标识符, QoS 0 消息原样返回
+    ///
+    pub fn append(&mut self, from: ClientID, to: ClientID, mut msg: PublishMessage) -> Option<PublishMessage> {
+        if msg.qos == MqttQos::Qos0 {
+            msg.message_id = 0;
+            return Some(msg);
+        }
+        msg.message_id = self.next_message_id()?;
+        msg.dup = MqttDup::Disable;
+        self.frames.push(MessageFrame::new(from, to, msg.clone()));
+        Some(msg)
+    }
+
+    ///
+    /// 连接中断期间记录的消息, 重连后第一次发送时不设置 DUP 标志
+    ///
+    pub fn defer(&mut self, message_id: u16) {
+        self.unsent.insert(message_id);
+    }
+
+    ///
+    /// 收到 PUBACK, QoS 1 流程结束
+    ///
+    pub fn puback(&mut self, message_id: u16) -> Option<MessageFrame> {
+        let index = self.frames.iter().position(|frame| {
+            frame.message_id == message_id && frame.msg.qos == MqttQos::Qos1
+        })?;
+        Some(self.frames.remove(index))
+    }
+
+    ///
+    /// 收到 PUBREC, 进入等待 PUBCOMP 阶段; 返回 `false` 表示没有对应的消息
+    ///
+    pub fn pubrec(&mut self, message_id: u16) -> bool {
+        match self.frames.iter_mut().find(|frame| frame.message_id == message_id && frame.msg.qos == MqttQos::Qos2) {
+            Some(frame) => {
+                frame.state = FrameState::Pubrel;
+                frame.timestamp = Instant::now();
+                true
+            }
+            None => false
+        }
     }
 
-    pub fn append(&mut self, message_id: u16, frame: MessageFrame) -> Option<MessageFrame> {
-        self.frames.insert(message_id, frame)
+    ///
+    /// 收到 PUBCOMP 或失败的 PUBREC, QoS 2 流程结束
+    ///
+    pub fn pubcomp(&mut self, message_id: u16) -> Option<MessageFrame> {
+        let index = self.frames.iter().position(|frame| {
+            frame.message_id == message_id && frame.msg.qos == MqttQos::Qos2
+        })?;
+        Some(self.frames.remove(index))
     }
 
-    fn remove(&mut self, message_id: u16) -> Option<MessageFrame> {
-        self.frames.remove(&message_id)
+    ///
+    /// 记录入站 QoS 2 报文标识符, 返回 `false` 表示该报文已经收到过 (重复投递)
+    ///
+    pub fn receive(&mut self, message_id: u16) -> bool {
+        self.received.insert(message_id)
     }
 
-    fn complete(&mut self, message_id: u16) {
-        if let Some(frame) = self.frames.get_mut(&message_id) {
-            frame.complete();
+    ///
+    /// 收到 PUBREL, 释放入站 QoS 2 报文标识符
+    ///
+    pub fn release(&mut self, message_id: u16) -> bool {
+        self.received.remove(&message_id)
+    }
+
+    ///
+    /// 离线期间的 QoS 1/2 消息进入队列, QoS 0 消息以及超出队列上限的消息被丢弃
+    ///
+    pub fn enqueue(&mut self, from: ClientID, msg: PublishMessage) -> bool {
+        if msg.qos == MqttQos::Qos0 || self.queue.len() >= MAX_QUEUED_MESSAGES {
+            return false;
         }
+        self.queue.push_back((from, msg));
+        true
+    }
+
+    ///
+    /// 取出全部排队的消息
+    ///
+    pub fn dequeue(&mut self) -> Vec<(ClientID, PublishMessage)> {
+        self.queue.drain(..).collect()
+    }
+
+    ///
+    /// 重连后按原顺序重发全部未完成的消息
+    ///
+    pub fn resend(&mut self) -> Vec<MessageFrame> {
+        let unsent = std::mem::take(&mut self.unsent);
+        self.frames.iter_mut()
+            .map(|frame| if unsent.contains(&frame.message_id) { frame.clone() } else { frame.resend() })
+            .collect()
+    }
+
+    ///
+    /// 服务端没有保留会话: 丢弃已经发送过的出站消息与入站 QoS 2 状态, 返回被丢弃的消息
+    ///
+    pub fn reset(&mut self) -> Vec<MessageFrame> {
+        self.received.clear();
+        let (frames, dropped) = std::mem::take(&mut self.frames).into_iter()
+            .partition(|frame| self.unsent.contains(&frame.message_id));
+        self.frames = frames;
+        dropped
+    }
+
+    ///
+    /// 重发超过 `timeout` 仍未确认的消息
+    ///
+    pub fn expired(&mut self, timeout: Duration) -> Vec<MessageFrame> {
+        self.frames.iter_mut()
+            .filter(|frame| frame.timestamp.elapsed() >= timeout)
+            .map(|frame| frame.resend())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    ///
+    /// 出站与入站的 QoS 1/2 流程都已完成
+    ///
+    pub fn is_settled(&self) -> bool {
+        self.frames.is_empty() && self.received.is_empty()
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct MessageFrame {
     from: ClientID,
     to: ClientID,
     message_id: u16,
-    bytes: Vec<u8>,
-    send: bool,
+    msg: PublishMessage,
+    state: FrameState,
+    timestamp: Instant,
 }
 
 impl MessageFrame {
-    pub fn new(from: ClientID, to: ClientID, bytes: Vec<u8>, message_id: u16) -> Self {
+    pub fn new(from: ClientID, to: ClientID, msg: PublishMessage) -> Self {
         MessageFrame {
             from,
             to,
-            message_id,
-            bytes,
-            send: false,
+            message_id: msg.message_id,
+            msg,
+            state: FrameState::Publish,
+            timestamp: Instant::now(),
         }
     }
 
-    pub fn complete(&mut self) {
-        self.send = true
+    pub fn from(&self) -> &ClientID {
+        &self.from
+    }
+
+    pub fn to(&self) -> &ClientID {
+        &self.to
+    }
+
+    pub fn message_id(&self) -> u16 {
+        self.message_id
+    }
+
+    pub fn state(&self) -> FrameState {
+        self.state
+    }
+
+    pub fn message(&self) -> &PublishMessage {
+        &self.msg
+    }
+
+    ///
+    /// 标记重发: PUBLISH 阶段设置 DUP 标志
+    ///
+    fn resend(&mut self) -> MessageFrame {
+        if self.state == FrameState::Publish {
+            self.msg.dup = MqttDup::Enable;
+        }
+        self.timestamp = Instant::now();
+        self.clone()
     }
 }
 
+#[derive(Default)]
 pub struct MessageContainer {
     inner: Arc<Mutex<HashMap<ClientID, ClientMessageFrames>>>,
 }
@@ -60,31 +279,180 @@ impl MessageContainer {
         MessageContainer { inner: Arc::new(Mutex::new(HashMap::default())) }
     }
 
-    pub async fn init(&self, client_id: ClientID){
-        if !self.inner.lock().await.contains_key(&client_id) {
-            self.inner.lock().await.insert(client_id, ClientMessageFrames::new());
-        }
+    pub async fn init(&self, client_id: ClientID) {
+        self.inner.lock().await.entry(client_id).or_default();
     }
 
-    pub async fn append(&self, client_id: ClientID, message_id: u16, frame: MessageFrame) {
-        if self.inner.lock().await.contains_key(&client_id) {
-            if let Some(frames) = self.inner.lock().await.get_mut(&client_id) {
-                frames.append(message_id, frame);
-            }
-        } else {
-            let mut frames = ClientMessageFrames::new();
-            frames.append(message_id, frame);
-            self.inner.lock().await.insert(client_id, frames);
-        }
+    pub async fn append(&self, client_id: &ClientID, from: ClientID, msg: PublishMessage) -> Option<PublishMessage> {
+        self.inner.lock().await
+            .entry(client_id.clone())
+            .or_default()
+            .append(from, client_id.clone(), msg)
     }
 
     pub async fn remove(&self, client_id: &ClientID) -> Option<ClientMessageFrames> {
         self.inner.lock().await.remove(client_id)
     }
 
-    pub async fn complete(&self, client_id: &ClientID, message_id: u16) {
-        if let Some(frames) = self.inner.lock().await.get_mut(client_id) {
-            frames.complete(message_id)
+    pub async fn puback(&self, client_id: &ClientID, message_id: u16) -> Option<MessageFrame> {
+        self.inner.lock().await.get_mut(client_id)?.puback(message_id)
+    }
+
+    pub async fn pubrec(&self, client_id: &ClientID, message_id: u16) -> bool {
+        self.inner.lock().await.get_mut(client_id).is_some_and(|frames| frames.pubrec(message_id))
+    }
+
+    pub async fn pubcomp(&self, client_id: &ClientID, message_id: u16) -> Option<MessageFrame> {
+        self.inner.lock().await.get_mut(client_id)?.pubcomp(message_id)
+    }
+
+    pub async fn receive(&self, client_id: &ClientID, message_id: u16) -> bool {
+        self.inner.lock().await.entry(client_id.clone()).or_default().receive(message_id)
+    }
+
+    pub async fn release(&self, client_id: &ClientID, message_id: u16) -> bool {
+        self.inner.lock().await.get_mut(client_id).is_some_and(|frames| frames.release(message_id))
+    }
+
+    ///
+    /// 只有已存在的会话才会排队, 清理会话断开后的消息直接丢弃
+    ///
+    pub async fn enqueue(&self, client_id: &ClientID, from: ClientID, msg: PublishMessage) -> bool {
+        self.inner.lock().await.get_mut(client_id).is_some_and(|frames| frames.enqueue(from, msg))
+    }
+
+    pub async fn dequeue(&self, client_id: &ClientID) -> Vec<(ClientID, PublishMessage)> {
+        self.inner.lock().await.get_mut(client_id).map(|frames| frames.dequeue()).unwrap_or_default()
+    }
+
+    pub async fn resend(&self, client_id: &ClientID) -> Vec<MessageFrame> {
+        self.inner.lock().await.get_mut(client_id).map(|frames| frames.resend()).unwrap_or_default()
+    }
+
+    pub async fn expired(&self, client_id: &ClientID, timeout: Duration) -> Vec<MessageFrame> {
+        self.inner.lock().await.get_mut(client_id).map(|frames| frames.expired(timeout)).unwrap_or_default()
+    }
+
+    ///
+    /// 未完成的出站消息与离线消息的副本
+    ///
+    pub async fn snapshot(&self, client_id: &ClientID) -> (Vec<MessageFrame>, Vec<(ClientID, PublishMessage)>) {
+        match self.inner.lock().await.get(client_id) {
+            Some(frames) => (frames.frames.clone(), frames.queue.iter().cloned().collect()),
+            None => (vec![], vec![])
         }
     }
+
+    pub async fn is_settled(&self, client_id: &ClientID) -> bool {
+        self.inner.lock().await.get(client_id).is_none_or(|frames| frames.is_settled())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::protocol::MqttRetain;
+
+    fn message(qos: MqttQos) -> PublishMessage {
+        PublishMessage::new(qos, MqttDup::Disable, MqttRetain::Disable, "inflight/test".to_owned(), 42, "1".to_owned(), None)
+    }
+
+    #[test]
+    fn assign_and_free_message_id() {
+        let mut frames = ClientMessageFrames::new();
+        let qos0 = frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos0)).unwrap();
+        assert_eq!(qos0.message_id, 0);
+        assert!(frames.is_empty());
+
+        let first = frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos1)).unwrap();
+        let second = frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos2)).unwrap();
+        assert_eq!(first.message_id, 1);
+        assert_eq!(second.message_id, 2);
+
+        assert!(frames.puback(second.message_id).is_none());
+        assert!(frames.puback(first.message_id).is_some());
+        assert!(frames.pubrec(second.message_id));
+        assert_eq!(frames.resend()[0].state(), FrameState::Pubrel);
+        assert!(frames.pubcomp(second.message_id).is_some());
+        assert!(frames.is_empty());
+
+        frames.next_id = u16::MAX - 1;
+        frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos1)).unwrap();
+        let wrapped = frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos1)).unwrap();
+        assert_eq!(wrapped.message_id, 1);
+    }
+
+    #[test]
+    fn reserve_message_id() {
+        let mut frames = ClientMessageFrames::new();
+        let subscribe = frames.reserve().unwrap();
+        let publish = frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos1)).unwrap();
+        assert_ne!(publish.message_id, subscribe);
+
+        frames.next_id = 0;
+        assert_eq!(frames.reserve(), Some(3));
+        assert!(frames.unreserve(subscribe));
+        assert!(!frames.unreserve(subscribe));
+        frames.clear_reserved();
+        frames.next_id = 0;
+        assert_eq!(frames.reserve(), Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resend_with_dup() {
+        let mut frames = ClientMessageFrames::new();
+        frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos1)).unwrap();
+        frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos2)).unwrap();
+        assert!(frames.expired(Duration::from_secs(10)).is_empty());
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let expired = frames.expired(Duration::from_secs(10));
+        assert_eq!(expired.len(), 2);
+        assert!(expired.iter().all(|frame| frame.message().dup == MqttDup::Enable));
+        assert!(frames.expired(Duration::from_secs(10)).is_empty());
+
+        let resend = frames.resend();
+        assert_eq!(resend.iter().map(|frame| frame.message_id()).collect::<Vec<u16>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn reset_sent_messages() {
+        let mut frames = ClientMessageFrames::new();
+        frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos1)).unwrap();
+        let unsent = frames.append(ClientID::from("a"), ClientID::from("b"), message(MqttQos::Qos2)).unwrap();
+        frames.defer(unsent.message_id);
+        assert!(frames.receive(7));
+
+        let dropped = frames.reset();
+        assert_eq!(dropped.iter().map(|frame| frame.message_id()).collect::<Vec<u16>>(), vec![1]);
+        assert!(frames.receive(7));
+        let resend = frames.resend();
+        assert_eq!(resend.len(), 1);
+        assert_eq!(resend[0].message().dup, MqttDup::Disable);
+        assert_eq!(frames.resend()[0].message().dup, MqttDup::Enable);
+    }
+
+    #[test]
+    fn queue_offline_messages() {
+        let mut frames = ClientMessageFrames::new();
+        assert!(!frames.enqueue(ClientID::from("a"), message(MqttQos::Qos0)));
+        for _ in 0..MAX_QUEUED_MESSAGES {
+            assert!(frames.enqueue(ClientID::from("a"), message(MqttQos::Qos1)));
+        }
+        assert!(!frames.enqueue(ClientID::from("a"), message(MqttQos::Qos2)));
+        assert_eq!(frames.dequeue().len(), MAX_QUEUED_MESSAGES);
+        assert!(frames.dequeue().is_empty());
+    }
+
+    #[test]
+    fn receive_qos2_once() {
+        let mut frames = ClientMessageFrames::new();
+        assert!(frames.receive(7));
+        assert!(!frames.receive(7));
+        assert!(!frames.is_settled());
+        assert!(frames.release(7));
+        assert!(frames.is_settled());
+        assert!(!frames.release(7));
+        assert!(frames.receive(7));
+    }
 }
@@ -0,0 +1,199 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::sync::watch;
+use crate::executor::MqttServerOption;
+use crate::tools::config::ServerConfig;
+use crate::tools::protocol::MqttProtocolLevel;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerProtocol {
+    Tcp,
+    Tls,
+    WebSocket,
+    WebSocketTls,
+    Unix,
+}
+
+impl ListenerProtocol {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, ListenerProtocol::Tls | ListenerProtocol::WebSocketTls)
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        matches!(self, ListenerProtocol::WebSocket | ListenerProtocol::WebSocketTls)
+    }
+}
+
+impl fmt::Display for ListenerProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerProtocol::Tcp => write!(f, "tcp"),
+            ListenerProtocol::Tls => write!(f, "tls"),
+            ListenerProtocol::WebSocket => write!(f, "ws"),
+            ListenerProtocol::WebSocketTls => write!(f, "wss"),
+            ListenerProtocol::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerAddr {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenerAddr {
+    pub fn inet(&self) -> Option<SocketAddr> {
+        match self {
+            ListenerAddr::Inet(addr) => Some(*addr),
+            ListenerAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ListenerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerAddr::Inet(addr) => write!(f, "{}", addr),
+            ListenerAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+///
+/// 监听定义, 同一个服务端的所有监听共享订阅、会话与保留消息
+///
+#[derive(Debug, Clone)]
+pub struct Listener {
+    protocol: ListenerProtocol,
+    addr: ListenerAddr,
+    pub(crate) option: Option<MqttServerOption>,
+    pub(crate) websocket_path: String,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) permissions: Option<u32>,
+    protocol_levels: Option<Vec<MqttProtocolLevel>>,
+}
+
+impl Listener {
+    pub fn new(protocol: ListenerProtocol, addr: SocketAddr) -> Listener {
+        Listener::bind(protocol, ListenerAddr::Inet(addr))
+    }
+
+    fn bind(protocol: ListenerProtocol, addr: ListenerAddr) -> Listener {
+        Listener {
+            protocol,
+            addr,
+            option: None,
+            websocket_path: String::from("/mqtt"),
+            max_connections: None,
+            permissions: None,
+            protocol_levels: None,
+        }
+    }
+
+    pub fn tcp(addr: SocketAddr) -> Listener {
+        Listener::new(ListenerProtocol::Tcp, addr)
+    }
+
+    pub fn tls(addr: SocketAddr, option: MqttServerOption) -> Listener {
+        Listener::new(ListenerProtocol::Tls, addr).option(option)
+    }
+
+    pub fn websocket(addr: SocketAddr) -> Listener {
+        Listener::new(ListenerProtocol::WebSocket, addr)
+    }
+
+    pub fn websocket_tls(addr: SocketAddr, option: MqttServerOption) -> Listener {
+        Listener::new(ListenerProtocol::WebSocketTls, addr).option(option)
+    }
+
+    ///
+    /// Unix 套接字监听, 启动时清理遗留的套接字文件
+    ///
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Listener {
+        Listener::bind(ListenerProtocol::Unix, ListenerAddr::Unix(path.into()))
+    }
+
+    ///
+    /// TLS 证书配置, 只用于 tls 与 wss 监听
+    ///
+    pub fn option(mut self, option: MqttServerOption) -> Listener {
+        self.option = Some(option);
+        self
+    }
+
+    ///
+    /// WebSocket 监听的请求路径, 默认为 `/mqtt`
+    ///
+    pub fn websocket_path<S: Into<String>>(mut self, path: S) -> Listener {
+        self.websocket_path = path.into();
+        self
+    }
+
+    ///
+    /// 最大连接数, 超过时新连接被直接关闭
+    ///
+    pub fn max_connections(mut self, max_connections: usize) -> Listener {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    ///
+    /// Unix 套接字文件的权限, 例如 `0o660`
+    ///
+    pub fn permissions(mut self, mode: u32) -> Listener {
+        self.permissions = Some(mode);
+        self
+    }
+
+    ///
+    /// 允许的协议版本, CONNECT 使用其他版本时回复 CONNACK 0x01 (v3) 或 0x84 (v5)
+    ///
+    pub fn protocol_levels(mut self, protocol_levels: Vec<MqttProtocolLevel>) -> Listener {
+        self.protocol_levels = Some(protocol_levels);
+        self
+    }
+
+    pub fn protocol(&self) -> ListenerProtocol {
+        self.protocol
+    }
+
+    pub fn addr(&self) -> &ListenerAddr {
+        &self.addr
+    }
+
+    ///
+    /// 在服务端配置的基础上应用监听自身的设置
+    ///
+    pub(crate) fn config(&self, config: &ServerConfig) -> ServerConfig {
+        ServerConfig { protocol_levels: self.protocol_levels.clone(), ..config.clone() }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", self.protocol, self.addr)
+    }
+}
+
+///
+/// 服务端已经开始接受连接的监听地址, 通过 `MqttServer::local_addrs` 获取
+///
+#[derive(Debug, Clone)]
+pub struct LocalAddrs(watch::Receiver<Vec<(ListenerProtocol, ListenerAddr)>>);
+
+impl LocalAddrs {
+    pub(crate) fn new(receiver: watch::Receiver<Vec<(ListenerProtocol, ListenerAddr)>>) -> LocalAddrs {
+        LocalAddrs(receiver)
+    }
+
+    ///
+    /// 等待 `protocol` 的监听开始接受连接, 返回实际绑定的地址, 端口为 0 时为系统分配的端口.
+    /// 服务端结束前没有该协议的监听时返回 `None`
+    ///
+    pub async fn wait(&mut self, protocol: ListenerProtocol) -> Option<ListenerAddr> {
+        let addrs = self.0.wait_for(|addrs| addrs.iter().any(|(bound, _)| *bound == protocol)).await.ok()?;
+        addrs.iter().find(|(bound, _)| *bound == protocol).map(|(_, addr)| addr.clone())
+    }
+}
@@ -1,30 +1,110 @@
 use std::path::PathBuf;
+use std::time::Duration;
+use bytes::Bytes;
 
+pub mod listener;
 pub mod v3_client;
 pub mod v3_server;
 
 pub enum ReturnKind {
     Response(Vec<u8>),
+    /// 转发的 PUBLISH, 第二段为多个订阅者共享的属性与载荷, 写出时不复制
+    Publish(Vec<u8>, Bytes),
     Exit,
 }
 
+///
+/// 客户端连接状态, 通过 `MqttClient::connection_state` 获取变化
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConnectionState {
+    /// 正在建立首次连接
+    Connecting,
+    /// 已收到成功的 CONNACK
+    Connected,
+    /// 连接中断, 正在进行第 n 次重连
+    Reconnecting(u32),
+    /// 连接已关闭, 不再重连
+    Disconnected,
+}
+
 pub struct MqttClientOption {
     cert: PathBuf,
+    client_cert: Option<(PathBuf, PathBuf)>,
+    server_name: Option<String>,
+    alpn_protocols: Vec<Vec<u8>>,
 }
 
 impl MqttClientOption {
+    ///
+    /// `cert` 为用于校验服务端证书的 CA 证书
+    ///
     pub fn new(cert: String) -> MqttClientOption {
-        MqttClientOption { cert: PathBuf::from(cert) }
+        MqttClientOption { cert: PathBuf::from(cert), client_cert: None, server_name: None, alpn_protocols: vec![] }
+    }
+
+    ///
+    /// 双向认证时提供给服务端的客户端证书与私钥
+    ///
+    pub fn client_cert(mut self, cert: String, key: String) -> MqttClientOption {
+        self.client_cert = Some((PathBuf::from(cert), PathBuf::from(key)));
+        self
+    }
+
+    ///
+    /// 用于 SNI 与证书校验的服务端名称, 未设置时使用连接地址中的 IP
+    ///
+    pub fn server_name(mut self, server_name: String) -> MqttClientOption {
+        self.server_name = Some(server_name);
+        self
+    }
+
+    ///
+    /// 按优先级添加 ALPN 协议, 例如 `mqtt`
+    ///
+    pub fn alpn_protocol(mut self, protocol: String) -> MqttClientOption {
+        self.alpn_protocols.push(protocol.into_bytes());
+        self
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct MqttServerOption {
     cert: PathBuf,
     key: PathBuf,
+    client_ca: Option<PathBuf>,
+    client_cert_required: bool,
+    reload_interval: Option<Duration>,
 }
 
 impl MqttServerOption {
     pub fn new(cert: String, key: String) -> MqttServerOption {
-        MqttServerOption { cert: PathBuf::from(cert), key: PathBuf::from(key) }
+        MqttServerOption { cert: PathBuf::from(cert), key: PathBuf::from(key), client_ca: None, client_cert_required: false, reload_interval: None }
+    }
+
+    ///
+    /// 请求客户端证书, 客户端提供的证书必须由 `ca` 中的证书签发, 也可以不提供证书
+    ///
+    pub fn request_client_cert(mut self, ca: String) -> MqttServerOption {
+        self.client_ca = Some(PathBuf::from(ca));
+        self.client_cert_required = false;
+        self
+    }
+
+    ///
+    /// 要求客户端提供由 `ca` 中的证书签发的证书, 否则握手失败
+    ///
+    pub fn require_client_cert(mut self, ca: String) -> MqttServerOption {
+        self.client_ca = Some(PathBuf::from(ca));
+        self.client_cert_required = true;
+        self
+    }
+
+    ///
+    /// 每隔 `interval` 检查证书与私钥文件, 有变化时重新加载, 不影响已建立的连接
+    ///
+    pub fn reload_interval(mut self, interval: Duration) -> MqttServerOption {
+        self.reload_interval = Some(interval);
+        self
     }
 }
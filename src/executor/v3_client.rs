@@ -1,24 +1,42 @@
 use std::convert::TryFrom;
+use std::fmt;
 use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpSocket, TcpStream};
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::Sender;
-use tokio_rustls::rustls::OwnedTrustAnchor;
-use tokio_rustls::{rustls, webpki, TlsConnector};
-use crate::executor::{MqttClientOption, ReturnKind};
+use bytes::{Buf, Bytes};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tokio_rustls::{rustls, TlsConnector};
+use crate::auth::AuthMechanism;
+use crate::executor::{ConnectionState, MqttClientOption, ReturnKind};
 use crate::handle::{HandleEvent, ClientExecute, Response};
 use crate::handle::v3_client_handle::ClientHandleV3;
 use crate::message::MqttMessageKind;
-use crate::message::entity::{ConnectMessage, PublishMessage, SubscribeMessage, UnsubscribeMessage};
+use crate::message::entity::{ConnectMessage, DisconnectMessage, PublishMessage};
+use crate::hex::{find_property, Property, PropertyItem, PropertyValue};
 use crate::message::v3::MqttMessageV3;
-use crate::session::ClientSession;
+use crate::message::v5::MqttMessageV5;
+use crate::session::{AckFuture, ClientSession, MqttSession, PublishAck, Subscription, UnsubscribeAck, DEFAULT_ACK_TIMEOUT};
 use crate::tools::config::Config;
-use crate::tools::protocol::{MqttCleanSession, MqttDup, MqttQos, MqttRetain};
+use crate::tools::error::{AckError, RequestError};
+use crate::tools::framer::{PacketFramer, MAX_PACKET_SIZE};
+use crate::tools::protocol::{MqttDup, MqttProtocolLevel, MqttQos, MqttRetain};
+use crate::tools::tls::{client_load_certs, load_certs, load_private_key};
+
+///
+/// 重连等待时间的上限
+///
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
 
 pub struct MqttClient<F, Fut>
     where
@@ -28,8 +46,12 @@ pub struct MqttClient<F, Fut>
     config: Config,
     address: SocketAddr,
     handle: Option<Box<F>>,
-    sender: Option<Sender<HandleEvent>>,
+    session: Option<ClientSession>,
     option: Option<MqttClientOption>,
+    max_packet_size: usize,
+    auth_mechanism: Option<Arc<dyn AuthMechanism>>,
+    ack_timeout: Duration,
+    state: watch::Sender<ConnectionState>,
 }
 
 impl<F, Fut> MqttClient<F, Fut>
@@ -38,7 +60,29 @@ impl<F, Fut> MqttClient<F, Fut>
         Fut: Future<Output=()> + Send,
 {
     pub fn new(config: Config, address: SocketAddr) -> MqttClient<F, Fut> {
-        MqttClient { config, address, handle: None, sender: None, option: None }
+        let (state, _) = watch::channel(ConnectionState::Disconnected);
+        MqttClient { config, address, handle: None, session: None, option: None, max_packet_size: MAX_PACKET_SIZE, auth_mechanism: None, ack_timeout: DEFAULT_ACK_TIMEOUT, state }
+    }
+
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> MqttClient<F, Fut> {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    ///
+    /// v5 增强认证方法, 连接时在 CONNECT 中携带认证方法与初始认证数据
+    ///
+    pub fn auth_mechanism<A: AuthMechanism + 'static>(mut self, mechanism: A) -> MqttClient<F, Fut> {
+        self.auth_mechanism = Some(Arc::new(mechanism));
+        self
+    }
+
+    ///
+    /// 等待 PUBACK / PUBCOMP / SUBACK / UNSUBACK 的超时时间
+    ///
+    pub fn ack_timeout(mut self, ack_timeout: Duration) -> MqttClient<F, Fut> {
+        self.ack_timeout = ack_timeout;
+        self
     }
 
     pub fn option(mut self, option: MqttClientOption) -> MqttClient<F, Fut> {
@@ -51,115 +95,191 @@ impl<F, Fut> MqttClient<F, Fut>
         self
     }
 
-    pub async fn publish(&self, topic: String, message: String, qos: MqttQos, dup: MqttDup, retain: MqttRetain) {
-        if let Some(sender) = self.sender.as_ref() {
-            let msg = MqttMessageV3::Publish(PublishMessage::new(qos, dup, retain, topic, 0, message, None))
-                .to_vec()
-                .unwrap();
-            sender.send(
-                HandleEvent::OutputEvent(
-                    Response(msg, self.config.protocol_level())
-                )
-            ).await;
+    ///
+    /// 连接状态的变化: 连接成功, 断开后重连, 以及不再重连
+    ///
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    ///
+    /// 发布消息, 返回等待确认的 future: QoS 1 完成于 PUBACK, QoS 2 完成于 PUBCOMP.
+    /// QoS 1/2 消息在确认前会在重连后重发; 重连时服务端没有保留会话则以 `AckError::ConnectionLost` 失败
+    ///
+    pub async fn publish<B: Into<Bytes>>(&self, topic: String, message: B, qos: MqttQos, dup: MqttDup, retain: MqttRetain) -> AckFuture<PublishAck> {
+        match self.session.as_ref() {
+            Some(session) => session.publish_with_ack(&PublishMessage::new(qos, dup, retain, topic, 0, message, None)).await,
+            None => AckFuture::ready(Err(AckError::NotConnected))
         }
     }
 
-    pub async fn subscribe(&self, topic: String, qos: MqttQos) {
-        if let Some(sender) = self.sender.as_ref() {
-            let msg = MqttMessageV3::Subscribe(SubscribeMessage::new(0, topic, qos))
-                .to_vec()
-                .unwrap();
-            sender.send(
-                HandleEvent::OutputEvent(
-                    Response(msg, self.config.protocol_level())
-                )
-            ).await;
+    ///
+    /// 订阅主题过滤器, 返回只包含匹配消息的订阅流, 授予的 QoS 通过 `Subscription::suback` 获取;
+    /// 重连后自动重新订阅, 订阅流释放时取消订阅
+    ///
+    pub async fn subscribe(&self, topic: String, qos: MqttQos) -> Option<Subscription> {
+        Some(self.session.as_ref()?.subscribe_stream(&topic, qos).await)
+    }
+
+    ///
+    /// 取消订阅, 返回等待 UNSUBACK 的 future
+    ///
+    pub async fn unsubscribe(&self, topic: String) -> AckFuture<UnsubscribeAck> {
+        match self.session.as_ref() {
+            Some(session) => session.unsubscribe(&topic).await,
+            None => AckFuture::ready(Err(AckError::NotConnected))
         }
     }
 
-    pub async fn unsubscribe(&self, topic: String) {
-        if let Some(sender) = self.sender.as_ref() {
-            let msg = MqttMessageV3::Unsubscribe(UnsubscribeMessage::new(0, topic))
-                .to_vec()
-                .unwrap();
-            sender.send(
-                HandleEvent::OutputEvent(
-                    Response(msg, self.config.protocol_level())
-                )
-            ).await;
+    ///
+    /// 使用相同的认证方法发起 v5 重新认证 (AUTH 0x19)
+    ///
+    pub async fn reauthenticate(&self) {
+        if let Some(session) = self.session.as_ref() {
+            session.send_event(HandleEvent::ReAuthEvent).await;
         }
     }
 
-    pub async fn disconnect(&self) {
-        if let Some(sender) = self.sender.as_ref() {
-            sender.send(HandleEvent::ExitEvent(true)).await;
+    ///
+    /// 发送 v5 请求并等待响应, 响应通过 ResponseTopic 与 CorrelationData 匹配
+    ///
+    pub async fn request<B: Into<Bytes>>(&self, topic: String, payload: B, timeout: Duration) -> Result<PublishMessage, RequestError> {
+        match self.session.as_ref() {
+            Some(session) => session.request(&topic, payload, timeout).await,
+            None => Err(RequestError::NotConnected)
         }
     }
 
-    async fn init(&self) -> TcpStream {
-        let socket = TcpSocket::new_v4().unwrap();
-        socket.connect(self.address).await.unwrap()
+    ///
+    /// 回复收到的请求
+    ///
+    pub async fn respond<B: Into<Bytes>>(&self, request: &PublishMessage, payload: B) -> Result<(), RequestError> {
+        match self.session.as_ref() {
+            Some(session) => session.respond(request, payload).await,
+            None => Err(RequestError::NotConnected)
+        }
+    }
+
+    pub async fn disconnect(&self) {
+        if let Some(session) = self.session.as_ref() {
+            session.exit().await;
+        }
     }
 
     fn init_handle(&mut self) -> ClientHandleV3 {
         let (sender, receiver) = mpsc::channel(512);
-        self.sender = Some(sender.clone());
-        ClientHandleV3::new(
-            ClientSession::new(
-                self.config.client_id().clone(),
-                self.config.protocol_level(),
-                sender,
-            ),
-            receiver,
-        )
-    }
-
-    // fn tls_connector(&self) -> Option<TlsConnector> {
-    //     if self.option.is_none() { return None; }
-    //     let mut root_cert_store = rustls::RootCertStore::empty();
-    //     let certs = client_load_certs(&self.option.as_ref().unwrap().cert).expect("load certs error");
-    //     let trust_anchors = certs.iter().map(|cert| {
-    //         let ta = webpki::TrustAnchor::try_from_cert_der(&cert[..]).unwrap();
-    //         OwnedTrustAnchor::from_subject_spki_name_constraints(
-    //             ta.subject,
-    //             ta.spki,
-    //             ta.name_constraints,
-    //         )
-    //     });
-    //     root_cert_store.add_server_trust_anchors(trust_anchors);
-    //     let config = rustls::ClientConfig::builder()
-    //         .with_safe_defaults()
-    //         .with_root_certificates(root_cert_store)
-    //         .with_no_client_auth();
-    //     Some(TlsConnector::from(Arc::new(config)))
-    // }
-    //
-    // pub async fn connect_with_tls(&mut self) {
-    //     if self.handle.is_none() { return; }
-    //     if let Some(connector) = self.tls_connector() {
-    //         let handle_message = **self.handle.as_ref().unwrap();
-    //         let mut stream = self.init().await;
-    //         let domain = rustls::ServerName::try_from(self.address.to_string().as_str())
-    //             .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))
-    //             .expect("into server name error");
-    //         let mut stream = connector.connect(domain, stream).await.expect("");
-    //         let link = self.init_link();
-    //         let config = self.config.clone();
-    //         tokio::spawn(async move {
-    //             run(stream, handle_message, link, config).await;
-    //         });
-    //     }
-    // }
-
-    pub async fn connect(&mut self) -> Option<mpsc::Receiver<String>> {
-        if self.handle.is_none() { return None; }
-        let callback = **self.handle.as_ref().unwrap();
-        let stream = self.init().await;
+        let session = ClientSession::new(
+            self.config.client_id().clone(),
+            self.config.protocol_level(),
+            sender,
+        ).ack_timeout(self.ack_timeout);
+        self.session = Some(session.clone());
+        let topic_alias_maximum = find_property(Some(self.config.properties()), Property::TopicAliasMaximum)
+            .and_then(PropertyItem::as_short)
+            .unwrap_or(0);
+        let handle = ClientHandleV3::new(session, receiver)
+            .connection_state(self.state.clone())
+            .topic_alias_maximum(topic_alias_maximum);
+        match self.auth_mechanism.clone() {
+            Some(mechanism) => handle.auth_mechanism(mechanism),
+            None => handle
+        }
+    }
+
+    fn tls_connector(&self) -> io::Result<Option<TlsConnector>> {
+        let option = match self.option.as_ref() {
+            Some(option) => option,
+            None => return Ok(None)
+        };
+        let mut root_cert_store = rustls::RootCertStore::empty();
+        let (_, ignored) = root_cert_store.add_parsable_certificates(&client_load_certs(&option.cert)?);
+        if ignored > 0 {
+            println!("ignored {} invalid ca certificates", ignored);
+        }
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_cert_store);
+        let mut config = match &option.client_cert {
+            Some((cert, key)) => {
+                let certs = load_certs(cert)?;
+                builder.with_single_cert(certs, load_private_key(key)?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            }
+            None => builder.with_no_client_auth()
+        };
+        config.alpn_protocols = option.alpn_protocols.clone();
+        Ok(Some(TlsConnector::from(Arc::new(config))))
+    }
+
+    fn server_name(&self) -> io::Result<rustls::ServerName> {
+        let name = self.option.as_ref()
+            .and_then(|option| option.server_name.clone())
+            .unwrap_or_else(|| self.address.ip().to_string());
+        rustls::ServerName::try_from(name.as_str())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid server name"))
+    }
+
+    ///
+    /// 通过 TLS 连接服务端, 需要先通过 `option` 设置 CA 证书
+    ///
+    pub async fn connect_with_tls(&mut self) -> Option<mpsc::Receiver<Bytes>> {
+        let connector = match self.tls_connector() {
+            Ok(Some(connector)) => connector,
+            Ok(None) => {
+                println!("tls option not set");
+                return None;
+            }
+            Err(e) => {
+                println!("failed to load tls config; err = {}", e);
+                return None;
+            }
+        };
+        let server_name = match self.server_name() {
+            Ok(server_name) => server_name,
+            Err(e) => {
+                println!("tls handshake failed; err = {}", e);
+                return None;
+            }
+        };
+        self.start(Transport::Tls(self.address, connector, server_name)).await
+    }
+
+    pub async fn connect(&mut self) -> Option<mpsc::Receiver<Bytes>> {
+        self.start(Transport::Tcp(self.address)).await
+    }
+
+    ///
+    /// 通过 Unix 套接字连接本机服务端, 不使用 `address`
+    ///
+    #[cfg(unix)]
+    pub async fn connect_unix<P: AsRef<Path>>(&mut self, path: P) -> Option<mpsc::Receiver<Bytes>> {
+        self.start(Transport::Unix(path.as_ref().to_path_buf())).await
+    }
+
+    ///
+    /// 建立首次连接, 失败时直接返回 `None`.
+    /// 之后由后台任务维持连接: 连接中断时按 `Config` 的 `delay` 与 `max_attempts` 重连,
+    /// 重连成功后重新订阅并重发未确认的 QoS 1/2 消息
+    ///
+    async fn start(&mut self, transport: Transport) -> Option<mpsc::Receiver<Bytes>> {
+        let callback = **self.handle.as_ref()?;
+        self.state.send_replace(ConnectionState::Connecting);
+        let stream = match transport.connect().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("failed to connect {}; err = {}", transport, e);
+                self.state.send_replace(ConnectionState::Disconnected);
+                return None;
+            }
+        };
         let handle = self.init_handle();
         let config = self.config.clone();
+        let max_packet_size = self.max_packet_size;
+        let state = self.state.clone();
         let (tx, rx) = mpsc::channel(512);
         tokio::spawn(async move {
-            run(stream, callback, Some(tx), handle, config).await;
+            supervise(stream, transport, callback, tx, handle, config, max_packet_size, &state).await;
+            state.send_replace(ConnectionState::Disconnected);
         });
         Some(rx)
     }
@@ -171,47 +291,216 @@ impl<F, Fut> Drop for MqttClient<F, Fut>
         Fut: Future<Output=()> + Send,
 {
     fn drop(&mut self) {
-        let sender = self.sender.clone();
-        tokio::spawn(async move {
-            if let Some(sender) = sender.as_ref() {
-                if let Err(e) = sender.send(HandleEvent::ExitEvent(true)).await {
-                    println!("failed to send subscribe message; err = {:?}", e);
-                }
+        if let Some(session) = self.session.clone() {
+            tokio::spawn(async move {
+                session.exit().await;
+            });
+        }
+    }
+}
+
+trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for S {}
+
+///
+/// 客户端的连接方式, 重连时使用相同的方式建立连接
+///
+enum Transport {
+    Tcp(SocketAddr),
+    Tls(SocketAddr, TlsConnector, rustls::ServerName),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Transport {
+    async fn connect(&self) -> io::Result<Box<dyn ClientStream>> {
+        Ok(match self {
+            Transport::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+            Transport::Tls(addr, connector, server_name) => {
+                let stream = TcpStream::connect(addr).await?;
+                Box::new(connector.connect(server_name.clone(), stream).await?)
             }
-        });
+            #[cfg(unix)]
+            Transport::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        })
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Transport::Tls(addr, _, _) => write!(f, "tls://{}", addr),
+            #[cfg(unix)]
+            Transport::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+///
+/// 一次连接结束的原因
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Disconnect {
+    /// 客户端主动断开
+    Closed,
+    /// 服务端拒绝连接
+    Refused,
+    /// 连接中断, 需要重连
+    Lost,
+}
+
+///
+/// 维持连接, 直到主动断开、服务端拒绝连接或者重连次数用完
+///
+#[allow(clippy::too_many_arguments)]
+async fn supervise<F, Fut>(stream: Box<dyn ClientStream>, transport: Transport, callback: F, sender: mpsc::Sender<Bytes>, mut handle: ClientHandleV3, config: Config, max_packet_size: usize, state: &watch::Sender<ConnectionState>)
+    where
+        F: Fn(ClientSession, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
+        Fut: Future<Output=()> + Send,
+{
+    let mut stream = Some(stream);
+    loop {
+        let current = match stream.take() {
+            Some(stream) => stream,
+            None => match reconnect(&transport, &config, state).await {
+                Some(stream) => stream,
+                None => break
+            }
+        };
+        let framer = PacketFramer::with_max_packet_size(max_packet_size);
+        let disconnect = run(current, callback, Some(sender.clone()), &mut handle, &config, framer).await;
+        println!("{}: connection ended; reason = {:?}", transport, disconnect);
+        if disconnect != Disconnect::Lost {
+            break;
+        }
+    }
+    handle.session().close().await;
+}
+
+///
+/// 按指数退避重连, 超过 `max_attempts` 次后放弃, `max_attempts` 为 -1 时一直重连
+///
+async fn reconnect(transport: &Transport, config: &Config, state: &watch::Sender<ConnectionState>) -> Option<Box<dyn ClientStream>> {
+    let mut attempt = 0;
+    loop {
+        if config.max_attempts() >= 0 && attempt >= config.max_attempts() as u32 {
+            println!("{}: giving up after {} attempts", transport, attempt);
+            return None;
+        }
+        attempt += 1;
+        state.send_replace(ConnectionState::Reconnecting(attempt));
+        tokio::time::sleep(jitter(backoff(config.delay(), attempt))).await;
+        match transport.connect().await {
+            Ok(stream) => return Some(stream),
+            Err(e) => println!("{}: reconnect attempt {} failed; err = {}", transport, attempt, e)
+        }
     }
 }
 
-async fn run<S, F, Fut>(mut stream: S, callback: F, sender: Option<mpsc::Sender<String>>, mut handle: ClientHandleV3, config: Config)
+///
+/// 第 n 次重连等待 `delay * 2^(n-1)` 毫秒, 不超过 `MAX_RECONNECT_DELAY`
+///
+fn backoff(delay: u32, attempt: u32) -> Duration {
+    let factor = 1u32 << attempt.saturating_sub(1).min(16);
+    Duration::from_millis(delay as u64).saturating_mul(factor).min(MAX_RECONNECT_DELAY)
+}
+
+///
+/// 在 `[delay / 2, delay]` 内随机选择等待时间, 避免大量客户端同时重连
+///
+fn jitter(delay: Duration) -> Duration {
+    let millis = delay.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+}
+
+///
+/// 连接中断: 之后的订阅与消息只记录到下一次连接, 并向事件队列放入 ExitEvent 结束本次连接
+///
+async fn connection_lost(handle: &ClientHandleV3) {
+    handle.session().offline().await;
+    handle.send_message(HandleEvent::ExitEvent(true)).await;
+}
+
+async fn run<S, F, Fut>(mut stream: S, callback: F, sender: Option<mpsc::Sender<Bytes>>, handle: &mut ClientHandleV3, config: &Config, mut framer: PacketFramer) -> Disconnect
     where
         F: Fn(ClientSession, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
         Fut: Future<Output=()> + Send,
         S: AsyncReadExt + AsyncWriteExt + Unpin
 {
-    let mut interval = tokio::time::interval(Duration::from_secs(config.keep_alive() as u64));
+    let keep_alive = Duration::from_secs(config.keep_alive() as u64);
+    let mut interval = tokio::time::interval_at(Instant::now() + keep_alive, keep_alive.max(Duration::from_secs(1)));
 
     let level = config.protocol_level();
 
-    let msg = MqttMessageV3::Connect(ConnectMessage::new(MqttCleanSession::Enable, config))
-        .to_vec()
-        .unwrap();
-    handle.send_message(
-        HandleEvent::OutputEvent(
-            Response(msg, level)
-        )
-    ).await;
+    let mut connect = ConnectMessage::new(config.clean_session(), config.clone());
+    let msg = if level == MqttProtocolLevel::Level5 {
+        let mut properties = connect.properties.take().unwrap_or_default();
+        // 没有配置时请求服务端在 CONNACK 中返回 ResponseInformation, 用于创建响应主题
+        if find_property(Some(&properties), Property::RequestResponseInformation).is_none() {
+            properties.push(PropertyItem(Property::RequestResponseInformation, PropertyValue::Byte(1)));
+        }
+        if let Some(method) = handle.authentication_method().map(str::to_owned) {
+            let data = handle.start_authentication().await.unwrap_or_default();
+            properties.push(PropertyItem(Property::AuthenticationMethod, PropertyValue::String(method)));
+            properties.push(PropertyItem(Property::AuthenticationData, PropertyValue::Binary(data)));
+        }
+        connect.properties = Some(properties);
+        MqttMessageV5::Connect(connect).to_vec().unwrap()
+    } else {
+        MqttMessageV3::Connect(connect).to_vec().unwrap()
+    };
+    // CONNECT 直接写入连接, 保证先于连接建立前已排队的报文发出; 重新订阅与重发在收到 CONNACK 之后进行
+    if let Err(e) = stream.write_all(msg.as_slice()).await {
+        println!("failed to write to socket; err = {:?}", e);
+        handle.session().offline().await;
+        return Disconnect::Lost;
+    }
 
     let mut buffer = [0; 1024];
+    // 连接中断后向事件队列放入 ExitEvent, 此前已排队的事件仍属于本次连接
+    let mut closed = false;
+    let mut last_packet = Instant::now();
 
     loop {
         let cp_sender = sender.clone();
+        let ready = !closed && match framer.next_packet() {
+            Ok(Some(packet)) => {
+                handle.send_message(HandleEvent::InputEvent(packet)).await;
+                true
+            }
+            Ok(None) => false,
+            Err(e) => {
+                println!("failed to frame packet; err = {}", e);
+                closed = true;
+                connection_lost(handle).await;
+                false
+            }
+        };
         let res = tokio::select! {
-            _ = interval.tick() => {
-                handle.send_message(HandleEvent::OutputEvent(Response(MqttMessageV3::ping().unwrap(),level))).await;
+            _ = interval.tick(), if !closed && !keep_alive.is_zero() => {
+                // 超过 1.5 倍保活时间没有收到任何报文, 认为连接已经中断
+                if last_packet.elapsed() > keep_alive + keep_alive / 2 {
+                    println!("keep alive timeout");
+                    closed = true;
+                    connection_lost(handle).await;
+                } else {
+                    handle.send_message(HandleEvent::OutputEvent(Response(MqttMessageV3::ping().unwrap(),level))).await;
+                }
                 None
             },
-            Ok(n) = stream.read(&mut buffer) => {
-                if n != 0 {handle.send_message(HandleEvent::InputEvent(buffer[0..n].to_vec())).await;}
+            res = stream.read(&mut buffer), if !ready && !closed => {
+                match res {
+                    Ok(n) if n > 0 => {
+                        last_packet = Instant::now();
+                        framer.extend(&buffer[0..n]);
+                    }
+                    _ => {
+                        closed = true;
+                        connection_lost(handle).await;
+                    }
+                }
                 None
             },
             kind = handle.execute(callback, cp_sender) => kind
@@ -224,15 +513,408 @@ async fn run<S, F, Fut>(mut stream: S, callback: F, sender: Option<mpsc::Sender<
                         println!("failed to write to socket; err = {:?}", e);
                     }
                 }
+                ReturnKind::Publish(head, rest) => {
+                    println!("client output: {:?} (+{} shared bytes)", head, rest.len());
+                    if let Err(e) = stream.write_all_buf(&mut Buf::chain(head.as_slice(), rest)).await {
+                        println!("failed to write to socket; err = {:?}", e);
+                    }
+                }
+                ReturnKind::Exit if closed => return Disconnect::Lost,
+                ReturnKind::Exit if handle.is_refused() => return Disconnect::Refused,
                 ReturnKind::Exit => {
                     let msg = MqttMessageV3::disconnect().unwrap();
                     if let Err(e) = stream.write_all(msg.as_slice()).await {
                         println!("failed to write to socket; err = {:?}", e);
                     }
-                    break;
+                    println!("client service stop!");
+                    return Disconnect::Closed;
+                }
+            }
+        }
+        if let Some(code) = handle.take_protocol_error().filter(|_| !closed) {
+            // 报文解析失败: v5 以原因码回复 DISCONNECT, 然后按连接中断处理并重连
+            if level == MqttProtocolLevel::Level5 {
+                let msg = MqttMessageV5::Disconnect(DisconnectMessage::new(code)).to_vec().unwrap();
+                if let Err(e) = stream.write_all(msg.as_slice()).await {
+                    println!("failed to write to socket; err = {:?}", e);
                 }
             }
+            closed = true;
+            connection_lost(handle).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::MqttServerOption;
+    use crate::auth::{Authenticator, Credentials, ScramSha256Client, ScramSha256Server};
+    use crate::executor::listener::{Listener, ListenerProtocol, LocalAddrs};
+    use crate::executor::v3_server::MqttServer;
+    use crate::hex::reason_code::ReasonPhrases;
+    use crate::session::{MqttSession, ServerSession};
+    use crate::tools::config::ConfigBuilder;
+
+    async fn server_message(session: ServerSession, kind: Option<MqttMessageKind>) {
+        match kind {
+            Some(MqttMessageKind::RequestV3(MqttMessageV3::Publish(msg))) |
+            Some(MqttMessageKind::RequestV5(MqttMessageV5::Publish(msg))) => session.publish(&msg).await,
+            _ => {}
+        }
+    }
+
+    async fn client_message(_session: ClientSession, _kind: Option<MqttMessageKind>) {}
+
+    async fn local_addr(addrs: &mut LocalAddrs, protocol: ListenerProtocol) -> SocketAddr {
+        tokio::time::timeout(Duration::from_secs(5), addrs.wait(protocol)).await.unwrap().and_then(|addr| addr.inet()).unwrap()
+    }
+
+    fn client_option(server_name: &str) -> MqttClientOption {
+        MqttClientOption::new("config/certs/ca.crt".to_owned())
+            .client_cert("config/certs/client-test.crt".to_owned(), "config/certs/client-test.key".to_owned())
+            .server_name(server_name.to_owned())
+            .alpn_protocol("mqtt".to_owned())
+    }
+
+    #[tokio::test]
+    async fn tls_loopback() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let option = MqttServerOption::new("config/certs/server-test.crt".to_owned(), "config/certs/server-test.key".to_owned())
+            .require_client_cert("config/certs/ca.crt".to_owned());
+        let server = MqttServer::new(addr).option(option).handle(server_message);
+        let mut addrs = server.local_addrs();
+        tokio::spawn(async move { server.start_with_tls().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tls).await;
+
+        let config = ConfigBuilder::default().client_id("tls-loopback").build().unwrap();
+        let mut client = MqttClient::new(config, addr).option(client_option("localhost")).handle(client_message);
+        let mut receiver = client.connect_with_tls().await.unwrap();
+        let _subscription = client.subscribe("tls/loopback".to_owned(), MqttQos::Qos0).await.unwrap();
+        client.publish("tls/loopback".to_owned(), "hello".to_owned(), MqttQos::Qos0, MqttDup::Disable, MqttRetain::Disable).await;
+        let received = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap();
+        assert_eq!(received, Some(Bytes::from("hello")));
+
+        let config = ConfigBuilder::default().client_id("tls-wrong-name").build().unwrap();
+        let mut client = MqttClient::new(config, addr).option(client_option("mqtt.example.com")).handle(client_message);
+        assert!(client.connect_with_tls().await.is_none());
+    }
+
+    #[cfg(unix)]
+    struct UidAuthenticator(u32);
+
+    #[cfg(unix)]
+    #[async_trait::async_trait]
+    impl Authenticator for UidAuthenticator {
+        async fn authenticate(&self, credentials: &Credentials<'_>) -> Result<(), ReasonPhrases> {
+            match credentials.peer_credentials {
+                Some(peer) if peer.uid == self.0 && peer.pid == Some(std::process::id() as i32) => Ok(()),
+                _ => Err(ReasonPhrases::NotAuthorized)
+            }
         }
     }
-    println!("client service stop!")
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_loopback() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let path = std::env::temp_dir().join(format!("mqtt-rs-{}.sock", std::process::id()));
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let uid = std::fs::metadata(&path).unwrap().uid();
+
+        let server = MqttServer::with_listeners(vec![Listener::unix(&path).permissions(0o600)])
+            .authenticator(UidAuthenticator(uid))
+            .handle(server_message);
+        let mut addrs = server.local_addrs();
+        tokio::spawn(async move { server.serve().await });
+        tokio::time::timeout(Duration::from_secs(5), addrs.wait(ListenerProtocol::Unix)).await.unwrap().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!path.with_file_name(format!(".mqtt-rs-{}.sock.{}", std::process::id(), std::process::id())).exists());
+
+        let config = ConfigBuilder::default().client_id("unix-loopback").build().unwrap();
+        let mut client = MqttClient::new(config, "127.0.0.1:1883".parse().unwrap()).handle(client_message);
+        let mut receiver = client.connect_unix(&path).await.unwrap();
+        let _subscription = client.subscribe("unix/loopback".to_owned(), MqttQos::Qos0).await.unwrap();
+        client.publish("unix/loopback".to_owned(), "hello".to_owned(), MqttQos::Qos0, MqttDup::Disable, MqttRetain::Disable).await;
+        let received = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap();
+        assert_eq!(received, Some(Bytes::from("hello")));
+
+        let second = MqttServer::with_listeners(vec![Listener::unix(&path)]).handle(server_message);
+        assert!(tokio::time::timeout(Duration::from_secs(1), second.serve()).await.is_ok());
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        assert_eq!(backoff(100, 1), Duration::from_millis(100));
+        assert_eq!(backoff(100, 4), Duration::from_millis(800));
+        assert_eq!(backoff(3000, 40), MAX_RECONNECT_DELAY);
+        let delay = jitter(Duration::from_millis(800));
+        assert!(delay >= Duration::from_millis(400) && delay <= Duration::from_millis(800));
+    }
+
+    async fn wait_state(state: &mut watch::Receiver<ConnectionState>, f: impl Fn(ConnectionState) -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), state.wait_for(|s| f(*s))).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = MqttServer::new(addr).shutdown_timeout(Duration::from_secs(1)).handle(server_message);
+        let shutdown = server.shutdown_handle();
+        let mut addrs = server.local_addrs();
+        let first = tokio::spawn(async move { server.start().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tcp).await;
+
+        let config = ConfigBuilder::default().client_id("reconnect").delay(50).max_attempts(-1).build().unwrap();
+        let mut client = MqttClient::new(config, addr).handle(client_message);
+        let mut state = client.connection_state();
+        let mut receiver = client.connect().await.unwrap();
+        wait_state(&mut state, |s| s == ConnectionState::Connected).await;
+        let mut subscription = client.subscribe("reconnect/binary".to_owned(), MqttQos::Qos1).await.unwrap();
+        subscription.suback().await.unwrap();
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), first).await.unwrap().unwrap();
+        wait_state(&mut state, |s| matches!(s, ConnectionState::Reconnecting(_))).await;
+        let payload = vec![0x00, 0x9F, 0x92, 0x96];
+        let published = client.publish("reconnect/binary".to_owned(), payload.clone(), MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable).await;
+        let mut late = client.subscribe("reconnect/late".to_owned(), MqttQos::Qos1).await.unwrap();
+
+        let server = MqttServer::new(addr).handle(server_message);
+        tokio::spawn(async move { server.start().await });
+        wait_state(&mut state, |s| s == ConnectionState::Connected).await;
+        assert!(published.await.unwrap().is_success());
+        assert_eq!(late.suback().await.unwrap().granted(), vec![Ok(MqttQos::Qos1)]);
+        let received = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap();
+        assert_eq!(received, Some(Bytes::from(payload.clone())));
+        let received = tokio::time::timeout(Duration::from_secs(5), subscription.recv()).await.unwrap().unwrap();
+        assert_eq!(received.payload(), payload.as_slice());
+
+        client.disconnect().await;
+        wait_state(&mut state, |s| s == ConnectionState::Disconnected).await;
+    }
+
+    #[tokio::test]
+    async fn reconnect_with_enhanced_authentication() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = MqttServer::new(addr)
+            .auth_mechanism(ScramSha256Server::new().user("scram-user", "scram-password"))
+            .shutdown_timeout(Duration::from_secs(1))
+            .handle(server_message);
+        let shutdown = server.shutdown_handle();
+        let mut addrs = server.local_addrs();
+        let first = tokio::spawn(async move { server.start().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tcp).await;
+
+        let config = ConfigBuilder::default().client_id("reconnect-scram").protocol_level(MqttProtocolLevel::Level5).delay(50).max_attempts(-1).build().unwrap();
+        let mut client = MqttClient::new(config, addr)
+            .auth_mechanism(ScramSha256Client::new("scram-user", "scram-password"))
+            .handle(client_message);
+        let mut state = client.connection_state();
+        let _receiver = client.connect().await.unwrap();
+        wait_state(&mut state, |s| s == ConnectionState::Connected).await;
+        let mut subscription = client.subscribe("reconnect/scram".to_owned(), MqttQos::Qos1).await.unwrap();
+        subscription.suback().await.unwrap();
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), first).await.unwrap().unwrap();
+        wait_state(&mut state, |s| matches!(s, ConnectionState::Reconnecting(_))).await;
+        let published = client.publish("reconnect/scram".to_owned(), "after", MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable).await;
+
+        let server = MqttServer::new(addr)
+            .auth_mechanism(ScramSha256Server::new().user("scram-user", "scram-password"))
+            .handle(server_message);
+        tokio::spawn(async move { server.start().await });
+        wait_state(&mut state, |s| s == ConnectionState::Connected).await;
+        assert!(published.await.unwrap().is_success());
+        let received = tokio::time::timeout(Duration::from_secs(5), subscription.recv()).await.unwrap().unwrap();
+        assert_eq!(received.payload(), b"after");
+        client.disconnect().await;
+    }
+
+    async fn read_packet(stream: &mut TcpStream, framer: &mut PacketFramer) -> Vec<u8> {
+        let mut buffer = [0; 1024];
+        loop {
+            if let Some(packet) = framer.next_packet().unwrap() {
+                return packet;
+            }
+            let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await.unwrap().unwrap();
+            assert!(n > 0);
+            framer.extend(&buffer[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_malformed_packet() {
+        use crate::hex::reason_code::{ReasonCodes, ReasonCodeV5};
+        use crate::message::entity::ConnackMessage;
+        use crate::tools::protocol::MqttSessionPresent;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ConfigBuilder::default().client_id("malformed").protocol_level(MqttProtocolLevel::Level5).delay(10).max_attempts(-1).build().unwrap();
+        let mut client = MqttClient::new(config, addr).handle(client_message);
+        let mut state = client.connection_state();
+        let _receiver = client.connect().await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut framer = PacketFramer::new();
+        assert_eq!(read_packet(&mut stream, &mut framer).await[0], 0x10);
+        let connack = ConnackMessage::new(MqttSessionPresent::Disable, ReasonCodes::V5(ReasonCodeV5::ReasonPhrases(ReasonPhrases::Success)));
+        stream.write_all(&MqttMessageV5::Connack(connack).to_vec().unwrap()).await.unwrap();
+        wait_state(&mut state, |s| s == ConnectionState::Connected).await;
+
+        let utf8 = Some(vec![PropertyItem(Property::PayloadFormatIndicator, PropertyValue::Byte(1))]);
+        let msg = PublishMessage::new(MqttQos::Qos0, MqttDup::Disable, MqttRetain::Disable, "malformed".to_owned(), 0, vec![0xFF, 0xFE], utf8);
+        stream.write_all(&MqttMessageV5::Publish(msg).to_vec().unwrap()).await.unwrap();
+        let disconnect = read_packet(&mut stream, &mut framer).await;
+        assert_eq!(disconnect, vec![0xE0, 0x01, ReasonPhrases::PayloadFormatInvalid.as_byte()]);
+
+        let (mut stream, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept()).await.unwrap().unwrap();
+        assert_eq!(read_packet(&mut stream, &mut PacketFramer::new()).await[0], 0x10);
+        assert!(matches!(*state.borrow(), ConnectionState::Reconnecting(_)));
+    }
+
+    #[tokio::test]
+    async fn give_up_reconnecting() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = MqttServer::new(addr).shutdown_timeout(Duration::from_secs(1)).handle(server_message);
+        let shutdown = server.shutdown_handle();
+        let mut addrs = server.local_addrs();
+        tokio::spawn(async move { server.start().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tcp).await;
+
+        let config = ConfigBuilder::default().client_id("give-up").delay(10).max_attempts(2).build().unwrap();
+        let mut client = MqttClient::new(config, addr).handle(client_message);
+        let mut state = client.connection_state();
+        let mut receiver = client.connect().await.unwrap();
+        wait_state(&mut state, |s| s == ConnectionState::Connected).await;
+
+        shutdown.cancel();
+        wait_state(&mut state, |s| s == ConnectionState::Disconnected).await;
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), receiver.recv()).await.unwrap(), None);
+    }
+
+    async fn echo(session: ClientSession, kind: Option<MqttMessageKind>) {
+        if let Some(MqttMessageKind::RequestV5(MqttMessageV5::Publish(msg))) = kind {
+            if msg.topic == "rpc/echo" {
+                session.respond(&msg, msg.msg_body.clone()).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn request_response() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = MqttServer::new(addr).handle(server_message);
+        let mut addrs = server.local_addrs();
+        tokio::spawn(async move { server.start().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tcp).await;
+
+        let config = ConfigBuilder::default().client_id("rpc-responder").protocol_level(MqttProtocolLevel::Level5).build().unwrap();
+        let mut responder = MqttClient::new(config, addr).handle(echo);
+        let _receiver = responder.connect().await.unwrap();
+        let _subscription = responder.subscribe("rpc/echo".to_owned(), MqttQos::Qos1).await.unwrap();
+
+        let config = ConfigBuilder::default().client_id("rpc-requester").protocol_level(MqttProtocolLevel::Level5).build().unwrap();
+        let mut requester = MqttClient::new(config, addr).handle(client_message);
+        let mut state = requester.connection_state();
+        let mut receiver = requester.connect().await.unwrap();
+        wait_state(&mut state, |s| s == ConnectionState::Connected).await;
+
+        let response = requester.request("rpc/echo".to_owned(), vec![0x00, 0x01], Duration::from_secs(5)).await.unwrap();
+        assert_eq!(response.topic, "response/rpc-requester");
+        assert_eq!(response.payload(), &[0x00, 0x01]);
+        assert!(receiver.try_recv().is_err());
+
+        let timeout = requester.request("rpc/nobody".to_owned(), "ping", Duration::from_millis(200)).await;
+        assert_eq!(timeout.unwrap_err(), RequestError::Timeout);
+
+        let config = ConfigBuilder::default().client_id("rpc-v3").build().unwrap();
+        let mut client = MqttClient::new(config, addr).handle(client_message);
+        let _receiver = client.connect().await.unwrap();
+        assert_eq!(client.request("rpc/echo".to_owned(), "ping", Duration::from_secs(1)).await.unwrap_err(), RequestError::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn subscription_streams() {
+        use futures_util::StreamExt;
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = MqttServer::new(addr).handle(server_message);
+        let mut addrs = server.local_addrs();
+        tokio::spawn(async move { server.start().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tcp).await;
+
+        let config = ConfigBuilder::default().client_id("streams").protocol_level(MqttProtocolLevel::Level5).build().unwrap();
+        let mut client = MqttClient::new(config, addr).handle(client_message);
+        let mut receiver = client.connect().await.unwrap();
+        let mut temperature = client.subscribe("streams/+/temp".to_owned(), MqttQos::Qos1).await.unwrap();
+        let mut all = client.subscribe("streams/#".to_owned(), MqttQos::Qos0).await.unwrap();
+        assert_eq!(temperature.topic_filter(), "streams/+/temp");
+
+        client.publish("streams/a/temp".to_owned(), "21", MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable).await;
+        client.publish("streams/a/humidity".to_owned(), "40", MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable).await;
+        let msg = tokio::time::timeout(Duration::from_secs(5), temperature.next()).await.unwrap().unwrap();
+        assert_eq!((msg.topic.as_str(), msg.payload()), ("streams/a/temp", b"21".as_slice()));
+        let msg = tokio::time::timeout(Duration::from_secs(5), all.next()).await.unwrap().unwrap();
+        assert_eq!(msg.topic, "streams/a/temp");
+        let msg = tokio::time::timeout(Duration::from_secs(5), all.next()).await.unwrap().unwrap();
+        assert_eq!(msg.topic, "streams/a/humidity");
+        assert!(tokio::time::timeout(Duration::from_millis(200), temperature.next()).await.is_err());
+
+        drop(all);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.publish("streams/b/humidity".to_owned(), "41", MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable).await;
+        client.publish("streams/b/temp".to_owned(), "22", MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable).await;
+        let msg = tokio::time::timeout(Duration::from_secs(5), temperature.next()).await.unwrap().unwrap();
+        assert_eq!(msg.topic, "streams/b/temp");
+        assert_eq!(receiver.recv().await, Some(Bytes::from("21")));
+        assert_eq!(receiver.recv().await, Some(Bytes::from("40")));
+        assert_eq!(receiver.recv().await, Some(Bytes::from("22")));
+    }
+
+    #[tokio::test]
+    async fn acknowledgements() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = MqttServer::new(addr).shutdown_timeout(Duration::from_secs(1)).handle(server_message);
+        let shutdown = server.shutdown_handle();
+        let mut addrs = server.local_addrs();
+        tokio::spawn(async move { server.start().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tcp).await;
+
+        let config = ConfigBuilder::default().client_id("acks").protocol_level(MqttProtocolLevel::Level5).delay(1000).max_attempts(1).build().unwrap();
+        let mut client = MqttClient::new(config, addr).ack_timeout(Duration::from_millis(200)).handle(client_message);
+        let mut state = client.connection_state();
+        let _receiver = client.connect().await.unwrap();
+        let mut subscription = client.subscribe("acks/+".to_owned(), MqttQos::Qos1).await.unwrap();
+        let suback = subscription.suback().await.unwrap();
+        assert_eq!(suback.granted(), vec![Ok(MqttQos::Qos1)]);
+        assert_eq!(subscription.suback().await.unwrap(), suback);
+        let mut invalid = client.subscribe("acks/#/invalid".to_owned(), MqttQos::Qos0).await.unwrap();
+        assert_eq!(invalid.suback().await.unwrap().granted(), vec![Err(ReasonPhrases::TopicFilterInvalid.as_byte())]);
+
+        let qos0 = client.publish("acks/a".to_owned(), "0", MqttQos::Qos0, MqttDup::Disable, MqttRetain::Disable).await;
+        let qos1 = client.publish("acks/a".to_owned(), "1", MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable).await;
+        let qos2 = client.publish("acks/a".to_owned(), "2", MqttQos::Qos2, MqttDup::Disable, MqttRetain::Disable).await;
+        assert_eq!(qos0.await.unwrap().message_id, 0);
+        let (qos1, qos2) = (qos1.await.unwrap(), qos2.await.unwrap());
+        assert!(qos1.is_success() && qos2.is_success());
+        assert_ne!(qos1.message_id, qos2.message_id);
+
+        let unsuback = client.unsubscribe("acks/none".to_owned()).await.await.unwrap();
+        assert_eq!(unsuback.code, ReasonPhrases::NoSubscriptionExisted);
+        let unsuback = client.unsubscribe("acks/+".to_owned()).await.await.unwrap();
+        assert_eq!(unsuback.code, ReasonPhrases::Success);
+
+        shutdown.cancel();
+        wait_state(&mut state, |s| matches!(s, ConnectionState::Reconnecting(_))).await;
+        let pending = client.publish("acks/a".to_owned(), "lost", MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable).await;
+        assert_eq!(pending.await.unwrap_err(), AckError::Timeout);
+        assert_eq!(client.unsubscribe("acks/#/invalid".to_owned()).await.await.unwrap_err(), AckError::ConnectionLost);
+
+        wait_state(&mut state, |s| s == ConnectionState::Disconnected).await;
+        let closed = client.publish("acks/a".to_owned(), "closed", MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable).await;
+        assert_eq!(closed.await.unwrap_err(), AckError::Closed);
+    }
 }
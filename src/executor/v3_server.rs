@@ -1,24 +1,59 @@
 use std::future::Future;
+use std::fs;
+use std::io;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use bytes::Buf;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+use futures_util::future::join_all;
 use tokio_rustls::rustls;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use crate::auth::{AuthMechanism, Authenticator, Authorizer, PeerCredentials};
 use crate::message::MqttMessageKind;
+use crate::registry::SessionStore;
+use crate::SESSION_REGISTRY;
 use crate::session::ServerSession;
-use crate::tools::tls::{load_certs, load_keys};
+use crate::tools::tls::{load_certs, CertResolver};
 use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
 use crate::executor::{MqttServerOption, ReturnKind};
+use crate::executor::listener::{Listener, ListenerAddr, ListenerProtocol, LocalAddrs};
 use crate::handle::{HandleEvent, ServerExecute, ServerHandler};
+use crate::tools::config::ServerConfig;
+use crate::tools::framer::PacketFramer;
+use crate::tools::tls::IdentityAs;
+use crate::tools::websocket::{self, WsStream};
+
+///
+/// 连接超过关闭期限后留给 DISCONNECT 写出的时间, 之后中止剩余连接
+///
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
 
 pub struct MqttServer<F, Fut>
     where
         F: Fn(ServerSession, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
         Fut: Future<Output=()> + Send,
 {
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
+    websocket_path: String,
+    listeners: Vec<Listener>,
     handle: Option<Box<F>>,
     option: Option<MqttServerOption>,
+    config: ServerConfig,
+    session_store: Option<Arc<dyn SessionStore>>,
+    shutdown: CancellationToken,
+    /// 已经开始接受连接的监听与实际绑定的地址
+    bound: watch::Sender<Vec<(ListenerProtocol, ListenerAddr)>>,
 }
 
 impl<F, Fut> MqttServer<F, Fut>
@@ -27,7 +62,153 @@ impl<F, Fut> MqttServer<F, Fut>
         Fut: Future<Output=()> + Send,
 {
     pub fn new(addr: SocketAddr) -> MqttServer<F, Fut> {
-        MqttServer { addr, handle: None, option: None }
+        MqttServer {
+            addr: Some(addr),
+            websocket_path: String::from("/mqtt"),
+            listeners: vec![],
+            handle: None,
+            option: None,
+            config: ServerConfig::default(),
+            session_store: None,
+            shutdown: CancellationToken::new(),
+            bound: watch::Sender::new(vec![]),
+        }
+    }
+
+    ///
+    /// 使用多个监听创建服务端, 通过 `serve` 同时运行
+    ///
+    pub fn with_listeners(listeners: Vec<Listener>) -> MqttServer<F, Fut> {
+        MqttServer {
+            addr: None,
+            websocket_path: String::from("/mqtt"),
+            listeners,
+            handle: None,
+            option: None,
+            config: ServerConfig::default(),
+            session_store: None,
+            shutdown: CancellationToken::new(),
+            bound: watch::Sender::new(vec![]),
+        }
+    }
+
+    pub fn listener(mut self, listener: Listener) -> MqttServer<F, Fut> {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> MqttServer<F, Fut> {
+        self.config.max_packet_size = max_packet_size;
+        self
+    }
+
+    pub fn retain_available(mut self, retain_available: bool) -> MqttServer<F, Fut> {
+        self.config.retain_available = retain_available;
+        self
+    }
+
+    ///
+    /// QoS 1/2 消息未确认时的重发间隔 (仅 v3.1.1, v5 只在重连时重发)
+    ///
+    pub fn retry_interval(mut self, retry_interval: Duration) -> MqttServer<F, Fut> {
+        self.config.retry_interval = retry_interval;
+        self
+    }
+
+    ///
+    /// v5 会话过期时间上限 (秒), 客户端请求的值超过上限时在 CONNACK 中告知实际值
+    ///
+    pub fn max_session_expiry_interval(mut self, max_session_expiry_interval: u32) -> MqttServer<F, Fut> {
+        self.config.max_session_expiry_interval = max_session_expiry_interval;
+        self
+    }
+
+    ///
+    /// v5 连接使用服务端指定的保活时间 (秒), 通过 CONNACK 的 ServerKeepAlive 属性告知客户端
+    ///
+    pub fn server_keep_alive(mut self, server_keep_alive: u16) -> MqttServer<F, Fut> {
+        self.config.server_keep_alive = Some(server_keep_alive);
+        self
+    }
+
+    ///
+    /// 连接认证, 未设置时接受所有连接
+    ///
+    pub fn authenticator<A: Authenticator + 'static>(mut self, authenticator: A) -> MqttServer<F, Fut> {
+        self.config.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    ///
+    /// 主题授权, 未设置时允许所有发布与订阅
+    ///
+    pub fn authorizer<A: Authorizer + 'static>(mut self, authorizer: A) -> MqttServer<F, Fut> {
+        self.config.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    ///
+    /// 添加 v5 增强认证方法, CONNECT 中的认证方法不受支持时回复 CONNACK 0x8C
+    ///
+    pub fn auth_mechanism<A: AuthMechanism + 'static>(mut self, mechanism: A) -> MqttServer<F, Fut> {
+        self.config.auth_mechanisms.push(Arc::new(mechanism));
+        self
+    }
+
+    ///
+    /// 把 TLS 客户端证书中的身份 (CN, 没有时使用第一个 SAN) 作为用户名或客户端标识,
+    /// 用于认证与主题授权; 客户端没有提供证书时仍使用 CONNECT 中的值
+    ///
+    pub fn use_identity_as(mut self, identity_as: IdentityAs) -> MqttServer<F, Fut> {
+        self.config.identity_as = Some(identity_as);
+        self
+    }
+
+    ///
+    /// WebSocket 监听的请求路径, 默认为 `/mqtt`
+    ///
+    pub fn websocket_path<S: Into<String>>(mut self, path: S) -> MqttServer<F, Fut> {
+        self.websocket_path = path.into();
+        self
+    }
+
+    ///
+    /// 关闭时等待进行中的 QoS 1/2 流程完成的期限, 超过后直接断开, 默认为 10 秒
+    ///
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> MqttServer<F, Fut> {
+        self.config.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    ///
+    /// 从接受连接到回复 CONNACK 的期限, 包括 TLS 与 WebSocket 握手以及增强认证, 超过后断开连接, 默认为 10 秒
+    ///
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> MqttServer<F, Fut> {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    ///
+    /// 会话持久化, 关闭后保存持久会话的订阅、未完成的出站消息与离线消息; 未设置时会话只保存在内存中, 随进程退出丢失
+    ///
+    pub fn session_store<S: SessionStore + 'static>(mut self, session_store: S) -> MqttServer<F, Fut> {
+        self.session_store = Some(Arc::new(session_store));
+        self
+    }
+
+    ///
+    /// 关闭句柄, 调用 `cancel` 后所有监听停止接受新连接, 已有连接的 QoS 流程完成后
+    /// 断开 (v5 回复 DISCONNECT 0x8B), 持久会话保留在会话注册表中, 全部连接结束后 `serve` 返回
+    ///
+    pub fn shutdown_handle(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    ///
+    /// 监听地址句柄, 每个监听开始接受连接后加入实际绑定的地址, 可以在 `start` 或 `serve` 之前获取
+    ///
+    pub fn local_addrs(&self) -> LocalAddrs {
+        LocalAddrs::new(self.bound.subscribe())
     }
 
     pub fn option(mut self, option: MqttServerOption) -> MqttServer<F, Fut> {
@@ -40,57 +221,408 @@ impl<F, Fut> MqttServer<F, Fut>
         self
     }
 
-    fn acceptor(&self) -> Option<TlsAcceptor> {
-        if self.option.is_none() { return None; }
-        let certs = load_certs(&self.option.as_ref().unwrap().cert).expect("load certs error");
-        let mut keys = load_keys(&self.option.as_ref().unwrap().key).expect("load keys error");
-        let config = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs, keys.remove(0)).expect("build server tls config error");
-        Some(TlsAcceptor::from(Arc::new(config)))
+    fn acceptor(&self, option: &MqttServerOption) -> io::Result<(TlsAcceptor, Arc<CertResolver>)> {
+        let resolver = Arc::new(CertResolver::new(&option.cert, &option.key)?);
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match &option.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca)? {
+                    roots.add(&cert).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", ca.display(), e)))?;
+                }
+                if option.client_cert_required {
+                    builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+                } else {
+                    builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+                }
+            }
+            None => builder.with_no_client_auth()
+        };
+        let config = builder.with_cert_resolver(resolver.clone());
+        Ok((TlsAcceptor::from(Arc::new(config)), resolver))
+    }
+
+    ///
+    /// 加载 TLS 配置, 设置了重新加载间隔时同时启动证书检查任务
+    ///
+    fn tls(&self, option: &MqttServerOption) -> Option<(TlsAcceptor, Option<JoinHandle<()>>)> {
+        match self.acceptor(option) {
+            Ok((acceptor, resolver)) => Some((acceptor, option.reload_interval.map(|interval| resolver.watch(interval)))),
+            Err(e) => {
+                println!("failed to load tls config; err = {}", e);
+                None
+            }
+        }
     }
 
     pub async fn start_with_tls(&self) {
-        if self.handle.is_none() { return; }
-        if let Some(acceptor) = self.acceptor() {
-            let listener: TcpListener = TcpListener::bind(self.addr).await.expect("listener error");
-            while let Ok((stream, addr)) = listener.accept().await {
-                let handle_message = **self.handle.as_ref().unwrap();
-                let acceptor = acceptor.clone();
-                let stream = acceptor.accept(stream).await.expect("");
-                tokio::spawn(async move {
-                    run(stream, addr, handle_message).await;
-                });
-            }
+        if let (Some(addr), Some(option)) = (self.addr, self.option.clone()) {
+            self.serve_listener(&Listener::tls(addr, option)).await;
+            self.save_sessions().await;
         }
     }
 
     pub async fn start(&self) {
-        if self.handle.is_none() { return; }
-        let listener: TcpListener = TcpListener::bind(self.addr).await.expect("listener error");
-        while let Ok((stream, addr)) = listener.accept().await {
-            let handle_message = **self.handle.as_ref().unwrap();
-            tokio::spawn(async move {
-                run(stream, addr, handle_message).await;
+        if let Some(addr) = self.addr {
+            self.serve_listener(&Listener::tcp(addr)).await;
+            self.save_sessions().await;
+        }
+    }
+
+    ///
+    /// 在 `addr` 上启动 WebSocket 监听, 可以与 TCP 监听同时运行, 共享同一个服务端状态
+    ///
+    pub async fn start_websocket(&self, addr: SocketAddr) {
+        self.serve_listener(&Listener::websocket(addr).websocket_path(self.websocket_path.clone())).await;
+        self.save_sessions().await;
+    }
+
+    ///
+    /// 在 `addr` 上启动基于 TLS 的 WebSocket (wss) 监听, 使用 `option` 中的证书
+    ///
+    pub async fn start_websocket_with_tls(&self, addr: SocketAddr) {
+        if let Some(option) = self.option.clone() {
+            self.serve_listener(&Listener::websocket_tls(addr, option).websocket_path(self.websocket_path.clone())).await;
+            self.save_sessions().await;
+        }
+    }
+
+    ///
+    /// 在 Unix 套接字 `path` 上启动监听, 需要设置文件权限时使用 `Listener::unix`
+    ///
+    pub async fn start_unix<P: Into<PathBuf>>(&self, path: P) {
+        self.serve_listener(&Listener::unix(path)).await;
+        self.save_sessions().await;
+    }
+
+    ///
+    /// 同时运行所有监听, 全部监听结束后返回
+    ///
+    pub async fn serve(&self) {
+        join_all(self.listeners.iter().map(|listener| self.serve_listener(listener))).await;
+        self.save_sessions().await;
+    }
+
+    ///
+    /// 关闭后把持久会话交给 `SessionStore` 保存, 同时运行多个 `start` 时每个都会保存一次
+    ///
+    async fn save_sessions(&self) {
+        if let Some(session_store) = self.session_store.as_ref().filter(|_| self.shutdown.is_cancelled()) {
+            let sessions = SESSION_REGISTRY.snapshot().await;
+            println!("saving {} sessions", sessions.len());
+            session_store.save(sessions).await;
+        }
+    }
+
+    async fn serve_listener(&self, listener: &Listener) {
+        let handle_message = match self.handle.as_ref() {
+            Some(handle) => **handle,
+            None => return
+        };
+        let config = Arc::new(listener.config(&self.config));
+        let limit = listener.max_connections.map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+        let addr = match listener.addr() {
+            ListenerAddr::Inet(addr) => *addr,
+            ListenerAddr::Unix(path) => return serve_unix(listener, path, limit, handle_message, config, &self.shutdown, &self.bound).await,
+        };
+        let (acceptor, watcher) = match (listener.protocol().is_tls(), listener.option.as_ref()) {
+            (false, _) => (None, None),
+            (true, Some(option)) => match self.tls(option) {
+                Some((acceptor, watcher)) => (Some(acceptor), watcher),
+                None => return
+            },
+            (true, None) => {
+                println!("[{}]: tls option not set", listener);
+                return;
+            }
+        };
+        let tcp_listener = match TcpListener::bind(addr).await {
+            Ok(tcp_listener) => tcp_listener,
+            Err(e) => {
+                println!("[{}]: failed to bind; err = {}", listener, e);
+                return;
+            }
+        };
+        listening(&self.bound, listener, ListenerAddr::Inet(tcp_listener.local_addr().unwrap_or(addr)));
+        let websocket_path = listener.protocol().is_websocket().then(|| listener.websocket_path.clone());
+        let mut connections = JoinSet::new();
+        loop {
+            let (stream, addr) = tokio::select! {
+                res = tcp_listener.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        println!("[{}]: failed to accept; err = {}", listener, e);
+                        connections.detach_all();
+                        break;
+                    }
+                },
+                Some(_) = connections.join_next() => continue,
+                _ = self.shutdown.cancelled() => {
+                    drop(tcp_listener);
+                    drain(listener, &mut connections, config.shutdown_timeout()).await;
+                    break;
+                }
+            };
+            let permit = match try_permit(&limit) {
+                Ok(permit) => permit,
+                Err(_) => {
+                    println!("[{}]: too many connections, closing {}", listener, addr);
+                    continue;
+                }
+            };
+            let deadline = Instant::now() + config.connect_timeout();
+            let config = config.clone();
+            let acceptor = acceptor.clone();
+            let websocket_path = websocket_path.clone();
+            let shutdown = self.shutdown.clone();
+            connections.spawn(async move {
+                let _permit = permit;
+                match acceptor {
+                    Some(acceptor) => {
+                        if let Some((stream, certificates)) = tls_accept(&acceptor, stream, addr, deadline).await {
+                            serve_stream(stream, addr, certificates, websocket_path, deadline, handle_message, config, shutdown).await;
+                        }
+                    }
+                    None => serve_stream(stream, addr, None, websocket_path, deadline, handle_message, config, shutdown).await
+                }
             });
         }
+        if let Some(watcher) = watcher {
+            watcher.abort();
+        }
     }
 }
 
-async fn run<S, F, Fut>(mut stream: S, addr: SocketAddr, callback: F)
+///
+/// 记录开始接受连接的监听
+///
+fn listening(bound: &watch::Sender<Vec<(ListenerProtocol, ListenerAddr)>>, listener: &Listener, addr: ListenerAddr) {
+    println!("[{}]: listening on {}", listener, addr);
+    bound.send_modify(|bound| bound.push((listener.protocol(), addr)));
+}
+
+///
+/// 等待连接在关闭期限内断开, 超时后中止剩余连接
+///
+async fn drain(listener: &Listener, connections: &mut JoinSet<()>, timeout: Duration) {
+    println!("[{}]: shutting down, {} connections", listener, connections.len());
+    let drained = time::timeout(timeout + SHUTDOWN_GRACE, async {
+        while connections.join_next().await.is_some() {}
+    }).await;
+    if drained.is_err() {
+        println!("[{}]: aborting {} connections", listener, connections.len());
+        connections.abort_all();
+        while connections.join_next().await.is_some() {}
+    }
+}
+
+fn try_permit(limit: &Option<Arc<Semaphore>>) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+    limit.as_ref().map(|limit| limit.clone().try_acquire_owned()).transpose()
+}
+
+///
+/// 绑定 Unix 套接字: 清理没有进程监听的遗留套接字文件, 文件仍在使用或不是套接字时返回错误
+///
+#[cfg(unix)]
+async fn bind_unix(path: &Path, permissions: Option<u32>) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{}: not a socket", path.display())));
+        }
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{}: socket in use", path.display())));
+        }
+        println!("removing stale socket {}", path.display());
+        fs::remove_file(path)?;
+    }
+    match permissions {
+        Some(mode) => bind_unix_private(path, mode),
+        None => UnixListener::bind(path)
+    }
+}
+
+///
+/// 在只有属主可以访问的临时目录中绑定套接字并设置权限, 再移动到 `path`,
+/// 套接字不会以默认 umask 的权限暴露给其他进程
+///
+#[cfg(unix)]
+fn bind_unix_private(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: not a file path", path.display())))?;
+    let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let temp = dir.join(name);
+    let listener = UnixListener::bind(&temp).and_then(|listener| {
+        fs::set_permissions(&temp, fs::Permissions::from_mode(mode))?;
+        fs::rename(&temp, path)?;
+        Ok(listener)
+    });
+    if listener.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    if let Err(e) = fs::remove_dir(&dir) {
+        println!("failed to remove directory {}; err = {}", dir.display(), e);
+    }
+    listener
+}
+
+#[cfg(unix)]
+async fn serve_unix<F, Fut>(listener: &Listener, path: &Path, limit: Option<Arc<Semaphore>>, callback: F, config: Arc<ServerConfig>, shutdown: &CancellationToken, bound: &watch::Sender<Vec<(ListenerProtocol, ListenerAddr)>>)
+    where
+        F: Fn(ServerSession, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
+        Fut: Future<Output=()> + Send,
+{
+    let unix_listener = match bind_unix(path, listener.permissions).await {
+        Ok(unix_listener) => unix_listener,
+        Err(e) => {
+            println!("[{}]: failed to bind; err = {}", listener, e);
+            return;
+        }
+    };
+    listening(bound, listener, ListenerAddr::Unix(path.to_path_buf()));
+    let mut connections = JoinSet::new();
+    loop {
+        let stream = tokio::select! {
+            res = unix_listener.accept() => match res {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    println!("[{}]: failed to accept; err = {}", listener, e);
+                    connections.detach_all();
+                    break;
+                }
+            },
+            Some(_) = connections.join_next() => continue,
+            _ = shutdown.cancelled() => {
+                drop(unix_listener);
+                drain(listener, &mut connections, config.shutdown_timeout()).await;
+                break;
+            }
+        };
+        let credentials = stream.peer_cred().ok().map(|cred| PeerCredentials { uid: cred.uid(), gid: cred.gid(), pid: cred.pid() });
+        let peer = match credentials.and_then(|credentials| credentials.pid) {
+            Some(pid) => format!("{}#{}", path.display(), pid),
+            None => path.display().to_string()
+        };
+        let permit = match try_permit(&limit) {
+            Ok(permit) => permit,
+            Err(_) => {
+                println!("[{}]: too many connections, closing {}", listener, peer);
+                continue;
+            }
+        };
+        let handle = ServerHandler::new(config.clone()).peer_credentials(credentials).shutdown(shutdown.clone());
+        let config = config.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            run(stream, peer, handle, callback, config).await;
+        });
+    }
+    if let Err(e) = fs::remove_file(path) {
+        println!("failed to remove socket {}; err = {}", path.display(), e);
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_unix<F, Fut>(listener: &Listener, _path: &Path, _limit: Option<Arc<Semaphore>>, _callback: F, _config: Arc<ServerConfig>, _shutdown: &CancellationToken, _bound: &watch::Sender<Vec<(ListenerProtocol, ListenerAddr)>>)
+    where
+        F: Fn(ServerSession, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
+        Fut: Future<Output=()> + Send,
+{
+    println!("[{}]: unix sockets are not supported on this platform", listener);
+}
+
+///
+/// 设置了 WebSocket 路径时先完成 WebSocket 握手, 握手与 CONNECT 都需要在 `deadline` 之前完成
+///
+#[allow(clippy::too_many_arguments)]
+async fn serve_stream<S, F, Fut>(stream: S, addr: SocketAddr, certificates: Option<Vec<rustls::Certificate>>, websocket_path: Option<String>, deadline: Instant, callback: F, config: Arc<ServerConfig>, shutdown: CancellationToken)
+    where
+        F: Fn(ServerSession, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
+        Fut: Future<Output=()> + Send,
+        S: AsyncReadExt + AsyncWriteExt + Unpin
+{
+    let handle = ServerHandler::new(config.clone()).peer(addr, certificates).connect_deadline(deadline).shutdown(shutdown);
+    match websocket_path {
+        Some(path) => {
+            if let Some(stream) = websocket_accept(stream, addr, &path, deadline).await {
+                run(stream, addr.to_string(), handle, callback, config).await;
+            }
+        }
+        None => run(stream, addr.to_string(), handle, callback, config).await
+    }
+}
+
+async fn tls_accept(acceptor: &TlsAcceptor, stream: TcpStream, addr: SocketAddr, deadline: Instant) -> Option<(TlsStream<TcpStream>, Option<Vec<rustls::Certificate>>)> {
+    match time::timeout_at(deadline, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => {
+            let certificates = stream.get_ref().1.peer_certificates().map(|certs| certs.to_vec());
+            Some((stream, certificates))
+        }
+        Ok(Err(e)) => {
+            println!("[{}]: tls handshake failed; err = {:?}", addr, e);
+            None
+        }
+        Err(_) => {
+            println!("[{}]: tls handshake timed out", addr);
+            None
+        }
+    }
+}
+
+async fn websocket_accept<S>(stream: S, addr: SocketAddr, path: &str, deadline: Instant) -> Option<WsStream<S>>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin
+{
+    match time::timeout_at(deadline, websocket::accept(stream, path)).await {
+        Ok(Ok(stream)) => Some(stream),
+        Ok(Err(e)) => {
+            println!("[{}]: websocket handshake failed; err = {}", addr, e);
+            None
+        }
+        Err(_) => {
+            println!("[{}]: websocket handshake timed out", addr);
+            None
+        }
+    }
+}
+
+///
+/// 读取报文交给 `handle` 处理, 直到连接断开; 关闭时的排空由 `handle` 完成
+///
+async fn run<S, F, Fut>(mut stream: S, peer: String, mut handle: ServerHandler, callback: F, config: Arc<ServerConfig>)
     where
         F: Fn(ServerSession, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
         Fut: Future<Output=()> + Send,
         S: AsyncReadExt + AsyncWriteExt + Unpin
 {
     let mut buf = [0; 1024];
-    let mut handle = ServerHandler::new();
-    println!("[{}]: connect!", addr.to_string());
+    let mut framer = PacketFramer::with_max_packet_size(config.max_packet_size());
+    let mut closed = false;
+    println!("[{}]: connect!", peer);
     loop {
+        let ready = !closed && match framer.next_packet() {
+            Ok(Some(packet)) => {
+                handle.send_message(HandleEvent::InputEvent(packet)).await;
+                true
+            }
+            Ok(None) => false,
+            Err(e) => {
+                println!("[{}]: failed to frame packet; err = {}", peer, e);
+                closed = true;
+                handle.send_message(HandleEvent::ExitEvent(true)).await;
+                false
+            }
+        };
         let res = tokio::select! {
-            Ok(n) = stream.read(&mut buf) => {
-                if n != 0 {handle.send_message(HandleEvent::InputEvent(buf[0..n].to_vec())).await;}
+            res = stream.read(&mut buf), if !ready && !closed => {
+                match res {
+                    Ok(n) if n > 0 => framer.extend(&buf[0..n]),
+                    _ => {
+                        closed = true;
+                        handle.send_message(HandleEvent::ExitEvent(true)).await;
+                    }
+                }
                 None
             },
             kind = handle.execute(callback) => kind
@@ -99,7 +631,13 @@ async fn run<S, F, Fut>(mut stream: S, addr: SocketAddr, callback: F)
             match kind {
                 ReturnKind::Response(data) => {
                     println!("server output: {:?}", data);
-                    if let Err(e) = stream.write_all(data.as_slice()).await {
+                    if let Err(e) = write(&mut stream, data.as_slice()).await {
+                        println!("failed to write to socket; err = {:?}", e);
+                    }
+                }
+                ReturnKind::Publish(head, rest) => {
+                    println!("server output: {:?} (+{} shared bytes)", head, rest.len());
+                    if let Err(e) = write(&mut stream, Buf::chain(head.as_slice(), rest)).await {
                         println!("failed to write to socket; err = {:?}", e);
                     }
                 }
@@ -107,5 +645,268 @@ async fn run<S, F, Fut>(mut stream: S, addr: SocketAddr, callback: F)
             }
         }
     }
-    println!("[{}]: disconnect!", addr.to_string());
+    println!("[{}]: disconnect!", peer);
+}
+
+async fn write<S: AsyncWriteExt + Unpin, B: Buf>(stream: &mut S, mut data: B) -> io::Result<()> {
+    stream.write_all_buf(&mut data).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::path::Path;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::client_async;
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::ServerName;
+    use crate::executor::MqttServerOption;
+    use crate::message::entity::ConnectMessage;
+    use crate::executor::listener::{Listener, ListenerProtocol, LocalAddrs};
+    use crate::message::v3::MqttMessageV3;
+    use crate::message::v5::MqttMessageV5;
+    use crate::tools::protocol::MqttProtocolLevel;
+    use crate::tools::config::ConfigBuilder;
+    use crate::tools::protocol::MqttCleanSession;
+    use crate::tools::tls::load_private_key;
+    use crate::session::MqttSession;
+    use crate::registry::{SessionSnapshot, SESSION_NEVER_EXPIRE};
+    use crate::subscript::ClientID;
+
+    async fn handle_message(_session: ServerSession, _kind: Option<MqttMessageKind>) {}
+
+    async fn publish_message(session: ServerSession, kind: Option<MqttMessageKind>) {
+        if let Some(MqttMessageKind::RequestV3(MqttMessageV3::Publish(msg))) = kind {
+            session.publish(&msg).await;
+        }
+    }
+
+    fn connector(client_cert: bool) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(Path::new("config/certs/ca.crt")).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        let builder = rustls::ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+        let config = if client_cert {
+            let certs = load_certs(Path::new("config/certs/client-test.crt")).unwrap();
+            let key = load_private_key(Path::new("config/certs/client-test.key")).unwrap();
+            builder.with_single_cert(certs, key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+
+    fn connect_packet(client_id: &str) -> Vec<u8> {
+        let config = ConfigBuilder::default().client_id(client_id).build().unwrap();
+        MqttMessageV3::Connect(ConnectMessage::new(MqttCleanSession::Enable, config)).to_vec().unwrap()
+    }
+
+    fn connect_packet_v5(client_id: &str) -> Vec<u8> {
+        let config = ConfigBuilder::default().client_id(client_id).protocol_level(MqttProtocolLevel::Level5).build().unwrap();
+        MqttMessageV5::Connect(ConnectMessage::new(MqttCleanSession::Enable, config)).to_vec().unwrap()
+    }
+
+    async fn local_addr(addrs: &mut LocalAddrs, protocol: ListenerProtocol) -> SocketAddr {
+        tokio::time::timeout(Duration::from_secs(5), addrs.wait(protocol)).await.unwrap().and_then(|addr| addr.inet()).unwrap()
+    }
+
+    async fn read_packet(stream: &mut TcpStream) -> Vec<u8> {
+        let mut buf = [0; 64];
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap().unwrap();
+        buf[..n].to_vec()
+    }
+
+    async fn connect(addr: SocketAddr, client_cert: bool) -> std::io::Result<Vec<u8>> {
+        let stream = TcpStream::connect(addr).await?;
+        let domain = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector(client_cert).connect(domain, stream).await?;
+        stream.write_all(&connect_packet(&format!("mtls-{}", client_cert))).await?;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        Ok(buf.to_vec())
+    }
+
+    #[tokio::test]
+    async fn mutual_tls() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let option = MqttServerOption::new("config/certs/server-test.crt".to_owned(), "config/certs/server-test.key".to_owned())
+            .require_client_cert("config/certs/ca.crt".to_owned());
+        let server = MqttServer::new(addr).option(option).handle(handle_message);
+        let mut addrs = server.local_addrs();
+        tokio::spawn(async move { server.start_with_tls().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tls).await;
+
+        assert_eq!(connect(addr, true).await.unwrap(), vec![0x20, 0x02, 0x00, 0x00]);
+        assert!(connect(addr, false).await.is_err());
+    }
+
+    async fn websocket_connect<S>(stream: S, url: &str, protocol: Option<&str>, client_id: &str) -> Result<Vec<u8>, WsError>
+        where
+            S: AsyncReadExt + AsyncWriteExt + Unpin
+    {
+        let mut request = url.into_client_request()?;
+        if let Some(protocol) = protocol {
+            request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_str(protocol).unwrap());
+        }
+        let (mut stream, response) = client_async(request, stream).await?;
+        assert_eq!(response.headers().get("Sec-WebSocket-Protocol").unwrap(), "mqtt");
+        stream.send(Message::Binary(connect_packet(client_id))).await?;
+        match stream.next().await {
+            Some(Ok(Message::Binary(data))) => Ok(data),
+            other => panic!("expected binary frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket() {
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let option = MqttServerOption::new("config/certs/server-test.crt".to_owned(), "config/certs/server-test.key".to_owned());
+        let server = Arc::new(MqttServer::new(any).option(option).websocket_path("/broker").handle(handle_message));
+        let mut addrs = server.local_addrs();
+        let ws_server = server.clone();
+        tokio::spawn(async move { ws_server.start_websocket(any).await });
+        tokio::spawn(async move { server.start_websocket_with_tls(any).await });
+        let ws = local_addr(&mut addrs, ListenerProtocol::WebSocket).await;
+        let wss = local_addr(&mut addrs, ListenerProtocol::WebSocketTls).await;
+
+        let connack = websocket_connect(TcpStream::connect(ws).await.unwrap(), "ws://localhost/broker", Some("mqttv3.1, mqtt"), "websocket").await;
+        assert_eq!(connack.unwrap(), vec![0x20, 0x02, 0x00, 0x00]);
+        let wrong_path = websocket_connect(TcpStream::connect(ws).await.unwrap(), "ws://localhost/mqtt", Some("mqtt"), "websocket").await;
+        assert!(matches!(wrong_path, Err(WsError::Http(response)) if response.status() == 404));
+        let no_protocol = websocket_connect(TcpStream::connect(ws).await.unwrap(), "ws://localhost/broker", None, "websocket").await;
+        assert!(matches!(no_protocol, Err(WsError::Http(response)) if response.status() == 400));
+
+        let stream = TcpStream::connect(wss).await.unwrap();
+        let stream = connector(false).connect(ServerName::try_from("localhost").unwrap(), stream).await.unwrap();
+        let connack = websocket_connect(stream, "wss://localhost/broker", Some("mqtt"), "websocket").await;
+        assert_eq!(connack.unwrap(), vec![0x20, 0x02, 0x00, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn listeners() {
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = Listener::tcp(any).max_connections(1).protocol_levels(vec![MqttProtocolLevel::Level5]);
+        let server = MqttServer::with_listeners(vec![listener]).listener(Listener::websocket(any)).handle(handle_message);
+        let mut addrs = server.local_addrs();
+        tokio::spawn(async move { server.serve().await });
+        let tcp = local_addr(&mut addrs, ListenerProtocol::Tcp).await;
+        let ws = local_addr(&mut addrs, ListenerProtocol::WebSocket).await;
+
+        let mut v3 = TcpStream::connect(tcp).await.unwrap();
+        v3.write_all(&connect_packet("listener-v3")).await.unwrap();
+        assert_eq!(read_packet(&mut v3).await, vec![0x20, 0x02, 0x00, 0x01]);
+        assert!(read_packet(&mut v3).await.is_empty());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut first = TcpStream::connect(tcp).await.unwrap();
+        first.write_all(&connect_packet_v5("listener-shared")).await.unwrap();
+        assert_eq!(read_packet(&mut first).await[3], 0x00);
+        let mut second = TcpStream::connect(tcp).await.unwrap();
+        assert!(read_packet(&mut second).await.is_empty());
+
+        let connack = websocket_connect(TcpStream::connect(ws).await.unwrap(), "ws://localhost/mqtt", Some("mqtt"), "listener-shared").await;
+        assert_eq!(connack.unwrap(), vec![0x20, 0x02, 0x00, 0x00]);
+        assert_eq!(read_packet(&mut first).await, vec![0xE0, 0x01, 0x8E]);
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = MqttServer::new(addr).shutdown_timeout(Duration::from_secs(5)).handle(publish_message);
+        let shutdown = server.shutdown_handle();
+        let mut addrs = server.local_addrs();
+        let server = tokio::spawn(async move { server.start().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tcp).await;
+
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        subscriber.write_all(&connect_packet_v5("shutdown-sub")).await.unwrap();
+        assert_eq!(read_packet(&mut subscriber).await[3], 0x00);
+        let mut subscribe = vec![0x82, 0x10, 0x00, 0x01, 0x00, 0x00, 0x0A];
+        subscribe.extend_from_slice(b"shutdown/t");
+        subscribe.push(0x01);
+        subscriber.write_all(&subscribe).await.unwrap();
+        assert_eq!(read_packet(&mut subscriber).await, vec![0x90, 0x04, 0x00, 0x01, 0x00, 0x01]);
+
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        publisher.write_all(&connect_packet("shutdown-pub")).await.unwrap();
+        assert_eq!(read_packet(&mut publisher).await, vec![0x20, 0x02, 0x00, 0x00]);
+        let mut publish = vec![0x32, 0x11, 0x00, 0x0A];
+        publish.extend_from_slice(b"shutdown/t");
+        publish.extend_from_slice(&[0x00, 0x01]);
+        publish.extend_from_slice(b"bye");
+        publisher.write_all(&publish).await.unwrap();
+        assert_eq!(read_packet(&mut publisher).await, vec![0x40, 0x02, 0x00, 0x01]);
+        let delivered = read_packet(&mut subscriber).await;
+        assert_eq!(delivered[0], 0x32);
+
+        shutdown.cancel();
+        assert!(read_packet(&mut publisher).await.is_empty());
+        let mut buf = [0; 8];
+        assert!(tokio::time::timeout(Duration::from_millis(300), subscriber.read(&mut buf)).await.is_err());
+        subscriber.write_all(&[0x40, 0x02, delivered[14], delivered[15]]).await.unwrap();
+        assert_eq!(read_packet(&mut subscriber).await, vec![0xE0, 0x01, 0x8B]);
+
+        tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[derive(Clone, Default)]
+    struct MemoryStore {
+        sessions: Arc<std::sync::Mutex<Vec<SessionSnapshot>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl SessionStore for MemoryStore {
+        async fn save(&self, sessions: Vec<SessionSnapshot>) {
+            self.sessions.lock().unwrap().extend(sessions);
+        }
+    }
+
+    #[tokio::test]
+    async fn save_sessions_on_shutdown() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let store = MemoryStore::default();
+        let server = MqttServer::new(addr).shutdown_timeout(Duration::from_millis(100)).session_store(store.clone()).handle(publish_message);
+        let shutdown = server.shutdown_handle();
+        let mut addrs = server.local_addrs();
+        let server = tokio::spawn(async move { server.start().await });
+        let addr = local_addr(&mut addrs, ListenerProtocol::Tcp).await;
+
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        let config = ConfigBuilder::default().client_id("store-sub").build().unwrap();
+        subscriber.write_all(&MqttMessageV3::Connect(ConnectMessage::new(MqttCleanSession::Disable, config)).to_vec().unwrap()).await.unwrap();
+        assert_eq!(read_packet(&mut subscriber).await, vec![0x20, 0x02, 0x00, 0x00]);
+        let mut subscribe = vec![0x82, 0x0C, 0x00, 0x01, 0x00, 0x07];
+        subscribe.extend_from_slice(b"store/t");
+        subscribe.push(0x01);
+        subscriber.write_all(&subscribe).await.unwrap();
+        assert_eq!(read_packet(&mut subscriber).await, vec![0x90, 0x03, 0x00, 0x01, 0x01]);
+
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        publisher.write_all(&connect_packet("store-pub")).await.unwrap();
+        assert_eq!(read_packet(&mut publisher).await, vec![0x20, 0x02, 0x00, 0x00]);
+        let mut publish = vec![0x32, 0x0E, 0x00, 0x07];
+        publish.extend_from_slice(b"store/t");
+        publish.extend_from_slice(&[0x00, 0x01]);
+        publish.extend_from_slice(b"bye");
+        publisher.write_all(&publish).await.unwrap();
+        assert_eq!(read_packet(&mut publisher).await, vec![0x40, 0x02, 0x00, 0x01]);
+        assert_eq!(read_packet(&mut subscriber).await[0], 0x32);
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(3), server).await.unwrap().unwrap();
+        let sessions = store.sessions.lock().unwrap();
+        let session = sessions.iter().find(|session| session.client_id == ClientID::from("store-sub")).unwrap();
+        assert_eq!(session.expiry_interval, SESSION_NEVER_EXPIRE);
+        assert_eq!(session.subscriptions.iter().map(|(filter, _)| filter.as_str()).collect::<Vec<&str>>(), vec!["store/t"]);
+        assert_eq!(session.frames.len(), 1);
+        assert_eq!(session.frames[0].message().topic, "store/t");
+        assert!(!sessions.iter().any(|session| session.client_id == ClientID::from("store-pub")));
+    }
 }
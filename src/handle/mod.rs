@@ -1,14 +1,30 @@
 use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use async_trait::async_trait;
+use bytes::Bytes;
 use tokio::sync::mpsc;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tokio_rustls::rustls::Certificate;
+use tokio_util::sync::CancellationToken;
+use crate::auth::{AuthExchange, PeerCredentials};
 use crate::executor::ReturnKind;
-use crate::message::{MqttMessageKind, VariableHeader};
+use crate::hex::reason_code::ReasonPhrases;
+use crate::message::MqttMessageKind;
 use crate::session::{MqttSession, ServerSession};
 use crate::subscript::TopicMessage;
+use crate::tools::config::ServerConfig;
 use crate::tools::protocol::MqttProtocolLevel;
+use crate::MESSAGE_CONTAINER;
 pub mod server_handle;
 pub mod v3_client_handle;
 
+///
+/// 关闭期间检查连接是否还有进行中的 QoS 流程的间隔
+///
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
 #[derive(Debug)]
 pub struct Response(pub Vec<u8>, pub MqttProtocolLevel);
 
@@ -18,6 +34,10 @@ pub enum HandleEvent {
     BroadcastEvent(TopicMessage),
     OutputEvent(Response),
     ExitEvent(bool),
+    /// 服务端主动断开连接, v5 回复带原因码的 DISCONNECT
+    DisconnectEvent(ReasonPhrases),
+    /// 客户端发起 v5 重新认证
+    ReAuthEvent,
 }
 
 #[async_trait]
@@ -32,7 +52,7 @@ pub trait ServerExecute {
 #[async_trait]
 pub trait ClientExecute {
     type Ses: MqttSession;
-    async fn execute<F, Fut>(&mut self, f: F, sender: Option<mpsc::Sender<String>>) -> Option<ReturnKind>
+    async fn execute<F, Fut>(&mut self, f: F, sender: Option<mpsc::Sender<Bytes>>) -> Option<ReturnKind>
         where
             F: Fn(Self::Ses, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
             Fut: Future<Output=()> + Send;
@@ -41,19 +61,82 @@ pub trait ClientExecute {
 pub struct ServerHandler {
     session: ServerSession,
     receiver: mpsc::Receiver<HandleEvent>,
+    retry_timer: Interval,
+    last_packet: Instant,
+    /// 完成连接的期限, 回复 CONNACK 之前有效
+    connect_deadline: Option<Instant>,
+    /// 进行中的增强认证, 第二项表示是否为 CONNECT 时的认证
+    authentication: Option<(Box<dyn AuthExchange>, bool)>,
+    /// 服务端关闭信号
+    shutdown: Option<CancellationToken>,
+    /// 关闭期限, 收到关闭信号后开始计算
+    drain_deadline: Option<Instant>,
+    drain_timer: Interval,
+    disconnecting: bool,
 }
 
 impl ServerHandler {
-    pub fn new() -> ServerHandler {
+    pub fn new(config: Arc<ServerConfig>) -> ServerHandler {
         let (sender, receiver) = mpsc::channel(512);
+        let retry_interval = config.retry_interval();
+        let connect_deadline = Instant::now() + config.connect_timeout();
+        let mut drain_timer = time::interval(DRAIN_CHECK_INTERVAL);
+        drain_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
         ServerHandler {
-            session: ServerSession::new(sender),
+            session: ServerSession::new(sender, config),
             receiver,
+            retry_timer: time::interval_at(Instant::now() + retry_interval, retry_interval),
+            last_packet: Instant::now(),
+            connect_deadline: Some(connect_deadline),
+            authentication: None,
+            shutdown: None,
+            drain_deadline: None,
+            drain_timer,
+            disconnecting: false,
         }
     }
 
+    ///
+    /// 记录对端地址与 TLS 客户端证书, 用于连接认证
+    ///
+    pub fn peer(mut self, peer_addr: SocketAddr, peer_certificates: Option<Vec<Certificate>>) -> ServerHandler {
+        self.session.init_peer(peer_addr, peer_certificates);
+        self
+    }
+
+    ///
+    /// 记录 Unix 套接字对端进程的凭据, 用于连接认证
+    ///
+    pub fn peer_credentials(mut self, peer_credentials: Option<PeerCredentials>) -> ServerHandler {
+        self.session.init_credentials(peer_credentials);
+        self
+    }
+
+    ///
+    /// 完成连接的期限, 默认从创建时开始计算; 接受连接后需要先完成 TLS 或 WebSocket 握手时由调用方指定
+    ///
+    pub fn connect_deadline(mut self, deadline: Instant) -> ServerHandler {
+        self.connect_deadline = Some(deadline);
+        self
+    }
+
+    ///
+    /// 服务端关闭信号: 收到后继续处理报文, 直到进行中的 QoS 流程完成或超过关闭期限, 然后以 0x8B 断开
+    ///
+    pub fn shutdown(mut self, shutdown: CancellationToken) -> ServerHandler {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
     pub async fn send_message(&self, msg: HandleEvent) {
         self.session.send_event(msg).await;
     }
+
+    ///
+    /// 未连接或者没有进行中的 QoS 1/2 流程, 可以直接断开
+    ///
+    pub async fn is_settled(&self) -> bool {
+        !self.session.is_connected() || MESSAGE_CONTAINER.is_settled(self.session.get_client_id()).await
+    }
 }
 
@@ -1,17 +1,29 @@
+use std::convert::TryFrom;
 use std::future::Future;
-use tokio::sync::mpsc;
+use std::time::Duration;
 use async_trait::async_trait;
-use crate::handle::{HandleEvent, ServerExecute, ServerHandler};
+use rand::Rng;
+use crate::auth::{Action, AuthExchange, AuthStep, Credentials};
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+use crate::handle::{HandleEvent, Response, ServerExecute, ServerHandler};
+use crate::hex::{find_property, Property, PropertyItem, PropertyValue};
+use crate::hex::reason_code::{ReasonCodes, ReasonCodeV3, ReasonCodeV5, ReasonPhrases};
 use crate::message::{MqttMessageKind, BaseMessage, VariableHeader, MqttProtocolLevelInfo};
 use crate::session::{MqttSession, ServerSession};
-use crate::{SUBSCRIPT, MESSAGE_CONTAINER};
-use crate::container::MessageFrame;
+use crate::{SUBSCRIPT, MESSAGE_CONTAINER, SESSION_REGISTRY};
+use crate::container::{FrameState, MessageFrame};
+use crate::registry::SESSION_NEVER_EXPIRE;
 use crate::executor::ReturnKind;
-use crate::message::entity::PublishMessage;
+use crate::message::entity::{AuthMessage, ConnackMessage, ConnectMessage, DisconnectMessage, PubrecMessage, PublishMessage, SubackMessage, SubscribeMessage, UnsubackMessage};
+use crate::packet::{encode_ack, encode_publish};
 use crate::message::v3::MqttMessageV3;
 use crate::message::v5::MqttMessageV5;
+use crate::subscript::{is_valid_topic_filter, SubscriptOption};
 use crate::subscript::TopicMessage::Content;
-use crate::tools::protocol::{MqttCleanSession, MqttProtocolLevel, MqttQos};
+use crate::tools::error::DecodeError;
+use crate::tools::tls::IdentityAs;
+use crate::tools::protocol::{MqttCleanSession, MqttProtocolLevel, MqttQos, MqttRetain, MqttSessionPresent};
 use crate::tools::types::TypeKind;
 use crate::tools::un_pack_tool::get_connect_variable_header;
 
@@ -23,55 +35,87 @@ impl ServerExecute for ServerHandler {
             F: Fn(Self::Ses, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
             Fut: Future<Output=()> + Send,
     {
-        return match self.receiver.recv().await {
+        let keep_alive = self.keep_alive_timeout();
+        let deadline = self.last_packet + keep_alive.unwrap_or_default();
+        let connect_deadline = self.connect_deadline.filter(|_| self.is_connecting());
+        let shutdown = self.shutdown.clone().filter(|_| self.drain_deadline.is_none());
+        let event = tokio::select! {
+            event = self.receiver.recv() => event,
+            _ = self.retry_timer.tick() => return self.retransmit_expired().await,
+            _ = time::sleep_until(deadline), if keep_alive.is_some() => return self.keep_alive_expired().await,
+            _ = time::sleep_until(connect_deadline.unwrap_or(deadline)), if connect_deadline.is_some() => return self.connect_expired().await,
+            _ = cancelled(shutdown.as_ref()), if shutdown.is_some() => {
+                self.drain_deadline = Some(Instant::now() + self.session.config().shutdown_timeout());
+                return None;
+            },
+            _ = self.drain_timer.tick(), if self.drain_deadline.is_some() && !self.disconnecting => return self.drain_expired().await,
+        };
+        return match event {
             Some(msg) => return match msg {
                 HandleEvent::InputEvent(data) => {
                     println!("server input: {:?}", data);
-                    let base_msg = BaseMessage::from(data);
-                    let type_kind = base_msg.msg_type;
-                    if type_kind == TypeKind::CONNECT {
-                        let (header, _) = get_connect_variable_header(base_msg.bytes.as_slice());
-                        self.init_session_protocol(&header);
+                    self.last_packet = Instant::now();
+                    match self.input(data) {
+                        Ok(mut request) => {
+                            if self.session.is_connected() && is_connect(&request) {
+                                println!("duplicate connect; client = {:?}", self.session.get_client_id());
+                                self.protocol_error(ReasonPhrases::ProtocolError).await;
+                                return None;
+                            }
+                            self.init_session(&request);
+                            match self.handle_request(&mut request).await {
+                                Ok(response) => {
+                                    f(self.session.clone(), request).await;
+                                    if !response.is_empty() {
+                                        return Some(ReturnKind::Response(response));
+                                    }
+                                }
+                                Err(code) => {
+                                    println!("protocol error; reason = {}", code.as_str());
+                                    self.protocol_error(code).await;
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            println!("failed to decode packet; err = {}", err);
+                            self.decode_error(err).await;
+                        }
                     }
-                    let mut request = self.request(base_msg);
-                    self.init_session(&request);
-                    self.handle_request(&mut request).await;
-                    f(self.session.clone(), request).await;
                     None
                 }
                 HandleEvent::BroadcastEvent(Content(from_id, content)) => {
                     let client_id = self.session().get_client_id();
                     println!("from: {:?}", from_id);
                     println!("to: {:?}", client_id);
-                    if content.qos == MqttQos::Qos2 {
-                        MESSAGE_CONTAINER.append(
-                            client_id.clone(),
-                            content.message_id,
-                            MessageFrame::new(
-                                from_id.clone(),
-                                client_id.clone(),
-                                content.bytes.as_ref().unwrap().clone(),
-                                content.message_id,
-                            ),
-                        ).await;
-                    }
-
-                    if client_id != &from_id {
-                        let bytes = self.publish(content);
-                        return Some(ReturnKind::Response(bytes));
+                    match MESSAGE_CONTAINER.append(client_id, from_id, content).await {
+                        Some(msg) => encode_publish(self.session.protocol_level.unwrap(), &msg).map(|(head, rest)| ReturnKind::Publish(head, rest)),
+                        None => {
+                            println!("no packet identifier available; client = {:?}", client_id);
+                            None
+                        }
                     }
-                    None
                 }
                 HandleEvent::ExitEvent(will) => {
-                    if will && self.session.is_will_flag() {
-                        if let Some(ref topic_msg) = self.session.get_will_message() {
-                            SUBSCRIPT.broadcast(self.session.get_will_topic(), topic_msg).await;
-                        }
+                    if self.authentication.take().is_some_and(|(_, connecting)| connecting) {
+                        self.session.refuse();
+                    }
+                    if !self.session.is_connected() {
+                        return Some(ReturnKind::Exit);
                     }
-                    SUBSCRIPT.exit(self.session.get_client_id()).await;
+                    let will = if will && self.session.is_will_flag() {
+                        self.session.get_will_message().map(|Content(_, msg)| msg)
+                    } else {
+                        None
+                    };
+                    self.close_session(will).await;
                     Some(ReturnKind::Exit)
                 }
-                HandleEvent::OutputEvent(data) => Some(ReturnKind::Response(data.0))
+                HandleEvent::DisconnectEvent(code) => {
+                    self.server_disconnect(code).await;
+                    None
+                }
+                HandleEvent::OutputEvent(data) => Some(ReturnKind::Response(data.0)),
+                HandleEvent::ReAuthEvent => None
             },
             _ => None
         };
@@ -79,22 +123,156 @@ impl ServerExecute for ServerHandler {
 }
 
 impl ServerHandler {
-    fn request(&self, base_msg: BaseMessage) -> Option<MqttMessageKind> {
-        if let Some(level) = self.protocol_level() {
-            match level {
-                MqttProtocolLevel::Level3_1_1 => {
-                    MqttMessageKind::to_v3_request(base_msg)
-                }
-                MqttProtocolLevel::Level5 => {
-                    MqttMessageKind::to_v5_request(base_msg)
-                }
-                _ => None
+    fn input(&mut self, data: Vec<u8>) -> Result<Option<MqttMessageKind>, DecodeError> {
+        let base_msg = BaseMessage::try_from(data)?;
+        if base_msg.msg_type == TypeKind::CONNECT && !self.session.is_connected() {
+            let (header, _) = get_connect_variable_header(base_msg.bytes.as_slice())?;
+            self.init_session_protocol(&header);
+            if let Some(level) = header.protocol_level.filter(|level| !self.session.config().allow_protocol_level(*level)) {
+                return Err(DecodeError::UnsupportedProtocolLevel(level as u8));
+            }
+        }
+        self.request(base_msg)
+    }
+
+    fn request(&self, base_msg: BaseMessage) -> Result<Option<MqttMessageKind>, DecodeError> {
+        match self.protocol_level() {
+            Some(MqttProtocolLevel::Level3_1_1) => {
+                MqttMessageKind::to_v3_request(base_msg).map(Some)
+            }
+            Some(MqttProtocolLevel::Level5) => {
+                MqttMessageKind::to_v5_request(base_msg).map(Some)
             }
+            Some(MqttProtocolLevel::Level3_1) => {
+                Err(DecodeError::UnsupportedProtocolLevel(MqttProtocolLevel::Level3_1 as u8))
+            }
+            None => Ok(None)
+        }
+    }
+
+    ///
+    /// 协议错误: v5 回复带原因码的 DISCONNECT, v3 直接断开连接
+    ///
+    async fn protocol_error(&self, code: ReasonPhrases) {
+        if self.protocol_level() == Some(MqttProtocolLevel::Level5) {
+            if let Some(data) = MqttMessageV5::Disconnect(DisconnectMessage::new(code)).to_vec() {
+                self.session.send_event(HandleEvent::OutputEvent(Response(data, MqttProtocolLevel::Level5))).await;
+            }
+        }
+        self.session.exit().await;
+    }
+
+    ///
+    /// 报文解析失败: v5 回复 CONNACK / DISCONNECT 原因码, v3 协议版本不支持时回复 CONNACK 0x01, 然后断开连接
+    ///
+    async fn decode_error(&self, err: DecodeError) {
+        let connected = self.session.is_connected();
+        let response = match self.protocol_level() {
+            Some(MqttProtocolLevel::Level5) if connected => {
+                MqttMessageV5::Disconnect(DisconnectMessage::new(err.reason_code())).to_vec()
+            }
+            Some(MqttProtocolLevel::Level5) => {
+                MqttMessageV5::Connack(ConnackMessage::new(MqttSessionPresent::Disable, ReasonCodes::V5(ReasonCodeV5::ReasonPhrases(err.reason_code())))).to_vec()
+            }
+            _ if !connected && matches!(err, DecodeError::UnsupportedProtocolLevel(_)) => {
+                MqttMessageV3::Connack(ConnackMessage::new(MqttSessionPresent::Disable, ReasonCodes::V3(ReasonCodeV3::UnacceptableProtocolVersion))).to_vec()
+            }
+            _ => None
+        };
+        if let Some(data) = response {
+            let level = self.protocol_level().unwrap_or(MqttProtocolLevel::Level3_1_1);
+            self.session.send_event(HandleEvent::OutputEvent(Response(data, level))).await;
+        }
+        self.session.exit().await;
+    }
+
+    ///
+    /// 服务端主动断开连接, 已连接时 v5 回复带原因码的 DISCONNECT
+    ///
+    async fn server_disconnect(&self, code: ReasonPhrases) {
+        if self.session.is_connected() {
+            self.protocol_error(code).await;
         } else {
-            None
+            self.session.exit().await;
+        }
+    }
+
+    ///
+    /// 关闭期间定期检查: 进行中的 QoS 流程完成或超过关闭期限时以 0x8B 断开
+    ///
+    async fn drain_expired(&mut self) -> Option<ReturnKind> {
+        let expired = self.drain_deadline.is_some_and(|deadline| deadline <= Instant::now());
+        if expired || self.is_settled().await {
+            self.disconnecting = true;
+            self.server_disconnect(ReasonPhrases::ServerShuttingDown).await;
+        }
+        None
+    }
+
+    ///
+    /// 保活超时时间为保活时间的 1.5 倍, 保活时间为 0 时不检测
+    ///
+    fn keep_alive_timeout(&self) -> Option<Duration> {
+        match self.session.keep_alive() {
+            0 => None,
+            _ if !self.session.is_connected() => None,
+            keep_alive => Some(Duration::from_millis(keep_alive as u64 * 1500))
         }
     }
 
+    ///
+    /// 还没有回复 CONNACK: 没有收到 CONNECT, 或者 CONNECT 时的增强认证还在进行
+    ///
+    fn is_connecting(&self) -> bool {
+        !self.session.is_connected() || self.authentication.as_ref().is_some_and(|(_, connecting)| *connecting)
+    }
+
+    ///
+    /// 连接超时: 期限内没有完成连接时断开, 不回复任何报文
+    ///
+    async fn connect_expired(&mut self) -> Option<ReturnKind> {
+        println!("connect timeout; peer = {:?}", self.session.peer_addr());
+        self.connect_deadline = None;
+        self.session.exit().await;
+        None
+    }
+
+    ///
+    /// 保活超时: v5 回复 DISCONNECT 0x8D, 断开连接并发布遗嘱
+    ///
+    async fn keep_alive_expired(&mut self) -> Option<ReturnKind> {
+        println!("keep alive timeout; client = {:?}", self.session.get_client_id());
+        self.last_packet = Instant::now();
+        self.protocol_error(ReasonPhrases::KeepAliveTimeout).await;
+        None
+    }
+
+    ///
+    /// 重发超时未确认的消息, v5 协议只允许在重连时重发
+    ///
+    async fn retransmit_expired(&self) -> Option<ReturnKind> {
+        if !self.session.is_connected() || self.protocol_level() != Some(MqttProtocolLevel::Level3_1_1) {
+            return None;
+        }
+        let frames = MESSAGE_CONTAINER.expired(self.session.get_client_id(), self.session.config().retry_interval()).await;
+        let response = frames.into_iter().flat_map(|frame| self.retransmit(frame)).collect::<Vec<u8>>();
+        if response.is_empty() {
+            return None;
+        }
+        Some(ReturnKind::Response(response))
+    }
+
+    fn retransmit(&self, frame: MessageFrame) -> Vec<u8> {
+        match frame.state() {
+            FrameState::Publish => self.publish(frame.message().clone()),
+            FrameState::Pubrel => self.ack(TypeKind::PUBREL, frame.message_id(), ReasonPhrases::Success),
+        }
+    }
+
+    fn ack(&self, kind: TypeKind, message_id: u16, code: ReasonPhrases) -> Vec<u8> {
+        encode_ack(self.protocol_level().unwrap_or(MqttProtocolLevel::Level3_1_1), kind, message_id, code)
+    }
+
     fn publish(&self, content: PublishMessage) -> Vec<u8> {
         match self.session().protocol_level.unwrap() {
             MqttProtocolLevel::Level3_1_1 => {
@@ -108,6 +286,24 @@ impl ServerHandler {
     }
 }
 
+async fn cancelled(shutdown: Option<&CancellationToken>) {
+    match shutdown {
+        Some(shutdown) => shutdown.cancelled().await,
+        None => std::future::pending().await
+    }
+}
+
+fn is_connect(request: &Option<MqttMessageKind>) -> bool {
+    matches!(request, Some(MqttMessageKind::RequestV3(MqttMessageV3::Connect(_))) | Some(MqttMessageKind::RequestV5(MqttMessageV5::Connect(_))))
+}
+
+///
+/// 为空客户端标识分配一个唯一的标识
+///
+fn assign_client_id() -> String {
+    format!("auto-{:016x}", rand::thread_rng().gen::<u64>())
+}
+
 #[async_trait]
 pub trait HandleSession {
     fn session(&self) -> &ServerSession;
@@ -131,42 +327,102 @@ pub trait HandleSession {
                 _ => None
             };
             if let Some(connect) = connect_msg {
+                let client_id = self.session().identity_name(IdentityAs::ClientId)
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| connect.payload.client_id.clone());
+                let client_id = if client_id.is_empty() { assign_client_id() } else { client_id };
+                let assigned_client_id = client_id != connect.payload.client_id;
+                let username = self.session().identity_name(IdentityAs::Username)
+                    .map(str::to_owned)
+                    .or_else(|| connect.payload.user_name.clone());
                 self.session_mut().init(
-                    connect.payload.client_id.clone().into(),
+                    client_id.into(),
                     connect.will_flag,
                     connect.will_qos,
                     connect.will_retain,
                     connect.payload.will_topic.clone().unwrap(),
                     connect.payload.will_message.clone().unwrap(),
                 );
-                MESSAGE_CONTAINER.init(connect.payload.client_id.clone().into());
+                let session_expiry_interval = match connect.protocol_level {
+                    MqttProtocolLevel::Level5 => {
+                        find_property(connect.properties.as_deref(), Property::SessionExpiryInterval)
+                            .and_then(PropertyItem::as_long)
+                            .unwrap_or(0)
+                            .min(self.session().config().max_session_expiry_interval())
+                    }
+                    _ if connect.clean_session == MqttCleanSession::Disable => SESSION_NEVER_EXPIRE,
+                    _ => 0
+                };
+                let will_delay_interval = find_property(connect.payload.properties.as_deref(), Property::WillDelayInterval)
+                    .and_then(PropertyItem::as_long)
+                    .unwrap_or(0);
+                let keep_alive = match self.session().config().server_keep_alive() {
+                    Some(keep_alive) if connect.protocol_level == MqttProtocolLevel::Level5 => keep_alive,
+                    _ => connect.keep_alive
+                };
+                let session = self.session_mut();
+                session.username = username;
+                session.assigned_client_id = assigned_client_id;
+                session.authentication_method = find_property(connect.properties.as_deref(), Property::AuthenticationMethod)
+                    .and_then(PropertyItem::as_str)
+                    .cloned();
+                session.keep_alive = keep_alive;
+                session.clean_session = Some(connect.clean_session);
+                session.session_expiry_interval = session_expiry_interval;
+                session.will_delay_interval = will_delay_interval;
             }
         }
     }
+}
 
-    async fn handle_request(&self, request: &mut Option<MqttMessageKind>) {
+impl ServerHandler {
+    ///
+    /// 处理协议层面的请求, 返回需要立即回复的报文; 返回 `Err` 时以对应原因码断开连接.
+    /// 重复收到的 QoS 2 消息不再交给回调处理
+    ///
+    async fn handle_request(&mut self, request: &mut Option<MqttMessageKind>) -> Result<Vec<u8>, ReasonPhrases> {
+        let mut response = vec![];
+        let mut deliver = true;
         if let Some(kind) = request {
+            if self.authentication.as_ref().is_some_and(|(_, connecting)| *connecting) && !matches!(kind, MqttMessageKind::RequestV5(MqttMessageV5::Auth(_) | MqttMessageV5::Disconnect(_))) {
+                return Err(ReasonPhrases::ProtocolError);
+            }
             match kind {
                 MqttMessageKind::RequestV3(v3) => {
                     v3.set_protocol_level(self.protocol_level().unwrap());
                     match v3 {
-                        MqttMessageV3::Unsubscribe(msg) => {
-                            SUBSCRIPT.unsubscript(&msg.topic, self.session().get_client_id()).await;
+                        MqttMessageV3::Connect(msg) if msg.payload.client_id.is_empty() && msg.clean_session == MqttCleanSession::Disable && self.session.identity_name(IdentityAs::ClientId).is_none() => {
+                            response.extend(self.refuse(ReasonPhrases::ClientIdentifierNotValid).await);
+                            deliver = false;
+                        }
+                        MqttMessageV3::Connect(msg) => {
+                            match self.authenticate(msg).await {
+                                Ok(_) => response.extend(self.connect(None).await),
+                                Err(code) => {
+                                    response.extend(self.refuse(code).await);
+                                    deliver = false;
+                                }
+                            }
+                        }
+                        MqttMessageV3::Publish(msg) => {
+                            let (ack, first) = self.receive(msg).await;
+                            response.extend(ack);
+                            deliver = first;
+                        }
+                        MqttMessageV3::Puback(msg) => {
+                            MESSAGE_CONTAINER.puback(self.session().get_client_id(), msg.message_id).await;
+                        }
+                        MqttMessageV3::Pubrec(msg) => {
+                            response.extend(self.pubrec(msg).await);
                         }
                         MqttMessageV3::Pubrel(msg) => {
-                            MESSAGE_CONTAINER.complete(self.session().get_client_id(), msg.message_id).await;
+                            response.extend(self.pubrel(msg.message_id).await);
+                        }
+                        MqttMessageV3::Pubcomp(msg) => {
+                            MESSAGE_CONTAINER.pubcomp(self.session().get_client_id(), msg.message_id).await;
                         }
                         MqttMessageV3::Disconnect(_) => {
-                            if self.session().is_will_flag() {
-                                if let Some(ref topic_msg) = self.session().get_will_message() {
-                                    SUBSCRIPT.broadcast(self.session().get_will_topic(), topic_msg).await;
-                                }
-                            }
-                            SUBSCRIPT.exit(self.session().get_client_id()).await;
-
-                            if self.session().clean_session.is_some() && self.session().clean_session.unwrap() == MqttCleanSession::Enable {
-                                MESSAGE_CONTAINER.remove(self.session().get_client_id()).await;
-                            }
+                            self.disconnect(false).await;
                         }
                         _ => {}
                     }
@@ -174,32 +430,405 @@ pub trait HandleSession {
                 MqttMessageKind::RequestV5(v5) => {
                     v5.set_protocol_level(self.protocol_level().unwrap());
                     match v5 {
-                        MqttMessageV5::Unsubscribe(msg) => {
-                            SUBSCRIPT.unsubscript(&msg.topic, self.session().get_client_id()).await;
+                        MqttMessageV5::Connect(msg) => {
+                            let method = self.session.authentication_method().map(str::to_owned);
+                            match (self.authenticate(msg).await, method) {
+                                (Ok(_), Some(method)) => {
+                                    let data = find_property(msg.properties.as_deref(), Property::AuthenticationData).and_then(PropertyItem::as_binary).cloned();
+                                    response.extend(self.start_authentication(&method, data, true).await?);
+                                    deliver = self.authentication.is_none() && self.session.is_connected();
+                                }
+                                (Ok(_), None) => response.extend(self.connect(None).await),
+                                (Err(code), _) => {
+                                    response.extend(self.refuse(code).await);
+                                    deliver = false;
+                                }
+                            }
+                        }
+                        MqttMessageV5::Auth(msg) => {
+                            let method = find_property(msg.properties.as_deref(), Property::AuthenticationMethod).and_then(PropertyItem::as_str);
+                            if method.is_none() || method.map(String::as_str) != self.session.authentication_method() {
+                                return Err(ReasonPhrases::ProtocolError);
+                            }
+                            let data = find_property(msg.properties.as_deref(), Property::AuthenticationData).and_then(PropertyItem::as_binary).cloned();
+                            match (ReasonPhrases::try_from(msg.code), self.authentication.take()) {
+                                (Ok(ReasonPhrases::ContinueAuthentication), Some((exchange, connecting))) => {
+                                    response.extend(self.authentication_step(exchange, connecting, data).await?);
+                                }
+                                (Ok(ReasonPhrases::ReAuthenticate), None) => {
+                                    let method = method.unwrap().to_owned();
+                                    response.extend(self.start_authentication(&method, data, false).await?);
+                                }
+                                _ => return Err(ReasonPhrases::ProtocolError)
+                            }
+                        }
+                        MqttMessageV5::Publish(msg) if msg.retain == MqttRetain::Enable && !self.session.config().retain_available() => {
+                            return Err(ReasonPhrases::RetainNotSupported);
+                        }
+                        MqttMessageV5::Publish(msg) => {
+                            let (ack, first) = self.receive(msg).await;
+                            response.extend(ack);
+                            deliver = first;
+                        }
+                        MqttMessageV5::Puback(msg) => {
+                            MESSAGE_CONTAINER.puback(self.session().get_client_id(), msg.message_id).await;
+                        }
+                        MqttMessageV5::Pubrec(msg) => {
+                            response.extend(self.pubrec(msg).await);
                         }
                         MqttMessageV5::Pubrel(msg) => {
-                            MESSAGE_CONTAINER.complete(self.session().get_client_id(), msg.message_id).await;
+                            response.extend(self.pubrel(msg.message_id).await);
                         }
-                        MqttMessageV5::Disconnect(_) => {
-                            if self.session().is_will_flag() {
-                                if let Some(ref topic_msg) = self.session().get_will_message() {
-                                    SUBSCRIPT.broadcast(self.session().get_will_topic(), topic_msg).await;
+                        MqttMessageV5::Pubcomp(msg) => {
+                            MESSAGE_CONTAINER.pubcomp(self.session().get_client_id(), msg.message_id).await;
+                        }
+                        MqttMessageV5::Disconnect(msg) => {
+                            if let Some(expiry) = find_property(msg.properties.as_deref(), Property::SessionExpiryInterval).and_then(PropertyItem::as_long) {
+                                if self.session.session_expiry_interval == 0 && expiry != 0 {
+                                    return Err(ReasonPhrases::ProtocolError);
                                 }
+                                self.session.session_expiry_interval = expiry.min(self.session.config().max_session_expiry_interval());
                             }
-                            SUBSCRIPT.exit(self.session().get_client_id()).await;
-
-                            if self.session().clean_session.is_some() && self.session().clean_session.unwrap() == MqttCleanSession::Enable {
-                                MESSAGE_CONTAINER.remove(self.session().get_client_id()).await;
-                            }
+                            self.disconnect(msg.code == Some(ReasonPhrases::DisconnectWithWillMessage.as_byte())).await;
                         }
                         _ => {}
                     }
                 }
-                _ => {}
+                MqttMessageKind::RequestV3Vec(items) => {
+                    let level = self.protocol_level().unwrap();
+                    let mut subscribes = vec![];
+                    let mut unsubscribes = vec![];
+                    for item in items.iter_mut() {
+                        item.set_protocol_level(level);
+                        match item {
+                            MqttMessageV3::Subscribe(msg) => subscribes.push(&*msg),
+                            MqttMessageV3::Unsubscribe(msg) => {
+                                let exist = SUBSCRIPT.unsubscript(&msg.topic, self.session().get_client_id()).await;
+                                unsubscribes.push((msg.message_id, exist));
+                            }
+                            _ => {}
+                        }
+                    }
+                    response.extend(self.subscribe(subscribes).await);
+                    response.extend(self.unsuback(unsubscribes));
+                }
+                MqttMessageKind::RequestV5Vec(items) => {
+                    let level = self.protocol_level().unwrap();
+                    let mut subscribes = vec![];
+                    let mut unsubscribes = vec![];
+                    for item in items.iter_mut() {
+                        item.set_protocol_level(level);
+                        match item {
+                            MqttMessageV5::Subscribe(msg) => subscribes.push(&*msg),
+                            MqttMessageV5::Unsubscribe(msg) => {
+                                let exist = SUBSCRIPT.unsubscript(&msg.topic, self.session().get_client_id()).await;
+                                unsubscribes.push((msg.message_id, exist));
+                            }
+                            _ => {}
+                        }
+                    }
+                    response.extend(self.subscribe(subscribes).await);
+                    response.extend(self.unsuback(unsubscribes));
+                }
+            }
+        }
+        if !deliver {
+            *request = None;
+        }
+        Ok(response)
+    }
+
+    ///
+    /// 连接认证, 没有配置认证器时接受所有连接.
+    /// 客户端标识与用户名取自会话, 可能已被替换为客户端证书中的身份
+    ///
+    async fn authenticate(&self, msg: &ConnectMessage) -> Result<(), ReasonPhrases> {
+        let authenticator = match self.session.config().authenticator() {
+            Some(authenticator) => authenticator,
+            None => return Ok(())
+        };
+        let credentials = Credentials {
+            client_id: &self.session.get_client_id().0,
+            username: self.session.username(),
+            password: msg.payload.password.as_deref(),
+            peer_addr: self.session.peer_addr(),
+            peer_certificate: self.session.peer_certificates().and_then(|certs| certs.first()),
+            peer_credentials: self.session.peer_credentials(),
+        };
+        authenticator.authenticate(&credentials).await
+    }
+
+    ///
+    /// 开始增强认证, 服务端不支持该认证方法时拒绝连接 (重新认证时断开连接)
+    ///
+    async fn start_authentication(&mut self, method: &str, data: Option<Vec<u8>>, connecting: bool) -> Result<Vec<u8>, ReasonPhrases> {
+        match self.session.config().auth_mechanism(method).cloned() {
+            Some(mechanism) => self.authentication_step(mechanism.start(), connecting, data).await,
+            None if connecting => Ok(self.refuse(ReasonPhrases::BadAuthenticationMethod).await),
+            None => Err(ReasonPhrases::BadAuthenticationMethod)
+        }
+    }
+
+    ///
+    /// 增强认证前进一步: 需要继续时回复 AUTH 0x18; 成功时完成连接 (CONNACK) 或回复 AUTH 0x00;
+    /// 失败时拒绝连接, 重新认证失败则以原因码断开连接
+    ///
+    async fn authentication_step(&mut self, mut exchange: Box<dyn AuthExchange>, connecting: bool, data: Option<Vec<u8>>) -> Result<Vec<u8>, ReasonPhrases> {
+        match exchange.step(data.as_deref()).await {
+            AuthStep::Continue(data) => {
+                self.authentication = Some((exchange, connecting));
+                Ok(self.auth(ReasonPhrases::ContinueAuthentication, Some(data)))
+            }
+            AuthStep::Success(data) if connecting => Ok(self.connect(data).await),
+            AuthStep::Success(data) => Ok(self.auth(ReasonPhrases::Success, data)),
+            AuthStep::Failure(code) if connecting => Ok(self.refuse(code).await),
+            AuthStep::Failure(code) => Err(code)
+        }
+    }
+
+    fn auth(&self, code: ReasonPhrases, data: Option<Vec<u8>>) -> Vec<u8> {
+        let method = self.session.authentication_method().unwrap_or_default().to_owned();
+        let mut properties = vec![PropertyItem(Property::AuthenticationMethod, PropertyValue::String(method))];
+        if let Some(data) = data {
+            properties.push(PropertyItem(Property::AuthenticationData, PropertyValue::Binary(data)));
+        }
+        MqttMessageV5::Auth(AuthMessage { code: code.as_byte(), properties: Some(properties), ..AuthMessage::default() }).to_vec().unwrap_or_default()
+    }
+
+    ///
+    /// 拒绝连接: 回复带原因码的 CONNACK 后断开连接, 不绑定会话也不发布遗嘱
+    ///
+    async fn refuse(&mut self, code: ReasonPhrases) -> Vec<u8> {
+        println!("connection refused; client = {:?}, reason = {}", self.session.get_client_id(), code.as_str());
+        self.session.refuse();
+        self.session.exit().await;
+        let data = match self.protocol_level() {
+            Some(MqttProtocolLevel::Level5) => {
+                MqttMessageV5::Connack(ConnackMessage::new(MqttSessionPresent::Disable, ReasonCodes::V5(ReasonCodeV5::ReasonPhrases(code)))).to_vec()
             }
+            _ => MqttMessageV3::Connack(ConnackMessage::new(MqttSessionPresent::Disable, ReasonCodes::V3(code.into()))).to_vec()
+        };
+        data.unwrap_or_default()
+    }
+
+    ///
+    /// 建立连接: 没有可继续的会话时丢弃旧的订阅与消息; 否则恢复订阅, 在 CONNACK 之后重发未完成的消息并投递离线消息
+    ///
+    async fn connect(&self, auth_data: Option<Vec<u8>>) -> Vec<u8> {
+        let client_id = self.session.get_client_id();
+        let clean_start = self.session.clean_session != Some(MqttCleanSession::Disable);
+        let (session_present, previous) = SESSION_REGISTRY.connect(client_id, self.session.sender().clone(), clean_start, self.session.session_expiry_interval()).await;
+        if let Some(previous) = previous {
+            println!("session taken over; client = {:?}", client_id);
+            if let Err(e) = previous.send(HandleEvent::DisconnectEvent(ReasonPhrases::SessionTakenOver)).await {
+                println!("failed to close previous connection; err = {:?}", e);
+            }
+        }
+        let mut response = self.connack(session_present, auth_data);
+        for frame in MESSAGE_CONTAINER.resend(client_id).await {
+            response.extend(self.retransmit(frame));
+        }
+        for (from, msg) in MESSAGE_CONTAINER.dequeue(client_id).await {
+            if let Some(msg) = MESSAGE_CONTAINER.append(client_id, from, msg).await {
+                response.extend(self.publish(msg));
+            }
+        }
+        response
+    }
+
+    ///
+    /// 收到 PUBLISH: QoS 1 回复 PUBACK, QoS 2 回复 PUBREC; 返回值第二项为 `false` 表示消息不再转发 (重复的 QoS 2 消息或未授权)
+    ///
+    /// 未授权的消息在 v5 中以原因码 0x87 确认, v3 照常确认后丢弃
+    ///
+    async fn receive(&self, msg: &PublishMessage) -> (Vec<u8>, bool) {
+        if !self.session.authorize(Action::Publish, &msg.topic).await {
+            println!("publish not authorized; client = {:?}, topic = {}", self.session.get_client_id(), msg.topic);
+            let ack = match msg.qos {
+                MqttQos::Qos1 => self.ack(TypeKind::PUBACK, msg.message_id, ReasonPhrases::NotAuthorized),
+                MqttQos::Qos2 => self.ack(TypeKind::PUBREC, msg.message_id, ReasonPhrases::NotAuthorized),
+                _ => vec![]
+            };
+            return (ack, false);
+        }
+        match msg.qos {
+            MqttQos::Qos1 => (self.ack(TypeKind::PUBACK, msg.message_id, ReasonPhrases::Success), true),
+            MqttQos::Qos2 => {
+                let first = MESSAGE_CONTAINER.receive(self.session.get_client_id(), msg.message_id).await;
+                (self.ack(TypeKind::PUBREC, msg.message_id, ReasonPhrases::Success), first)
+            }
+            _ => (vec![], true)
+        }
+    }
+
+    ///
+    /// 收到 PUBREC: 回复 PUBREL; v5 中失败的原因码直接结束该消息流程
+    ///
+    async fn pubrec(&self, msg: &PubrecMessage) -> Vec<u8> {
+        let client_id = self.session.get_client_id();
+        if msg.code.is_some_and(|code| code.as_byte() >= 0x80) {
+            MESSAGE_CONTAINER.pubcomp(client_id, msg.message_id).await;
+            return vec![];
+        }
+        let code = if MESSAGE_CONTAINER.pubrec(client_id, msg.message_id).await {
+            ReasonPhrases::Success
+        } else {
+            ReasonPhrases::PacketIdentifierNotFound
+        };
+        self.ack(TypeKind::PUBREL, msg.message_id, code)
+    }
+
+    ///
+    /// 收到 PUBREL: 释放报文标识符并回复 PUBCOMP
+    ///
+    async fn pubrel(&self, message_id: u16) -> Vec<u8> {
+        let code = if MESSAGE_CONTAINER.release(self.session.get_client_id(), message_id).await {
+            ReasonPhrases::Success
+        } else {
+            ReasonPhrases::PacketIdentifierNotFound
+        };
+        self.ack(TypeKind::PUBCOMP, message_id, code)
+    }
 
+    fn connack(&self, session_present: bool, auth_data: Option<Vec<u8>>) -> Vec<u8> {
+        let session_present = if session_present { MqttSessionPresent::Enable } else { MqttSessionPresent::Disable };
+        match self.protocol_level() {
+            Some(MqttProtocolLevel::Level5) => {
+                let mut msg = ConnackMessage::new(session_present, ReasonCodes::V5(ReasonCodeV5::ReasonPhrases(ReasonPhrases::Success)));
+                let mut properties = vec![
+                    PropertyItem(Property::RetainAvailable, PropertyValue::Byte(self.session.config().retain_available() as u8)),
+                ];
+                let max_session_expiry_interval = self.session.config().max_session_expiry_interval();
+                if let Some(keep_alive) = self.session.config().server_keep_alive() {
+                    properties.push(PropertyItem(Property::ServerKeepAlive, PropertyValue::Short(keep_alive)));
+                }
+                if max_session_expiry_interval != SESSION_NEVER_EXPIRE && self.session.session_expiry_interval() == max_session_expiry_interval {
+                    properties.push(PropertyItem(Property::SessionExpiryInterval, PropertyValue::Long(max_session_expiry_interval)));
+                }
+                if self.session.assigned_client_id {
+                    properties.push(PropertyItem(Property::AssignedClientIdentifier, PropertyValue::String(self.session.get_client_id().0.clone())));
+                }
+                if let Some(method) = self.session.authentication_method() {
+                    properties.push(PropertyItem(Property::AuthenticationMethod, PropertyValue::String(method.to_owned())));
+                }
+                if let Some(data) = auth_data {
+                    properties.push(PropertyItem(Property::AuthenticationData, PropertyValue::Binary(data)));
+                }
+                msg.properties = Some(properties);
+                MqttMessageV5::Connack(msg).to_vec().unwrap_or_default()
+            }
+            _ => {
+                let msg = ConnackMessage::new(session_present, ReasonCodes::V3(ReasonCodeV3::ConnectionAccepted));
+                MqttMessageV3::Connack(msg).to_vec().unwrap_or_default()
+            }
         }
     }
+
+    ///
+    /// 处理同一个 SUBSCRIBE 报文中的全部主题过滤器, 回复一个 SUBACK 以及匹配的保留消息
+    ///
+    async fn subscribe(&self, subscribes: Vec<&SubscribeMessage>) -> Vec<u8> {
+        let message_id = match subscribes.first() {
+            Some(msg) => msg.message_id,
+            None => return vec![],
+        };
+        let level = self.protocol_level().unwrap();
+        let mut codes = vec![];
+        let mut retained = vec![];
+        for msg in subscribes {
+            if !is_valid_topic_filter(&msg.topic) {
+                codes.push(if level == MqttProtocolLevel::Level5 { ReasonPhrases::TopicFilterInvalid.as_byte() } else { MqttQos::Failure.as_byte() });
+                continue;
+            }
+            if !self.session.authorize(Action::Subscribe, &msg.topic).await {
+                println!("subscribe not authorized; client = {:?}, topic = {}", self.session.get_client_id(), msg.topic);
+                codes.push(if level == MqttProtocolLevel::Level5 { ReasonPhrases::NotAuthorized.as_byte() } else { MqttQos::Failure.as_byte() });
+                continue;
+            }
+            let option = SubscriptOption::from(msg);
+            retained.extend(self.session.subscribe_with_option(&msg.topic, option).await);
+            codes.push(option.qos.as_byte());
+        }
+        let suback = SubackMessage::with_codes(message_id, codes);
+        let mut response = if level == MqttProtocolLevel::Level5 {
+            MqttMessageV5::Suback(suback).to_vec().unwrap_or_default()
+        } else {
+            MqttMessageV3::Suback(suback).to_vec().unwrap_or_default()
+        };
+        let client_id = self.session.get_client_id();
+        for msg in retained {
+            if let Some(msg) = MESSAGE_CONTAINER.append(client_id, client_id.clone(), msg).await {
+                response.extend(self.publish(msg));
+            }
+        }
+        response
+    }
+
+    ///
+    /// 同一个 UNSUBSCRIBE 报文中的主题过滤器全部取消后回复一个 UNSUBACK.
+    /// v5 中只要有主题过滤器没有被订阅, 原因码即为 NoSubscriptionExisted
+    ///
+    fn unsuback(&self, unsubscribes: Vec<(u16, bool)>) -> Vec<u8> {
+        let message_id = match unsubscribes.first() {
+            Some((message_id, _)) => *message_id,
+            None => return vec![],
+        };
+        if self.protocol_level() == Some(MqttProtocolLevel::Level5) {
+            let code = if unsubscribes.iter().all(|(_, exist)| *exist) {
+                ReasonPhrases::Success
+            } else {
+                ReasonPhrases::NoSubscriptionExisted
+            };
+            MqttMessageV5::Unsuback(UnsubackMessage::new(message_id, Some(code.as_byte()))).to_vec().unwrap_or_default()
+        } else {
+            MqttMessageV3::Unsuback(UnsubackMessage::new(message_id, None)).to_vec().unwrap_or_default()
+        }
+    }
+
+    ///
+    /// 收到 DISCONNECT: 除非 v5 原因码要求发送遗嘱, 否则丢弃遗嘱消息, 然后断开连接
+    ///
+    async fn disconnect(&mut self, will: bool) {
+        if !will {
+            self.session.clear_will();
+        }
+        self.session.send_event(HandleEvent::ExitEvent(will)).await;
+    }
+
+    ///
+    /// 连接结束: 过期时间为 0 的会话立即删除订阅与飞行窗口;
+    /// 其余会话保留订阅, 把尚未处理的转发消息放回离线队列, 并按会话过期时间与遗嘱延迟设置定时器
+    ///
+    async fn close_session(&mut self, will: Option<PublishMessage>) {
+        let client_id = self.session.get_client_id().clone();
+        let expiry_interval = self.session.session_expiry_interval();
+        let will_delay_interval = self.session.will_delay_interval().min(expiry_interval);
+        let current = SESSION_REGISTRY.disconnect(&client_id, self.session.sender(), expiry_interval).await;
+        if current && expiry_interval == 0 {
+            if let Some(will) = will {
+                self.session.publish(&will).await;
+            }
+            return;
+        }
+        while let Ok(event) = self.receiver.try_recv() {
+            if let HandleEvent::BroadcastEvent(Content(from, msg)) = event {
+                SESSION_REGISTRY.forward(&client_id, from, msg).await;
+            }
+        }
+        if !current {
+            // 会话已被新连接接管, 延迟遗嘱随之取消
+            if let Some(will) = will.filter(|_| will_delay_interval == 0) {
+                self.session.publish(&will).await;
+            }
+            return;
+        }
+        match will {
+            Some(will) if will_delay_interval == 0 => self.session.publish(&will).await,
+            Some(will) => SESSION_REGISTRY.delay_will(&self.session, will, will_delay_interval).await,
+            None => {}
+        }
+        SESSION_REGISTRY.expire_after(&self.session, expiry_interval).await;
+    }
 }
 
 #[async_trait]
@@ -216,3 +845,663 @@ impl HandleSession for ServerHandler {
         self.session.protocol_level
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AclAuthorizer, AuthMechanism, ScramSha256Client, ScramSha256Server, StaticAuthenticator};
+    use crate::auth::scram::SCRAM_SHA_256;
+    use std::sync::Arc;
+    use crate::RETAIN_CONTAINER;
+    use crate::subscript::ClientID;
+    use crate::message::entity::{ConnectMessage, PingreqMessage, PubackMessage, PubcompMessage, PubrelMessage};
+    use crate::packet::v5_unpacket;
+    use crate::tools::config::{ConfigBuilder, ServerConfig, Will};
+    use crate::tools::framer::PacketFramer;
+    use crate::tools::tls::{load_certs, PeerIdentity};
+    use crate::tools::un_pack_tool::{get_type, parse_short_int, parse_string};
+    use std::time::Duration;
+    use crate::tools::protocol::{MqttDup, MqttRetainAsPublished};
+
+    async fn handle_message(session: ServerSession, kind: Option<MqttMessageKind>) {
+        match kind {
+            Some(MqttMessageKind::RequestV3(MqttMessageV3::Publish(msg))) => session.publish(&msg).await,
+            Some(MqttMessageKind::RequestV5(MqttMessageV5::Publish(msg))) => session.publish(&msg).await,
+            _ => {}
+        }
+    }
+
+    async fn exchange(handler: &mut ServerHandler, data: Vec<u8>) -> Vec<Vec<u8>> {
+        handler.send_message(HandleEvent::InputEvent(data)).await;
+        drain(handler).await
+    }
+
+    async fn drain(handler: &mut ServerHandler) -> Vec<Vec<u8>> {
+        let mut framer = PacketFramer::new();
+        while !handler.receiver.is_empty() {
+            match handler.execute(handle_message).await {
+                Some(ReturnKind::Response(response)) => framer.extend(&response),
+                Some(ReturnKind::Publish(head, rest)) => {
+                    framer.extend(&head);
+                    framer.extend(&rest);
+                }
+                _ => {}
+            }
+        }
+        let mut packets = vec![];
+        while let Some(packet) = framer.next_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    async fn connect(client_id: &str, level: MqttProtocolLevel, config: ServerConfig) -> (ServerHandler, Vec<u8>) {
+        let (handler, mut packets) = connect_with(new_handler(config), MqttCleanSession::Enable, client(client_id, level)).await;
+        (handler, packets.remove(0))
+    }
+
+    fn new_handler(config: ServerConfig) -> ServerHandler {
+        ServerHandler::new(Arc::new(config))
+    }
+
+    fn client(client_id: &str, level: MqttProtocolLevel) -> ConfigBuilder {
+        ConfigBuilder::default().client_id(client_id).protocol_level(level)
+    }
+
+    async fn connect_with(mut handler: ServerHandler, clean_session: MqttCleanSession, client: ConfigBuilder) -> (ServerHandler, Vec<Vec<u8>>) {
+        let msg = ConnectMessage::new(clean_session, client.build().unwrap());
+        let data = if msg.protocol_level == MqttProtocolLevel::Level5 {
+            MqttMessageV5::Connect(msg).to_vec().unwrap()
+        } else {
+            MqttMessageV3::Connect(msg).to_vec().unwrap()
+        };
+        let packets = exchange(&mut handler, data).await;
+        (handler, packets)
+    }
+
+    async fn close(mut handler: ServerHandler) {
+        handler.send_message(HandleEvent::ExitEvent(false)).await;
+        handler.execute(handle_message).await;
+    }
+
+    fn disconnect_v5(session_expiry_interval: Option<u32>) -> Vec<u8> {
+        let mut msg = DisconnectMessage::new(ReasonPhrases::Success);
+        msg.properties = session_expiry_interval.map(|expiry| vec![PropertyItem(Property::SessionExpiryInterval, PropertyValue::Long(expiry))]);
+        MqttMessageV5::Disconnect(msg).to_vec().unwrap()
+    }
+
+    fn publish(topic: &str, body: &str) -> Vec<u8> {
+        publish_qos(topic, body, MqttQos::Qos0, 0, MqttRetain::Enable)
+    }
+
+    fn publish_qos(topic: &str, body: &str, qos: MqttQos, message_id: u16, retain: MqttRetain) -> Vec<u8> {
+        let msg = PublishMessage::new(qos, MqttDup::Disable, retain, topic.to_owned(), message_id, body.to_owned(), None);
+        MqttMessageV3::Publish(msg).to_vec().unwrap()
+    }
+
+    fn subscribe_v3(message_id: u16, topic: &str, qos: MqttQos) -> Vec<u8> {
+        MqttMessageV3::Subscribe(SubscribeMessage::new(message_id, topic.to_owned(), qos)).to_vec().unwrap()
+    }
+
+    fn subscribe_v5(message_id: u16, topic: &str, retain_handling: u8) -> Vec<u8> {
+        let mut msg = SubscribeMessage::new(message_id, topic.to_owned(), MqttQos::Qos1);
+        msg.retain_handling = Some(retain_handling);
+        msg.retain_as_published = Some(MqttRetainAsPublished::Enable);
+        MqttMessageV5::Subscribe(msg).to_vec().unwrap()
+    }
+
+    #[tokio::test]
+    async fn retained_message_on_subscribe() {
+        let (mut publisher, _) = connect("retain-publisher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut publisher, publish("retain/test/a", "21")).await;
+        exchange(&mut publisher, publish("retain/test/b", "22")).await;
+
+        let (mut subscriber, _) = connect("retain-subscriber-v3", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        let packets = exchange(&mut subscriber, subscribe_v3(1, "retain/test/+", MqttQos::Qos0)).await;
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0], vec![0x90, 0x03, 0x00, 0x01, 0x00]);
+        for packet in packets[1..].iter() {
+            assert_eq!(packet[0], 0x31);
+        }
+
+        let (mut subscriber, connack) = connect("retain-subscriber-v5", MqttProtocolLevel::Level5, ServerConfig::default()).await;
+        match v5_unpacket::connack(BaseMessage::try_from(connack).unwrap()).unwrap() {
+            MqttMessageV5::Connack(msg) => {
+                let properties = msg.properties.unwrap();
+                assert!(matches!(properties[0], PropertyItem(Property::RetainAvailable, PropertyValue::Byte(1))));
+            }
+            _ => panic!("expected connack"),
+        }
+        assert_eq!(exchange(&mut subscriber, subscribe_v5(1, "retain/test/a", 2)).await.len(), 1);
+        assert_eq!(exchange(&mut subscriber, subscribe_v5(2, "retain/test/b", 1)).await.len(), 2);
+        assert_eq!(exchange(&mut subscriber, subscribe_v5(3, "retain/test/b", 1)).await.len(), 1);
+
+        exchange(&mut publisher, publish("retain/test/a", "")).await;
+        assert!(RETAIN_CONTAINER.get("retain/test/a").await.is_none());
+        assert_eq!(drain(&mut subscriber).await.len(), 1);
+        let packets = exchange(&mut subscriber, subscribe_v5(4, "retain/test/#", 0)).await;
+        assert_eq!(packets.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn retain_unavailable() {
+        let config = ServerConfig { retain_available: false, ..ServerConfig::default() };
+        let (mut publisher, connack) = connect("retain-unavailable", MqttProtocolLevel::Level5, config).await;
+        match v5_unpacket::connack(BaseMessage::try_from(connack).unwrap()).unwrap() {
+            MqttMessageV5::Connack(msg) => {
+                let properties = msg.properties.unwrap();
+                assert!(matches!(properties[0], PropertyItem(Property::RetainAvailable, PropertyValue::Byte(0))));
+            }
+            _ => panic!("expected connack"),
+        }
+        let msg = PublishMessage::new(MqttQos::Qos0, MqttDup::Disable, MqttRetain::Enable, "retain/unavailable".to_owned(), 0, "1".to_owned(), Some(vec![]));
+        let packets = exchange(&mut publisher, MqttMessageV5::Publish(msg).to_vec().unwrap()).await;
+        assert_eq!(packets, vec![vec![0xE0, 0x01, ReasonPhrases::RetainNotSupported.as_byte()]]);
+        assert!(RETAIN_CONTAINER.get("retain/unavailable").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn qos2_inbound_once() {
+        let (mut subscriber, _) = connect("inbound-subscriber", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut subscriber, subscribe_v3(1, "inflight/inbound", MqttQos::Qos2)).await;
+
+        let (mut publisher, _) = connect("inbound-publisher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        let packets = exchange(&mut publisher, publish_qos("inflight/inbound", "1", MqttQos::Qos1, 4, MqttRetain::Disable)).await;
+        assert_eq!(packets, vec![vec![0x40, 0x02, 0x00, 0x04]]);
+        for _ in 0..2 {
+            let packets = exchange(&mut publisher, publish_qos("inflight/inbound", "2", MqttQos::Qos2, 5, MqttRetain::Disable)).await;
+            assert_eq!(packets, vec![vec![0x50, 0x02, 0x00, 0x05]]);
+        }
+        let pubrel = MqttMessageV3::Pubrel(PubrelMessage::new(5)).to_vec().unwrap();
+        assert_eq!(exchange(&mut publisher, pubrel).await, vec![vec![0x70, 0x02, 0x00, 0x05]]);
+
+        let packets = drain(&mut subscriber).await;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0][0], 0x32);
+        assert_eq!(packets[1][0], 0x34);
+    }
+
+    #[tokio::test]
+    async fn qos_outbound_resend_on_reconnect() {
+        let client_id = "outbound-subscriber";
+        let (mut subscriber, _) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client(client_id, MqttProtocolLevel::Level3_1_1)).await;
+        exchange(&mut subscriber, subscribe_v3(1, "inflight/outbound", MqttQos::Qos2)).await;
+
+        let (mut publisher, _) = connect("outbound-publisher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut publisher, publish_qos("inflight/outbound", "1", MqttQos::Qos1, 9, MqttRetain::Disable)).await;
+        exchange(&mut publisher, publish_qos("inflight/outbound", "2", MqttQos::Qos2, 9, MqttRetain::Disable)).await;
+
+        let packets = drain(&mut subscriber).await;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0][0], 0x32);
+        assert_eq!(packets[1][0], 0x34);
+        let message_ids = packets.iter().map(|packet| {
+            let (.., data) = get_type(packet).unwrap();
+            let (_, data) = parse_string(data).unwrap();
+            parse_short_int(data).unwrap().0
+        }).collect::<Vec<u16>>();
+        assert_eq!(message_ids, vec![1, 2]);
+
+        let puback = MqttMessageV3::Puback(PubackMessage::new(1)).to_vec().unwrap();
+        assert!(exchange(&mut subscriber, puback).await.is_empty());
+        close(subscriber).await;
+
+        let (mut subscriber, packets) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client(client_id, MqttProtocolLevel::Level3_1_1)).await;
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1][0], 0x3C);
+        let pubrec = MqttMessageV3::Pubrec(PubrecMessage::new(2)).to_vec().unwrap();
+        assert_eq!(exchange(&mut subscriber, pubrec).await, vec![vec![0x62, 0x02, 0x00, 0x02]]);
+        close(subscriber).await;
+
+        let (mut subscriber, packets) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client(client_id, MqttProtocolLevel::Level3_1_1)).await;
+        assert_eq!(packets[1..], [vec![0x62, 0x02, 0x00, 0x02]]);
+        let pubcomp = MqttMessageV3::Pubcomp(PubcompMessage::new(2)).to_vec().unwrap();
+        assert!(exchange(&mut subscriber, pubcomp).await.is_empty());
+        close(subscriber).await;
+
+        let (_, packets) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client(client_id, MqttProtocolLevel::Level3_1_1)).await;
+        assert_eq!(packets.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn qos_outbound_resend_on_timeout() {
+        let (mut subscriber, _) = connect("timeout-subscriber", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut subscriber, subscribe_v3(1, "inflight/timeout", MqttQos::Qos1)).await;
+        let (mut publisher, _) = connect("timeout-publisher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut publisher, publish_qos("inflight/timeout", "1", MqttQos::Qos1, 3, MqttRetain::Disable)).await;
+        assert_eq!(drain(&mut subscriber).await[0][0], 0x32);
+
+        tokio::time::advance(ServerConfig::default().retry_interval()).await;
+        match subscriber.execute(handle_message).await {
+            Some(ReturnKind::Response(data)) => assert_eq!(data[0], 0x3A),
+            _ => panic!("expected retransmission"),
+        }
+    }
+
+    #[tokio::test]
+    async fn persistent_session() {
+        let client_id = "persistent-subscriber";
+        let (mut subscriber, packets) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client(client_id, MqttProtocolLevel::Level3_1_1)).await;
+        assert_eq!(packets, vec![vec![0x20, 0x02, 0x00, 0x00]]);
+        exchange(&mut subscriber, subscribe_v3(1, "persistent/+", MqttQos::Qos1)).await;
+        let disconnect = MqttMessageV3::Disconnect(DisconnectMessage::default()).to_vec().unwrap();
+        exchange(&mut subscriber, disconnect).await;
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from(client_id)).await, Some(false));
+
+        let (mut publisher, _) = connect("persistent-publisher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut publisher, publish_qos("persistent/a", "1", MqttQos::Qos0, 0, MqttRetain::Disable)).await;
+        exchange(&mut publisher, publish_qos("persistent/a", "2", MqttQos::Qos1, 1, MqttRetain::Disable)).await;
+        exchange(&mut publisher, publish_qos("persistent/b", "3", MqttQos::Qos2, 2, MqttRetain::Disable)).await;
+
+        let (mut subscriber, packets) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client(client_id, MqttProtocolLevel::Level3_1_1)).await;
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0], vec![0x20, 0x02, 0x01, 0x00]);
+        assert_eq!(packets[1][0], 0x32);
+        assert_eq!(packets[2][0], 0x32);
+
+        exchange(&mut publisher, publish_qos("persistent/c", "4", MqttQos::Qos1, 3, MqttRetain::Disable)).await;
+        assert_eq!(drain(&mut subscriber).await.len(), 1);
+        close(subscriber).await;
+
+        let (mut subscriber, packets) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Enable, client(client_id, MqttProtocolLevel::Level3_1_1)).await;
+        assert_eq!(packets, vec![vec![0x20, 0x02, 0x00, 0x00]]);
+        exchange(&mut publisher, publish_qos("persistent/d", "5", MqttQos::Qos1, 4, MqttRetain::Disable)).await;
+        assert!(drain(&mut subscriber).await.is_empty());
+        close(subscriber).await;
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from(client_id)).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_expiry() {
+        let client_id = ClientID::from("expiry-client");
+        let (mut handler, _) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client("expiry-client", MqttProtocolLevel::Level5).session_expiry_interval(60)).await;
+        exchange(&mut handler, subscribe_v5(1, "expiry/topic", 0)).await;
+        close(handler).await;
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(SESSION_REGISTRY.is_online(&client_id).await, Some(false));
+        let (handler, packets) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client("expiry-client", MqttProtocolLevel::Level5).session_expiry_interval(60)).await;
+        assert_eq!(packets[0][2], MqttSessionPresent::Enable as u8);
+        close(handler).await;
+
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(SUBSCRIPT.is_subscript("expiry/topic", &client_id).await);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(SESSION_REGISTRY.is_online(&client_id).await, None);
+        assert!(!SUBSCRIPT.is_subscript("expiry/topic", &client_id).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_expiry_capped_and_updated() {
+        let config = ServerConfig { max_session_expiry_interval: 10, ..ServerConfig::default() };
+        let (mut handler, mut packets) = connect_with(new_handler(config.clone()), MqttCleanSession::Disable, client("expiry-capped", MqttProtocolLevel::Level5).session_expiry_interval(100)).await;
+        match v5_unpacket::connack(BaseMessage::try_from(packets.remove(0)).unwrap()).unwrap() {
+            MqttMessageV5::Connack(msg) => {
+                let expiry = find_property(msg.properties.as_deref(), Property::SessionExpiryInterval).and_then(PropertyItem::as_long);
+                assert_eq!(expiry, Some(10));
+            }
+            _ => panic!("expected connack"),
+        }
+        assert!(exchange(&mut handler, disconnect_v5(Some(0))).await.is_empty());
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from("expiry-capped")).await, None);
+
+        let (mut handler, _) = connect_with(new_handler(config), MqttCleanSession::Disable, client("expiry-zero", MqttProtocolLevel::Level5).session_expiry_interval(0)).await;
+        let packets = exchange(&mut handler, disconnect_v5(Some(30))).await;
+        assert_eq!(packets, vec![vec![0xE0, 0x01, ReasonPhrases::ProtocolError.as_byte()]]);
+    }
+
+    #[tokio::test]
+    async fn session_taken_over() {
+        let client_id = ClientID::from("takeover-client");
+        let (mut previous, _) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client("takeover-client", MqttProtocolLevel::Level5).session_expiry_interval(60).will(Will::new("takeover/will", "offline").delay_interval(0))).await;
+        exchange(&mut previous, subscribe_v5(1, "takeover/topic", 0)).await;
+
+        let (mut current, packets) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client("takeover-client", MqttProtocolLevel::Level5).session_expiry_interval(60)).await;
+        assert_eq!(packets[0][2], MqttSessionPresent::Enable as u8);
+        let packets = drain(&mut previous).await;
+        assert_eq!(packets, vec![vec![0xE0, 0x01, ReasonPhrases::SessionTakenOver.as_byte()]]);
+        assert_eq!(SESSION_REGISTRY.is_online(&client_id).await, Some(true));
+
+        let (mut publisher, _) = connect("takeover-publisher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut publisher, publish_qos("takeover/topic", "1", MqttQos::Qos1, 1, MqttRetain::Disable)).await;
+        let packets = drain(&mut current).await;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0][0], 0x32);
+        close(current).await;
+    }
+
+    #[tokio::test]
+    async fn empty_client_id() {
+        let (mut first, connack) = connect("", MqttProtocolLevel::Level5, ServerConfig::default()).await;
+        let assigned = match v5_unpacket::connack(BaseMessage::try_from(connack).unwrap()).unwrap() {
+            MqttMessageV5::Connack(msg) => find_property(msg.properties.as_deref(), Property::AssignedClientIdentifier).and_then(PropertyItem::as_str).cloned().unwrap(),
+            _ => panic!("expected connack"),
+        };
+        assert!(!assigned.is_empty());
+        assert_eq!(first.session.get_client_id(), &ClientID::from(assigned.as_str()));
+
+        let (second, connack) = connect("", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        assert_eq!(connack, vec![0x20, 0x02, 0x00, 0x00]);
+        assert_ne!(second.session.get_client_id(), first.session.get_client_id());
+        assert!(drain(&mut first).await.is_empty());
+        assert_eq!(SESSION_REGISTRY.is_online(first.session.get_client_id()).await, Some(true));
+
+        let (_, packets) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client("", MqttProtocolLevel::Level3_1_1)).await;
+        assert_eq!(packets, vec![vec![0x20, 0x02, 0x00, ReasonCodeV3::IdentifierRejected as u8]]);
+        close(first).await;
+        close(second).await;
+    }
+
+    #[tokio::test]
+    async fn duplicate_connect() {
+        let client_id = ClientID::from("duplicate-connect-v3");
+        let (handler, _) = connect("duplicate-connect-v3", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        let (_, packets) = connect_with(handler, MqttCleanSession::Enable, client("duplicate-connect-other", MqttProtocolLevel::Level3_1_1)).await;
+        assert!(packets.is_empty());
+        assert_eq!(SESSION_REGISTRY.is_online(&client_id).await, None);
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from("duplicate-connect-other")).await, None);
+
+        let (handler, _) = connect("duplicate-connect-v5", MqttProtocolLevel::Level5, ServerConfig::default()).await;
+        let (_, packets) = connect_with(handler, MqttCleanSession::Enable, client("duplicate-connect-v5", MqttProtocolLevel::Level5)).await;
+        assert_eq!(packets, vec![vec![0xE0, 0x01, ReasonPhrases::ProtocolError.as_byte()]]);
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from("duplicate-connect-v5")).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_on_shutdown() {
+        let shutdown = CancellationToken::new();
+        let handler = new_handler(ServerConfig::default()).shutdown(shutdown.clone());
+        let (mut handler, _) = connect_with(handler, MqttCleanSession::Enable, client("drain-client", MqttProtocolLevel::Level5)).await;
+        let msg = PublishMessage::new(MqttQos::Qos2, MqttDup::Disable, MqttRetain::Disable, "drain/topic".to_owned(), 5, "1".to_owned(), Some(vec![]));
+        let packets = exchange(&mut handler, MqttMessageV5::Publish(msg).to_vec().unwrap()).await;
+        assert_eq!(packets[0][0], 0x50);
+
+        shutdown.cancel();
+        for _ in 0..4 {
+            assert!(handler.execute(handle_message).await.is_none());
+        }
+        assert!(handler.receiver.is_empty());
+        let pubrel = encode_ack(MqttProtocolLevel::Level5, TypeKind::PUBREL, 5, ReasonPhrases::Success);
+        assert_eq!(exchange(&mut handler, pubrel).await[0][0], 0x70);
+        assert!(handler.execute(handle_message).await.is_none());
+        assert_eq!(drain(&mut handler).await, vec![vec![0xE0, 0x01, ReasonPhrases::ServerShuttingDown.as_byte()]]);
+    }
+
+    #[tokio::test]
+    async fn authenticate_connect() {
+        let (mut watcher, _) = connect("auth-watcher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut watcher, subscribe_v3(1, "auth/will", MqttQos::Qos0)).await;
+
+        let authenticator = Arc::new(StaticAuthenticator::new().user("auth-user", "auth-password"));
+        let config = ServerConfig { authenticator: Some(authenticator), ..ServerConfig::default() };
+        let client_v3 = |password| client("auth-v3", MqttProtocolLevel::Level3_1_1).username("auth-user").password(password).will(Will::new("auth/will", "offline"));
+        let (handler, packets) = connect_with(new_handler(config.clone()), MqttCleanSession::Enable, client_v3("wrong")).await;
+        assert_eq!(packets, vec![vec![0x20, 0x02, 0x00, ReasonCodeV3::BadUsernameOrPassword.as_byte()]]);
+        assert!(!handler.session.is_connected());
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from("auth-v3")).await, None);
+
+        let client_v5 = client("auth-v5", MqttProtocolLevel::Level5).username("nobody").password("auth-password").will(Will::new("auth/will", "offline"));
+        let (_, packets) = connect_with(new_handler(config.clone()), MqttCleanSession::Enable, client_v5).await;
+        assert_eq!(packets[0][3], ReasonPhrases::BadUserNameOrPassword.as_byte());
+        assert!(drain(&mut watcher).await.is_empty());
+
+        let (handler, packets) = connect_with(new_handler(config), MqttCleanSession::Enable, client_v3("auth-password")).await;
+        assert_eq!(packets, vec![vec![0x20, 0x02, 0x00, 0x00]]);
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from("auth-v3")).await, Some(true));
+        close(handler).await;
+    }
+
+    #[tokio::test]
+    async fn authorize_topics() {
+        let acl = AclAuthorizer::parse("allow subscribe acl/public/#\nallow publish acl/%c/#\n").unwrap();
+        let config = ServerConfig { authorizer: Some(Arc::new(acl)), ..ServerConfig::default() };
+        let (mut watcher, _) = connect("acl-watcher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut watcher, subscribe_v3(1, "acl/#", MqttQos::Qos0)).await;
+
+        let (mut handler, _) = connect("acl-v3", MqttProtocolLevel::Level3_1_1, config.clone()).await;
+        let packets = exchange(&mut handler, subscribe_v3(1, "acl/secret", MqttQos::Qos1)).await;
+        assert_eq!(packets, vec![vec![0x90, 0x03, 0x00, 0x01, MqttQos::Failure.as_byte()]]);
+        let packets = exchange(&mut handler, subscribe_v3(2, "acl/public/#", MqttQos::Qos1)).await;
+        assert_eq!(packets, vec![vec![0x90, 0x03, 0x00, 0x02, 0x01]]);
+        exchange(&mut handler, publish_qos("acl/acl-v5/a", "1", MqttQos::Qos0, 0, MqttRetain::Disable)).await;
+        assert!(drain(&mut watcher).await.is_empty());
+        close(handler).await;
+
+        let (mut handler, _) = connect("acl-v5", MqttProtocolLevel::Level5, config).await;
+        let packets = exchange(&mut handler, subscribe_v5(1, "acl/secret", 0)).await;
+        assert_eq!(packets[0].last(), Some(&ReasonPhrases::NotAuthorized.as_byte()));
+        let publish_v5 = |topic: &str, message_id| {
+            let msg = PublishMessage::new(MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable, topic.to_owned(), message_id, "1".to_owned(), None);
+            MqttMessageV5::Publish(msg).to_vec().unwrap()
+        };
+        let packets = exchange(&mut handler, publish_v5("acl/other/a", 1)).await;
+        assert_eq!(packets[0][0], 0x40);
+        assert_eq!(packets[0][4], ReasonPhrases::NotAuthorized.as_byte());
+        assert!(drain(&mut watcher).await.is_empty());
+        let packets = exchange(&mut handler, publish_v5("acl/acl-v5/a", 2)).await;
+        assert_eq!(packets[0][0], 0x40);
+        assert_eq!(drain(&mut watcher).await.len(), 1);
+        close(handler).await;
+    }
+
+    fn auth_message(packet: Vec<u8>) -> (u8, Option<Vec<u8>>) {
+        let base = BaseMessage::try_from(packet).unwrap();
+        let (code, properties) = match MqttMessageKind::to_v5_request(base).unwrap() {
+            MqttMessageKind::RequestV5(MqttMessageV5::Auth(msg)) => (msg.code, msg.properties),
+            MqttMessageKind::RequestV5(MqttMessageV5::Connack(msg)) => (msg.return_code.unwrap(), msg.properties),
+            kind => panic!("unexpected packet {:?}", kind),
+        };
+        (code, find_property(properties.as_deref(), Property::AuthenticationData).and_then(PropertyItem::as_binary).cloned())
+    }
+
+    fn auth_v5(code: ReasonPhrases, method: &str, data: Vec<u8>) -> Vec<u8> {
+        let properties = vec![
+            PropertyItem(Property::AuthenticationMethod, PropertyValue::String(method.to_owned())),
+            PropertyItem(Property::AuthenticationData, PropertyValue::Binary(data)),
+        ];
+        MqttMessageV5::Auth(AuthMessage { code: code.as_byte(), properties: Some(properties), ..AuthMessage::default() }).to_vec().unwrap()
+    }
+
+    async fn connect_scram(client_id: &str, mechanism: &ScramSha256Client, method: &str) -> (ServerHandler, Box<dyn AuthExchange>, Vec<Vec<u8>>) {
+        let config = ServerConfig { auth_mechanisms: vec![Arc::new(ScramSha256Server::new().user("scram-user", "scram-password"))], ..ServerConfig::default() };
+        let mut handler = ServerHandler::new(Arc::new(config));
+        let mut client = mechanism.start();
+        let data = match client.step(None).await {
+            AuthStep::Continue(data) => data,
+            step => panic!("unexpected step {:?}", step),
+        };
+        let config = ConfigBuilder::default().client_id(client_id).protocol_level(MqttProtocolLevel::Level5).build().unwrap();
+        let mut msg = ConnectMessage::new(MqttCleanSession::Enable, config);
+        msg.properties = Some(vec![
+            PropertyItem(Property::AuthenticationMethod, PropertyValue::String(method.to_owned())),
+            PropertyItem(Property::AuthenticationData, PropertyValue::Binary(data)),
+        ]);
+        let packets = exchange(&mut handler, MqttMessageV5::Connect(msg).to_vec().unwrap()).await;
+        (handler, client, packets)
+    }
+
+    #[tokio::test]
+    async fn enhanced_authentication() {
+        let client_id = ClientID::from("scram-client");
+        let mechanism = ScramSha256Client::new("scram-user", "scram-password");
+        let (mut handler, mut client, mut packets) = connect_scram("scram-client", &mechanism, SCRAM_SHA_256).await;
+        let (code, challenge) = auth_message(packets.remove(0));
+        assert_eq!(code, ReasonPhrases::ContinueAuthentication.as_byte());
+        assert_eq!(SESSION_REGISTRY.is_online(&client_id).await, None);
+        let response = match client.step(challenge.as_deref()).await {
+            AuthStep::Continue(data) => data,
+            step => panic!("unexpected step {:?}", step),
+        };
+        let mut packets = exchange(&mut handler, auth_v5(ReasonPhrases::ContinueAuthentication, SCRAM_SHA_256, response)).await;
+        let (code, last) = auth_message(packets.remove(0));
+        assert_eq!(code, ReasonPhrases::Success.as_byte());
+        assert_eq!(client.step(last.as_deref()).await, AuthStep::Success(None));
+        assert_eq!(SESSION_REGISTRY.is_online(&client_id).await, Some(true));
+
+        let mut client = mechanism.start();
+        let data = match client.step(None).await {
+            AuthStep::Continue(data) => data,
+            step => panic!("unexpected step {:?}", step),
+        };
+        let mut packets = exchange(&mut handler, auth_v5(ReasonPhrases::ReAuthenticate, SCRAM_SHA_256, data)).await;
+        let (code, challenge) = auth_message(packets.remove(0));
+        assert_eq!(code, ReasonPhrases::ContinueAuthentication.as_byte());
+        let response = match client.step(challenge.as_deref()).await {
+            AuthStep::Continue(data) => data,
+            step => panic!("unexpected step {:?}", step),
+        };
+        let mut packets = exchange(&mut handler, auth_v5(ReasonPhrases::ContinueAuthentication, SCRAM_SHA_256, response)).await;
+        assert_eq!(packets[0][0], 0xF0);
+        let (code, last) = auth_message(packets.remove(0));
+        assert_eq!(code, ReasonPhrases::Success.as_byte());
+        assert_eq!(client.step(last.as_deref()).await, AuthStep::Success(None));
+
+        let packets = exchange(&mut handler, auth_v5(ReasonPhrases::ContinueAuthentication, SCRAM_SHA_256, vec![])).await;
+        assert_eq!(packets, vec![vec![0xE0, 0x01, ReasonPhrases::ProtocolError.as_byte()]]);
+        assert_eq!(SESSION_REGISTRY.is_online(&client_id).await, None);
+    }
+
+    #[tokio::test]
+    async fn enhanced_authentication_refused() {
+        let mechanism = ScramSha256Client::new("scram-user", "scram-password");
+        let (_, _, mut packets) = connect_scram("scram-method", &mechanism, "UNKNOWN").await;
+        assert_eq!(auth_message(packets.remove(0)).0, ReasonPhrases::BadAuthenticationMethod.as_byte());
+
+        let mechanism = ScramSha256Client::new("scram-user", "wrong");
+        let (mut handler, mut client, mut packets) = connect_scram("scram-wrong", &mechanism, SCRAM_SHA_256).await;
+        let (_, challenge) = auth_message(packets.remove(0));
+        let response = match client.step(challenge.as_deref()).await {
+            AuthStep::Continue(data) => data,
+            step => panic!("unexpected step {:?}", step),
+        };
+        let mut packets = exchange(&mut handler, auth_v5(ReasonPhrases::ContinueAuthentication, SCRAM_SHA_256, response)).await;
+        assert_eq!(auth_message(packets.remove(0)).0, ReasonPhrases::BadUserNameOrPassword.as_byte());
+        assert!(!handler.session.is_connected());
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from("scram-wrong")).await, None);
+    }
+
+    #[tokio::test]
+    async fn subscribe_during_enhanced_authentication() {
+        let mechanism = ScramSha256Client::new("scram-user", "scram-password");
+        let (mut handler, _, mut packets) = connect_scram("scram-subscribe", &mechanism, SCRAM_SHA_256).await;
+        assert_eq!(auth_message(packets.remove(0)).0, ReasonPhrases::ContinueAuthentication.as_byte());
+        let packets = exchange(&mut handler, subscribe_v5(1, "scram/subscribe/#", 0)).await;
+        assert_eq!(packets, vec![vec![0xE0, 0x01, ReasonPhrases::ProtocolError.as_byte()]]);
+        assert!(SUBSCRIPT.subscriptions(ClientID::from("scram-subscribe")).await.is_empty());
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from("scram-subscribe")).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn will_delay() {
+        let (mut watcher, _) = connect("will-watcher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut watcher, subscribe_v3(1, "will/delay/+", MqttQos::Qos0)).await;
+
+        let (mut handler, _) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client("will-delay", MqttProtocolLevel::Level5).session_expiry_interval(60).will(Will::new("will/delay/a", "offline").delay_interval(5))).await;
+        handler.send_message(HandleEvent::ExitEvent(true)).await;
+        handler.execute(handle_message).await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(drain(&mut watcher).await.is_empty());
+
+        let (mut handler, _) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client("will-delay", MqttProtocolLevel::Level5).session_expiry_interval(60).will(Will::new("will/delay/a", "offline").delay_interval(5))).await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(drain(&mut watcher).await.is_empty());
+        handler.send_message(HandleEvent::ExitEvent(true)).await;
+        handler.execute(handle_message).await;
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(drain(&mut watcher).await.len(), 1);
+
+        let (mut handler, _) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client("will-expiry", MqttProtocolLevel::Level5).session_expiry_interval(2).will(Will::new("will/delay/b", "offline").delay_interval(30))).await;
+        handler.send_message(HandleEvent::ExitEvent(true)).await;
+        handler.execute(handle_message).await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(drain(&mut watcher).await.len(), 1);
+
+        let (handler, _) = connect_with(new_handler(ServerConfig::default()), MqttCleanSession::Disable, client("will-normal", MqttProtocolLevel::Level5).session_expiry_interval(60).will(Will::new("will/delay/c", "offline").delay_interval(0))).await;
+        close(handler).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(drain(&mut watcher).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_alive_timeout() {
+        let (mut watcher, _) = connect("keep-alive-watcher", MqttProtocolLevel::Level3_1_1, ServerConfig::default()).await;
+        exchange(&mut watcher, subscribe_v3(1, "keep-alive/+", MqttQos::Qos0)).await;
+
+        let config = ServerConfig { retry_interval: Duration::from_secs(100), ..ServerConfig::default() };
+        let client_v3 = client("keep-alive-v3", MqttProtocolLevel::Level3_1_1).keep_alive(10).will(Will::new("keep-alive/v3", "offline"));
+        let (mut handler, _) = connect_with(new_handler(config), MqttCleanSession::Enable, client_v3).await;
+        assert!(time::timeout(Duration::from_secs(14), handler.execute(handle_message)).await.is_err());
+        let pingreq = MqttMessageV3::Pingreq(PingreqMessage::default()).to_vec().unwrap();
+        exchange(&mut handler, pingreq).await;
+        assert!(time::timeout(Duration::from_secs(14), handler.execute(handle_message)).await.is_err());
+        assert!(drain(&mut watcher).await.is_empty());
+
+        assert!(handler.execute(handle_message).await.is_none());
+        assert!(drain(&mut handler).await.is_empty());
+        assert_eq!(drain(&mut watcher).await.len(), 1);
+
+        let config = ServerConfig { server_keep_alive: Some(4), ..ServerConfig::default() };
+        let client_v5 = client("keep-alive-v5", MqttProtocolLevel::Level5).keep_alive(60).will(Will::new("keep-alive/v5", "offline"));
+        let (mut handler, mut packets) = connect_with(new_handler(config), MqttCleanSession::Enable, client_v5).await;
+        match v5_unpacket::connack(BaseMessage::try_from(packets.remove(0)).unwrap()).unwrap() {
+            MqttMessageV5::Connack(msg) => {
+                let keep_alive = find_property(msg.properties.as_deref(), Property::ServerKeepAlive).and_then(PropertyItem::as_short);
+                assert_eq!(keep_alive, Some(4));
+            }
+            _ => panic!("expected connack"),
+        }
+        assert!(handler.execute(handle_message).await.is_none());
+        assert_eq!(drain(&mut handler).await, vec![vec![0xE0, 0x01, ReasonPhrases::KeepAliveTimeout.as_byte()]]);
+        assert_eq!(drain(&mut watcher).await.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_timeout() {
+        let config = ServerConfig { connect_timeout: Duration::from_secs(5), retry_interval: Duration::from_secs(100), ..ServerConfig::default() };
+        let mut handler = ServerHandler::new(Arc::new(config.clone()));
+        assert!(time::timeout(Duration::from_secs(4), handler.execute(handle_message)).await.is_err());
+        assert!(handler.execute(handle_message).await.is_none());
+        assert!(matches!(handler.execute(handle_message).await, Some(ReturnKind::Exit)));
+
+        let (mut handler, _) = connect("connect-timeout", MqttProtocolLevel::Level3_1_1, config).await;
+        assert!(time::timeout(Duration::from_secs(60), handler.execute(handle_message)).await.is_err());
+        close(handler).await;
+    }
+
+    #[tokio::test]
+    async fn certificate_identity() {
+        let acl = AclAuthorizer::parse("user device-1\nallow publish cert/%u/#\n").unwrap();
+        let config = ServerConfig { authorizer: Some(Arc::new(acl)), identity_as: Some(IdentityAs::Username), ..ServerConfig::default() };
+        let certs = load_certs(std::path::Path::new("config/certs/client-test.crt")).unwrap();
+        let peer = "127.0.0.1:1883".parse().unwrap();
+        let (handler, packets) = connect_with(new_handler(config).peer(peer, Some(certs.clone())), MqttCleanSession::Enable, client("cert-v3", MqttProtocolLevel::Level3_1_1)).await;
+        assert_eq!(packets, vec![vec![0x20, 0x02, 0x00, 0x00]]);
+        assert_eq!(handler.session.peer_identity().and_then(PeerIdentity::common_name), Some("device-1"));
+        assert_eq!(handler.session.get_client_id(), &ClientID::from("cert-v3"));
+        assert_eq!(handler.session.username(), Some("device-1"));
+        assert!(handler.session.authorize(Action::Publish, "cert/device-1/state").await);
+        assert!(!handler.session.authorize(Action::Publish, "cert/device-2/state").await);
+        close(handler).await;
+
+        let config = ServerConfig { identity_as: Some(IdentityAs::ClientId), ..ServerConfig::default() };
+        let (handler, mut packets) = connect_with(new_handler(config.clone()).peer(peer, Some(certs.clone())), MqttCleanSession::Enable, client("device-1", MqttProtocolLevel::Level5)).await;
+        match v5_unpacket::connack(BaseMessage::try_from(packets.remove(0)).unwrap()).unwrap() {
+            MqttMessageV5::Connack(msg) => assert!(find_property(msg.properties.as_deref(), Property::AssignedClientIdentifier).is_none()),
+            _ => panic!("expected connack"),
+        }
+        close(handler).await;
+
+        let (handler, mut packets) = connect_with(new_handler(config).peer(peer, Some(certs)), MqttCleanSession::Enable, client("cert-v5", MqttProtocolLevel::Level5)).await;
+        match v5_unpacket::connack(BaseMessage::try_from(packets.remove(0)).unwrap()).unwrap() {
+            MqttMessageV5::Connack(msg) => {
+                let assigned = find_property(msg.properties.as_deref(), Property::AssignedClientIdentifier).and_then(PropertyItem::as_str);
+                assert_eq!(assigned.map(String::as_str), Some("device-1"));
+            }
+            _ => panic!("expected connack"),
+        }
+        assert_eq!(handler.session.get_client_id(), &ClientID::from("device-1"));
+        assert_eq!(handler.session.username(), None);
+        assert_eq!(SESSION_REGISTRY.is_online(&ClientID::from("device-1")).await, Some(true));
+        close(handler).await;
+    }
+}
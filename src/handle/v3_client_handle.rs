@@ -1,18 +1,40 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::future::Future;
 use std::option::Option::Some;
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
 use async_trait::async_trait;
-use crate::executor::ReturnKind;
+use bytes::Bytes;
+use crate::auth::{AuthExchange, AuthMechanism, AuthStep};
+use crate::container::FrameState;
+use crate::executor::{ConnectionState, ReturnKind};
 use crate::handle::{ClientExecute, HandleEvent};
+use crate::hex::{find_property, Property, PropertyItem, PropertyValue};
+use crate::hex::reason_code::ReasonPhrases;
 use crate::message::{MqttMessageKind, BaseMessage};
+use crate::message::entity::{AuthMessage, PublishMessage, PubrecMessage};
+use crate::packet::encode_ack;
 use crate::message::v3::MqttMessageV3;
 use crate::message::v5::MqttMessageV5;
 use crate::session::{ClientSession, MqttSession};
-use crate::tools::protocol::MqttProtocolLevel;
+use crate::tools::error::DecodeError;
+use crate::tools::protocol::{MqttProtocolLevel, MqttQos, MqttSessionPresent};
+use crate::tools::types::TypeKind;
 
 pub struct ClientHandleV3 {
     session: ClientSession,
     receiver: mpsc::Receiver<HandleEvent>,
+    mechanism: Option<Arc<dyn AuthMechanism>>,
+    authentication: Option<Box<dyn AuthExchange>>,
+    state: Option<watch::Sender<ConnectionState>>,
+    refused: bool,
+    /// 收到无法解析的报文, v5 以该原因码回复 DISCONNECT 后重连
+    protocol_error: Option<ReasonPhrases>,
+    /// CONNECT 中声明的 TopicAliasMaximum
+    topic_alias_maximum: u16,
+    /// 服务端在当前连接上建立的主题别名
+    topic_aliases: HashMap<u16, String>,
 }
 
 impl ClientHandleV3 {
@@ -20,6 +42,69 @@ impl ClientHandleV3 {
         ClientHandleV3 {
             session,
             receiver,
+            mechanism: None,
+            authentication: None,
+            state: None,
+            refused: false,
+            protocol_error: None,
+            topic_alias_maximum: 0,
+            topic_aliases: HashMap::new(),
+        }
+    }
+
+    ///
+    /// CONNECT 中声明的 TopicAliasMaximum, 服务端发送的主题别名超过该值时断开连接
+    ///
+    pub fn topic_alias_maximum(mut self, topic_alias_maximum: u16) -> ClientHandleV3 {
+        self.topic_alias_maximum = topic_alias_maximum;
+        self
+    }
+
+    ///
+    /// 收到成功的 CONNACK 时通知连接状态
+    ///
+    pub fn connection_state(mut self, state: watch::Sender<ConnectionState>) -> ClientHandleV3 {
+        self.state = Some(state);
+        self
+    }
+
+    ///
+    /// 服务端是否以失败的 CONNACK 拒绝了连接
+    ///
+    pub fn is_refused(&self) -> bool {
+        self.refused
+    }
+
+    ///
+    /// 取出本次连接上报文解析失败的原因码
+    ///
+    pub fn take_protocol_error(&mut self) -> Option<ReasonPhrases> {
+        self.protocol_error.take()
+    }
+
+    ///
+    /// v5 增强认证方法
+    ///
+    pub fn auth_mechanism(mut self, mechanism: Arc<dyn AuthMechanism>) -> ClientHandleV3 {
+        self.mechanism = Some(mechanism);
+        self
+    }
+
+    pub fn authentication_method(&self) -> Option<&str> {
+        self.mechanism.as_ref().map(|mechanism| mechanism.method())
+    }
+
+    ///
+    /// 开始一次增强认证, 返回 CONNECT 或 AUTH 0x19 中携带的初始认证数据
+    ///
+    pub async fn start_authentication(&mut self) -> Option<Vec<u8>> {
+        let mut exchange = self.mechanism.as_ref()?.start();
+        match exchange.step(None).await {
+            AuthStep::Continue(data) => {
+                self.authentication = Some(exchange);
+                Some(data)
+            }
+            _ => None
         }
     }
 
@@ -36,7 +121,7 @@ impl ClientHandleV3 {
 impl ClientExecute for ClientHandleV3 {
     type Ses = ClientSession;
 
-    async fn execute<F, Fut>(&mut self, f: F, sender: Option<mpsc::Sender<String>>) -> Option<ReturnKind>
+    async fn execute<F, Fut>(&mut self, f: F, sender: Option<mpsc::Sender<Bytes>>) -> Option<ReturnKind>
         where
             F: Fn(Self::Ses, Option<MqttMessageKind>) -> Fut + Copy + Clone + Send + Sync + 'static,
             Fut: Future<Output=()> + Send
@@ -46,19 +131,62 @@ impl ClientExecute for ClientHandleV3 {
                 match msg {
                     HandleEvent::InputEvent(data) => {
                         println!("client input: {:?}", data);
-                        let base_msg = BaseMessage::from(data);
-                        let request = self.request(base_msg);
-                        if let Some(send) = sender {
-                            if let Some(kind) = request.as_ref() {
-                                match kind {
-                                    MqttMessageKind::RequestV3(MqttMessageV3::Publish(msg)) => { send.send(msg.msg_body.clone()).await; }
-                                    MqttMessageKind::RequestV5(MqttMessageV5::Publish(msg)) => { send.send(msg.msg_body.clone()).await; }
-                                    _ => {}
+                        let mut request = match BaseMessage::try_from(data).and_then(|base_msg| self.request(base_msg)) {
+                            Ok(request) => request,
+                            Err(err) => {
+                                println!("failed to decode packet; err = {}", err);
+                                self.protocol_error = Some(err.reason_code());
+                                return None;
+                            }
+                        };
+                        if let Some(MqttMessageKind::RequestV5(MqttMessageV5::Publish(msg))) = request.as_mut() {
+                            if let Err(code) = self.resolve_topic_alias(msg) {
+                                println!("invalid topic alias; reason = {}", code.as_str());
+                                self.protocol_error = Some(code);
+                                return None;
+                            }
+                        }
+                        let mut response = vec![];
+                        if let Some(MqttMessageKind::RequestV5(msg)) = request.as_ref() {
+                            match self.authenticate(msg).await {
+                                Ok(data) => response.extend(data),
+                                Err(code) => {
+                                    println!("authentication failed; reason = {}", code.as_str());
+                                    return Some(ReturnKind::Exit);
                                 }
                             }
+                        }
+                        let deliver = match self.handle_request(request.as_ref()).await {
+                            Ok((data, deliver)) => {
+                                response.extend(data);
+                                deliver
+                            }
+                            Err(code) => {
+                                println!("connection refused; code = {:#04x}", code);
+                                self.refused = true;
+                                return Some(ReturnKind::Exit);
+                            }
                         };
-                        f(self.session.clone(), request).await;
-                        None
+                        if deliver {
+                            let publish = match request.as_ref() {
+                                Some(MqttMessageKind::RequestV3(MqttMessageV3::Publish(msg))) => Some(msg),
+                                Some(MqttMessageKind::RequestV5(MqttMessageV5::Publish(msg))) => Some(msg),
+                                _ => None
+                            };
+                            if let Some(msg) = publish {
+                                self.session.dispatch(msg).await;
+                                if let Some(send) = sender {
+                                    if let Err(e) = send.send(msg.msg_body.clone()).await {
+                                        println!("failed to forward publish message; err = {:?}", e);
+                                    }
+                                }
+                            }
+                            f(self.session.clone(), request).await;
+                        }
+                        if response.is_empty() {
+                            return None;
+                        }
+                        Some(ReturnKind::Response(response))
                     }
                     HandleEvent::OutputEvent(data) => {
                         Some(ReturnKind::Response(data.0))
@@ -66,6 +194,13 @@ impl ClientExecute for ClientHandleV3 {
                     HandleEvent::ExitEvent(_) => {
                         Some(ReturnKind::Exit)
                     }
+                    HandleEvent::ReAuthEvent => {
+                        if self.authentication.is_some() {
+                            return None;
+                        }
+                        self.start_authentication().await
+                            .map(|data| ReturnKind::Response(self.auth(ReasonPhrases::ReAuthenticate, data)))
+                    }
                     _ => None
                 }
             }
@@ -75,15 +210,402 @@ impl ClientExecute for ClientHandleV3 {
 }
 
 impl ClientHandleV3 {
-    fn request(&self, base_msg: BaseMessage) -> Option<MqttMessageKind> {
+    ///
+    /// 处理协议层面的报文, 返回需要回复的报文以及是否交给回调处理 (重复的 QoS 2 消息不再处理).
+    /// 连接被拒绝时返回 CONNACK 中的返回码
+    ///
+    async fn handle_request(&mut self, request: Option<&MqttMessageKind>) -> Result<(Vec<u8>, bool), u8> {
+        use MqttMessageKind::{RequestV3, RequestV5};
+        let request = match request {
+            Some(request) => request,
+            None => return Ok((vec![], true))
+        };
+        let response = match request {
+            RequestV3(MqttMessageV3::Connack(msg)) | RequestV5(MqttMessageV5::Connack(msg)) => {
+                let code = msg.return_code.unwrap_or_default();
+                if code != 0 {
+                    return Err(code);
+                }
+                // 主题别名只在一次网络连接内有效
+                self.topic_aliases.clear();
+                if let RequestV5(MqttMessageV5::Connack(msg)) = request {
+                    let response_information = find_property(msg.properties.as_deref(), Property::ResponseInformation)
+                        .and_then(PropertyItem::as_str)
+                        .cloned();
+                    self.session.set_response_information(response_information).await;
+                    let identifier_available = find_property(msg.properties.as_deref(), Property::SubscriptionIdentifierAvailable)
+                        .and_then(PropertyItem::as_byte)
+                        .is_none_or(|available| available != 0);
+                    self.session.set_subscription_identifier_available(identifier_available).await;
+                }
+                let response = self.resume(msg.session_present == MqttSessionPresent::Enable).await;
+                if let Some(state) = self.state.as_ref() {
+                    state.send_replace(ConnectionState::Connected);
+                }
+                response
+            }
+            RequestV3(MqttMessageV3::Publish(msg)) | RequestV5(MqttMessageV5::Publish(msg)) => {
+                return Ok(self.receive(msg).await);
+            }
+            RequestV3(MqttMessageV3::Puback(msg)) | RequestV5(MqttMessageV5::Puback(msg)) => {
+                let frame = self.session.frames().lock().await.puback(msg.message_id);
+                if frame.is_some() {
+                    self.session.publish_acked(msg.message_id, msg.code.unwrap_or(ReasonPhrases::Success)).await;
+                }
+                vec![]
+            }
+            RequestV3(MqttMessageV3::Pubrec(msg)) | RequestV5(MqttMessageV5::Pubrec(msg)) => {
+                self.pubrec(msg).await
+            }
+            RequestV3(MqttMessageV3::Pubrel(msg)) | RequestV5(MqttMessageV5::Pubrel(msg)) => {
+                let code = if self.session.frames().lock().await.release(msg.message_id) {
+                    ReasonPhrases::Success
+                } else {
+                    ReasonPhrases::PacketIdentifierNotFound
+                };
+                self.ack(TypeKind::PUBCOMP, msg.message_id, code)
+            }
+            RequestV3(MqttMessageV3::Pubcomp(msg)) | RequestV5(MqttMessageV5::Pubcomp(msg)) => {
+                let frame = self.session.frames().lock().await.pubcomp(msg.message_id);
+                if frame.is_some() {
+                    self.session.publish_acked(msg.message_id, msg.code.unwrap_or(ReasonPhrases::Success)).await;
+                }
+                vec![]
+            }
+            RequestV3(MqttMessageV3::Suback(msg)) | RequestV5(MqttMessageV5::Suback(msg)) => {
+                self.session.subscribe_acked(msg.message_id, msg.codes.clone()).await;
+                vec![]
+            }
+            RequestV3(MqttMessageV3::Unsuback(msg)) | RequestV5(MqttMessageV5::Unsuback(msg)) => {
+                let code = msg.code
+                    .map(|code| ReasonPhrases::try_from(code).unwrap_or(ReasonPhrases::UnspecifiedError))
+                    .unwrap_or(ReasonPhrases::Success);
+                self.session.unsubscribe_acked(msg.message_id, code).await;
+                vec![]
+            }
+            _ => vec![]
+        };
+        Ok((response, true))
+    }
+
+    ///
+    /// 重新连接后收到成功的 CONNACK: 重新订阅已记录的主题, 然后按原顺序重发未确认的 PUBLISH (设置 DUP) 与 PUBREL.
+    /// 服务端没有保留会话时不再重发之前的连接上发送过的消息. 之后的订阅与消息直接在新连接上发送
+    ///
+    async fn resume(&self, session_present: bool) -> Vec<u8> {
+        let mut online = self.session.online().await;
+        if *online {
+            return vec![];
+        }
+        *online = true;
+        if !session_present {
+            self.session.reset().await;
+        }
+        let mut response = self.session.resubscribe().await;
+        let frames = self.session.frames().lock().await.resend();
+        for frame in frames {
+            response.extend(match frame.state() {
+                FrameState::Publish if self.session.protocol_level == MqttProtocolLevel::Level5 => {
+                    MqttMessageV5::Publish(frame.message().clone()).to_vec().unwrap_or_default()
+                }
+                FrameState::Publish => MqttMessageV3::Publish(frame.message().clone()).to_vec().unwrap_or_default(),
+                FrameState::Pubrel => self.ack(TypeKind::PUBREL, frame.message_id(), ReasonPhrases::Success),
+            });
+        }
+        response
+    }
+
+    ///
+    /// 解析服务端发送的主题别名: 主题名非空时记录别名, 为空时填入别名对应的主题名
+    ///
+    fn resolve_topic_alias(&mut self, msg: &mut PublishMessage) -> Result<(), ReasonPhrases> {
+        let alias = match find_property(msg.properties.as_deref(), Property::TopicAlias).and_then(PropertyItem::as_short) {
+            Some(alias) => alias,
+            None if msg.topic.is_empty() => return Err(ReasonPhrases::ProtocolError),
+            None => return Ok(())
+        };
+        if alias == 0 || alias > self.topic_alias_maximum {
+            return Err(ReasonPhrases::TopicAliasInvalid);
+        }
+        if msg.topic.is_empty() {
+            msg.topic = self.topic_aliases.get(&alias).cloned().ok_or(ReasonPhrases::ProtocolError)?;
+        } else {
+            self.topic_aliases.insert(alias, msg.topic.clone());
+        }
+        Ok(())
+    }
+
+    ///
+    /// 收到 PUBLISH: QoS 1 回复 PUBACK, QoS 2 回复 PUBREC; 重复的 QoS 2 消息与请求的响应不再转发
+    ///
+    async fn receive(&self, msg: &PublishMessage) -> (Vec<u8>, bool) {
+        let (response, first) = match msg.qos {
+            MqttQos::Qos1 => (self.ack(TypeKind::PUBACK, msg.message_id, ReasonPhrases::Success), true),
+            MqttQos::Qos2 => {
+                let first = self.session.frames().lock().await.receive(msg.message_id);
+                (self.ack(TypeKind::PUBREC, msg.message_id, ReasonPhrases::Success), first)
+            }
+            _ => (vec![], true)
+        };
+        (response, first && !self.session.resolve(msg).await)
+    }
+
+    ///
+    /// 收到 PUBREC: 回复 PUBREL; v5 中失败的原因码直接结束该消息流程
+    ///
+    async fn pubrec(&self, msg: &PubrecMessage) -> Vec<u8> {
+        let mut frames = self.session.frames().lock().await;
+        if let Some(code) = msg.code.filter(|code| code.as_byte() >= 0x80) {
+            let frame = frames.pubcomp(msg.message_id);
+            drop(frames);
+            if frame.is_some() {
+                self.session.publish_acked(msg.message_id, code).await;
+            }
+            return vec![];
+        }
+        let code = if frames.pubrec(msg.message_id) {
+            ReasonPhrases::Success
+        } else {
+            ReasonPhrases::PacketIdentifierNotFound
+        };
+        self.ack(TypeKind::PUBREL, msg.message_id, code)
+    }
+
+    fn ack(&self, kind: TypeKind, message_id: u16, code: ReasonPhrases) -> Vec<u8> {
+        encode_ack(self.session.protocol_level, kind, message_id, code)
+    }
+
+    ///
+    /// 处理服务端的增强认证报文: AUTH 0x18 继续交换; CONNACK 或 AUTH 0x00 校验服务端最后的认证数据.
+    /// 返回需要回复的报文, 返回 `Err` 时断开连接
+    ///
+    async fn authenticate(&mut self, msg: &MqttMessageV5) -> Result<Vec<u8>, ReasonPhrases> {
+        let (code, properties) = match msg {
+            MqttMessageV5::Auth(msg) => (msg.code, msg.properties.as_deref()),
+            MqttMessageV5::Connack(msg) if self.authentication.is_some() => (msg.return_code.unwrap_or_default(), msg.properties.as_deref()),
+            _ => return Ok(vec![])
+        };
+        let mut exchange = self.authentication.take().ok_or(ReasonPhrases::ProtocolError)?;
+        let code = ReasonPhrases::try_from(code).map_err(|_| ReasonPhrases::ProtocolError)?;
+        if code.as_byte() >= 0x80 {
+            return Err(code);
+        }
+        let data = find_property(properties, Property::AuthenticationData).and_then(PropertyItem::as_binary);
+        match (exchange.step(data.map(Vec::as_slice)).await, code) {
+            (AuthStep::Continue(data), ReasonPhrases::ContinueAuthentication) => {
+                self.authentication = Some(exchange);
+                Ok(self.auth(ReasonPhrases::ContinueAuthentication, data))
+            }
+            (AuthStep::Success(_), ReasonPhrases::Success) => Ok(vec![]),
+            (AuthStep::Failure(code), _) => Err(code),
+            _ => Err(ReasonPhrases::ProtocolError)
+        }
+    }
+
+    fn auth(&self, code: ReasonPhrases, data: Vec<u8>) -> Vec<u8> {
+        let properties = vec![
+            PropertyItem(Property::AuthenticationMethod, PropertyValue::String(self.authentication_method().unwrap_or_default().to_owned())),
+            PropertyItem(Property::AuthenticationData, PropertyValue::Binary(data)),
+        ];
+        MqttMessageV5::Auth(AuthMessage { code: code.as_byte(), properties: Some(properties), ..AuthMessage::default() }).to_vec().unwrap_or_default()
+    }
+
+    fn request(&self, base_msg: BaseMessage) -> Result<Option<MqttMessageKind>, DecodeError> {
         match self.session.protocol_level {
             MqttProtocolLevel::Level3_1_1 => {
-                MqttMessageKind::to_v3_request(base_msg)
+                MqttMessageKind::to_v3_request(base_msg).map(Some)
             }
             MqttProtocolLevel::Level5 => {
-                MqttMessageKind::to_v5_request(base_msg)
+                MqttMessageKind::to_v5_request(base_msg).map(Some)
             }
-            _ => None
+            MqttProtocolLevel::Level3_1 => {
+                Err(DecodeError::UnsupportedProtocolLevel(MqttProtocolLevel::Level3_1 as u8))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{ScramSha256Client, ScramSha256Server};
+    use crate::auth::scram::SCRAM_SHA_256;
+    use crate::hex::reason_code::{ReasonCodes, ReasonCodeV3, ReasonCodeV5};
+    use crate::message::entity::ConnackMessage;
+    use crate::tools::error::AckError;
+    use crate::tools::protocol::{MqttDup, MqttRetain};
+
+    async fn callback(_session: ClientSession, _request: Option<MqttMessageKind>) {}
+
+    fn properties(data: Vec<u8>) -> Option<Vec<PropertyItem>> {
+        Some(vec![
+            PropertyItem(Property::AuthenticationMethod, PropertyValue::String(SCRAM_SHA_256.to_owned())),
+            PropertyItem(Property::AuthenticationData, PropertyValue::Binary(data)),
+        ])
+    }
+
+    fn auth_data(packet: Vec<u8>) -> Vec<u8> {
+        match MqttMessageKind::to_v5_request(BaseMessage::try_from(packet).unwrap()).unwrap() {
+            MqttMessageKind::RequestV5(MqttMessageV5::Auth(msg)) => {
+                find_property(msg.properties.as_deref(), Property::AuthenticationData).and_then(PropertyItem::as_binary).cloned().unwrap()
+            }
+            kind => panic!("unexpected packet {:?}", kind),
         }
     }
+
+    async fn handshake(password: &str, tamper: bool) -> Option<ReturnKind> {
+        let (sender, receiver) = mpsc::channel(16);
+        let session = ClientSession::new("scram-client".to_owned(), MqttProtocolLevel::Level5, sender);
+        let mut handle = ClientHandleV3::new(session, receiver)
+            .auth_mechanism(Arc::new(ScramSha256Client::new("scram-user", password)));
+        let mut server = ScramSha256Server::new().user("scram-user", "scram-password").start();
+
+        let initial = handle.start_authentication().await.unwrap();
+        let challenge = match server.step(Some(&initial)).await {
+            AuthStep::Continue(data) => data,
+            step => panic!("unexpected step {:?}", step),
+        };
+        let msg = AuthMessage { code: ReasonPhrases::ContinueAuthentication.as_byte(), properties: properties(challenge), ..AuthMessage::default() };
+        handle.send_message(HandleEvent::InputEvent(MqttMessageV5::Auth(msg).to_vec().unwrap())).await;
+        let response = match handle.execute(callback, None).await {
+            Some(ReturnKind::Response(data)) => auth_data(data),
+            _ => panic!("expected auth response"),
+        };
+        let mut last = match server.step(Some(&response)).await {
+            AuthStep::Success(Some(data)) => data,
+            AuthStep::Failure(code) => return Some(ReturnKind::Response(vec![code.as_byte()])),
+            step => panic!("unexpected step {:?}", step),
+        };
+        if tamper {
+            last.push(b'x');
+        }
+        let mut connack = ConnackMessage::new(MqttSessionPresent::Disable, ReasonCodes::V5(ReasonCodeV5::ReasonPhrases(ReasonPhrases::Success)));
+        connack.properties = properties(last);
+        handle.send_message(HandleEvent::InputEvent(MqttMessageV5::Connack(connack).to_vec().unwrap())).await;
+        handle.execute(callback, None).await
+    }
+
+    #[tokio::test]
+    async fn scram_handshake() {
+        assert!(handshake("scram-password", false).await.is_none());
+        assert!(matches!(handshake("scram-password", true).await, Some(ReturnKind::Exit)));
+        match handshake("wrong", false).await {
+            Some(ReturnKind::Response(code)) => assert_eq!(code, vec![ReasonPhrases::BadUserNameOrPassword.as_byte()]),
+            _ => panic!("expected server rejection"),
+        }
+    }
+
+    #[tokio::test]
+    async fn response_information() {
+        let (sender, receiver) = mpsc::channel(16);
+        let session = ClientSession::new("rr-client".to_owned(), MqttProtocolLevel::Level5, sender);
+        let mut handle = ClientHandleV3::new(session.clone(), receiver);
+        let mut connack = ConnackMessage::new(MqttSessionPresent::Disable, ReasonCodes::V5(ReasonCodeV5::ReasonPhrases(ReasonPhrases::Success)));
+        connack.properties = Some(vec![PropertyItem(Property::ResponseInformation, PropertyValue::String("rr/base/".to_owned()))]);
+        handle.send_message(HandleEvent::InputEvent(MqttMessageV5::Connack(connack).to_vec().unwrap())).await;
+        assert!(handle.execute(callback, None).await.is_none());
+
+        let request = tokio::spawn(async move { session.request("rr/service", "ping", std::time::Duration::from_secs(5)).await });
+        assert!(matches!(handle.execute(callback, None).await, Some(ReturnKind::Response(_))));
+        let correlation = match handle.execute(callback, None).await {
+            Some(ReturnKind::Response(data)) => match MqttMessageKind::to_v5_request(BaseMessage::try_from(data).unwrap()).unwrap() {
+                MqttMessageKind::RequestV5(MqttMessageV5::Publish(msg)) => {
+                    let properties = msg.properties.as_deref();
+                    assert_eq!(find_property(properties, Property::ResponseTopic).and_then(PropertyItem::as_str).unwrap(), "rr/base/rr-client");
+                    find_property(properties, Property::CorrelationData).cloned().unwrap()
+                }
+                kind => panic!("unexpected packet {:?}", kind),
+            },
+            _ => panic!("expected request"),
+        };
+
+        let response = PublishMessage::new(MqttQos::Qos0, MqttDup::Disable, MqttRetain::Disable, "rr/base/rr-client".to_owned(), 0, "pong", Some(vec![correlation]));
+        handle.send_message(HandleEvent::InputEvent(MqttMessageV5::Publish(response).to_vec().unwrap())).await;
+        let (sender, mut forwarded) = mpsc::channel(1);
+        assert!(handle.execute(callback, Some(sender)).await.is_none());
+        assert!(forwarded.try_recv().is_err());
+        assert_eq!(request.await.unwrap().unwrap().payload(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn slow_subscription() {
+        let (sender, receiver) = mpsc::channel(16);
+        let session = ClientSession::new("slow-client".to_owned(), MqttProtocolLevel::Level3_1_1, sender);
+        let mut handle = ClientHandleV3::new(session.clone(), receiver);
+        let mut subscription = session.subscribe_stream("slow/+", MqttQos::Qos1).await;
+        for message_id in 1..=600 {
+            let msg = PublishMessage::new(MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable, "slow/topic".to_owned(), message_id, "1", None);
+            handle.send_message(HandleEvent::InputEvent(MqttMessageV3::Publish(msg).to_vec().unwrap())).await;
+            let response = tokio::time::timeout(std::time::Duration::from_secs(1), handle.execute(callback, None)).await.unwrap();
+            assert!(matches!(response, Some(ReturnKind::Response(_))));
+        }
+        assert_eq!(subscription.recv().await.unwrap().message_id, 1);
+    }
+
+    async fn resume(handle: &mut ClientHandleV3, session_present: MqttSessionPresent) -> PublishMessage {
+        let connack = ConnackMessage::new(session_present, ReasonCodes::V3(ReasonCodeV3::ConnectionAccepted));
+        handle.send_message(HandleEvent::InputEvent(MqttMessageV3::Connack(connack).to_vec().unwrap())).await;
+        match handle.execute(callback, None).await {
+            Some(ReturnKind::Response(data)) => match MqttMessageKind::to_v3_request(BaseMessage::try_from(data).unwrap()).unwrap() {
+                MqttMessageKind::RequestV3(MqttMessageV3::Publish(msg)) => msg,
+                kind => panic!("unexpected packet {:?}", kind),
+            },
+            _ => panic!("expected resent publish"),
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_after_connack() {
+        let (sender, receiver) = mpsc::channel(16);
+        let session = ClientSession::new("resume-client".to_owned(), MqttProtocolLevel::Level3_1_1, sender);
+        let mut handle = ClientHandleV3::new(session.clone(), receiver);
+        let message = |body: &str| PublishMessage::new(MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable, "resume/topic".to_owned(), 0, body.to_owned(), None);
+        let sent = session.publish_with_ack(&message("sent")).await;
+        assert!(matches!(handle.execute(callback, None).await, Some(ReturnKind::Response(_))));
+
+        session.offline().await;
+        let _queued = session.publish_with_ack(&message("queued")).await;
+        let msg = resume(&mut handle, MqttSessionPresent::Disable).await;
+        assert_eq!((msg.message_id, msg.dup), (2, MqttDup::Disable));
+        assert_eq!(sent.await.unwrap_err(), AckError::ConnectionLost);
+
+        session.offline().await;
+        let msg = resume(&mut handle, MqttSessionPresent::Enable).await;
+        assert_eq!((msg.message_id, msg.dup), (2, MqttDup::Enable));
+    }
+
+    #[tokio::test]
+    async fn topic_alias() {
+        let (sender, receiver) = mpsc::channel(16);
+        let session = ClientSession::new("alias-client".to_owned(), MqttProtocolLevel::Level5, sender);
+        let mut handle = ClientHandleV3::new(session.clone(), receiver).topic_alias_maximum(2);
+        let connack = MqttMessageV5::Connack(ConnackMessage::new(MqttSessionPresent::Disable, ReasonCodes::V5(ReasonCodeV5::ReasonPhrases(ReasonPhrases::Success)))).to_vec().unwrap();
+        handle.send_message(HandleEvent::InputEvent(connack.clone())).await;
+        assert!(handle.execute(callback, None).await.is_none());
+        let mut subscription = session.subscribe_stream("alias/+", MqttQos::Qos0).await;
+        assert!(matches!(handle.execute(callback, None).await, Some(ReturnKind::Response(_))));
+
+        let publish = |topic: &str, alias: u16| {
+            let properties = vec![PropertyItem(Property::TopicAlias, PropertyValue::Short(alias))];
+            MqttMessageV5::Publish(PublishMessage::new(MqttQos::Qos0, MqttDup::Disable, MqttRetain::Disable, topic.to_owned(), 0, "1", Some(properties))).to_vec().unwrap()
+        };
+        handle.send_message(HandleEvent::InputEvent(publish("alias/a", 1))).await;
+        assert!(handle.execute(callback, None).await.is_none());
+        handle.send_message(HandleEvent::InputEvent(publish("", 1))).await;
+        assert!(handle.execute(callback, None).await.is_none());
+        assert_eq!(subscription.recv().await.unwrap().topic, "alias/a");
+        assert_eq!(subscription.recv().await.unwrap().topic, "alias/a");
+
+        handle.send_message(HandleEvent::InputEvent(publish("", 3))).await;
+        assert!(handle.execute(callback, None).await.is_none());
+        assert_eq!(handle.take_protocol_error(), Some(ReasonPhrases::TopicAliasInvalid));
+
+        // 新连接上之前的别名不再有效
+        session.offline().await;
+        handle.send_message(HandleEvent::InputEvent(connack)).await;
+        handle.execute(callback, None).await;
+        handle.send_message(HandleEvent::InputEvent(publish("", 1))).await;
+        assert!(handle.execute(callback, None).await.is_none());
+        assert_eq!(handle.take_protocol_error(), Some(ReasonPhrases::ProtocolError));
+    }
 }
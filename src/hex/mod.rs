@@ -1,6 +1,7 @@
 use num_enum::TryFromPrimitive;
-use crate::tools::un_pack_tool::{parse_long_int, parse_string, parse_byte, parse_short_int, unpack_var_int};
-use crate::tools::pack_tool::{pack_long_int, pack_string, pack_byte, pack_short_int, pack_var_int};
+use crate::tools::un_pack_tool::{parse_long_int, parse_string, parse_byte, parse_short_int, unpack_var_int, parse_binary};
+use crate::tools::pack_tool::{pack_long_int, pack_string, pack_byte, pack_short_int, pack_var_int, pack_binary};
+use crate::tools::error::DecodeError;
 
 pub mod reason_code;
 pub mod un_pack_property;
@@ -12,12 +13,20 @@ pub enum PropertyValue {
     Short(u16),
     Byte(u8),
     String(String),
+    Binary(Vec<u8>),
     Map(String, String),
 }
 
 #[derive(Debug, Clone)]
 pub struct PropertyItem(pub Property, pub PropertyValue);
 
+///
+/// 查找属性列表中的指定属性
+///
+pub fn find_property(properties: Option<&[PropertyItem]>, property: Property) -> Option<&PropertyItem> {
+    properties?.iter().find(|item| item.0 == property)
+}
+
 impl PropertyItem {
     pub fn as_long(&self) -> Option<u32> {
         match self.1 {
@@ -55,6 +64,15 @@ impl PropertyItem {
         }
     }
 
+    pub fn as_binary(&self) -> Option<&Vec<u8>> {
+        match self.1 {
+            PropertyValue::Binary(ref val) => {
+                Some(val)
+            }
+            _ => { None }
+        }
+    }
+
     pub fn as_map(&self) -> Option<(&String, &String)> {
         match self.1 {
             PropertyValue::Map(ref key, ref value) => {
@@ -65,7 +83,7 @@ impl PropertyItem {
     }
 }
 
-#[derive(Debug, Copy, Clone, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, TryFromPrimitive, Eq, PartialEq)]
 #[repr(u8)]
 pub enum Property {
     PayloadFormatIndicator = 0x01,
@@ -133,22 +151,21 @@ impl Property {
 
 impl Property {
     pub fn is_connect_property(&self) -> bool {
-        match self {
+        matches!(self,
             Property::SessionExpiryInterval |
             Property::AuthenticationMethod |
             Property::AuthenticationData |
             Property::RequestProblemInformation |
             Property::RequestResponseInformation |
             Property::ReceiveMaximum |
-            Property::TopicAlias |
+            Property::TopicAliasMaximum |
             Property::UserProperty |
-            Property::MaximumPacketSize => { true }
-            _ => { false }
-        }
+            Property::MaximumPacketSize
+        )
     }
 
     pub fn is_connack_property(&self) -> bool {
-        match self {
+        matches!(self,
             Property::SessionExpiryInterval |
             Property::AssignedClientIdentifier |
             Property::ServerKeepAlive |
@@ -165,13 +182,12 @@ impl Property {
             Property::MaximumPacketSize |
             Property::WildcardSubscriptionAvailable |
             Property::SubscriptionIdentifierAvailable |
-            Property::SharedSubscriptionAvailable => { true }
-            _ => { false }
-        }
+            Property::SharedSubscriptionAvailable
+        )
     }
 
     pub fn is_publish_property(&self) -> bool {
-        match self {
+        matches!(self,
             Property::PayloadFormatIndicator |
             Property::MessageExpiryInterval |
             Property::ContentType |
@@ -179,70 +195,63 @@ impl Property {
             Property::CorrelationData |
             Property::SubscriptionIdentifier |
             Property::TopicAlias |
-            Property::UserProperty => { true }
-            _ => { false }
-        }
+            Property::UserProperty
+        )
     }
 
     pub fn is_pub_and_sub_property(&self) -> bool {
-        match self {
+        matches!(self,
             Property::ReasonString |
-            Property::UserProperty => { true }
-            _ => { false }
-        }
+            Property::UserProperty
+        )
     }
 
     pub fn is_subscribe_property(&self) -> bool {
-        match self {
+        matches!(self,
             Property::SubscriptionIdentifier |
-            Property::UserProperty => { true }
-            _ => { false }
-        }
+            Property::UserProperty
+        )
     }
 
     pub fn is_unsubscribe_property(&self) -> bool {
-        match self {
-            Property::UserProperty => { true }
-            _ => { false }
-        }
+        matches!(self,
+            Property::UserProperty
+        )
     }
 
     pub fn is_disconnect_property(&self) -> bool {
-        match self {
+        matches!(self,
             Property::SessionExpiryInterval |
             Property::ServerReference |
             Property::ReasonString |
-            Property::UserProperty => { true }
-            _ => { false }
-        }
+            Property::UserProperty
+        )
     }
 
     pub fn is_auth_property(&self) -> bool {
-        match self {
+        matches!(self,
             Property::AuthenticationMethod |
             Property::AuthenticationData |
             Property::ReasonString |
-            Property::UserProperty => { true }
-            _ => { false }
-        }
+            Property::UserProperty
+        )
     }
 
     pub fn is_will_property(&self) -> bool {
-        match self {
+        matches!(self,
             Property::PayloadFormatIndicator |
             Property::MessageExpiryInterval |
             Property::ContentType |
             Property::ResponseTopic |
             Property::CorrelationData |
             Property::WillDelayInterval |
-            Property::UserProperty => { true }
-            _ => { false }
-        }
+            Property::UserProperty
+        )
     }
 }
 
 impl Property {
-    pub fn pack_property_handle(item: &PropertyItem, length: &mut usize, body: &mut Vec<u8>) {
+    pub fn pack_property_handle(item: &PropertyItem, body: &mut Vec<u8>) {
         body.push(item.0 as u8);
 
         match item.0 {
@@ -250,22 +259,20 @@ impl Property {
             Property::MessageExpiryInterval |
             Property::WillDelayInterval |
             Property::MaximumPacketSize => {
-                let long_val = pack_long_int(item.as_long().unwrap());
-                *length += 5;
-                body.extend(long_val);
+                body.extend(pack_long_int(item.as_long().unwrap()));
             }
             Property::ContentType |
             Property::ResponseTopic |
-            Property::CorrelationData |
             Property::AssignedClientIdentifier |
             Property::ResponseInformation |
             Property::ServerReference |
             Property::ReasonString |
-            Property::AuthenticationMethod |
+            Property::AuthenticationMethod => {
+                body.extend(pack_string(item.as_str().unwrap()));
+            }
+            Property::CorrelationData |
             Property::AuthenticationData => {
-                let string_val = pack_string(item.as_str().unwrap());
-                *length += string_val.len() + 3;
-                body.extend(string_val);
+                body.extend(pack_binary(item.as_binary().unwrap()));
             }
             Property::PayloadFormatIndicator |
             Property::MaximumQos |
@@ -275,57 +282,48 @@ impl Property {
             Property::SharedSubscriptionAvailable |
             Property::RequestProblemInformation |
             Property::RequestResponseInformation => {
-                let byte_val = pack_byte(item.as_byte().unwrap());
-                *length += 2;
-                body.extend(byte_val);
+                body.extend(pack_byte(item.as_byte().unwrap()));
             }
             Property::ServerKeepAlive |
             Property::ReceiveMaximum |
             Property::TopicAlias |
             Property::TopicAliasMaximum => {
-                let short_val = pack_short_int(item.as_short().unwrap());
-                *length += 3;
-                body.extend(short_val);
+                body.extend(pack_short_int(item.as_short().unwrap()));
             }
             Property::UserProperty => {
-                let user = item.as_map();
-                let user_key = pack_string(user.as_ref().unwrap().0);
-                let user_value = pack_string(user.as_ref().unwrap().1);
-                *length += (user_key.len() + user_value.len() + 5);
-                body.push(Property::UserProperty as u8);
-                body.extend(user_key);
-                body.extend(user_value);
+                let (key, value) = item.as_map().unwrap();
+                body.extend(pack_string(key));
+                body.extend(pack_string(value));
             }
             Property::SubscriptionIdentifier => {
-                let si = pack_var_int(1);
-                *length += (si.len() + 1);
-                body.extend(si);
+                body.extend(pack_var_int(item.as_long().unwrap() as usize));
             }
         }
     }
 
-    pub fn unpack_property_handle<'a>(&self, length: &mut u32, data: &'a [u8]) -> Option<(PropertyItem, &'a [u8])> {
+    pub fn unpack_property_handle<'a>(&self, data: &'a [u8]) -> Result<(PropertyItem, &'a [u8]), DecodeError> {
         match self {
             Property::SessionExpiryInterval |
             Property::MessageExpiryInterval |
             Property::WillDelayInterval |
             Property::MaximumPacketSize => {
-                let (val, last_data) = parse_long_int(data);
-                *length -= 5;
-                Some((PropertyItem(Property::SessionExpiryInterval, PropertyValue::Long(val)), last_data))
+                let (val, last_data) = parse_long_int(data)?;
+                Ok((PropertyItem(*self, PropertyValue::Long(val)), last_data))
             }
             Property::ContentType |
             Property::ResponseTopic |
-            Property::CorrelationData |
             Property::AssignedClientIdentifier |
             Property::ResponseInformation |
             Property::ServerReference |
             Property::ReasonString |
-            Property::AuthenticationMethod |
+            Property::AuthenticationMethod => {
+                let (val, last_data) = parse_string(data)?;
+                Ok((PropertyItem(*self, PropertyValue::String(val)), last_data))
+            }
+            Property::CorrelationData |
             Property::AuthenticationData => {
-                let (val, last_data) = parse_string(data).unwrap();
-                *length -= (val.len() as u32 + 3);
-                Some((PropertyItem(*self, PropertyValue::String(val)), last_data.unwrap()))
+                let (val, last_data) = parse_binary(data)?;
+                Ok((PropertyItem(*self, PropertyValue::Binary(val)), last_data))
             }
             Property::PayloadFormatIndicator |
             Property::MaximumQos |
@@ -335,28 +333,24 @@ impl Property {
             Property::SharedSubscriptionAvailable |
             Property::RequestProblemInformation |
             Property::RequestResponseInformation => {
-                let (val, last_data) = parse_byte(data);
-                *length -= 2;
-                Some((PropertyItem(*self, PropertyValue::Byte(val)), last_data))
+                let (val, last_data) = parse_byte(data)?;
+                Ok((PropertyItem(*self, PropertyValue::Byte(val)), last_data))
             }
             Property::ServerKeepAlive |
             Property::ReceiveMaximum |
             Property::TopicAlias |
             Property::TopicAliasMaximum => {
-                let (val, last_data) = parse_short_int(data);
-                *length -= 3;
-                Some((PropertyItem(*self, PropertyValue::Short(val)), last_data))
+                let (val, last_data) = parse_short_int(data)?;
+                Ok((PropertyItem(*self, PropertyValue::Short(val)), last_data))
             }
             Property::UserProperty => {
-                let (user_key, last_data) = parse_string(data).unwrap();
-                let (user_value, last_data) = parse_string(last_data.unwrap()).unwrap();
-                *length -= (user_key.len() as u32 + user_value.len() as u32 + 5);
-                Some((PropertyItem(Property::UserProperty, PropertyValue::Map(user_key, user_value)), last_data.unwrap()))
+                let (user_key, last_data) = parse_string(data)?;
+                let (user_value, last_data) = parse_string(last_data)?;
+                Ok((PropertyItem(Property::UserProperty, PropertyValue::Map(user_key, user_value)), last_data))
             }
             Property::SubscriptionIdentifier => {
-                let (val, last_data) = unpack_var_int(data);
-                *length -= (val.len() as u32 + 1);
-                Some((PropertyItem(Property::SubscriptionIdentifier, PropertyValue::String(val)), last_data))
+                let (val, last_data) = unpack_var_int(data)?;
+                Ok((PropertyItem(Property::SubscriptionIdentifier, PropertyValue::Long(val)), last_data))
             }
         }
     }
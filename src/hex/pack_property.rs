@@ -1,119 +1,50 @@
 use crate::hex::{PropertyItem, Property};
+use crate::tools::pack_tool::pack_var_int;
 
-pub fn connect(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
+fn handle_properties(data: &[PropertyItem], allowed: fn(&Property) -> bool) -> Vec<u8> {
     let mut body = vec![];
     for item in data {
-        if item.0.is_connect_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
+        if allowed(&item.0) {
+            Property::pack_property_handle(item, &mut body);
         }
     }
-    body.insert(0, length as u8);
-    body
+    let mut properties = pack_var_int(body.len());
+    properties.extend(body);
+    properties
 }
 
-pub fn connack(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-    for item in data {
-        if item.0.is_connack_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn connect(data: &[PropertyItem]) -> Vec<u8> {
+    handle_properties(data, Property::is_connect_property)
 }
 
-pub fn will_properties(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_will_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn connack(data: &[PropertyItem]) -> Vec<u8> {
+    handle_properties(data, Property::is_connack_property)
 }
 
-pub fn subscribe(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_subscribe_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn will_properties(data: &[PropertyItem]) -> Vec<u8> {
+    handle_properties(data, Property::is_will_property)
 }
 
-pub fn unsubscribe(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_unsubscribe_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn subscribe(data: &[PropertyItem]) -> Vec<u8> {
+    handle_properties(data, Property::is_subscribe_property)
 }
 
-pub fn suback(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_pub_and_sub_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn unsubscribe(data: &[PropertyItem]) -> Vec<u8> {
+    handle_properties(data, Property::is_unsubscribe_property)
 }
 
-pub fn disconnect(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_disconnect_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn suback(data: &[PropertyItem]) -> Vec<u8> {
+    handle_properties(data, Property::is_pub_and_sub_property)
 }
 
-pub fn auth(data: &Vec<PropertyItem>) -> Vec<u8>{
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_auth_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn disconnect(data: &[PropertyItem]) -> Vec<u8> {
+    handle_properties(data, Property::is_disconnect_property)
 }
 
-pub fn publish(data: &Vec<PropertyItem>) -> Vec<u8>{
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_publish_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn auth(data: &[PropertyItem]) -> Vec<u8> {
+    handle_properties(data, Property::is_auth_property)
 }
 
-
-
+pub fn publish(data: &[PropertyItem]) -> Vec<u8> {
+    handle_properties(data, Property::is_publish_property)
+}
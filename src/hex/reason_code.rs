@@ -1,7 +1,7 @@
 use num_enum::TryFromPrimitive;
 use crate::tools::protocol::MqttQos;
 
-#[derive(Debug, Copy, Clone, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum ReasonCodeV3 {
     ConnectionAccepted = 0x00,
@@ -29,6 +29,35 @@ impl ReasonCodeV3 {
     }
 }
 
+///
+/// v5 原因码转换为 v3.1.1 CONNACK 返回码, 没有对应返回码的按服务不可用处理
+///
+impl From<ReasonPhrases> for ReasonCodeV3 {
+    fn from(code: ReasonPhrases) -> Self {
+        match code {
+            ReasonPhrases::Success => ReasonCodeV3::ConnectionAccepted,
+            ReasonPhrases::UnsupportedProtocolVersion => ReasonCodeV3::UnacceptableProtocolVersion,
+            ReasonPhrases::ClientIdentifierNotValid => ReasonCodeV3::IdentifierRejected,
+            ReasonPhrases::BadUserNameOrPassword => ReasonCodeV3::BadUsernameOrPassword,
+            ReasonPhrases::NotAuthorized | ReasonPhrases::Banned | ReasonPhrases::BadAuthenticationMethod => ReasonCodeV3::NotAuthorized,
+            _ => ReasonCodeV3::ServerUnavailable
+        }
+    }
+}
+
+impl From<ReasonCodeV3> for ReasonPhrases {
+    fn from(code: ReasonCodeV3) -> Self {
+        match code {
+            ReasonCodeV3::ConnectionAccepted => ReasonPhrases::Success,
+            ReasonCodeV3::UnacceptableProtocolVersion => ReasonPhrases::UnsupportedProtocolVersion,
+            ReasonCodeV3::IdentifierRejected => ReasonPhrases::ClientIdentifierNotValid,
+            ReasonCodeV3::ServerUnavailable => ReasonPhrases::ServerUnavailable,
+            ReasonCodeV3::BadUsernameOrPassword => ReasonPhrases::BadUserNameOrPassword,
+            ReasonCodeV3::NotAuthorized => ReasonPhrases::NotAuthorized,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum ReasonCodes {
     V3(ReasonCodeV3),
@@ -81,7 +110,7 @@ impl ReasonCodeV5 {
     }
 }
 
-#[derive(Debug, Copy, Clone, TryFromPrimitive)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum ReasonPhrases {
     Success = 0x00,
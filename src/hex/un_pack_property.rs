@@ -1,232 +1,63 @@
 use crate::hex::{PropertyItem, Property};
+use crate::tools::error::DecodeError;
+use crate::tools::un_pack_tool::parse_byte;
 use std::convert::TryFrom;
-use std::option::Option::Some;
 
-
-fn handle_property<'a>(property: Property, properties: &mut Vec<PropertyItem>, length: &mut u32, data: &'a [u8]) -> Option<&'a [u8]> {
-    if let Some((item, last_data)) = property.unpack_property_handle(length, data.get(1..).unwrap()) {
-        properties.push(item);
-        return Some(last_data);
-    }
-    None
-}
-
-pub fn connect(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
+fn handle_properties(mut data: &[u8], allowed: fn(&Property) -> bool) -> Result<Vec<PropertyItem>, DecodeError> {
     let mut properties = vec![];
-
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_connect_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
+    while !data.is_empty() {
+        let (property, last_data) = parse_byte(data)?;
+        let p = Property::try_from(property).map_err(|_| DecodeError::InvalidProperty(property))?;
+        if !allowed(&p) {
+            return Err(DecodeError::InvalidProperty(property));
         }
+        let (item, last_data) = p.unpack_property_handle(last_data)?;
+        properties.push(item);
+        data = last_data;
     }
-    properties
+    Ok(properties)
 }
 
-pub fn connack(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_connack_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
-
-    properties
+pub fn connect(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_connect_property)
 }
 
-pub fn publish(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_publish_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
-
-    properties
+pub fn connack(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_connack_property)
 }
 
-pub fn subscribe(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_subscribe_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
-
-    properties
+pub fn publish(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_publish_property)
 }
 
-pub fn unsubscribe(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_unsubscribe_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
-
-    properties
+pub fn subscribe(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_subscribe_property)
 }
 
-pub fn suback(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_pub_and_sub_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
-
-    properties
+pub fn unsubscribe(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_unsubscribe_property)
 }
 
-pub fn unsuback(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_pub_and_sub_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
-
-    properties
+pub fn suback(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_pub_and_sub_property)
 }
 
-pub fn disconnect(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_disconnect_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
-
-    properties
+pub fn unsuback(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_pub_and_sub_property)
 }
 
-pub fn auth(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_auth_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
-
-    properties
+pub fn disconnect(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_disconnect_property)
 }
 
-pub fn pub_and_sub(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_pub_and_sub_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
-
-    properties
+pub fn auth(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_auth_property)
 }
 
-pub fn will_properties(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    while length > 0 {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_will_property() {
-                    if let Some(v) = handle_property(p, &mut properties, &mut length, data.get(1..).unwrap()) { data = v; }
-                }
-            }
-            Err(e) => {
-                println!("Property {:?} not exist", e);
-                break;
-            }
-        }
-    }
+pub fn pub_and_sub(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_pub_and_sub_property)
+}
 
-    properties
+pub fn will_properties(data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    handle_properties(data, Property::is_will_property)
 }
@@ -3,6 +3,8 @@ extern crate lazy_static;
 
 use crate::subscript::Subscript;
 use crate::container::MessageContainer;
+use crate::retain::RetainContainer;
+use crate::registry::SessionRegistry;
 
 pub mod hex;
 pub mod tools;
@@ -11,6 +13,9 @@ pub mod message;
 pub mod subscript;
 pub mod session;
 pub mod container;
+pub mod retain;
+pub mod registry;
+pub mod auth;
 pub mod handle;
 pub mod executor;
 
@@ -18,5 +23,7 @@ pub mod executor;
 lazy_static! {
     pub static ref SUBSCRIPT: Subscript = Subscript::new();
     pub static ref MESSAGE_CONTAINER: MessageContainer = MessageContainer::new();
+    pub static ref RETAIN_CONTAINER: RetainContainer = RetainContainer::new();
+    pub static ref SESSION_REGISTRY: SessionRegistry = SessionRegistry::new();
 }
 
@@ -1,4 +1,7 @@
-use crate::hex::PropertyItem;
+use std::str::Utf8Error;
+use std::sync::{Arc, OnceLock};
+use bytes::Bytes;
+use crate::hex::{find_property, Property, PropertyItem};
 use crate::hex::reason_code::{ReasonCodes, ReasonPhrases};
 use crate::message::{BaseMessage, ConnectMessagePayload, MqttMessageType, WillField};
 use crate::tools::config::Config;
@@ -44,9 +47,9 @@ impl ConnectMessage {
                 will_message: config.will().will_message(),
                 user_name: config.username(),
                 password: config.password(),
-                properties: None,
+                properties: Some(config.will().properties().to_vec()),
             },
-            properties: None,
+            properties: Some(config.properties().to_vec()),
             bytes: None,
         }
     }
@@ -57,7 +60,7 @@ impl WillField for ConnectMessage {
         self.payload.will_topic.as_ref()
     }
 
-    fn message_str(&self) -> Option<&String> {
+    fn message_bytes(&self) -> Option<&Bytes> {
         self.payload.will_message.as_ref()
     }
 
@@ -183,6 +186,19 @@ impl SubackMessage {
     }
 }
 
+impl SubackMessage {
+    pub fn with_codes(message_id: u16, codes: Vec<u8>) -> Self {
+        SubackMessage {
+            msg_type: TypeKind::SUBACK,
+            protocol_level: None,
+            message_id,
+            codes,
+            properties: None,
+            bytes: None,
+        }
+    }
+}
+
 impl From<&SubscribeMessage> for SubackMessage {
     fn from(smsg: &SubscribeMessage) -> Self {
         let codes = if (smsg.qos.unwrap() as u32) < 3 {
@@ -268,9 +284,10 @@ pub struct PublishMessage {
     pub dup: MqttDup,
     pub qos: MqttQos,
     pub retain: MqttRetain,
-    pub msg_body: String,
+    pub msg_body: Bytes,
     pub properties: Option<Vec<PropertyItem>>,
-    pub bytes: Option<Vec<u8>>,
+    pub bytes: Option<Bytes>,
+    pub cache: Option<Arc<PublishCache>>,
 }
 
 impl MqttMessageType for PublishMessage {
@@ -280,7 +297,7 @@ impl MqttMessageType for PublishMessage {
 }
 
 impl PublishMessage {
-    pub fn new(qos: MqttQos, dup: MqttDup, retain: MqttRetain, topic: String, message_id: u16, message_body: String, properties: Option<Vec<PropertyItem>>) -> PublishMessage {
+    pub fn new<B: Into<Bytes>>(qos: MqttQos, dup: MqttDup, retain: MqttRetain, topic: String, message_id: u16, message_body: B, properties: Option<Vec<PropertyItem>>) -> PublishMessage {
         PublishMessage {
             msg_type: TypeKind::PUBLISH,
             protocol_level: None,
@@ -289,11 +306,58 @@ impl PublishMessage {
             dup,
             qos,
             retain,
-            msg_body: message_body,
+            msg_body: message_body.into(),
             properties,
             bytes: None,
+            cache: None,
         }
     }
+
+    ///
+    /// 附带新的编码缓存, 转发给多个订阅者的副本共享同一份主题、属性与载荷的编码结果
+    ///
+    /// 缓存只覆盖与订阅者无关的部分, 修改主题、属性或载荷后需要重新调用或者清除 `cache`
+    ///
+    pub fn shared(&self) -> PublishMessage {
+        PublishMessage { bytes: None, cache: Some(Arc::new(PublishCache::default())), ..self.clone() }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.msg_body
+    }
+
+    ///
+    /// 以 UTF-8 字符串读取消息体
+    ///
+    pub fn payload_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.msg_body)
+    }
+
+    ///
+    /// v5 PayloadFormatIndicator 为 1, 消息体是 UTF-8 字符串
+    ///
+    pub fn is_utf8_payload(&self) -> bool {
+        find_property(self.properties.as_deref(), Property::PayloadFormatIndicator).and_then(PropertyItem::as_byte) == Some(1)
+    }
+}
+
+///
+/// PUBLISH 报文中不含报文标识符的可变报头与载荷, 按协议版本分别编码一次
+///
+#[derive(Debug, Default)]
+pub struct PublishCache {
+    v3: OnceLock<Bytes>,
+    v5: OnceLock<Bytes>,
+}
+
+impl PublishCache {
+    pub fn get_or_encode<F: FnOnce() -> Vec<u8>>(&self, level: MqttProtocolLevel, encode: F) -> Bytes {
+        let cell = match level {
+            MqttProtocolLevel::Level5 => &self.v5,
+            _ => &self.v3,
+        };
+        cell.get_or_init(|| Bytes::from(encode())).clone()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -457,6 +521,18 @@ impl Default for DisconnectMessage {
     }
 }
 
+impl DisconnectMessage {
+    pub fn new(code: ReasonPhrases) -> DisconnectMessage {
+        DisconnectMessage {
+            msg_type: TypeKind::DISCONNECT,
+            code: Some(code.as_byte()),
+            protocol_level: None,
+            properties: None,
+            bytes: None,
+        }
+    }
+}
+
 impl From<BaseMessage> for DisconnectMessage {
     fn from(base: BaseMessage) -> Self {
         DisconnectMessage { msg_type: base.msg_type, code: None, protocol_level: None, properties: None, bytes: Some(base.bytes) }
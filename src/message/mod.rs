@@ -1,10 +1,12 @@
+use std::convert::TryFrom;
+use bytes::Bytes;
 use crate::tools::types::TypeKind;
+use crate::tools::error::DecodeError;
 use crate::tools::un_pack_tool::get_type;
 use crate::tools::protocol::{MqttProtocolLevel, MqttDup, MqttQos, MqttRetain, MqttCleanSession, MqttWillFlag, MqttPasswordFlag, MqttUsernameFlag};
 use crate::hex::PropertyItem;
 use crate::message::entity::{DisconnectMessage, PingreqMessage, PingrespMessage};
 use crate::message::v3::MqttMessageV3;
-use crate::message::v3::MqttMessageV3::Pingreq;
 use crate::packet::{v3_unpacket, v5_unpacket};
 use crate::message::v5::MqttMessageV5;
 
@@ -93,46 +95,46 @@ impl MqttProtocolLevelInfo for MqttMessageKind {
 }
 
 impl MqttMessageKind {
-    pub fn to_v3_request(base_msg: BaseMessage) -> Option<MqttMessageKind> {
-        match base_msg.get_message_type() {
-            TypeKind::CONNECT => { Some(Self::RequestV3(v3_unpacket::connect(base_msg))) }
-            TypeKind::CONNACK => { Some(Self::RequestV3(v3_unpacket::connack(base_msg))) }
-            TypeKind::PUBLISH => { Some(Self::RequestV3(v3_unpacket::publish(base_msg))) }
-            TypeKind::PUBACK => { Some(Self::RequestV3(v3_unpacket::puback(base_msg))) }
-            TypeKind::PUBREC => { Some(Self::RequestV3(v3_unpacket::pubrec(base_msg))) }
-            TypeKind::PUBREL => { Some(Self::RequestV3(v3_unpacket::pubrel(base_msg))) }
-            TypeKind::PUBCOMP => { Some(Self::RequestV3(v3_unpacket::pubcomp(base_msg))) }
-            TypeKind::SUBSCRIBE => { Some(Self::RequestV3Vec(v3_unpacket::subscribe(base_msg))) }
-            TypeKind::SUBACK => { Some(Self::RequestV3(v3_unpacket::suback(base_msg))) }
-            TypeKind::UNSUBSCRIBE => { Some(Self::RequestV3Vec(v3_unpacket::unsubscribe(base_msg))) }
-            TypeKind::UNSUBACK => { Some(Self::RequestV3(v3_unpacket::unsuback(base_msg))) }
-            TypeKind::PINGREQ => { Some(Self::RequestV3(MqttMessageV3::Pingreq(PingreqMessage::from(base_msg)))) }
-            TypeKind::PINGRESP => { Some(Self::RequestV3(MqttMessageV3::Pingresp(PingrespMessage::from(base_msg)))) }
-            TypeKind::DISCONNECT => { Some(Self::RequestV3(MqttMessageV3::Disconnect(DisconnectMessage::default()))) }
-            _ => { None }
-        }
+    pub fn to_v3_request(base_msg: BaseMessage) -> Result<MqttMessageKind, DecodeError> {
+        Ok(match base_msg.get_message_type() {
+            TypeKind::CONNECT => { Self::RequestV3(v3_unpacket::connect(base_msg)?) }
+            TypeKind::CONNACK => { Self::RequestV3(v3_unpacket::connack(base_msg)?) }
+            TypeKind::PUBLISH => { Self::RequestV3(v3_unpacket::publish(base_msg)?) }
+            TypeKind::PUBACK => { Self::RequestV3(v3_unpacket::puback(base_msg)?) }
+            TypeKind::PUBREC => { Self::RequestV3(v3_unpacket::pubrec(base_msg)?) }
+            TypeKind::PUBREL => { Self::RequestV3(v3_unpacket::pubrel(base_msg)?) }
+            TypeKind::PUBCOMP => { Self::RequestV3(v3_unpacket::pubcomp(base_msg)?) }
+            TypeKind::SUBSCRIBE => { Self::RequestV3Vec(v3_unpacket::subscribe(base_msg)?) }
+            TypeKind::SUBACK => { Self::RequestV3(v3_unpacket::suback(base_msg)?) }
+            TypeKind::UNSUBSCRIBE => { Self::RequestV3Vec(v3_unpacket::unsubscribe(base_msg)?) }
+            TypeKind::UNSUBACK => { Self::RequestV3(v3_unpacket::unsuback(base_msg)?) }
+            TypeKind::PINGREQ => { Self::RequestV3(MqttMessageV3::Pingreq(PingreqMessage::from(base_msg))) }
+            TypeKind::PINGRESP => { Self::RequestV3(MqttMessageV3::Pingresp(PingrespMessage::from(base_msg))) }
+            TypeKind::DISCONNECT => { Self::RequestV3(MqttMessageV3::Disconnect(DisconnectMessage::default())) }
+            TypeKind::AUTH => { return Err(DecodeError::UnknownPacketType(TypeKind::AUTH as u8)); }
+        })
     }
 }
 
 impl MqttMessageKind {
-    pub fn to_v5_request(base_msg: BaseMessage) -> Option<MqttMessageKind> {
-        match base_msg.msg_type {
-            TypeKind::CONNECT => { Some(Self::RequestV5(v5_unpacket::connect(base_msg))) }
-            TypeKind::CONNACK => { Some(Self::RequestV5(v5_unpacket::connack(base_msg))) }
-            TypeKind::PUBLISH => { Some(Self::RequestV5(v5_unpacket::publish(base_msg))) }
-            TypeKind::PUBACK => { Some(Self::RequestV5(v5_unpacket::puback(base_msg))) }
-            TypeKind::PUBREC => { Some(Self::RequestV5(v5_unpacket::pubrec(base_msg))) }
-            TypeKind::PUBREL => { Some(Self::RequestV5(v5_unpacket::pubrel(base_msg))) }
-            TypeKind::PUBCOMP => { Some(Self::RequestV5(v5_unpacket::pubcomp(base_msg))) }
-            TypeKind::SUBSCRIBE => { Some(Self::RequestV5Vec(v5_unpacket::subscribe(base_msg))) }
-            TypeKind::SUBACK => { Some(Self::RequestV5(v5_unpacket::suback(base_msg))) }
-            TypeKind::UNSUBSCRIBE => { Some(Self::RequestV5Vec(v5_unpacket::unsubscribe(base_msg))) }
-            TypeKind::UNSUBACK => { Some(Self::RequestV5(v5_unpacket::unsuback(base_msg))) }
-            TypeKind::PINGREQ => { Some(Self::RequestV5(MqttMessageV5::Pingresp(PingrespMessage::from(base_msg)))) }
-            TypeKind::PINGRESP => { Some(Self::RequestV5(MqttMessageV5::Pingresp(PingrespMessage::from(base_msg)))) }
-            TypeKind::DISCONNECT => { Some(Self::RequestV5(v5_unpacket::disconnect(base_msg))) }
-            TypeKind::AUTH => { Some(Self::RequestV5(v5_unpacket::auth(base_msg))) }
-        }
+    pub fn to_v5_request(base_msg: BaseMessage) -> Result<MqttMessageKind, DecodeError> {
+        Ok(match base_msg.msg_type {
+            TypeKind::CONNECT => { Self::RequestV5(v5_unpacket::connect(base_msg)?) }
+            TypeKind::CONNACK => { Self::RequestV5(v5_unpacket::connack(base_msg)?) }
+            TypeKind::PUBLISH => { Self::RequestV5(v5_unpacket::publish(base_msg)?) }
+            TypeKind::PUBACK => { Self::RequestV5(v5_unpacket::puback(base_msg)?) }
+            TypeKind::PUBREC => { Self::RequestV5(v5_unpacket::pubrec(base_msg)?) }
+            TypeKind::PUBREL => { Self::RequestV5(v5_unpacket::pubrel(base_msg)?) }
+            TypeKind::PUBCOMP => { Self::RequestV5(v5_unpacket::pubcomp(base_msg)?) }
+            TypeKind::SUBSCRIBE => { Self::RequestV5Vec(v5_unpacket::subscribe(base_msg)?) }
+            TypeKind::SUBACK => { Self::RequestV5(v5_unpacket::suback(base_msg)?) }
+            TypeKind::UNSUBSCRIBE => { Self::RequestV5Vec(v5_unpacket::unsubscribe(base_msg)?) }
+            TypeKind::UNSUBACK => { Self::RequestV5(v5_unpacket::unsuback(base_msg)?) }
+            TypeKind::PINGREQ => { Self::RequestV5(MqttMessageV5::Pingreq(PingreqMessage::from(base_msg))) }
+            TypeKind::PINGRESP => { Self::RequestV5(MqttMessageV5::Pingresp(PingrespMessage::from(base_msg))) }
+            TypeKind::DISCONNECT => { Self::RequestV5(v5_unpacket::disconnect(base_msg)?) }
+            TypeKind::AUTH => { Self::RequestV5(v5_unpacket::auth(base_msg)?) }
+        })
     }
 }
 
@@ -164,17 +166,21 @@ impl MqttMessageType for BaseMessage {
     }
 }
 
-impl From<Vec<u8>> for BaseMessage {
-    fn from(data: Vec<u8>) -> Self {
-        let (r#type2, retain, qos, dup, _last_bytes) = get_type(data.as_slice());
-        BaseMessage { msg_type: r#type2.unwrap(), dup, qos, retain, bytes: data }
+impl TryFrom<Vec<u8>> for BaseMessage {
+    type Error = DecodeError;
+
+    fn try_from(data: Vec<u8>) -> Result<Self, Self::Error> {
+        let (msg_type, retain, qos, dup, _last_bytes) = get_type(data.as_slice())?;
+        Ok(BaseMessage { msg_type, dup, qos, retain, bytes: data })
     }
 }
 
-impl From<&[u8]> for BaseMessage {
-    fn from(data: &[u8]) -> Self {
-        let (r#type2, retain, qos, dup, _last_bytes) = get_type(data);
-        BaseMessage { msg_type: r#type2.unwrap(), dup, qos, retain, bytes: data.to_vec() }
+impl TryFrom<&[u8]> for BaseMessage {
+    type Error = DecodeError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let (msg_type, retain, qos, dup, _last_bytes) = get_type(data)?;
+        Ok(BaseMessage { msg_type, dup, qos, retain, bytes: data.to_vec() })
     }
 }
 
@@ -182,7 +188,7 @@ impl From<&[u8]> for BaseMessage {
 pub struct ConnectMessagePayload {
     pub client_id: String,
     pub will_topic: Option<String>,
-    pub will_message: Option<String>,
+    pub will_message: Option<Bytes>,
     pub user_name: Option<String>,
     pub password: Option<String>,
     pub properties: Option<Vec<PropertyItem>>,
@@ -203,7 +209,7 @@ pub struct VariableHeader {
 
 pub trait WillField {
     fn topic_str(&self) -> Option<&String>;
-    fn message_str(&self) -> Option<&String>;
+    fn message_bytes(&self) -> Option<&Bytes>;
     fn username_str(&self) -> Option<&String>;
     fn password_str(&self) -> Option<&String>;
 }
@@ -99,10 +99,10 @@ impl MqttMessageV5 {
             MqttMessageV5::Subscribe(msg) => { Some(v5_packet::subscribe(msg)) }
             MqttMessageV5::Suback(msg) => { Some(v5_packet::suback(msg)) }
             MqttMessageV5::Unsubscribe(msg) => { Some(v5_packet::unsubscribe(msg)) }
-            MqttMessageV5::Unsuback(msg) => { Some(v5_packet::common(msg.message_id, msg.code.unwrap(), msg.properties.as_ref(), msg.get_message_type())) }
+            MqttMessageV5::Unsuback(msg) => { Some(v5_packet::unsuback(msg)) }
             MqttMessageV5::Pingreq(msg) => { Some(pack_header(msg.get_message_type(), 0)) }
             MqttMessageV5::Pingresp(msg) => { Some(pack_header(msg.get_message_type(), 0)) }
-            MqttMessageV5::Disconnect(msg) => { Some(pack_header(msg.get_message_type(), 0)) }
+            MqttMessageV5::Disconnect(msg) => { Some(v5_packet::disconnect(msg)) }
             MqttMessageV5::Auth(msg) => { Some(v5_packet::auth(msg)) }
         }
     }
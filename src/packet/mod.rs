@@ -1,6 +1,42 @@
+use bytes::Bytes;
+use crate::hex::reason_code::ReasonPhrases;
+use crate::message::entity::{PubackMessage, PublishMessage, PubcompMessage, PubrecMessage, PubrelMessage};
+use crate::message::v3::MqttMessageV3;
+use crate::message::v5::MqttMessageV5;
+use crate::tools::protocol::MqttProtocolLevel;
+use crate::tools::types::TypeKind;
+
 pub mod v3_packet;
 pub mod v3_unpacket;
 pub mod v5_packet;
 pub mod v5_unpacket;
 
+///
+/// 编码 PUBACK / PUBREC / PUBREL / PUBCOMP, 原因码只在 v5 中发送
+///
+pub fn encode_ack(level: MqttProtocolLevel, kind: TypeKind, message_id: u16, code: ReasonPhrases) -> Vec<u8> {
+    let v5 = level == MqttProtocolLevel::Level5;
+    let data = match kind {
+        TypeKind::PUBACK if v5 => MqttMessageV5::Puback(PubackMessage { code: Some(code), ..PubackMessage::new(message_id) }).to_vec(),
+        TypeKind::PUBACK => MqttMessageV3::Puback(PubackMessage::new(message_id)).to_vec(),
+        TypeKind::PUBREC if v5 => MqttMessageV5::Pubrec(PubrecMessage { code: Some(code), ..PubrecMessage::new(message_id) }).to_vec(),
+        TypeKind::PUBREC => MqttMessageV3::Pubrec(PubrecMessage::new(message_id)).to_vec(),
+        TypeKind::PUBREL if v5 => MqttMessageV5::Pubrel(PubrelMessage { code: Some(code), ..PubrelMessage::new(message_id) }).to_vec(),
+        TypeKind::PUBREL => MqttMessageV3::Pubrel(PubrelMessage::new(message_id)).to_vec(),
+        TypeKind::PUBCOMP if v5 => MqttMessageV5::Pubcomp(PubcompMessage { code: Some(code), ..PubcompMessage::new(message_id) }).to_vec(),
+        TypeKind::PUBCOMP => MqttMessageV3::Pubcomp(PubcompMessage::new(message_id)).to_vec(),
+        _ => None
+    };
+    data.unwrap_or_default()
+}
 
+///
+/// 分段编码转发给订阅者的 PUBLISH, 第一段为固定报头、主题名与报文标识符, 第二段与其他订阅者共享
+///
+pub fn encode_publish(level: MqttProtocolLevel, msg: &PublishMessage) -> Option<(Vec<u8>, Bytes)> {
+    match level {
+        MqttProtocolLevel::Level3_1_1 => Some(v3_packet::publish_chunks(msg)),
+        MqttProtocolLevel::Level5 => Some(v5_packet::publish_chunks(msg)),
+        _ => None
+    }
+}
@@ -1,5 +1,6 @@
-use crate::tools::pack_tool::{pack_protocol_name, pack_connect_flags, pack_client_id, pack_will_topic, pack_will_message, pack_username, pack_password, pack_header, pack_message_short_id, pack_string, pack_publish_header, pack_short_int};
-use crate::tools::protocol::{MqttWillFlag, MqttSessionPresent, MqttQos};
+use bytes::Bytes;
+use crate::tools::pack_tool::{pack_protocol_name, pack_connect_flags, pack_client_id, pack_will_topic, pack_will_message, pack_username, pack_password, pack_header, pack_message_short_id, pack_string, pack_publish, pack_short_int};
+use crate::tools::protocol::{MqttWillFlag, MqttSessionPresent, MqttProtocolLevel};
 use crate::tools::types::TypeKind;
 use crate::message::entity::{ConnectMessage, PublishMessage, SubackMessage, SubscribeMessage, UnsubscribeMessage};
 
@@ -18,7 +19,7 @@ pub fn connect(msg: &ConnectMessage) -> Vec<u8> {
             msg.payload.password.as_ref(),
         ).unwrap());
 
-    body.extend(pack_short_int(msg.keep_alive as u16));
+    body.extend(pack_short_int(msg.keep_alive));
 
     body.extend(pack_client_id(&msg.payload.client_id));
 
@@ -60,19 +61,30 @@ pub fn connack(session_present: MqttSessionPresent, return_code: Option<u8>) ->
 }
 
 pub fn publish(msg: &PublishMessage) -> Vec<u8> {
-    let mut body = pack_string(&msg.topic);
+    let (mut package, rest) = publish_chunks(msg);
+    package.extend_from_slice(&rest);
+    package
+}
 
-    if msg.qos > MqttQos::Qos0 {
-        body.extend(pack_message_short_id(msg.message_id));
+///
+/// 分段编码 PUBLISH, 第二段为共享的主题名之后的部分, 见 `pack_publish`
+///
+pub fn publish_chunks(msg: &PublishMessage) -> (Vec<u8>, Bytes) {
+    match msg.cache.as_ref() {
+        Some(cache) => pack_publish(msg, cache.get_or_encode(MqttProtocolLevel::Level3_1_1, || publish_body(msg))),
+        None => pack_publish(msg, Bytes::from(publish_body(msg)))
     }
+}
 
-    body.extend(msg.msg_body.as_bytes().to_vec());
-
-    let mut package = pack_publish_header(msg.msg_type, body.len(), Option::from(msg.qos), Option::from(msg.dup), Option::from(msg.retain));
+///
+/// 主题名与载荷, 不含报文标识符
+///
+fn publish_body(msg: &PublishMessage) -> Vec<u8> {
+    let mut body = pack_string(&msg.topic);
 
-    package.extend(body);
+    body.extend_from_slice(&msg.msg_body);
 
-    package
+    body
 }
 
 pub fn subscribe(msg: &SubscribeMessage) -> Vec<u8> {
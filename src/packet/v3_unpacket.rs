@@ -1,20 +1,22 @@
 use crate::tools::protocol::{MqttSessionPresent, MqttQos, MqttDup, MqttRetain};
 use crate::message::BaseMessage;
+use crate::tools::error::DecodeError;
 use crate::tools::un_pack_tool::{get_connect_variable_header, get_connect_payload_data, parse_short_int, parse_string, parse_byte, get_remaining_data};
 use std::convert::TryFrom;
+use bytes::Bytes;
 use crate::message::entity::{ConnackMessage, ConnectMessage, PubackMessage, PubcompMessage, PublishMessage, PubrecMessage, PubrelMessage, SubackMessage, SubscribeMessage, UnsubackMessage, UnsubscribeMessage};
 use crate::message::v3::MqttMessageV3;
 
-pub fn connect(base: BaseMessage) -> MqttMessageV3 {
-    let (variable_header, last_data) = get_connect_variable_header(base.bytes.as_slice());
+pub fn connect(base: BaseMessage) -> Result<MqttMessageV3, DecodeError> {
+    let (variable_header, last_data) = get_connect_variable_header(base.bytes.as_slice())?;
     let payload = get_connect_payload_data(
         variable_header.protocol_level.unwrap(),
         last_data,
         variable_header.will_flag.unwrap(),
         variable_header.username_flag.unwrap(),
         variable_header.password_flag.unwrap(),
-    );
-    MqttMessageV3::Connect(
+    )?;
+    Ok(MqttMessageV3::Connect(
         ConnectMessage {
             msg_type: base.msg_type,
             protocol_name: variable_header.protocol_name.unwrap(),
@@ -28,14 +30,15 @@ pub fn connect(base: BaseMessage) -> MqttMessageV3 {
             properties: None,
             bytes: Some(base.bytes),
         }
-    )
+    ))
 }
 
-pub fn connack(base: BaseMessage) -> MqttMessageV3 {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let session_present = MqttSessionPresent::try_from((message_bytes.get(0).unwrap() & 1)).unwrap();
-    let return_code = *message_bytes.get(1).unwrap();
-    MqttMessageV3::Connack(
+pub fn connack(base: BaseMessage) -> Result<MqttMessageV3, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (flags, last_data) = parse_byte(message_bytes)?;
+    let session_present = MqttSessionPresent::try_from(flags & 1).unwrap();
+    let (return_code, _) = parse_byte(last_data)?;
+    Ok(MqttMessageV3::Connack(
         ConnackMessage {
             msg_type: base.msg_type,
             protocol_level: None,
@@ -44,50 +47,45 @@ pub fn connack(base: BaseMessage) -> MqttMessageV3 {
             properties: None,
             bytes: Some(base.bytes),
         }
-    )
+    ))
 }
 
-pub fn publish(base: BaseMessage) -> MqttMessageV3 {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (topic, last_data) = parse_string(message_bytes).unwrap();
-    let (message_id, msg_body) = if base.qos.is_some() {
-        let qos = base.qos.unwrap();
-        if qos > MqttQos::Qos0 {
-            let (message_id, last_data) = parse_short_int(last_data.unwrap());
-            let msg_body = String::from_utf8_lossy(last_data);
-            (message_id, msg_body)
-        } else {
-            let msg_body = String::from_utf8_lossy(last_data.unwrap());
-            (0, msg_body)
-        }
+pub fn publish(base: BaseMessage) -> Result<MqttMessageV3, DecodeError> {
+    let bytes = Bytes::from(base.bytes);
+    let message_bytes = get_remaining_data(&bytes)?;
+    let (topic, last_data) = parse_string(message_bytes)?;
+    let qos = base.qos.unwrap_or(MqttQos::Qos0);
+    let (message_id, msg_body) = if qos > MqttQos::Qos0 {
+        parse_short_int(last_data)?
     } else {
-        let msg_body = String::from_utf8_lossy(last_data.unwrap());
-        (0, msg_body)
+        (0, last_data)
     };
-    MqttMessageV3::Publish(
+    Ok(MqttMessageV3::Publish(
         PublishMessage {
             msg_type: base.msg_type,
             protocol_level: None,
             message_id,
             topic,
             dup: base.dup.unwrap_or(MqttDup::Disable),
-            qos: base.qos.unwrap_or(MqttQos::Qos0),
+            qos,
             retain: base.retain.unwrap_or(MqttRetain::Disable),
-            msg_body: msg_body.into_owned(),
+            msg_body: bytes.slice_ref(msg_body),
             properties: None,
-            bytes: Some(base.bytes),
+            bytes: Some(bytes),
+            cache: None,
         }
-    )
+    ))
 }
 
-pub fn subscribe(base: BaseMessage) -> Vec<MqttMessageV3> {
+pub fn subscribe(base: BaseMessage) -> Result<Vec<MqttMessageV3>, DecodeError> {
     let mut subs = vec![];
-    let mut data_bytes = base.bytes.as_slice();
-    loop {
-        let remain_data = get_remaining_data(data_bytes);
-        let (message_id, last_data) = parse_short_int(remain_data);
-        let (topic, last_data) = parse_string(last_data).unwrap();
-        let (qos, _) = parse_byte(last_data.unwrap());
+    let (message_id, mut last_data) = parse_short_int(get_remaining_data(base.bytes.as_slice())?)?;
+    while !last_data.is_empty() {
+        let (topic, data) = parse_string(last_data)?;
+        let (qos, data) = parse_byte(data)?;
+        if qos & !3 != 0 {
+            return Err(DecodeError::InvalidFlags(qos));
+        }
         subs.push(
             MqttMessageV3::Subscribe(
                 SubscribeMessage {
@@ -95,32 +93,28 @@ pub fn subscribe(base: BaseMessage) -> Vec<MqttMessageV3> {
                     protocol_level: None,
                     message_id,
                     topic,
-                    qos: MqttQos::try_from(qos).ok(),
+                    qos: Some(MqttQos::try_from(qos).map_err(|_| DecodeError::InvalidQos(qos))?),
                     no_local: None,
                     retain_as_published: None,
                     retain_handling: None,
                     properties: None,
-                    bytes: Some(data_bytes.get(..remain_data.len() + 2).unwrap().to_vec()),
+                    bytes: Some(base.bytes.clone()),
                 }
             )
         );
-        if let Some(last_data) = data_bytes.get(remain_data.len() + 2..) {
-            if last_data.len() > 0 { data_bytes = last_data; } else { break; }
-        } else {
-            break;
-        }
+        last_data = data;
     }
-    subs
+    if subs.is_empty() {
+        return Err(DecodeError::Truncated);
+    }
+    Ok(subs)
 }
 
-pub fn unsubscribe(base: BaseMessage) -> Vec<MqttMessageV3> {
+pub fn unsubscribe(base: BaseMessage) -> Result<Vec<MqttMessageV3>, DecodeError> {
     let mut subs = vec![];
-    let mut data_bytes = base.bytes.as_slice();
-
-    loop {
-        let remain_data = get_remaining_data(data_bytes);
-        let (message_id, last_data) = parse_short_int(remain_data);
-        let (topic, last_data) = parse_string(last_data).unwrap();
+    let (message_id, mut last_data) = parse_short_int(get_remaining_data(base.bytes.as_slice())?)?;
+    while !last_data.is_empty() {
+        let (topic, data) = parse_string(last_data)?;
         subs.push(
             MqttMessageV3::Unsubscribe(
                 UnsubscribeMessage {
@@ -129,23 +123,22 @@ pub fn unsubscribe(base: BaseMessage) -> Vec<MqttMessageV3> {
                     message_id,
                     topic,
                     properties: None,
-                    bytes: Some(data_bytes.get(..remain_data.len() + 2).unwrap().to_vec()),
+                    bytes: Some(base.bytes.clone()),
                 }
             )
         );
-        if let Some(last_data) = data_bytes.get(remain_data.len() + 2..) {
-            if last_data.len() > 0 { data_bytes = last_data; } else { break; }
-        } else {
-            break;
-        }
+        last_data = data;
+    }
+    if subs.is_empty() {
+        return Err(DecodeError::Truncated);
     }
-    subs
+    Ok(subs)
 }
 
-pub fn unsuback(base: BaseMessage) -> MqttMessageV3 {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    MqttMessageV3::Unsuback(
+pub fn unsuback(base: BaseMessage) -> Result<MqttMessageV3, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(MqttMessageV3::Unsuback(
         UnsubackMessage {
             msg_type: base.msg_type,
             protocol_level: None,
@@ -154,14 +147,14 @@ pub fn unsuback(base: BaseMessage) -> MqttMessageV3 {
             properties: None,
             bytes: Some(base.bytes)
         }
-    )
+    ))
 }
 
-pub fn suback(base: BaseMessage) -> MqttMessageV3 {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, last_data) = parse_short_int(message_bytes);
+pub fn suback(base: BaseMessage) -> Result<MqttMessageV3, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
     let codes = last_data.to_vec();
-    MqttMessageV3::Suback(
+    Ok(MqttMessageV3::Suback(
         SubackMessage {
             msg_type: base.msg_type,
             protocol_level: None,
@@ -170,37 +163,37 @@ pub fn suback(base: BaseMessage) -> MqttMessageV3 {
             properties: None,
             bytes: Some(base.bytes),
         }
-    )
+    ))
 }
 
-pub fn puback(base: BaseMessage) -> MqttMessageV3 {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    MqttMessageV3::Puback(
+pub fn puback(base: BaseMessage) -> Result<MqttMessageV3, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(MqttMessageV3::Puback(
         PubackMessage { msg_type: base.msg_type, protocol_level: None, message_id, code: None, properties: None, bytes: Some(base.bytes) }
-    )
+    ))
 }
 
-pub fn pubrec(base: BaseMessage) -> MqttMessageV3 {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    MqttMessageV3::Pubrec(
+pub fn pubrec(base: BaseMessage) -> Result<MqttMessageV3, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(MqttMessageV3::Pubrec(
         PubrecMessage { msg_type: base.msg_type, protocol_level: None, message_id, code: None, properties: None, bytes: Some(base.bytes) }
-    )
+    ))
 }
 
-pub fn pubrel(base: BaseMessage) -> MqttMessageV3 {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    MqttMessageV3::Pubrel(
+pub fn pubrel(base: BaseMessage) -> Result<MqttMessageV3, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(MqttMessageV3::Pubrel(
         PubrelMessage { msg_type: base.msg_type, code: None, properties: None, protocol_level: None, message_id, bytes: Some(base.bytes) }
-    )
+    ))
 }
 
-pub fn pubcomp(base: BaseMessage) -> MqttMessageV3 {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    MqttMessageV3::Pubcomp(
+pub fn pubcomp(base: BaseMessage) -> Result<MqttMessageV3, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(MqttMessageV3::Pubcomp(
         PubcompMessage { msg_type: base.msg_type, protocol_level: None, message_id, code: None, properties: None, bytes: Some(base.bytes) }
-    )
+    ))
 }
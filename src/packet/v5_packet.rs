@@ -1,5 +1,6 @@
-use crate::tools::pack_tool::{pack_connect_flags, pack_string, pack_short_int, pack_client_id, pack_header, pack_message_short_id, pack_publish_header};
-use crate::tools::protocol::{MqttWillFlag, MqttSessionPresent, MqttQos, MqttDup};
+use bytes::Bytes;
+use crate::tools::pack_tool::{pack_binary, pack_connect_flags, pack_string, pack_short_int, pack_client_id, pack_header, pack_message_short_id, pack_publish_header, pack_publish};
+use crate::tools::protocol::{MqttWillFlag, MqttSessionPresent, MqttQos, MqttDup, MqttProtocolLevel};
 use crate::hex::{pack_property, PropertyItem};
 use crate::tools::types::TypeKind;
 use crate::message::entity::{AuthMessage, ConnectMessage, DisconnectMessage, PublishMessage, SubackMessage, SubscribeMessage, UnsubackMessage, UnsubscribeMessage};
@@ -19,37 +20,29 @@ pub fn connect(msg: &ConnectMessage) -> Vec<u8> {
             msg.payload.password.as_ref(),
         ).unwrap());
 
-    body.extend(pack_short_int(msg.keep_alive as u16));
+    body.extend(pack_short_int(msg.keep_alive));
 
-    if msg.properties.is_some() {
-        body.extend(pack_property::connect(msg.properties.as_ref().unwrap()));
-    }
+    body.extend(pack_property::connect(msg.properties.as_deref().unwrap_or_default()));
 
     body.extend(pack_client_id(&msg.payload.client_id));
 
     if msg.will_flag == MqttWillFlag::Enable {
-        if msg.payload.properties.is_some() {
-            body.extend(pack_property::will_properties(msg.payload.properties.as_ref().unwrap()));
-        }
+        body.extend(pack_property::will_properties(msg.payload.properties.as_deref().unwrap_or_default()));
 
-        if msg.payload.will_topic.is_some() {
-            let will_topic = pack_string(msg.payload.will_topic.as_ref().unwrap());
-            body.extend(will_topic);
+        if let Some(will_topic) = msg.payload.will_topic.as_ref() {
+            body.extend(pack_string(will_topic));
         }
-        if msg.payload.will_message.is_some() {
-            let will_message = pack_string(msg.payload.will_message.as_ref().unwrap());
-            body.extend(will_message);
+        if let Some(will_message) = msg.payload.will_message.as_ref() {
+            body.extend(pack_binary(will_message));
         }
     }
 
-    if msg.payload.user_name.is_some() {
-        let username = pack_string(msg.payload.user_name.as_ref().unwrap());
-        body.extend(username);
+    if let Some(username) = msg.payload.user_name.as_ref() {
+        body.extend(pack_string(username));
     }
 
-    if msg.payload.password.is_some() {
-        let password = pack_string(msg.payload.password.as_ref().unwrap());
-        body.extend(password);
+    if let Some(password) = msg.payload.password.as_ref() {
+        body.extend(pack_string(password));
     }
 
     let mut package = pack_header(msg.msg_type, body.len());
@@ -62,9 +55,7 @@ pub fn connect(msg: &ConnectMessage) -> Vec<u8> {
 pub fn connack(session_present: MqttSessionPresent, return_code: u8, properties: Option<&Vec<PropertyItem>>) -> Vec<u8> {
     let mut body = vec![session_present as u8, return_code];
 
-    if properties.is_some() {
-        body.extend(pack_property::connack(properties.unwrap()));
-    }
+    body.extend(pack_property::connack(properties.map(|p| p.as_slice()).unwrap_or_default()));
 
     let mut package = pack_header(TypeKind::CONNACK, body.len());
 
@@ -74,31 +65,38 @@ pub fn connack(session_present: MqttSessionPresent, return_code: u8, properties:
 }
 
 pub fn publish(msg: &PublishMessage) -> Vec<u8> {
-    let mut body = pack_string(&msg.topic);
-
-    if msg.qos as i32 > 0 {
-        body.extend(pack_message_short_id(msg.message_id));
-    }
+    let (mut package, rest) = publish_chunks(msg);
+    package.extend_from_slice(&rest);
+    package
+}
 
-    if msg.properties.is_some() {
-        body.extend(pack_property::publish(msg.properties.as_ref().unwrap()));
+///
+/// 分段编码 PUBLISH, 第二段为共享的主题名之后的部分, 见 `pack_publish`
+///
+pub fn publish_chunks(msg: &PublishMessage) -> (Vec<u8>, Bytes) {
+    match msg.cache.as_ref() {
+        Some(cache) => pack_publish(msg, cache.get_or_encode(MqttProtocolLevel::Level5, || publish_body(msg))),
+        None => pack_publish(msg, Bytes::from(publish_body(msg)))
     }
+}
 
-    body.extend(msg.msg_body.as_bytes().to_vec());
+///
+/// 主题名、属性与载荷, 不含报文标识符
+///
+fn publish_body(msg: &PublishMessage) -> Vec<u8> {
+    let mut body = pack_string(&msg.topic);
 
-    let mut package = pack_publish_header(TypeKind::PUBLISH, body.len(), Option::from(msg.qos), Option::from(msg.dup), Option::from(msg.retain));
+    body.extend(pack_property::publish(msg.properties.as_deref().unwrap_or_default()));
 
-    package.extend(body);
+    body.extend_from_slice(&msg.msg_body);
 
-    package
+    body
 }
 
 pub fn subscribe(msg: &SubscribeMessage) -> Vec<u8> {
     let mut body = pack_message_short_id(msg.message_id);
 
-    if msg.properties.is_some() {
-        body.extend(pack_property::subscribe(msg.properties.as_ref().unwrap()));
-    }
+    body.extend(pack_property::subscribe(msg.properties.as_deref().unwrap_or_default()));
 
     let topic = pack_string(&msg.topic);
 
@@ -134,15 +132,13 @@ pub fn subscribe(msg: &SubscribeMessage) -> Vec<u8> {
 pub fn unsubscribe(msg: &UnsubscribeMessage) -> Vec<u8> {
     let mut body = pack_message_short_id(msg.message_id);
 
-    if msg.properties.is_some() {
-        body.extend(pack_property::unsubscribe(msg.properties.as_ref().unwrap()));
-    }
+    body.extend(pack_property::unsubscribe(msg.properties.as_deref().unwrap_or_default()));
 
     let topic = pack_string(&msg.topic);
 
     body.extend(topic);
 
-    let mut package = pack_header(TypeKind::SUBSCRIBE, body.len());
+    let mut package = pack_header(TypeKind::UNSUBSCRIBE, body.len());
 
     package.extend(body);
 
@@ -152,9 +148,7 @@ pub fn unsubscribe(msg: &UnsubscribeMessage) -> Vec<u8> {
 pub fn suback(msg: &SubackMessage) -> Vec<u8> {
     let mut body = pack_message_short_id(msg.message_id);
 
-    if msg.properties.is_some() {
-        body.extend(pack_property::suback(msg.properties.as_ref().unwrap()));
-    }
+    body.extend(pack_property::suback(msg.properties.as_deref().unwrap_or_default()));
 
     body.extend(msg.codes.clone());
 
@@ -168,11 +162,9 @@ pub fn suback(msg: &SubackMessage) -> Vec<u8> {
 pub fn unsuback(msg: &UnsubackMessage) -> Vec<u8> {
     let mut body = pack_message_short_id(msg.message_id);
 
-    if msg.properties.is_some() {
-        body.extend(pack_property::suback(msg.properties.as_ref().unwrap()));
-    }
+    body.extend(pack_property::suback(msg.properties.as_deref().unwrap_or_default()));
 
-    body.push(msg.code.unwrap());
+    body.push(msg.code.unwrap_or_default());
 
     let mut package = pack_header(TypeKind::UNSUBACK, body.len());
 
@@ -182,10 +174,13 @@ pub fn unsuback(msg: &UnsubackMessage) -> Vec<u8> {
 }
 
 pub fn disconnect(msg: &DisconnectMessage) -> Vec<u8> {
-    let mut body = msg.code.as_ref().unwrap().to_ne_bytes().to_vec();
+    let mut body = vec![];
 
-    if msg.properties.is_some() {
-        body.extend(pack_property::disconnect(msg.properties.as_ref().unwrap()));
+    if let Some(code) = msg.code {
+        body.push(code);
+        if let Some(properties) = msg.properties.as_ref() {
+            body.extend(pack_property::disconnect(properties));
+        }
     }
 
     let mut package = pack_header(TypeKind::DISCONNECT, body.len());
@@ -196,10 +191,10 @@ pub fn disconnect(msg: &DisconnectMessage) -> Vec<u8> {
 }
 
 pub fn auth(msg: &AuthMessage) -> Vec<u8> {
-    let mut body = msg.code.to_ne_bytes().to_vec();
+    let mut body = vec![msg.code];
 
-    if msg.properties.is_some() {
-        body.extend(pack_property::auth(msg.properties.as_ref().unwrap()));
+    if let Some(properties) = msg.properties.as_ref() {
+        body.extend(pack_property::auth(properties));
     }
 
     let mut package = pack_header(TypeKind::AUTH, body.len());
@@ -212,12 +207,12 @@ pub fn auth(msg: &AuthMessage) -> Vec<u8> {
 pub fn common(message_id: u16, code: u8, properties: Option<&Vec<PropertyItem>>, kind: TypeKind) -> Vec<u8> {
     let mut body = pack_message_short_id(message_id);
 
-    if properties.is_some() {
-        body.extend(pack_property::suback(properties.unwrap()));
-    }
-
     body.push(code);
 
+    if let Some(properties) = properties {
+        body.extend(pack_property::suback(properties));
+    }
+
     let mut package = if kind.is_pubrel() {
         pack_publish_header(kind, body.len(), Option::from(MqttQos::Qos1), Option::from(MqttDup::Disable), None)
     } else {
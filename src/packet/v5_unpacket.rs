@@ -1,26 +1,21 @@
 use crate::message::BaseMessage;
-use crate::tools::un_pack_tool::{parse_short_int, parse_byte, parse_string, get_connect_variable_header, get_connect_payload_data, get_remaining_data};
+use crate::tools::error::DecodeError;
+use crate::tools::un_pack_tool::{parse_short_int, parse_byte, parse_string, get_connect_variable_header, get_connect_payload_data, get_remaining_data, parse_properties_data, check_payload_format};
 use crate::hex::un_pack_property;
 use crate::tools::protocol::{MqttQos, MqttNoLocal, MqttRetainAsPublished, MqttSessionPresent, MqttDup, MqttRetain, MqttProtocolLevel};
 use std::convert::TryFrom;
+use bytes::Bytes;
 use crate::hex::reason_code::ReasonPhrases;
 use crate::message::entity::{AuthMessage, CommonPayloadMessage, ConnackMessage, ConnectMessage, DisconnectMessage, PublishMessage, SubackMessage, SubscribeMessage, UnsubackMessage, UnsubscribeMessage};
 use crate::message::v5::MqttMessageV5;
 
-pub fn connect(base: BaseMessage) -> MqttMessageV5 {
+pub fn connect(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
 
-    let (variable_header, last_data) = get_connect_variable_header(base.bytes.as_slice());
+    let (variable_header, last_data) = get_connect_variable_header(base.bytes.as_slice())?;
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
+    let (properties_data, last_data) = parse_properties_data(last_data)?;
 
-    let (properties, last_data) = if properties_total_length > 0 {
-        (
-            Some(un_pack_property::connect(properties_total_length as u32, last_data)),
-            last_data.get(properties_total_length as usize..).unwrap()
-        )
-    } else {
-        (Some(Vec::default()), last_data)
-    };
+    let properties = Some(un_pack_property::connect(properties_data)?);
 
     let payload = get_connect_payload_data(
         variable_header.protocol_level.unwrap(),
@@ -28,8 +23,8 @@ pub fn connect(base: BaseMessage) -> MqttMessageV5 {
         variable_header.will_flag.unwrap(),
         variable_header.username_flag.unwrap(),
         variable_header.password_flag.unwrap(),
-    );
-    MqttMessageV5::Connect(
+    )?;
+    Ok(MqttMessageV5::Connect(
         ConnectMessage {
             msg_type: base.msg_type,
             protocol_name: variable_header.protocol_name.unwrap(),
@@ -43,24 +38,25 @@ pub fn connect(base: BaseMessage) -> MqttMessageV5 {
             payload,
             bytes: Some(base.bytes),
         }
-    )
+    ))
 }
 
-pub fn connack(base: BaseMessage) -> MqttMessageV5 {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn connack(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let session_present = MqttSessionPresent::try_from((message_bytes.get(0).unwrap() & 1)).unwrap();
+    let (flags, last_data) = parse_byte(message_bytes)?;
 
-    let (return_code, last_data) = parse_byte(message_bytes);
+    let session_present = MqttSessionPresent::try_from(flags & 1).unwrap();
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
+    let (return_code, last_data) = parse_byte(last_data)?;
 
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::connack(properties_total_length as u32, last_data))
-    } else {
+    let properties = if last_data.is_empty() {
         Some(Vec::default())
+    } else {
+        let (properties_data, _) = parse_properties_data(last_data)?;
+        Some(un_pack_property::connack(properties_data)?)
     };
-    MqttMessageV5::Connack(
+    Ok(MqttMessageV5::Connack(
         ConnackMessage {
             msg_type: base.msg_type,
             protocol_level: None,
@@ -69,84 +65,68 @@ pub fn connack(base: BaseMessage) -> MqttMessageV5 {
             properties,
             bytes: Some(base.bytes),
         }
-    )
+    ))
 }
 
-pub fn publish(base: BaseMessage) -> MqttMessageV5 {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn publish(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    let bytes = Bytes::from(base.bytes);
+    let message_bytes = get_remaining_data(&bytes)?;
 
-    let (topic, last_data) = parse_string(message_bytes).unwrap();
+    let (topic, last_data) = parse_string(message_bytes)?;
 
-    let (message_id, last_data) = if base.qos.is_some() {
-        let qos = base.qos.unwrap();
-        if qos > MqttQos::Qos0 {
-            let (message_id, last_data) = parse_short_int(last_data.unwrap());
-            (message_id, last_data)
-        } else {
-            (0, last_data.unwrap())
-        }
+    let qos = base.qos.unwrap_or(MqttQos::Qos0);
+
+    let (message_id, last_data) = if qos > MqttQos::Qos0 {
+        parse_short_int(last_data)?
     } else {
-        (0, last_data.unwrap())
+        (0, last_data)
     };
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
+    let (properties_data, last_data) = parse_properties_data(last_data)?;
 
-    let (properties, msg_body) = if properties_total_length > 0 {
-        let msg_body = String::from_utf8_lossy(last_data.get(properties_total_length as usize..).unwrap());
-        (
-            Some(un_pack_property::publish(properties_total_length as u32, last_data)),
-            msg_body
-        )
-    } else {
-        let msg_body = String::from_utf8_lossy(last_data);
-        (
-            Some(Vec::default()),
-            msg_body
-        )
-    };
-    MqttMessageV5::Publish(
+    let properties = Some(un_pack_property::publish(properties_data)?);
+
+    let msg_body = check_payload_format(properties.as_deref(), bytes.slice_ref(last_data))?;
+
+    Ok(MqttMessageV5::Publish(
         PublishMessage {
             msg_type: base.msg_type,
             protocol_level: None,
             message_id,
             topic,
             dup: base.dup.unwrap_or(MqttDup::Disable),
-            qos: base.qos.unwrap_or(MqttQos::Qos0),
+            qos,
             retain: base.retain.unwrap_or(MqttRetain::Disable),
-            msg_body: msg_body.into_owned(),
+            msg_body,
             properties,
-            bytes: Some(base.bytes),
+            bytes: Some(bytes),
+            cache: None,
         }
-    )
+    ))
 }
 
-pub fn subscribe(base: BaseMessage) -> Vec<MqttMessageV5> {
-    println!("{:?}", base.bytes);
+pub fn subscribe(base: BaseMessage) -> Result<Vec<MqttMessageV5>, DecodeError> {
     let mut subs = vec![];
-    let mut data_bytes = base.bytes.as_slice();
-
-    loop {
-        let remain_data = get_remaining_data(data_bytes);
-        let (message_id, last_data) = parse_short_int(remain_data);
-        let (properties_total_length, last_data) = parse_byte(last_data);
-        let (properties, last_data) = if properties_total_length > 0 {
-            (
-                Some(un_pack_property::subscribe(properties_total_length as u32, last_data)),
-                last_data.get(properties_total_length as usize..).unwrap()
-            )
-        } else {
-            (
-                Some(Vec::default()),
-                last_data
-            )
-        };
 
-        let (topic, last_data) = parse_string(last_data).unwrap();
-        let (byte_data, _) = parse_byte(last_data.unwrap());
+    let (message_id, last_data) = parse_short_int(get_remaining_data(base.bytes.as_slice())?)?;
+
+    let (properties_data, mut last_data) = parse_properties_data(last_data)?;
+
+    let properties = un_pack_property::subscribe(properties_data)?;
+
+    while !last_data.is_empty() {
+        let (topic, data) = parse_string(last_data)?;
+        let (byte_data, data) = parse_byte(data)?;
+        if byte_data & 0b1100_0000 != 0 {
+            return Err(DecodeError::InvalidFlags(byte_data));
+        }
         let qos = byte_data & 3;
         let no_local = byte_data >> 2 & 1;
         let retain_as_published = byte_data >> 3 & 1;
         let retain_handling = byte_data >> 4;
+        if retain_handling > 2 {
+            return Err(DecodeError::InvalidFlags(byte_data));
+        }
         subs.push(
             MqttMessageV5::Subscribe(
                 SubscribeMessage {
@@ -154,50 +134,35 @@ pub fn subscribe(base: BaseMessage) -> Vec<MqttMessageV5> {
                     protocol_level: None,
                     message_id,
                     topic,
-                    qos: MqttQos::try_from(qos).ok(),
+                    qos: Some(MqttQos::try_from(qos).map_err(|_| DecodeError::InvalidQos(qos))?),
                     no_local: MqttNoLocal::try_from(no_local).ok(),
                     retain_as_published: MqttRetainAsPublished::try_from(retain_as_published).ok(),
                     retain_handling: Option::from(retain_handling),
-                    properties,
-                    bytes: Some(data_bytes.get(..remain_data.len() + 2).unwrap().to_vec()),
+                    properties: Some(properties.clone()),
+                    bytes: Some(base.bytes.clone()),
                 }
             )
         );
-
-        if let Some(last_data) = data_bytes.get(remain_data.len() + 2..) {
-            if last_data.len() > 0 { data_bytes = last_data; } else { break; }
-        } else {
-            break;
-        }
+        last_data = data;
     }
 
-    println!("{:?}", subs);
-    subs
+    if subs.is_empty() {
+        return Err(DecodeError::Truncated);
+    }
+    Ok(subs)
 }
 
-pub fn unsubscribe(base: BaseMessage) -> Vec<MqttMessageV5> {
+pub fn unsubscribe(base: BaseMessage) -> Result<Vec<MqttMessageV5>, DecodeError> {
     let mut subs = vec![];
-    let mut data_bytes = base.bytes.as_slice();
 
-    loop {
-        let remain_data = get_remaining_data(data_bytes);
-        let (message_id, last_data) = parse_short_int(remain_data);
+    let (message_id, last_data) = parse_short_int(get_remaining_data(base.bytes.as_slice())?)?;
 
-        let (properties_total_length, last_data) = parse_byte(last_data);
-        let (properties, last_data) = if properties_total_length > 0 {
-            (
-                Some(un_pack_property::unsubscribe(properties_total_length as u32, last_data)),
-                last_data.get(properties_total_length as usize..).unwrap()
-            )
-        } else {
-            (
-                Some(Vec::default()),
-                last_data
-            )
-        };
+    let (properties_data, mut last_data) = parse_properties_data(last_data)?;
 
-        let (topic, _) = parse_string(last_data).unwrap();
+    let properties = un_pack_property::unsubscribe(properties_data)?;
 
+    while !last_data.is_empty() {
+        let (topic, data) = parse_string(last_data)?;
         subs.push(
             MqttMessageV5::Unsubscribe(
                 UnsubscribeMessage {
@@ -205,39 +170,32 @@ pub fn unsubscribe(base: BaseMessage) -> Vec<MqttMessageV5> {
                     protocol_level: None,
                     message_id,
                     topic,
-                    properties,
-                    bytes: Some(data_bytes.get(..remain_data.len() + 2).unwrap().to_vec()),
+                    properties: Some(properties.clone()),
+                    bytes: Some(base.bytes.clone()),
                 }
             )
         );
-
-        if let Some(last_data) = data_bytes.get(remain_data.len() + 2..) {
-            if last_data.len() > 0 { data_bytes = last_data; } else { break; }
-        } else {
-            break;
-        }
+        last_data = data;
     }
 
-    println!("{:?}", subs);
-    subs
+    if subs.is_empty() {
+        return Err(DecodeError::Truncated);
+    }
+    Ok(subs)
 }
 
-pub fn suback(base: BaseMessage) -> MqttMessageV5 {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn suback(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (message_id, last_data) = parse_short_int(message_bytes);
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
+    let (properties_data, last_data) = parse_properties_data(last_data)?;
 
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::suback(properties_total_length as u32, last_data))
-    } else {
-        Some(Vec::default())
-    };
+    let properties = Some(un_pack_property::suback(properties_data)?);
 
     let codes = last_data.to_vec();
 
-    MqttMessageV5::Suback(
+    Ok(MqttMessageV5::Suback(
         SubackMessage {
             msg_type: base.msg_type,
             protocol_level: None,
@@ -246,24 +204,20 @@ pub fn suback(base: BaseMessage) -> MqttMessageV5 {
             properties,
             bytes: Some(base.bytes),
         }
-    )
+    ))
 }
 
-pub fn unsuback(base: BaseMessage) -> MqttMessageV5 {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn unsuback(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (message_id, last_data) = parse_short_int(message_bytes);
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
+    let (properties_data, last_data) = parse_properties_data(last_data)?;
 
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::suback(properties_total_length as u32, last_data))
-    } else {
-        Some(Vec::default())
-    };
+    let properties = Some(un_pack_property::unsuback(properties_data)?);
 
-    let code = *last_data.get(base.bytes.len() - 1).unwrap();
-    MqttMessageV5::Unsuback(
+    let (code, _) = parse_byte(last_data)?;
+    Ok(MqttMessageV5::Unsuback(
         UnsubackMessage {
             msg_type: base.msg_type,
             protocol_level: None,
@@ -272,32 +226,25 @@ pub fn unsuback(base: BaseMessage) -> MqttMessageV5 {
             properties,
             bytes: Some(base.bytes),
         }
-    )
+    ))
 }
 
-pub fn disconnect(base: BaseMessage) -> MqttMessageV5 {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn disconnect(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (code, mut last_data) = if message_bytes.len() > 0 {
-        parse_byte(message_bytes)
-    } else {
+    let (code, last_data) = if message_bytes.is_empty() {
         (ReasonPhrases::Success as u8, message_bytes)
+    } else {
+        parse_byte(message_bytes)?
     };
 
-    let mut properties_total_length = 0;
-
-    if last_data.len() > 0 {
-        let (length, last) = parse_byte(last_data);
-        properties_total_length = length;
-        last_data = last
-    }
-
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::suback(properties_total_length as u32, last_data))
-    } else {
+    let properties = if last_data.is_empty() {
         Some(Vec::default())
+    } else {
+        let (properties_data, _) = parse_properties_data(last_data)?;
+        Some(un_pack_property::disconnect(properties_data)?)
     };
-    MqttMessageV5::Disconnect(
+    Ok(MqttMessageV5::Disconnect(
         DisconnectMessage {
             msg_type: base.msg_type,
             code: Some(code),
@@ -305,32 +252,25 @@ pub fn disconnect(base: BaseMessage) -> MqttMessageV5 {
             properties,
             bytes: Some(base.bytes),
         }
-    )
+    ))
 }
 
-pub fn auth(base: BaseMessage) -> MqttMessageV5 {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn auth(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (code, mut last_data) = if message_bytes.len() > 0 {
-        parse_byte(message_bytes)
-    } else {
+    let (code, last_data) = if message_bytes.is_empty() {
         (ReasonPhrases::Success as u8, message_bytes)
+    } else {
+        parse_byte(message_bytes)?
     };
 
-    let mut properties_total_length = 0;
-
-    if last_data.len() > 0 {
-        let (length, last) = parse_byte(last_data);
-        properties_total_length = length;
-        last_data = last
-    }
-
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::auth(properties_total_length as u32, last_data))
-    } else {
+    let properties = if last_data.is_empty() {
         Some(Vec::default())
+    } else {
+        let (properties_data, _) = parse_properties_data(last_data)?;
+        Some(un_pack_property::auth(properties_data)?)
     };
-    MqttMessageV5::Auth(
+    Ok(MqttMessageV5::Auth(
         AuthMessage {
             msg_type: base.msg_type,
             protocol_level: Some(MqttProtocolLevel::Level5),
@@ -338,63 +278,134 @@ pub fn auth(base: BaseMessage) -> MqttMessageV5 {
             properties,
             bytes: Some(base.bytes),
         }
-    )
+    ))
 }
 
-pub fn puback(base: BaseMessage) -> MqttMessageV5 {
-    MqttMessageV5::Puback(
-        get_reason_code(base).into()
-    )
+pub fn puback(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    Ok(MqttMessageV5::Puback(
+        get_reason_code(base)?.into()
+    ))
 }
 
-pub fn pubrec(base: BaseMessage) -> MqttMessageV5 {
-    MqttMessageV5::Pubrec(
-        get_reason_code(base).into()
-    )
+pub fn pubrec(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    Ok(MqttMessageV5::Pubrec(
+        get_reason_code(base)?.into()
+    ))
 }
 
-pub fn pubrel(base: BaseMessage) -> MqttMessageV5 {
-    MqttMessageV5::Pubrel(
-        get_reason_code(base).into()
-    )
+pub fn pubrel(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    Ok(MqttMessageV5::Pubrel(
+        get_reason_code(base)?.into()
+    ))
 }
 
-pub fn pubcomp(base: BaseMessage) -> MqttMessageV5 {
-    MqttMessageV5::Pubcomp(
-        get_reason_code(base).into()
-    )
+pub fn pubcomp(base: BaseMessage) -> Result<MqttMessageV5, DecodeError> {
+    Ok(MqttMessageV5::Pubcomp(
+        get_reason_code(base)?.into()
+    ))
 }
 
-pub fn get_reason_code(base: BaseMessage) -> CommonPayloadMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn get_reason_code(base: BaseMessage) -> Result<CommonPayloadMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (message_id, last_data) = parse_short_int(message_bytes);
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
 
-    let (code, mut last_data) = if message_bytes.len() > 0 {
-        parse_byte(last_data)
-    } else {
+    let (code, last_data) = if last_data.is_empty() {
         (ReasonPhrases::Success as u8, last_data)
+    } else {
+        parse_byte(last_data)?
     };
 
-    let mut properties_total_length = 0;
-
-    if last_data.len() > 0 {
-        let (length, last) = parse_byte(last_data);
-        properties_total_length = length;
-        last_data = last
-    }
-
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::pub_and_sub(properties_total_length as u32, last_data))
-    } else {
+    let properties = if last_data.is_empty() {
         Some(Vec::default())
+    } else {
+        let (properties_data, _) = parse_properties_data(last_data)?;
+        Some(un_pack_property::pub_and_sub(properties_data)?)
     };
 
-    CommonPayloadMessage {
+    Ok(CommonPayloadMessage {
         msg_type: base.msg_type,
         message_id,
-        code: crate::hex::reason_code::ReasonPhrases::try_from(code).unwrap(),
+        code: ReasonPhrases::try_from(code).map_err(|_| DecodeError::InvalidReasonCode(code))?,
         properties,
         bytes: Some(base.bytes),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex::{find_property, Property, PropertyItem};
+    use crate::tools::config::{ConfigBuilder, Will};
+    use crate::tools::protocol::{MqttCleanSession, MqttWillFlag};
+
+    fn user_properties(properties: Option<&[PropertyItem]>) -> Vec<(&str, &str)> {
+        properties.unwrap_or_default().iter()
+            .filter(|item| item.0 == Property::UserProperty)
+            .filter_map(PropertyItem::as_map)
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn connect_round_trip() {
+        let will = Will::new("clients/will-test/status", "offline")
+            .qos(MqttQos::Qos1)
+            .retain(MqttRetain::Enable)
+            .delay_interval(30)
+            .payload_format_indicator(true)
+            .message_expiry_interval(600)
+            .content_type("text/plain")
+            .response_topic("clients/will-test/reply")
+            .correlation_data(vec![0x01, 0x02])
+            .user_property("reason", "crash");
+        let config = ConfigBuilder::default()
+            .client_id("will-test")
+            .username("user")
+            .password("secret")
+            .protocol_level(MqttProtocolLevel::Level5)
+            .session_expiry_interval(3600)
+            .receive_maximum(20)
+            .maximum_packet_size(65536)
+            .topic_alias_maximum(10)
+            .request_response_information(true)
+            .request_problem_information(false)
+            .receive_maximum(32)
+            .user_property("region", "eu")
+            .user_property("region", "us")
+            .will(will)
+            .build()
+            .unwrap();
+        let packet = MqttMessageV5::Connect(ConnectMessage::new(MqttCleanSession::Enable, config)).to_vec().unwrap();
+        let msg = match connect(BaseMessage::try_from(packet).unwrap()).unwrap() {
+            MqttMessageV5::Connect(msg) => msg,
+            msg => panic!("unexpected packet {:?}", msg),
+        };
+
+        let properties = msg.properties.as_deref();
+        assert_eq!(find_property(properties, Property::SessionExpiryInterval).and_then(PropertyItem::as_long), Some(3600));
+        assert_eq!(find_property(properties, Property::ReceiveMaximum).and_then(PropertyItem::as_short), Some(32));
+        assert_eq!(find_property(properties, Property::MaximumPacketSize).and_then(PropertyItem::as_long), Some(65536));
+        assert_eq!(find_property(properties, Property::TopicAliasMaximum).and_then(PropertyItem::as_short), Some(10));
+        assert_eq!(find_property(properties, Property::RequestResponseInformation).and_then(PropertyItem::as_byte), Some(1));
+        assert_eq!(find_property(properties, Property::RequestProblemInformation).and_then(PropertyItem::as_byte), Some(0));
+        assert_eq!(user_properties(properties), vec![("region", "eu"), ("region", "us")]);
+        assert_eq!(properties.unwrap().len(), 8);
+
+        assert_eq!((msg.will_flag, msg.will_qos, msg.will_retain), (MqttWillFlag::Enable, MqttQos::Qos1, MqttRetain::Enable));
+        assert_eq!(msg.payload.client_id, "will-test");
+        assert_eq!(msg.payload.user_name.as_deref(), Some("user"));
+        assert_eq!(msg.payload.password.as_deref(), Some("secret"));
+        assert_eq!(msg.payload.will_topic.as_deref(), Some("clients/will-test/status"));
+        assert_eq!(msg.payload.will_message, Some(Bytes::from("offline")));
+
+        let will = msg.payload.properties.as_deref();
+        assert_eq!(find_property(will, Property::WillDelayInterval).and_then(PropertyItem::as_long), Some(30));
+        assert_eq!(find_property(will, Property::PayloadFormatIndicator).and_then(PropertyItem::as_byte), Some(1));
+        assert_eq!(find_property(will, Property::MessageExpiryInterval).and_then(PropertyItem::as_long), Some(600));
+        assert_eq!(find_property(will, Property::ContentType).and_then(PropertyItem::as_str).map(String::as_str), Some("text/plain"));
+        assert_eq!(find_property(will, Property::ResponseTopic).and_then(PropertyItem::as_str).map(String::as_str), Some("clients/will-test/reply"));
+        assert_eq!(find_property(will, Property::CorrelationData).and_then(PropertyItem::as_binary), Some(&vec![0x01, 0x02]));
+        assert_eq!(user_properties(will), vec![("reason", "crash")]);
     }
 }
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use crate::container::MessageFrame;
+use crate::handle::HandleEvent;
+use crate::message::entity::PublishMessage;
+use crate::session::{MqttSession, ServerSession};
+use crate::subscript::{ClientID, SubscriptOption, TopicMessage};
+use crate::{MESSAGE_CONTAINER, SESSION_REGISTRY, SUBSCRIPT};
+
+///
+/// 会话过期时间为该值时永不过期
+///
+pub const SESSION_NEVER_EXPIRE: u32 = u32::MAX;
+
+///
+/// 会话状态, 离线的持久会话 `sender` 为 `None`
+///
+#[derive(Debug, Default)]
+pub struct SessionState {
+    sender: Option<Sender<HandleEvent>>,
+    expiry_interval: u32,
+    will: Option<PublishMessage>,
+    timers: Vec<JoinHandle<()>>,
+}
+
+impl SessionState {
+    pub fn is_online(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    fn cancel(&mut self) {
+        self.will = None;
+        for timer in self.timers.drain(..) {
+            timer.abort();
+        }
+    }
+}
+
+///
+/// 持久会话的快照, 由 `SessionStore` 在服务端关闭时保存
+///
+#[derive(Debug, Clone)]
+pub struct SessionSnapshot {
+    pub client_id: ClientID,
+    /// 会话过期时间 (秒), `SESSION_NEVER_EXPIRE` 表示永不过期
+    pub expiry_interval: u32,
+    pub subscriptions: Vec<(String, SubscriptOption)>,
+    /// 未完成的出站 QoS 1/2 消息
+    pub frames: Vec<MessageFrame>,
+    /// 离线期间排队的消息, 第一项为发送者
+    pub queue: Vec<(ClientID, PublishMessage)>,
+}
+
+///
+/// 会话持久化, 服务端关闭并断开所有连接后调用 `save` 保存过期时间不为 0 的会话
+///
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn save(&self, sessions: Vec<SessionSnapshot>);
+}
+
+///
+/// 会话注册表, 按客户端标识记录会话以及当前连接
+///
+/// 订阅由 `SUBSCRIPT` 保存, 飞行窗口与离线消息由 `MESSAGE_CONTAINER` 保存;
+/// 会话的绑定、接管与结束都在注册表的锁内完成, 保证同一客户端的并发连接按顺序生效
+///
+#[derive(Default)]
+pub struct SessionRegistry {
+    inner: Arc<Mutex<HashMap<ClientID, SessionState>>>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> SessionRegistry {
+        SessionRegistry { inner: Arc::new(Mutex::new(HashMap::default())) }
+    }
+
+    ///
+    /// 绑定新连接, 取消会话过期与延迟遗嘱
+    ///
+    /// 存在可以继续使用的会话时把订阅绑定到新连接, 否则丢弃旧的订阅与消息.
+    /// 返回是否继续使用原会话 (CONNACK session present) 以及需要被关闭的旧连接
+    ///
+    pub async fn connect(&self, client_id: &ClientID, sender: Sender<HandleEvent>, clean_start: bool, expiry_interval: u32) -> (bool, Option<Sender<HandleEvent>>) {
+        let mut inner = self.inner.lock().await;
+        let state = SessionState { sender: Some(sender.clone()), expiry_interval, ..SessionState::default() };
+        let (session_present, previous) = match inner.insert(client_id.clone(), state) {
+            Some(mut exist) => {
+                exist.cancel();
+                (!clean_start, exist.sender)
+            }
+            None => (false, None)
+        };
+        if session_present {
+            SUBSCRIPT.online(client_id, &sender).await;
+        } else {
+            SUBSCRIPT.exit(client_id).await;
+            MESSAGE_CONTAINER.remove(client_id).await;
+        }
+        MESSAGE_CONTAINER.init(client_id.clone()).await;
+        (session_present, previous)
+    }
+
+    ///
+    /// 连接断开: 过期时间为 0 的会话删除订阅与飞行窗口, 其余会话保留订阅并标记为离线
+    ///
+    /// 只有当前绑定的连接才能结束会话, 已被新连接接管时返回 `false`
+    ///
+    pub async fn disconnect(&self, client_id: &ClientID, sender: &Sender<HandleEvent>, expiry_interval: u32) -> bool {
+        let mut inner = self.inner.lock().await;
+        let state = match inner.get_mut(client_id) {
+            Some(state) if state.sender.as_ref().is_some_and(|exist| exist.same_channel(sender)) => state,
+            _ => return false
+        };
+        if expiry_interval == 0 {
+            inner.remove(client_id);
+            SUBSCRIPT.exit(client_id).await;
+            MESSAGE_CONTAINER.remove(client_id).await;
+        } else {
+            state.sender = None;
+            state.expiry_interval = expiry_interval;
+            SUBSCRIPT.offline(client_id).await;
+        }
+        true
+    }
+
+    ///
+    /// 转发连接关闭时尚未处理的消息: 会话已被新连接接管时交给新连接, 否则进入离线队列
+    ///
+    pub async fn forward(&self, client_id: &ClientID, from: ClientID, msg: PublishMessage) {
+        let inner = self.inner.lock().await;
+        if let Some(sender) = inner.get(client_id).and_then(|state| state.sender.as_ref()) {
+            if let Err(e) = sender.try_send(HandleEvent::BroadcastEvent(TopicMessage::Content(from, msg))) {
+                if let HandleEvent::BroadcastEvent(TopicMessage::Content(from, msg)) = e.into_inner() {
+                    MESSAGE_CONTAINER.enqueue(client_id, from, msg).await;
+                }
+            }
+            return;
+        }
+        MESSAGE_CONTAINER.enqueue(client_id, from, msg).await;
+    }
+
+    ///
+    /// 离线会话在 `delay` 秒后发布遗嘱, 期间重新连接则取消
+    ///
+    pub async fn delay_will(&self, session: &ServerSession, will: PublishMessage, delay: u32) {
+        let client_id = session.get_client_id().clone();
+        if let Some(state) = self.inner.lock().await.get_mut(&client_id) {
+            state.will = Some(will);
+            let session = session.clone();
+            state.timers.push(tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(delay as u64)).await;
+                if let Some(will) = SESSION_REGISTRY.take_will(&client_id).await {
+                    session.publish(&will).await;
+                }
+            }));
+        }
+    }
+
+    ///
+    /// 离线会话在 `expiry_interval` 秒后结束, 期间重新连接则取消
+    ///
+    pub async fn expire_after(&self, session: &ServerSession, expiry_interval: u32) {
+        if expiry_interval == SESSION_NEVER_EXPIRE {
+            return;
+        }
+        let client_id = session.get_client_id().clone();
+        if let Some(state) = self.inner.lock().await.get_mut(&client_id) {
+            let session = session.clone();
+            state.timers.push(tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(expiry_interval as u64)).await;
+                SESSION_REGISTRY.expire(&session).await;
+            }));
+        }
+    }
+
+    async fn take_will(&self, client_id: &ClientID) -> Option<PublishMessage> {
+        let mut inner = self.inner.lock().await;
+        let state = inner.get_mut(client_id).filter(|state| !state.is_online())?;
+        state.will.take()
+    }
+
+    ///
+    /// 会话过期: 发布尚未发布的遗嘱, 删除订阅与飞行窗口
+    ///
+    async fn expire(&self, session: &ServerSession) {
+        let client_id = session.get_client_id();
+        let state = {
+            let mut inner = self.inner.lock().await;
+            if inner.get(client_id).is_none_or(|state| state.is_online()) {
+                return;
+            }
+            SUBSCRIPT.exit(client_id).await;
+            MESSAGE_CONTAINER.remove(client_id).await;
+            inner.remove(client_id)
+        };
+        if let Some(will) = state.and_then(|mut state| state.will.take()) {
+            session.publish(&will).await;
+        }
+    }
+
+    ///
+    /// 过期时间不为 0 的会话快照, 包括订阅、未完成的出站消息与离线消息
+    ///
+    pub async fn snapshot(&self) -> Vec<SessionSnapshot> {
+        let inner = self.inner.lock().await;
+        let mut sessions = vec![];
+        for (client_id, state) in inner.iter().filter(|(_, state)| state.expiry_interval != 0) {
+            let (frames, queue) = MESSAGE_CONTAINER.snapshot(client_id).await;
+            sessions.push(SessionSnapshot {
+                client_id: client_id.clone(),
+                expiry_interval: state.expiry_interval,
+                subscriptions: SUBSCRIPT.subscriptions(client_id).await,
+                frames,
+                queue,
+            });
+        }
+        sessions
+    }
+
+    pub async fn is_online(&self, client_id: &ClientID) -> Option<bool> {
+        self.inner.lock().await.get(client_id).map(|state| state.is_online())
+    }
+
+    pub async fn remove(&self, client_id: &ClientID) -> Option<SessionState> {
+        self.inner.lock().await.remove(client_id).map(|mut state| {
+            state.cancel();
+            state
+        })
+    }
+
+    pub async fn contain(&self, client_id: &ClientID) -> bool {
+        self.inner.lock().await.contains_key(client_id)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.inner.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.inner.lock().await.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn session_present() {
+        let registry = SessionRegistry::new();
+        let client_id = ClientID::from("registry");
+        let (first, _first_receiver) = mpsc::channel(1);
+        let (second, _second_receiver) = mpsc::channel(1);
+
+        assert!(!registry.connect(&client_id, first.clone(), false, SESSION_NEVER_EXPIRE).await.0);
+        assert!(registry.disconnect(&client_id, &first, SESSION_NEVER_EXPIRE).await);
+        assert_eq!(registry.is_online(&client_id).await, Some(false));
+
+        let (session_present, previous) = registry.connect(&client_id, second.clone(), false, SESSION_NEVER_EXPIRE).await;
+        assert!(session_present);
+        assert!(previous.is_none());
+        assert!(!registry.disconnect(&client_id, &first, SESSION_NEVER_EXPIRE).await);
+        assert_eq!(registry.is_online(&client_id).await, Some(true));
+
+        let (session_present, previous) = registry.connect(&client_id, first.clone(), true, SESSION_NEVER_EXPIRE).await;
+        assert!(!session_present);
+        assert!(previous.unwrap().same_channel(&second));
+        assert!(!registry.disconnect(&client_id, &second, 0).await);
+        assert!(registry.disconnect(&client_id, &first, 0).await);
+        assert!(registry.is_empty().await);
+    }
+}
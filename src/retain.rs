@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use crate::hex::Property;
+use crate::message::entity::PublishMessage;
+use crate::subscript::topic_matches;
+use crate::tools::protocol::{MqttDup, MqttRetain};
+
+///
+/// 保留消息, 每个主题只保存最后一条
+///
+#[derive(Default)]
+pub struct RetainContainer {
+    inner: Arc<Mutex<HashMap<String, PublishMessage>>>,
+}
+
+impl RetainContainer {
+    pub fn new() -> RetainContainer {
+        RetainContainer { inner: Arc::new(Mutex::new(HashMap::default())) }
+    }
+
+    ///
+    /// 保存保留消息, 消息体为空时清除该主题的保留消息
+    ///
+    pub async fn retain(&self, msg: &PublishMessage) -> Option<PublishMessage> {
+        if msg.msg_body.is_empty() {
+            return self.remove(&msg.topic).await;
+        }
+        let mut retained = msg.clone();
+        retained.message_id = 0;
+        retained.dup = MqttDup::Disable;
+        retained.retain = MqttRetain::Enable;
+        retained.protocol_level = None;
+        retained.bytes = None;
+        retained.cache = None;
+        if let Some(properties) = retained.properties.as_mut() {
+            properties.retain(|item| !matches!(item.0, Property::TopicAlias | Property::SubscriptionIdentifier));
+        }
+        self.inner.lock().await.insert(msg.topic.clone(), retained)
+    }
+
+    pub async fn remove<S: AsRef<str>>(&self, topic_name: S) -> Option<PublishMessage> {
+        self.inner.lock().await.remove(topic_name.as_ref())
+    }
+
+    pub async fn get<S: AsRef<str>>(&self, topic_name: S) -> Option<PublishMessage> {
+        self.inner.lock().await.get(topic_name.as_ref()).cloned()
+    }
+
+    ///
+    /// 查找匹配主题过滤器的保留消息
+    ///
+    pub async fn matches<S: AsRef<str>>(&self, topic_filter: S) -> Vec<PublishMessage> {
+        self.inner.lock().await
+            .iter()
+            .filter(|(topic_name, _)| topic_matches(topic_filter.as_ref(), topic_name))
+            .map(|(_, msg)| msg.clone())
+            .collect::<Vec<PublishMessage>>()
+    }
+
+    pub async fn len(&self) -> usize {
+        self.inner.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.inner.lock().await.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::protocol::MqttQos;
+
+    fn retained(topic: &str, body: &str) -> PublishMessage {
+        PublishMessage::new(MqttQos::Qos1, MqttDup::Enable, MqttRetain::Enable, topic.to_owned(), 7, body.to_owned(), None)
+    }
+
+    #[tokio::test]
+    async fn retain_and_clear() {
+        let container = RetainContainer::new();
+        container.retain(&retained("home/kitchen/temp", "21")).await;
+        container.retain(&retained("home/garden/temp", "15")).await;
+        container.retain(&retained("home/kitchen/temp", "22")).await;
+        assert_eq!(container.len().await, 2);
+
+        let msg = container.get("home/kitchen/temp").await.unwrap();
+        assert_eq!(msg.msg_body, "22");
+        assert_eq!(msg.message_id, 0);
+        assert_eq!(msg.dup, MqttDup::Disable);
+
+        assert_eq!(container.matches("home/+/temp").await.len(), 2);
+        assert_eq!(container.matches("home/kitchen/#").await.len(), 1);
+        assert!(container.matches("office/#").await.is_empty());
+
+        container.retain(&retained("home/kitchen/temp", "")).await;
+        assert!(container.get("home/kitchen/temp").await.is_none());
+        container.retain(&retained("home/garden/temp", "")).await;
+        assert!(container.is_empty().await);
+    }
+}
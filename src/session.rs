@@ -1,21 +1,37 @@
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
-use crate::message::MqttMessageKind;
-use crate::subscript::{ClientID, TopicMessage};
-use crate::tools::protocol::{MqttCleanSession, MqttProtocolLevel, MqttQos, MqttRetain, MqttWillFlag};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use bytes::Bytes;
+use futures_util::Stream;
+use tokio_rustls::rustls::Certificate;
+use crate::subscript::{identify, topic_matches, ClientID, SubscriptOption, TopicMessage};
+use crate::tools::protocol::{MqttCleanSession, MqttDup, MqttProtocolLevel, MqttQos, MqttRetain, MqttWillFlag};
 use async_trait::async_trait;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::error::SendError;
+use crate::auth::{Action, PeerCredentials};
+use tokio::sync::{mpsc, oneshot, Mutex, MutexGuard};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::Sleep;
+use crate::container::ClientMessageFrames;
 use crate::handle::{HandleEvent, Response};
-use crate::message::entity::{PublishMessage, SubscribeMessage};
+use crate::hex::{find_property, Property, PropertyItem, PropertyValue};
+use crate::hex::reason_code::ReasonPhrases;
+use crate::message::entity::{PublishMessage, SubscribeMessage, UnsubscribeMessage};
 use crate::message::v3::MqttMessageV3;
 use crate::message::v5::MqttMessageV5;
-use crate::SUBSCRIPT;
+use crate::tools::config::ServerConfig;
+use crate::tools::error::{AckError, RequestError};
+use crate::tools::tls::{IdentityAs, PeerIdentity};
+use crate::{RETAIN_CONTAINER, SUBSCRIPT};
 
 #[async_trait]
 pub trait MqttSession: Clone {
     fn session_id(&self) -> &String;
     async fn publish(&self, msg: &PublishMessage);
-    async fn subscribe(&self, topic: &String);
+    async fn subscribe(&self, topic: &str);
     async fn exit(&self);
     async fn send(&self, msg: Vec<u8>);
     async fn send_event(&self, event: HandleEvent);
@@ -26,6 +42,251 @@ pub struct ClientSession {
     session_id: String,
     pub protocol_level: MqttProtocolLevel,
     sender: mpsc::Sender<HandleEvent>,
+    /// 未完成确认的出站 QoS 1/2 消息, 重连后重发
+    frames: Arc<Mutex<ClientMessageFrames>>,
+    /// 已订阅的主题与订阅流, 重连后重新订阅
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    /// 请求/响应状态
+    requests: Arc<Mutex<Requests>>,
+    /// 连接中断后为 `false`, 期间的订阅与 QoS 1/2 消息只记录, 在下一次连接收到 CONNACK 之后发送
+    online: Arc<Mutex<bool>>,
+    /// 等待 PUBACK / PUBCOMP / SUBACK / UNSUBACK 的报文
+    acks: Arc<Mutex<Acks>>,
+    ack_timeout: Duration,
+}
+
+///
+/// 等待确认的默认超时时间
+///
+pub(crate) const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+///
+/// v5 订阅标识符的最大值
+///
+const MAX_SUBSCRIPTION_IDENTIFIER: u32 = 268_435_455;
+
+///
+/// 客户端订阅状态: 已订阅的主题过滤器, 以及按主题过滤器接收消息的流
+///
+#[derive(Default)]
+struct Subscriptions {
+    /// 主题过滤器、QoS 与 v5 订阅标识符, 重连后按相同的参数重新订阅
+    filters: Vec<(String, MqttQos, Option<u32>)>,
+    /// 直接订阅的主题过滤器, 不随订阅流的释放而取消订阅
+    pinned: HashSet<String>,
+    streams: Vec<(u64, String, mpsc::Sender<PublishMessage>)>,
+    next_stream: u64,
+    next_identifier: u32,
+    /// 服务端在 CONNACK 中声明不支持订阅标识符
+    identifiers_unavailable: bool,
+}
+
+impl Subscriptions {
+    fn record(&mut self, filter: &str, qos: MqttQos, identifier: Option<u32>) {
+        self.filters.retain(|(exist, _, _)| exist != filter);
+        self.filters.push((filter.to_owned(), qos, identifier));
+    }
+
+    fn identifier(&self, filter: &str) -> Option<u32> {
+        self.filters.iter().find(|(exist, _, _)| exist == filter).and_then(|(_, _, identifier)| *identifier)
+    }
+
+    fn next_identifier(&mut self) -> u32 {
+        self.next_identifier = self.next_identifier % MAX_SUBSCRIPTION_IDENTIFIER + 1;
+        self.next_identifier
+    }
+
+    ///
+    /// 消息带有订阅标识符时只交给对应主题过滤器的流, 否则交给所有匹配主题名的流
+    ///
+    fn route(&self, msg: &PublishMessage) -> Vec<mpsc::Sender<PublishMessage>> {
+        let identifiers = msg.properties.iter().flatten()
+            .filter(|item| item.0 == Property::SubscriptionIdentifier)
+            .filter_map(PropertyItem::as_long)
+            .collect::<Vec<u32>>();
+        let filters = self.filters.iter()
+            .filter(|(_, _, identifier)| identifier.is_some_and(|identifier| identifiers.contains(&identifier)))
+            .map(|(filter, _, _)| filter.as_str())
+            .collect::<Vec<&str>>();
+        self.streams.iter()
+            .filter(|(_, filter, _)| if identifiers.is_empty() {
+                topic_matches(filter, &msg.topic)
+            } else {
+                filters.contains(&filter.as_str())
+            })
+            .map(|(_, _, sender)| sender.clone())
+            .collect()
+    }
+}
+
+///
+/// 只包含匹配主题过滤器的消息的订阅流, 释放时取消订阅 (仍有其他流或直接订阅使用该过滤器时除外)
+///
+#[must_use = "dropping a subscription unsubscribes from its topic filter"]
+pub struct Subscription {
+    key: u64,
+    filter: String,
+    receiver: mpsc::Receiver<PublishMessage>,
+    session: ClientSession,
+    ack: AckFuture<SubscribeAck>,
+    suback: Option<Result<SubscribeAck, AckError>>,
+}
+
+impl Subscription {
+    pub fn topic_filter(&self) -> &str {
+        &self.filter
+    }
+
+    ///
+    /// 等待 SUBACK, 结果中包含服务端授予的 QoS; 结果会被保存, 可以重复调用
+    ///
+    pub async fn suback(&mut self) -> Result<SubscribeAck, AckError> {
+        if let Some(suback) = self.suback.clone() {
+            return suback;
+        }
+        let suback = (&mut self.ack).await;
+        self.suback = Some(suback.clone());
+        suback
+    }
+
+    ///
+    /// 接收下一条消息, 连接不再恢复时返回 `None`
+    ///
+    pub async fn recv(&mut self) -> Option<PublishMessage> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for Subscription {
+    type Item = PublishMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let (session, key) = (self.session.clone(), self.key);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                session.release(key).await;
+            });
+        }
+    }
+}
+
+///
+/// 请求/响应状态: 接收响应的主题, 以及按 CorrelationData 等待响应的请求
+///
+#[derive(Default)]
+struct Requests {
+    response_information: Option<String>,
+    response_topic: Option<String>,
+    next_correlation: u64,
+    pending: HashMap<Vec<u8>, oneshot::Sender<PublishMessage>>,
+}
+
+///
+/// PUBLISH 的确认结果: QoS 1 为 PUBACK 中的原因码, QoS 2 为 PUBCOMP 或失败的 PUBREC 中的原因码 (v3 总是 Success).
+/// QoS 0 消息交给连接发送后即以 Success 完成, 报文标识符为 0
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PublishAck {
+    pub message_id: u16,
+    pub code: ReasonPhrases,
+}
+
+impl PublishAck {
+    pub fn is_success(&self) -> bool {
+        self.code.as_byte() < 0x80
+    }
+}
+
+///
+/// SUBACK 中按主题过滤器顺序返回的结果
+///
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SubscribeAck {
+    pub message_id: u16,
+    pub codes: Vec<u8>,
+}
+
+impl SubscribeAck {
+    ///
+    /// 每个主题过滤器授予的 QoS, 订阅失败时为 `Err` 中的返回码 (v5 为原因码)
+    ///
+    pub fn granted(&self) -> Vec<Result<MqttQos, u8>> {
+        self.codes.iter()
+            .map(|code| match MqttQos::try_from(*code) {
+                Ok(qos) if qos != MqttQos::Failure => Ok(qos),
+                _ => Err(*code)
+            })
+            .collect()
+    }
+}
+
+///
+/// UNSUBACK 的结果, v3 中没有原因码, 总是 Success
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UnsubscribeAck {
+    pub message_id: u16,
+    pub code: ReasonPhrases,
+}
+
+///
+/// 等待确认的 future, 超时或者连接不再恢复时返回错误; 不关心确认结果时直接释放即可
+///
+pub struct AckFuture<T> {
+    receiver: oneshot::Receiver<Result<T, AckError>>,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<T> AckFuture<T> {
+    fn new(receiver: oneshot::Receiver<Result<T, AckError>>, timeout: Duration) -> AckFuture<T> {
+        AckFuture { receiver, deadline: Box::pin(tokio::time::sleep(timeout)) }
+    }
+
+    pub(crate) fn ready(result: Result<T, AckError>) -> AckFuture<T> {
+        let (sender, receiver) = oneshot::channel();
+        let _ = sender.send(result);
+        AckFuture::new(receiver, Duration::ZERO)
+    }
+}
+
+impl<T> Future for AckFuture<T> {
+    type Output = Result<T, AckError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(result) = Pin::new(&mut self.receiver).poll(cx) {
+            return Poll::Ready(result.unwrap_or(Err(AckError::Closed)));
+        }
+        self.deadline.as_mut().poll(cx).map(|_| Err(AckError::Timeout))
+    }
+}
+
+type AckSender<T> = oneshot::Sender<Result<T, AckError>>;
+
+///
+/// 等待确认的报文
+///
+enum PendingAck {
+    Publish(AckSender<PublishAck>),
+    /// 主题过滤器, 以及等待该主题过滤器 SUBACK 的全部调用
+    Subscribe(String, Vec<AckSender<SubscribeAck>>),
+    Unsubscribe(AckSender<UnsubscribeAck>),
+}
+
+///
+/// 按报文标识符等待确认的报文, 以及在重连后的重新订阅中等待 SUBACK 的订阅
+///
+#[derive(Default)]
+struct Acks {
+    pending: HashMap<u16, PendingAck>,
+    resubscribe: Vec<(String, AckSender<SubscribeAck>)>,
+    /// 连接不再恢复, 之后的报文不会再收到确认
+    closed: bool,
 }
 
 #[async_trait]
@@ -35,26 +296,11 @@ impl MqttSession for ClientSession {
     }
 
     async fn publish(&self, msg: &PublishMessage) {
-
-        let msg = if self.protocol_level == MqttProtocolLevel::Level5 {
-            MqttMessageV5::Publish(msg.clone()).to_vec().unwrap()
-        }else{
-            MqttMessageV3::Publish(msg.clone()).to_vec().unwrap()
-        };
-        if let Err(e) = self.sender.send(HandleEvent::OutputEvent(Response(msg, self.protocol_level))).await {
-            println!("failed to send subscribe message; err = {:?}", e);
-        }
+        self.publish_with_ack(msg).await;
     }
 
-    async fn subscribe(&self, topic: &String) {
-        let msg = if self.protocol_level == MqttProtocolLevel::Level5 {
-            MqttMessageV5::Subscribe(SubscribeMessage::new(0, topic.clone(), MqttQos::Qos1)).to_vec().unwrap()
-        }else{
-            MqttMessageV3::Subscribe(SubscribeMessage::new(0, topic.clone(), MqttQos::Qos1)).to_vec().unwrap()
-        };
-        if let Err(e) = self.sender.send(HandleEvent::OutputEvent(Response(msg, self.protocol_level))).await {
-            println!("failed to send subscribe message; err = {:?}", e);
-        }
+    async fn subscribe(&self, topic: &str) {
+        self.subscribe_with_qos(topic, MqttQos::Qos1).await;
     }
 
     async fn exit(&self) {
@@ -64,11 +310,15 @@ impl MqttSession for ClientSession {
     }
 
     async fn send(&self, msg: Vec<u8>) {
-        self.sender.send(HandleEvent::OutputEvent(Response(msg, self.protocol_level)));
+        if let Err(e) = self.sender.send(HandleEvent::OutputEvent(Response(msg, self.protocol_level))).await {
+            println!("failed to send message; err = {:?}", e);
+        }
     }
 
     async fn send_event(&self, event: HandleEvent) {
-        self.sender.send(event).await;
+        if let Err(e) = self.sender.send(event).await {
+            println!("failed to send event; err = {:?}", e);
+        }
     }
 }
 
@@ -78,28 +328,493 @@ impl ClientSession {
             session_id,
             protocol_level,
             sender,
+            frames: Arc::new(Mutex::new(ClientMessageFrames::new())),
+            subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+            requests: Arc::new(Mutex::new(Requests::default())),
+            online: Arc::new(Mutex::new(true)),
+            acks: Arc::new(Mutex::new(Acks::default())),
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+        }
+    }
+
+    ///
+    /// 等待 PUBACK / PUBCOMP / SUBACK / UNSUBACK 的超时时间
+    ///
+    pub fn ack_timeout(mut self, ack_timeout: Duration) -> ClientSession {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+
+    ///
+    /// 发布消息并返回等待确认的 future. QoS 1/2 消息在连接中断后以相同的报文标识符重发, 继续等待确认;
+    /// 连接中断期间的 QoS 0 消息被丢弃
+    ///
+    pub async fn publish_with_ack(&self, msg: &PublishMessage) -> AckFuture<PublishAck> {
+        let client_id = ClientID::from(self.session_id.as_str());
+        let (msg, online, ack) = {
+            let online = self.online.lock().await;
+            let mut frames = self.frames.lock().await;
+            let msg = match frames.append(client_id.clone(), client_id, msg.clone()) {
+                Some(msg) => msg,
+                None => {
+                    println!("no packet identifier available; client = {}", self.session_id);
+                    return AckFuture::ready(Err(AckError::NoPacketIdentifier));
+                }
+            };
+            if !*online && msg.qos != MqttQos::Qos0 {
+                frames.defer(msg.message_id);
+            }
+            drop(frames);
+            let ack = match msg.qos {
+                MqttQos::Qos0 => None,
+                _ => Some(self.expect(msg.message_id, PendingAck::Publish).await)
+            };
+            (msg, *online, ack)
+        };
+        if !online {
+            return ack.unwrap_or_else(|| {
+                println!("connection lost, qos 0 message dropped; client = {}", self.session_id);
+                AckFuture::ready(Err(AckError::ConnectionLost))
+            });
+        }
+        let message_id = msg.message_id;
+        let msg = self.encode(msg, MqttMessageV3::Publish, MqttMessageV5::Publish);
+        if let Err(e) = self.sender.send(HandleEvent::OutputEvent(Response(msg, self.protocol_level))).await {
+            println!("failed to send publish message; err = {:?}", e);
+            return AckFuture::ready(Err(AckError::Closed));
         }
+        ack.unwrap_or_else(|| AckFuture::ready(Ok(PublishAck { message_id, code: ReasonPhrases::Success })))
+    }
+
+    ///
+    /// 发送 v5 请求并等待响应: 首次请求时订阅本客户端的响应主题, 请求中携带响应主题与唯一的 CorrelationData
+    ///
+    pub async fn request<B: Into<Bytes>>(&self, topic: &str, payload: B, timeout: Duration) -> Result<PublishMessage, RequestError> {
+        if self.protocol_level != MqttProtocolLevel::Level5 {
+            return Err(RequestError::Unsupported);
+        }
+        let (response_topic, subscribe, correlation, receiver) = {
+            let mut requests = self.requests.lock().await;
+            let subscribe = requests.response_topic.is_none();
+            let response_topic = match requests.response_topic.clone() {
+                Some(response_topic) => response_topic,
+                None => {
+                    let base = requests.response_information.as_deref().unwrap_or("response").trim_end_matches('/');
+                    let response_topic = format!("{}/{}", base, self.session_id);
+                    requests.response_topic = Some(response_topic.clone());
+                    response_topic
+                }
+            };
+            requests.next_correlation += 1;
+            let correlation = requests.next_correlation.to_be_bytes().to_vec();
+            let (sender, receiver) = oneshot::channel();
+            requests.pending.insert(correlation.clone(), sender);
+            (response_topic, subscribe, correlation, receiver)
+        };
+        if subscribe {
+            self.subscribe_with_qos(&response_topic, MqttQos::Qos1).await;
+        }
+        let properties = vec![
+            PropertyItem(Property::ResponseTopic, PropertyValue::String(response_topic)),
+            PropertyItem(Property::CorrelationData, PropertyValue::Binary(correlation.clone())),
+        ];
+        self.publish(&PublishMessage::new(MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable, topic.to_owned(), 0, payload, Some(properties))).await;
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(RequestError::Closed),
+            Err(_) => {
+                self.requests.lock().await.pending.remove(&correlation);
+                Err(RequestError::Timeout)
+            }
+        }
+    }
+
+    ///
+    /// 回复请求: 发布到请求的 ResponseTopic, 并带回相同的 CorrelationData
+    ///
+    pub async fn respond<B: Into<Bytes>>(&self, request: &PublishMessage, payload: B) -> Result<(), RequestError> {
+        let properties = request.properties.as_deref();
+        let response_topic = find_property(properties, Property::ResponseTopic)
+            .and_then(PropertyItem::as_str)
+            .ok_or(RequestError::NoResponseTopic)?;
+        let mut response_properties = vec![];
+        if let Some(correlation) = find_property(properties, Property::CorrelationData) {
+            response_properties.push(correlation.clone());
+        }
+        let qos = request.qos.min(MqttQos::Qos1);
+        self.publish(&PublishMessage::new(qos, MqttDup::Disable, MqttRetain::Disable, response_topic.to_owned(), 0, payload, Some(response_properties))).await;
+        Ok(())
+    }
+
+    ///
+    /// 收到的消息是等待中请求的响应时交给对应的请求, 返回 `true` 表示不再作为普通消息处理
+    ///
+    pub(crate) async fn resolve(&self, msg: &PublishMessage) -> bool {
+        let correlation = match find_property(msg.properties.as_deref(), Property::CorrelationData).and_then(PropertyItem::as_binary) {
+            Some(correlation) => correlation,
+            None => return false
+        };
+        let mut requests = self.requests.lock().await;
+        if requests.response_topic.as_deref() != Some(msg.topic.as_str()) {
+            return false;
+        }
+        match requests.pending.remove(correlation) {
+            Some(sender) => {
+                let _ = sender.send(msg.clone());
+                true
+            }
+            None => false
+        }
+    }
+
+    ///
+    /// 连接不再恢复: 等待中的请求与确认全部失败, 订阅流全部结束
+    ///
+    pub(crate) async fn close(&self) {
+        self.requests.lock().await.pending.clear();
+        self.subscriptions.lock().await.streams.clear();
+        let mut acks = self.acks.lock().await;
+        acks.pending.clear();
+        acks.resubscribe.clear();
+        acks.closed = true;
+    }
+
+    ///
+    /// CONNACK 中的 ResponseInformation, 作为之后创建的响应主题的前缀
+    ///
+    pub(crate) async fn set_response_information(&self, response_information: Option<String>) {
+        self.requests.lock().await.response_information = response_information;
+    }
+
+    ///
+    /// 接收响应的主题, 在第一次请求时确定
+    ///
+    pub async fn response_topic(&self) -> Option<String> {
+        self.requests.lock().await.response_topic.clone()
+    }
+
+    ///
+    /// 订阅主题并记录, 重连后使用相同的 QoS 重新订阅; 返回等待 SUBACK 的 future
+    ///
+    pub async fn subscribe_with_qos(&self, topic: &str, qos: MqttQos) -> AckFuture<SubscribeAck> {
+        let online = self.online.lock().await;
+        let identifier = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            let identifier = subscriptions.identifier(topic);
+            subscriptions.record(topic, qos, identifier);
+            subscriptions.pinned.insert(topic.to_owned());
+            identifier.filter(|_| !subscriptions.identifiers_unavailable)
+        };
+        let (message_id, ack) = self.expect_suback(*online, topic).await;
+        drop(online);
+        if let Some(message_id) = message_id {
+            self.send(self.subscribe_message(message_id, topic, qos, identifier)).await;
+        }
+        ack
+    }
+
+    ///
+    /// 订阅主题过滤器, 返回只包含匹配消息的订阅流.
+    /// v5 中为每个主题过滤器分配订阅标识符, 重叠的过滤器按服务端返回的订阅标识符分发
+    ///
+    pub async fn subscribe_stream(&self, filter: &str, qos: MqttQos) -> Subscription {
+        let (sender, receiver) = mpsc::channel(512);
+        let online = self.online.lock().await;
+        let (key, identifier) = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            let identifier = match subscriptions.identifier(filter) {
+                Some(identifier) => Some(identifier),
+                None if self.protocol_level == MqttProtocolLevel::Level5 => Some(subscriptions.next_identifier()),
+                None => None
+            };
+            subscriptions.record(filter, qos, identifier);
+            subscriptions.next_stream += 1;
+            let key = subscriptions.next_stream;
+            subscriptions.streams.push((key, filter.to_owned(), sender));
+            (key, identifier.filter(|_| !subscriptions.identifiers_unavailable))
+        };
+        let (message_id, ack) = self.expect_suback(*online, filter).await;
+        drop(online);
+        if let Some(message_id) = message_id {
+            self.send(self.subscribe_message(message_id, filter, qos, identifier)).await;
+        }
+        Subscription { key, filter: filter.to_owned(), receiver, session: self.clone(), ack, suback: None }
+    }
+
+    ///
+    /// 取消订阅并返回等待 UNSUBACK 的 future. 连接中断期间不发送, 重连后也不再重新订阅该主题过滤器
+    ///
+    pub async fn unsubscribe(&self, topic: &str) -> AckFuture<UnsubscribeAck> {
+        let online = self.online.lock().await;
+        {
+            let mut subscriptions = self.subscriptions.lock().await;
+            subscriptions.filters.retain(|(exist, _, _)| exist != topic);
+            subscriptions.pinned.remove(topic);
+        }
+        if !*online {
+            return AckFuture::ready(Err(AckError::ConnectionLost));
+        }
+        let message_id = self.frames.lock().await.reserve();
+        let (message_id, ack) = match message_id {
+            Some(message_id) => (message_id, self.expect(message_id, PendingAck::Unsubscribe).await),
+            None => return AckFuture::ready(Err(AckError::NoPacketIdentifier))
+        };
+        drop(online);
+        let msg = UnsubscribeMessage::new(message_id, topic.to_owned());
+        self.send(self.encode(msg, MqttMessageV3::Unsubscribe, MqttMessageV5::Unsubscribe)).await;
+        ack
+    }
+
+    ///
+    /// 按报文标识符等待确认
+    ///
+    async fn expect<T>(&self, message_id: u16, pending: impl FnOnce(AckSender<T>) -> PendingAck) -> AckFuture<T> {
+        let mut acks = self.acks.lock().await;
+        if acks.closed {
+            return AckFuture::ready(Err(AckError::Closed));
+        }
+        let (sender, receiver) = oneshot::channel();
+        acks.pending.insert(message_id, pending(sender));
+        AckFuture::new(receiver, self.ack_timeout)
+    }
+
+    ///
+    /// 为 SUBSCRIBE 分配报文标识符并等待 SUBACK; 连接中断期间不分配, 在重连后的重新订阅中等待 SUBACK.
+    /// 调用时需要持有 `online`
+    ///
+    async fn expect_suback(&self, online: bool, filter: &str) -> (Option<u16>, AckFuture<SubscribeAck>) {
+        if !online {
+            let mut acks = self.acks.lock().await;
+            if acks.closed {
+                return (None, AckFuture::ready(Err(AckError::Closed)));
+            }
+            let (sender, receiver) = oneshot::channel();
+            acks.resubscribe.push((filter.to_owned(), sender));
+            return (None, AckFuture::new(receiver, self.ack_timeout));
+        }
+        let message_id = self.frames.lock().await.reserve();
+        match message_id {
+            Some(message_id) => (Some(message_id), self.expect(message_id, |sender| PendingAck::Subscribe(filter.to_owned(), vec![sender])).await),
+            None => (None, AckFuture::ready(Err(AckError::NoPacketIdentifier)))
+        }
+    }
+
+    ///
+    /// 收到 PUBACK / PUBCOMP 或失败的 PUBREC, 完成对应的发布
+    ///
+    pub(crate) async fn publish_acked(&self, message_id: u16, code: ReasonPhrases) {
+        if let Some(PendingAck::Publish(sender)) = self.acks.lock().await.pending.remove(&message_id) {
+            let _ = sender.send(Ok(PublishAck { message_id, code }));
+        }
+    }
+
+    ///
+    /// 收到 SUBACK, 释放报文标识符并完成对应的订阅
+    ///
+    pub(crate) async fn subscribe_acked(&self, message_id: u16, codes: Vec<u8>) {
+        if !self.frames.lock().await.unreserve(message_id) {
+            return;
+        }
+        if let Some(PendingAck::Subscribe(_, senders)) = self.acks.lock().await.pending.remove(&message_id) {
+            for sender in senders {
+                let _ = sender.send(Ok(SubscribeAck { message_id, codes: codes.clone() }));
+            }
+        }
+    }
+
+    ///
+    /// 收到 UNSUBACK, 释放报文标识符并完成对应的取消订阅
+    ///
+    pub(crate) async fn unsubscribe_acked(&self, message_id: u16, code: ReasonPhrases) {
+        if !self.frames.lock().await.unreserve(message_id) {
+            return;
+        }
+        if let Some(PendingAck::Unsubscribe(sender)) = self.acks.lock().await.pending.remove(&message_id) {
+            let _ = sender.send(Ok(UnsubscribeAck { message_id, code }));
+        }
+    }
+
+    ///
+    /// 订阅流被释放: 没有其他流或直接订阅使用该主题过滤器时取消订阅
+    ///
+    async fn release(&self, key: u64) {
+        let filter = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            let index = match subscriptions.streams.iter().position(|(exist, _, _)| *exist == key) {
+                Some(index) => index,
+                None => return
+            };
+            let (_, filter, _) = subscriptions.streams.remove(index);
+            let used = subscriptions.pinned.contains(&filter) || subscriptions.streams.iter().any(|(_, exist, _)| *exist == filter);
+            if used {
+                return;
+            }
+            filter
+        };
+        self.unsubscribe(&filter).await;
+    }
+
+    ///
+    /// 把收到的消息交给匹配的订阅流; 订阅流的缓冲已满时丢弃该消息, 不阻塞连接的处理
+    ///
+    pub(crate) async fn dispatch(&self, msg: &PublishMessage) {
+        let senders = self.subscriptions.lock().await.route(msg);
+        for sender in senders {
+            match sender.try_send(msg.clone()) {
+                Ok(_) => {}
+                Err(TrySendError::Full(msg)) => {
+                    println!("subscription stream is full, message dropped; topic = {}", msg.topic);
+                }
+                Err(e) => {
+                    println!("failed to forward publish message to subscription; err = {:?}", e);
+                }
+            }
+        }
+    }
+
+    ///
+    /// CONNACK 中 SubscriptionIdentifierAvailable 为 0 时, 之后的订阅不再携带订阅标识符
+    ///
+    pub(crate) async fn set_subscription_identifier_available(&self, available: bool) {
+        self.subscriptions.lock().await.identifiers_unavailable = !available;
+    }
+
+    fn subscribe_message(&self, message_id: u16, topic: &str, qos: MqttQos, identifier: Option<u32>) -> Vec<u8> {
+        let mut msg = SubscribeMessage::new(message_id, topic.to_owned(), qos);
+        if self.protocol_level == MqttProtocolLevel::Level5 {
+            msg.properties = identifier.map(|identifier| vec![PropertyItem(Property::SubscriptionIdentifier, PropertyValue::Long(identifier))]);
+        }
+        self.encode(msg, MqttMessageV3::Subscribe, MqttMessageV5::Subscribe)
+    }
+
+    ///
+    /// 按会话的协议版本编码报文
+    ///
+    fn encode<M>(&self, msg: M, v3: fn(M) -> MqttMessageV3, v5: fn(M) -> MqttMessageV5) -> Vec<u8> {
+        if self.protocol_level == MqttProtocolLevel::Level5 {
+            v5(msg).to_vec().unwrap()
+        } else {
+            v3(msg).to_vec().unwrap()
+        }
+    }
+
+    pub(crate) fn frames(&self) -> &Mutex<ClientMessageFrames> {
+        &self.frames
+    }
+
+    ///
+    /// 是否在当前连接上直接发送, 持有期间新的订阅与消息等待判断结果
+    ///
+    pub(crate) async fn online(&self) -> MutexGuard<'_, bool> {
+        self.online.lock().await
+    }
+
+    ///
+    /// 连接中断: 未确认的发布在重连后以相同的报文标识符重发, 未确认的订阅在重新订阅时继续等待 SUBACK,
+    /// 未确认的取消订阅直接失败
+    ///
+    pub(crate) async fn offline(&self) {
+        let mut online = self.online.lock().await;
+        *online = false;
+        {
+            let mut acks = self.acks.lock().await;
+            for (message_id, pending) in std::mem::take(&mut acks.pending) {
+                match pending {
+                    PendingAck::Publish(sender) => {
+                        acks.pending.insert(message_id, PendingAck::Publish(sender));
+                    }
+                    PendingAck::Subscribe(filter, senders) => {
+                        acks.resubscribe.extend(senders.into_iter().map(|sender| (filter.clone(), sender)));
+                    }
+                    PendingAck::Unsubscribe(sender) => {
+                        let _ = sender.send(Err(AckError::ConnectionLost));
+                    }
+                }
+            }
+        }
+        self.frames.lock().await.clear_reserved();
+    }
+
+    ///
+    /// 服务端没有保留会话: 丢弃在之前的连接上发送过的消息流程, 对应的发布以 `ConnectionLost` 失败
+    ///
+    pub(crate) async fn reset(&self) {
+        let frames = self.frames.lock().await.reset();
+        let mut acks = self.acks.lock().await;
+        for frame in frames {
+            println!("session not present, message dropped; client = {}, message_id = {}", self.session_id, frame.message_id());
+            if let Some(PendingAck::Publish(sender)) = acks.pending.remove(&frame.message_id()) {
+                let _ = sender.send(Err(AckError::ConnectionLost));
+            }
+        }
+    }
+
+    ///
+    /// 重新订阅全部已记录的主题, 连接中断前未确认的订阅等待新的 SUBACK
+    ///
+    pub(crate) async fn resubscribe(&self) -> Vec<u8> {
+        let mut waiting = std::mem::take(&mut self.acks.lock().await.resubscribe);
+        let mut pending = vec![];
+        let mut msg = vec![];
+        {
+            let subscriptions = self.subscriptions.lock().await;
+            let mut frames = self.frames.lock().await;
+            for (topic, qos, identifier) in subscriptions.filters.iter() {
+                let message_id = match frames.reserve() {
+                    Some(message_id) => message_id,
+                    None => {
+                        println!("no packet identifier available; client = {}", self.session_id);
+                        break;
+                    }
+                };
+                let (senders, rest): (Vec<_>, Vec<_>) = waiting.into_iter().partition(|(filter, _)| filter == topic);
+                waiting = rest;
+                pending.push((message_id, PendingAck::Subscribe(topic.clone(), senders.into_iter().map(|(_, sender)| sender).collect())));
+                msg.extend(self.subscribe_message(message_id, topic, *qos, identifier.filter(|_| !subscriptions.identifiers_unavailable)));
+            }
+        }
+        self.acks.lock().await.pending.extend(pending);
+        for (_, sender) in waiting {
+            let _ = sender.send(Err(AckError::ConnectionLost));
+        }
+        msg
     }
 }
 
 #[derive(Clone)]
 pub struct ServerSession {
     sender: mpsc::Sender<HandleEvent>,
+    config: Arc<ServerConfig>,
     pub(crate) clean_session: Option<MqttCleanSession>,
+    pub(crate) session_expiry_interval: u32,
+    pub(crate) will_delay_interval: u32,
+    pub(crate) keep_alive: u16,
     client_id: Option<ClientID>,
+    /// 客户端标识与 CONNECT 中的不同 (由服务端分配或取自客户端证书), v5 在 CONNACK 中返回
+    pub(crate) assigned_client_id: bool,
+    pub(crate) username: Option<String>,
+    pub(crate) authentication_method: Option<String>,
     protocol_name: Option<String>,
     pub(crate) protocol_level: Option<MqttProtocolLevel>,
     will_flag: Option<MqttWillFlag>,
     will_qos: Option<MqttQos>,
     will_retain: Option<MqttRetain>,
     will_topic: Option<String>,
-    will_message: Option<String>,
+    will_message: Option<Bytes>,
+    peer_addr: Option<SocketAddr>,
+    peer_certificates: Option<Vec<Certificate>>,
+    peer_identity: Option<PeerIdentity>,
+    peer_credentials: Option<PeerCredentials>,
 }
 
 impl ServerSession {
-    pub fn new(sender: mpsc::Sender<HandleEvent>) -> ServerSession {
+    pub fn new(sender: mpsc::Sender<HandleEvent>, config: Arc<ServerConfig>) -> ServerSession {
         ServerSession {
+            config,
             client_id: None,
+            assigned_client_id: false,
+            username: None,
+            authentication_method: None,
             protocol_name: None,
             protocol_level: None,
             will_flag: None,
@@ -109,10 +824,17 @@ impl ServerSession {
             will_message: None,
             sender,
             clean_session: None,
+            session_expiry_interval: 0,
+            will_delay_interval: 0,
+            keep_alive: 0,
+            peer_addr: None,
+            peer_certificates: None,
+            peer_identity: None,
+            peer_credentials: None,
         }
     }
 
-    pub fn init(&mut self, client_id: ClientID, will_flag: MqttWillFlag, will_qos: MqttQos, will_retain: MqttRetain, will_topic: String, will_message: String) {
+    pub fn init(&mut self, client_id: ClientID, will_flag: MqttWillFlag, will_qos: MqttQos, will_retain: MqttRetain, will_topic: String, will_message: Bytes) {
         self.client_id = Some(client_id);
         self.will_flag = Some(will_flag);
         self.will_qos = Some(will_qos);
@@ -121,6 +843,98 @@ impl ServerSession {
         self.will_message = Some(will_message);
     }
 
+    pub(crate) fn init_peer(&mut self, peer_addr: SocketAddr, peer_certificates: Option<Vec<Certificate>>) {
+        self.peer_addr = Some(peer_addr);
+        self.peer_identity = peer_certificates.as_ref()
+            .and_then(|certs| certs.first())
+            .and_then(PeerIdentity::parse);
+        self.peer_certificates = peer_certificates;
+    }
+
+    pub(crate) fn init_credentials(&mut self, peer_credentials: Option<PeerCredentials>) {
+        self.peer_credentials = peer_credentials;
+    }
+
+    ///
+    /// 连接被拒绝时解除客户端标识, 断开时不再处理会话与遗嘱
+    ///
+    pub(crate) fn refuse(&mut self) {
+        self.client_id = None;
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    ///
+    /// v5 增强认证方法, 重新认证必须使用相同的方法
+    ///
+    pub fn authentication_method(&self) -> Option<&str> {
+        self.authentication_method.as_deref()
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    ///
+    /// TLS 客户端证书链, 第一个为客户端自身的证书
+    ///
+    pub fn peer_certificates(&self) -> Option<&[Certificate]> {
+        self.peer_certificates.as_deref()
+    }
+
+    ///
+    /// Unix 套接字对端进程的凭据
+    ///
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.peer_credentials
+    }
+
+    ///
+    /// 客户端证书中的 CN 与 SAN
+    ///
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer_identity.as_ref()
+    }
+
+    ///
+    /// 配置为 `identity_as` 用途时使用的证书身份
+    ///
+    pub(crate) fn identity_name(&self, identity_as: IdentityAs) -> Option<&str> {
+        if self.config.identity_as() != Some(identity_as) {
+            return None;
+        }
+        self.peer_identity().and_then(PeerIdentity::name)
+    }
+
+    ///
+    /// 正常断开连接时丢弃遗嘱消息
+    ///
+    pub fn clear_will(&mut self) {
+        self.will_flag = Some(MqttWillFlag::Disable);
+    }
+
+    pub(crate) fn sender(&self) -> &mpsc::Sender<HandleEvent> {
+        &self.sender
+    }
+
+    pub fn session_expiry_interval(&self) -> u32 {
+        self.session_expiry_interval
+    }
+
+    pub fn keep_alive(&self) -> u16 {
+        self.keep_alive
+    }
+
+    pub fn will_delay_interval(&self) -> u32 {
+        self.will_delay_interval
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
     pub fn get_client_id(&self) -> &ClientID {
         self.client_id.as_ref().unwrap()
     }
@@ -130,8 +944,12 @@ impl ServerSession {
         self.protocol_level = protocol_level;
     }
 
+    pub fn is_connected(&self) -> bool {
+        self.client_id.is_some()
+    }
+
     pub fn is_will_flag(&self) -> bool {
-        self.will_flag.unwrap() == MqttWillFlag::Enable
+        self.will_flag == Some(MqttWillFlag::Enable)
     }
 
     pub fn get_will_topic(&self) -> &String {
@@ -139,7 +957,7 @@ impl ServerSession {
     }
 
     pub fn get_will_message(&self) -> Option<TopicMessage> {
-        return match self.protocol_level.as_ref().unwrap() {
+        match self.protocol_level.as_ref().unwrap() {
             MqttProtocolLevel::Level3_1_1 => {
                 Some(
                     TopicMessage::generate_v3_topic_message(
@@ -163,7 +981,42 @@ impl ServerSession {
                 )
             }
             _ => None
+        }
+    }
+}
+
+impl ServerSession {
+    ///
+    /// 检查主题授权, 没有配置授权器时全部允许
+    ///
+    pub async fn authorize(&self, action: Action, topic: &str) -> bool {
+        match self.config.authorizer() {
+            Some(authorizer) => authorizer.authorize(&self.get_client_id().0, self.username(), action, topic).await,
+            None => true
+        }
+    }
+
+    ///
+    /// 按订阅选项订阅主题过滤器, 返回需要发送的保留消息
+    ///
+    pub async fn subscribe_with_option(&self, topic: &str, option: SubscriptOption) -> Vec<PublishMessage> {
+        let exist = SUBSCRIPT.subscript_with_option(topic, self.get_client_id(), self.sender.clone(), option).await;
+        let send_retained = match option.retain_handling {
+            0 => true,
+            1 => !exist,
+            _ => false
         };
+        if !send_retained || !self.config.retain_available() {
+            return vec![];
+        }
+        RETAIN_CONTAINER.matches(topic).await
+            .into_iter()
+            .map(|mut msg| {
+                msg.qos = msg.qos.min(option.qos);
+                identify(&mut msg, option.identifier.as_slice());
+                msg
+            })
+            .collect()
     }
 }
 
@@ -174,18 +1027,25 @@ impl MqttSession for ServerSession {
     }
 
     async fn publish(&self, msg: &PublishMessage) {
+        if !self.authorize(Action::Publish, &msg.topic).await {
+            println!("publish not authorized; client = {:?}, topic = {}", self.get_client_id(), msg.topic);
+            return;
+        }
+        if msg.retain == MqttRetain::Enable && self.config.retain_available() {
+            RETAIN_CONTAINER.retain(msg).await;
+        }
         let topic_msg = TopicMessage::Content(self.get_client_id().to_owned(), msg.clone());
         println!("topic: {:?}", topic_msg);
         SUBSCRIPT.broadcast(&msg.topic, &topic_msg).await;
     }
 
-    async fn subscribe(&self, topic: &String) {
+    async fn subscribe(&self, topic: &str) {
         println!("{:?}", topic);
-        if SUBSCRIPT.contain(topic).await {
-            SUBSCRIPT.subscript(topic, self.get_client_id(), self.sender.clone());
-        } else {
-            SUBSCRIPT.new_subscript(topic, self.get_client_id(), self.sender.clone()).await;
+        if !self.authorize(Action::Subscribe, topic).await {
+            println!("subscribe not authorized; client = {:?}, topic = {}", self.get_client_id(), topic);
+            return;
         }
+        SUBSCRIPT.subscript(topic, self.get_client_id(), self.sender.clone()).await;
         println!("broadcast topic len: {}", SUBSCRIPT.len().await);
         println!("broadcast topic list: {:?}", SUBSCRIPT.topics().await);
         println!("broadcast client len: {:?}", SUBSCRIPT.client_len(topic).await);
@@ -1,19 +1,21 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use bytes::Bytes;
 use tokio::sync::mpsc::Sender;
 
 use tokio::sync::Mutex;
 use crate::handle::HandleEvent;
-use crate::hex::{Property, PropertyItem, PropertyValue};
-use crate::message::entity::PublishMessage;
-use crate::tools::protocol::{MqttDup, MqttQos, MqttRetain};
+use crate::hex::{find_property, Property, PropertyItem, PropertyValue};
+use crate::MESSAGE_CONTAINER;
+use crate::message::entity::{PublishMessage, SubscribeMessage};
+use crate::tools::protocol::{MqttDup, MqttNoLocal, MqttQos, MqttRetain, MqttRetainAsPublished};
 
-#[derive(Debug, Clone, Eq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct ClientID(pub String);
 
 impl AsRef<ClientID> for ClientID {
     fn as_ref(&self) -> &ClientID {
-        &self
+        self
     }
 }
 
@@ -35,23 +37,13 @@ impl From<&ClientID> for ClientID {
     }
 }
 
-impl PartialEq for ClientID {
-    fn eq(&self, other: &Self) -> bool {
-        PartialEq::eq(&self.0, &other.0)
-    }
-
-    fn ne(&self, other: &Self) -> bool {
-        PartialEq::ne(&self.0, &other.0)
-    }
-}
-
 #[derive(Debug, Clone)]
 pub enum TopicMessage {
     Content(ClientID, PublishMessage)
 }
 
 impl TopicMessage {
-    pub fn generate_v3_topic_message(client_id: ClientID, will_qos: MqttQos, will_retain: MqttRetain, will_topic: String, will_message: String) -> TopicMessage {
+    pub fn generate_v3_topic_message(client_id: ClientID, will_qos: MqttQos, will_retain: MqttRetain, will_topic: String, will_message: Bytes) -> TopicMessage {
         let msg = PublishMessage::new(
             will_qos,
             MqttDup::Disable,
@@ -64,7 +56,7 @@ impl TopicMessage {
         TopicMessage::Content(client_id, msg)
     }
 
-    pub fn generate_v5_topic_message(client_id: ClientID, will_qos: MqttQos, will_retain: MqttRetain, will_topic: String, will_message: String) -> TopicMessage {
+    pub fn generate_v5_topic_message(client_id: ClientID, will_qos: MqttQos, will_retain: MqttRetain, will_topic: String, will_message: Bytes) -> TopicMessage {
         let msg = PublishMessage::new(
             will_qos,
             MqttDup::Disable,
@@ -72,82 +64,325 @@ impl TopicMessage {
             will_topic,
             0,
             will_message,
-            Some(vec![
-                PropertyItem(Property::TopicAlias, PropertyValue::Byte(1)),
-                PropertyItem(Property::MessageExpiryInterval, PropertyValue::Byte(12)),
-            ]),
+            Some(vec![]),
         );
         TopicMessage::Content(client_id, msg)
     }
 }
 
+///
+/// 订阅选项
+///
+#[derive(Debug, Copy, Clone)]
+pub struct SubscriptOption {
+    pub qos: MqttQos,
+    pub no_local: MqttNoLocal,
+    pub retain_as_published: MqttRetainAsPublished,
+    pub retain_handling: u8,
+    pub identifier: Option<u32>,
+}
+
+impl Default for SubscriptOption {
+    fn default() -> Self {
+        SubscriptOption {
+            qos: MqttQos::Qos2,
+            no_local: MqttNoLocal::Disable,
+            retain_as_published: MqttRetainAsPublished::Disable,
+            retain_handling: 0,
+            identifier: None,
+        }
+    }
+}
+
+impl From<&SubscribeMessage> for SubscriptOption {
+    fn from(msg: &SubscribeMessage) -> Self {
+        SubscriptOption {
+            qos: msg.qos.unwrap_or(MqttQos::Qos0),
+            no_local: msg.no_local.unwrap_or(MqttNoLocal::Disable),
+            retain_as_published: msg.retain_as_published.unwrap_or(MqttRetainAsPublished::Disable),
+            retain_handling: msg.retain_handling.unwrap_or(0),
+            identifier: find_property(msg.properties.as_deref(), Property::SubscriptionIdentifier).and_then(PropertyItem::as_long),
+        }
+    }
+}
+
+impl SubscriptOption {
+    ///
+    /// 按订阅选项调整转发给订阅者的消息: QoS 取两者较小值, 未设置 retain_as_published 时清除 retain 标识
+    ///
+    pub fn apply(&self, msg: &PublishMessage) -> PublishMessage {
+        let mut msg = msg.clone();
+        msg.qos = msg.qos.min(self.qos);
+        if self.retain_as_published == MqttRetainAsPublished::Disable {
+            msg.retain = MqttRetain::Disable;
+        }
+        msg
+    }
+}
+
+///
+/// 把匹配的 v5 订阅标识符附加到转发的消息中, 属性因订阅者而不同, 不再使用共享的编码缓存
+///
+pub fn identify(msg: &mut PublishMessage, identifiers: &[u32]) {
+    if identifiers.is_empty() {
+        return;
+    }
+    let properties = msg.properties.get_or_insert_with(Vec::new);
+    properties.retain(|item| item.0 != Property::SubscriptionIdentifier);
+    properties.extend(identifiers.iter().map(|identifier| PropertyItem(Property::SubscriptionIdentifier, PropertyValue::Long(*identifier))));
+    msg.cache = None;
+}
+
+///
+/// 订阅者, 持久会话离线时 `sender` 为 `None`
+///
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    pub sender: Option<Sender<HandleEvent>>,
+    pub option: SubscriptOption,
+    /// v5 订阅标识符, 匹配时合并同一客户端重叠订阅的订阅标识符
+    pub identifiers: Vec<u32>,
+}
+
 #[derive(Debug)]
 pub struct Topic {
     name: String,
-    senders: HashMap<ClientID, Sender<HandleEvent>>,
+    subscribers: HashMap<ClientID, Subscriber>,
 }
 
 impl Topic {
     pub fn new<S: Into<String>>(name: S) -> Topic {
-        Topic { name: name.into(), senders: HashMap::new() }
+        Topic { name: name.into(), subscribers: HashMap::new() }
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
     }
 }
 
 impl Topic {
     pub fn subscript<S: Into<ClientID>>(&mut self, client_id: S, sender: Sender<HandleEvent>) {
+        self.subscript_with_option(client_id, sender, SubscriptOption::default());
+    }
+
+    pub fn subscript_with_option<S: Into<ClientID>>(&mut self, client_id: S, sender: Sender<HandleEvent>, option: SubscriptOption) -> Option<Subscriber> {
         let id = client_id.into();
         println!("subscript client id: {:?}", &id);
-        self.senders.insert(id, sender);
+        self.subscribers.insert(id, Subscriber { sender: Some(sender), option, identifiers: option.identifier.into_iter().collect() })
     }
 
     pub fn unsubscript<S: AsRef<ClientID>>(&mut self, client_id: S) -> Option<Sender<HandleEvent>> {
-        if self.senders.contains_key(client_id.as_ref()) {
-            return self.senders.remove(client_id.as_ref());
-        }
-        None
+        self.subscribers.remove(client_id.as_ref()).and_then(|subscriber| subscriber.sender)
     }
 
     pub fn client_id_list(&self) -> Vec<ClientID> {
-        self.senders.keys().cloned().collect::<Vec<ClientID>>()
+        self.subscribers.keys().cloned().collect::<Vec<ClientID>>()
     }
 
     pub fn client_len(&self) -> usize {
-        self.senders.len()
+        self.subscribers.len()
     }
 
     pub async fn broadcast(&self, msg: &TopicMessage) {
-        for (_, sender) in self.senders.iter() {
-            // sender.send(LinkMessage::HandleMessage(msg.clone())).await;
-            if let Err(e) = sender.send(HandleEvent::BroadcastEvent(msg.clone())).await{
+        let TopicMessage::Content(from, content) = msg;
+        let msg = TopicMessage::Content(from.clone(), content.shared());
+        for sender in self.subscribers.values().filter_map(|subscriber| subscriber.sender.as_ref()) {
+            if let Err(e) = sender.send(HandleEvent::BroadcastEvent(msg.clone())).await {
                 println!("failed to broadcast message; err = {:?}", e);
             }
         }
     }
 
     pub fn contain<S: AsRef<ClientID>>(&self, client_id: S) -> bool {
-        self.senders.contains_key(client_id.as_ref())
+        self.subscribers.contains_key(client_id.as_ref())
+    }
+}
+
+///
+/// 校验主题过滤器: `#` 只能单独出现在最后一层, `+` 必须占据整层
+///
+pub fn is_valid_topic_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.contains('\0') {
+        return false;
+    }
+    let levels = filter.split('/').collect::<Vec<&str>>();
+    let last = levels.len() - 1;
+    levels.iter().enumerate().all(|(index, level)| {
+        match *level {
+            "#" => index == last,
+            "+" => true,
+            _ => !level.contains('#') && !level.contains('+')
+        }
+    })
+}
+
+///
+/// 校验主题名: 不能为空, 不能包含通配符
+///
+pub fn is_valid_topic_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['#', '+', '\0'])
+}
+
+///
+/// 主题名是否匹配主题过滤器
+///
+/// 以 `$` 开头的主题名不会被以通配符开头的过滤器匹配
+///
+pub fn topic_matches(filter: &str, name: &str) -> bool {
+    if name.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut name_levels = name.split('/');
+    loop {
+        match (filter_levels.next(), name_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(n)) if f == n => {}
+            (None, None) => return true,
+            _ => return false
+        }
+    }
+}
+
+///
+/// 主题过滤器前缀树, 每层对应过滤器中以 `/` 分隔的一级
+///
+#[derive(Debug, Default)]
+struct TopicNode {
+    topic: Option<Topic>,
+    children: HashMap<String, TopicNode>,
+}
+
+impl TopicNode {
+    fn get(&self, filter: &str) -> Option<&Topic> {
+        filter.split('/')
+            .try_fold(self, |node, level| node.children.get(level))
+            .and_then(|node| node.topic.as_ref())
+    }
+
+    fn get_mut(&mut self, filter: &str) -> Option<&mut Topic> {
+        filter.split('/')
+            .try_fold(self, |node, level| node.children.get_mut(level))
+            .and_then(|node| node.topic.as_mut())
+    }
+
+    fn entry(&mut self, filter: &str) -> &mut Option<Topic> {
+        &mut filter.split('/')
+            .fold(self, |node, level| node.children.entry(level.to_owned()).or_default())
+            .topic
+    }
+
+    fn remove(&mut self, filter: &str) -> Option<Topic> {
+        let levels = filter.split('/').collect::<Vec<&str>>();
+        self.remove_levels(&levels)
+    }
+
+    fn remove_levels(&mut self, levels: &[&str]) -> Option<Topic> {
+        match levels.split_first() {
+            None => self.topic.take(),
+            Some((level, rest)) => {
+                let child = self.children.get_mut(*level)?;
+                let topic = child.remove_levels(rest);
+                if child.is_empty() {
+                    self.children.remove(*level);
+                }
+                topic
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.topic.is_none() && self.children.is_empty()
+    }
+
+    fn topics(&self) -> Vec<&Topic> {
+        let mut topics = vec![];
+        self.collect(&mut topics);
+        topics
+    }
+
+    fn collect<'a>(&'a self, topics: &mut Vec<&'a Topic>) {
+        if let Some(topic) = self.topic.as_ref() {
+            topics.push(topic);
+        }
+        for child in self.children.values() {
+            child.collect(topics);
+        }
+    }
+
+    fn retain<F: FnMut(&mut Topic) -> bool>(&mut self, f: &mut F) {
+        if let Some(topic) = self.topic.as_mut() {
+            if !f(topic) {
+                self.topic = None;
+            }
+        }
+        self.children.retain(|_, child| {
+            child.retain(f);
+            !child.is_empty()
+        });
+    }
+
+    ///
+    /// 查找所有匹配主题名的过滤器
+    ///
+    fn matches(&self, name: &str) -> Vec<&Topic> {
+        let levels = name.split('/').collect::<Vec<&str>>();
+        let mut topics = vec![];
+        self.match_levels(&levels, name.starts_with('$'), &mut topics);
+        topics
+    }
+
+    fn match_levels<'a>(&'a self, levels: &[&str], skip_wildcard: bool, topics: &mut Vec<&'a Topic>) {
+        if !skip_wildcard {
+            if let Some(topic) = self.children.get("#").and_then(|node| node.topic.as_ref()) {
+                topics.push(topic);
+            }
+        }
+        match levels.split_first() {
+            None => {
+                if let Some(topic) = self.topic.as_ref() {
+                    topics.push(topic);
+                }
+            }
+            Some((level, rest)) => {
+                if !skip_wildcard {
+                    if let Some(node) = self.children.get("+") {
+                        node.match_levels(rest, false, topics);
+                    }
+                }
+                if let Some(node) = self.children.get(*level) {
+                    node.match_levels(rest, false, topics);
+                }
+            }
+        }
     }
 }
 
+#[derive(Default)]
 pub struct Subscript {
-    container: Arc<Mutex<HashMap<String, Topic>>>,
+    container: Arc<Mutex<TopicNode>>,
 }
 
 impl Subscript {
     pub fn new() -> Subscript {
-        Subscript { container: Arc::new(Mutex::new(HashMap::default())) }
+        Subscript { container: Arc::new(Mutex::new(TopicNode::default())) }
     }
 
     pub async fn contain<S: AsRef<str>>(&self, topic_name: S) -> bool {
-        self.container.lock().await.contains_key(topic_name.as_ref())
+        self.container.lock().await.get(topic_name.as_ref()).is_some()
     }
 
     pub async fn len(&self) -> usize {
-        self.container.lock().await.len()
+        self.container.lock().await.topics().len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.container.lock().await.is_empty()
     }
 
     pub async fn add<S: Into<String>>(&self, topic_name: S, topic: Topic) -> Option<Topic> {
-        self.container.lock().await.insert(topic_name.into(), topic)
+        self.container.lock().await.entry(&topic_name.into()).replace(topic)
     }
 
     pub async fn remove<S: AsRef<str>>(&self, topic_name: S) -> Option<Topic> {
@@ -155,57 +390,268 @@ impl Subscript {
     }
 
     pub async fn is_subscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS) -> bool {
-        self.container.lock().await.get(topic_name.as_ref()).unwrap().contain(client_id)
+        self.container.lock().await.get(topic_name.as_ref()).is_some_and(|topic| topic.contain(client_id))
     }
 
     pub async fn new_subscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS, sender: Sender<HandleEvent>) {
-        let mut top = Topic::new(topic_name.as_ref());
-        top.subscript(client_id.as_ref(), sender);
-        self.add(topic_name.as_ref(), top).await;
+        self.subscript(topic_name, client_id, sender).await;
     }
 
-    pub fn subscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS, sender: Sender<HandleEvent>) {
-        match self.container.try_lock() {
-            Ok(mut container) => {
-                if let Some(t) = container.get_mut(topic_name.as_ref()) {
-                    t.subscript(client_id.as_ref(), sender);
-                }
-            }
-            Err(e) => {
-                println!("{:?}", e)
-            }
+    pub async fn subscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS, sender: Sender<HandleEvent>) {
+        self.subscript_with_option(topic_name, client_id, sender, SubscriptOption::default()).await;
+    }
+
+    ///
+    /// 添加订阅, 返回该客户端此前是否已订阅同一主题过滤器
+    ///
+    pub async fn subscript_with_option<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS, sender: Sender<HandleEvent>, option: SubscriptOption) -> bool {
+        if !is_valid_topic_filter(topic_name.as_ref()) {
+            println!("invalid topic filter: {}", topic_name.as_ref());
+            return false;
         }
+        self.container.lock().await
+            .entry(topic_name.as_ref())
+            .get_or_insert_with(|| Topic::new(topic_name.as_ref()))
+            .subscript_with_option(client_id.as_ref(), sender, option)
+            .is_some()
     }
 
-    pub async fn unsubscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS) {
-        self.container.lock().await.get_mut(topic_name.as_ref()).unwrap().unsubscript(client_id);
+    ///
+    /// 取消订阅, 返回该客户端此前是否订阅了这个主题过滤器
+    ///
+    pub async fn unsubscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS) -> bool {
+        let mut container = self.container.lock().await;
+        let topic = match container.get_mut(topic_name.as_ref()) {
+            Some(topic) => topic,
+            None => return false
+        };
+        let exist = topic.subscribers.contains_key(client_id.as_ref());
+        topic.unsubscript(client_id);
+        if topic.client_len() == 0 {
+            container.remove(topic_name.as_ref());
+        }
+        exist
     }
 
     pub async fn exit<S: AsRef<ClientID>>(&self, client_id: S) {
-        for (_, topic) in self.container.lock().await.iter_mut() {
+        self.container.lock().await.retain(&mut |topic: &mut Topic| {
             topic.unsubscript(client_id.as_ref());
-        }
+            topic.client_len() > 0
+        });
+    }
+
+    ///
+    /// 持久会话断开连接: 保留订阅, 之后匹配的消息进入离线队列
+    ///
+    pub async fn offline<S: AsRef<ClientID>>(&self, client_id: S) {
+        self.container.lock().await.retain(&mut |topic: &mut Topic| {
+            if let Some(subscriber) = topic.subscribers.get_mut(client_id.as_ref()) {
+                subscriber.sender = None;
+            }
+            true
+        });
+    }
+
+    ///
+    /// 持久会话重新连接: 将保留的订阅绑定到新的连接
+    ///
+    pub async fn online<S: AsRef<ClientID>>(&self, client_id: S, sender: &Sender<HandleEvent>) {
+        self.container.lock().await.retain(&mut |topic: &mut Topic| {
+            if let Some(subscriber) = topic.subscribers.get_mut(client_id.as_ref()) {
+                subscriber.sender = Some(sender.clone());
+            }
+            true
+        });
     }
 
     pub async fn topics(&self) -> Vec<String> {
-        self.container.lock().await.keys().cloned().collect::<Vec<String>>()
+        self.container.lock().await.topics().iter().map(|topic| topic.name.clone()).collect::<Vec<String>>()
+    }
+
+    ///
+    /// 客户端订阅的全部主题过滤器以及订阅选项
+    ///
+    pub async fn subscriptions<S: AsRef<ClientID>>(&self, client_id: S) -> Vec<(String, SubscriptOption)> {
+        self.container.lock().await.topics().iter()
+            .filter_map(|topic| topic.subscribers.get(client_id.as_ref()).map(|subscriber| (topic.name.clone(), subscriber.option)))
+            .collect()
     }
 
     pub async fn clients<S: AsRef<str>>(&self, topic_name: S) -> Vec<ClientID> {
-        self.container.lock().await.get(topic_name.as_ref()).unwrap().client_id_list()
+        self.container.lock().await.get(topic_name.as_ref()).map(|topic| topic.client_id_list()).unwrap_or_default()
     }
 
     pub async fn client_len<S: AsRef<str>>(&self, topic_name: S) -> usize {
-        self.container.lock().await.get(topic_name.as_ref()).unwrap().client_len()
+        self.container.lock().await.get(topic_name.as_ref()).map_or(0, |topic| topic.client_len())
+    }
+
+    ///
+    /// 查找订阅了匹配主题名的客户端
+    ///
+    /// 同一客户端的多个重叠订阅只返回一次, QoS 取最大值并合并订阅标识符; 设置了 no_local 的订阅不匹配发布者自己
+    ///
+    pub async fn matches<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, from: SS) -> HashMap<ClientID, Subscriber> {
+        let mut subscribers: HashMap<ClientID, Subscriber> = HashMap::new();
+        for topic in self.container.lock().await.matches(topic_name.as_ref()) {
+            for (client_id, subscriber) in topic.subscribers.iter() {
+                if subscriber.option.no_local == MqttNoLocal::Enable && client_id == from.as_ref() {
+                    continue;
+                }
+                match subscribers.get_mut(client_id) {
+                    Some(exist) => {
+                        exist.option.qos = exist.option.qos.max(subscriber.option.qos);
+                        exist.option.retain_as_published = exist.option.retain_as_published.max(subscriber.option.retain_as_published);
+                        exist.identifiers.extend(subscriber.identifiers.iter().copied());
+                    }
+                    None => {
+                        subscribers.insert(client_id.clone(), subscriber.clone());
+                    }
+                }
+            }
+        }
+        subscribers
     }
 
     pub async fn broadcast<S: AsRef<str>>(&self, topic_name: S, msg: &TopicMessage) {
-        if let Some(t) = self.container.lock().await.get(topic_name.as_ref()) {
-            t.broadcast(msg).await
+        if !is_valid_topic_name(topic_name.as_ref()) {
+            println!("invalid topic name: {}", topic_name.as_ref());
+            return;
+        }
+        let TopicMessage::Content(from, content) = msg;
+        // 所有订阅者共享载荷与编码缓存, 每个订阅者只需要填入报文标识符与标志位
+        let content = content.shared();
+        for (client_id, subscriber) in self.matches(topic_name, from).await {
+            let mut msg = subscriber.option.apply(&content);
+            identify(&mut msg, &subscriber.identifiers);
+            let sender = match subscriber.sender {
+                Some(sender) => sender,
+                None => {
+                    MESSAGE_CONTAINER.enqueue(&client_id, from.clone(), msg).await;
+                    continue;
+                }
+            };
+            if let Err(e) = sender.send(HandleEvent::BroadcastEvent(TopicMessage::Content(from.clone(), msg))).await {
+                println!("failed to broadcast message; err = {:?}", e);
+                if let HandleEvent::BroadcastEvent(TopicMessage::Content(from, msg)) = e.0 {
+                    MESSAGE_CONTAINER.enqueue(&client_id, from, msg).await;
+                }
+            }
+        }
+    }
+
+    pub async fn get_client<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS) -> Option<Sender<HandleEvent>> {
+        self.container.lock().await.get(topic_name.as_ref()).and_then(|topic| topic.subscribers.get(client_id.as_ref()).and_then(|subscriber| subscriber.sender.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn filter_validation() {
+        assert!(is_valid_topic_filter("sport/tennis/player1/#"));
+        assert!(is_valid_topic_filter("sport/#"));
+        assert!(is_valid_topic_filter("#"));
+        assert!(is_valid_topic_filter("+"));
+        assert!(is_valid_topic_filter("+/tennis/#"));
+        assert!(is_valid_topic_filter("sport/+/player1"));
+        assert!(is_valid_topic_filter("/finance"));
+        assert!(!is_valid_topic_filter(""));
+        assert!(!is_valid_topic_filter("sport/tennis#"));
+        assert!(!is_valid_topic_filter("sport/tennis/#/ranking"));
+        assert!(!is_valid_topic_filter("sport+"));
+
+        assert!(is_valid_topic_name("sport/tennis"));
+        assert!(!is_valid_topic_name(""));
+        assert!(!is_valid_topic_name("sport/+"));
+        assert!(!is_valid_topic_name("sport/#"));
+    }
+
+    #[test]
+    fn multi_level_wildcard() {
+        assert!(topic_matches("sport/tennis/player1/#", "sport/tennis/player1"));
+        assert!(topic_matches("sport/tennis/player1/#", "sport/tennis/player1/ranking"));
+        assert!(topic_matches("sport/tennis/player1/#", "sport/tennis/player1/score/wimbledon"));
+        assert!(topic_matches("sport/#", "sport"));
+        assert!(topic_matches("#", "sport/tennis"));
+        assert!(!topic_matches("sport/tennis/#", "sport/football"));
+    }
+
+    #[test]
+    fn single_level_wildcard() {
+        assert!(topic_matches("sport/tennis/+", "sport/tennis/player1"));
+        assert!(topic_matches("sport/tennis/+", "sport/tennis/player2"));
+        assert!(!topic_matches("sport/tennis/+", "sport/tennis/player1/ranking"));
+        assert!(!topic_matches("sport/+", "sport"));
+        assert!(topic_matches("sport/+", "sport/"));
+        assert!(topic_matches("+/+", "/finance"));
+        assert!(topic_matches("/+", "/finance"));
+        assert!(!topic_matches("+", "/finance"));
+        assert!(topic_matches("+/tennis/#", "sport/tennis/player1"));
+    }
+
+    #[test]
+    fn dollar_topics() {
+        assert!(!topic_matches("#", "$SYS/broker/clients"));
+        assert!(!topic_matches("+/monitor/Clients", "$SYS/monitor/Clients"));
+        assert!(topic_matches("$SYS/#", "$SYS/monitor/Clients"));
+        assert!(topic_matches("$SYS/monitor/+", "$SYS/monitor/Clients"));
+    }
+
+    fn filters(node: &TopicNode, name: &str) -> Vec<String> {
+        let mut names = node.matches(name).iter().map(|topic| topic.name.clone()).collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn trie_matches() {
+        let patterns = ["sport/tennis/player1/#", "sport/tennis/+", "sport/#", "#", "+/tennis/#", "/+", "+/+", "$SYS/#", "sport/tennis/player1"];
+        let mut node = TopicNode::default();
+        for pattern in patterns.iter() {
+            node.entry(pattern).replace(Topic::new(*pattern));
+        }
+
+        assert_eq!(filters(&node, "sport/tennis/player1"), vec!["#", "+/tennis/#", "sport/#", "sport/tennis/+", "sport/tennis/player1", "sport/tennis/player1/#"]);
+        assert_eq!(filters(&node, "sport"), vec!["#", "sport/#"]);
+        assert_eq!(filters(&node, "/finance"), vec!["#", "+/+", "/+"]);
+        assert_eq!(filters(&node, "$SYS/broker"), vec!["$SYS/#"]);
+
+        for name in ["sport/tennis/player1", "sport", "/finance", "$SYS/broker", "a/b/c"].iter() {
+            let expected = patterns.iter().filter(|p| topic_matches(p, name)).count();
+            assert_eq!(node.matches(name).len(), expected, "{}", name);
+        }
+
+        assert!(node.remove("sport/tennis/player1/#").is_some());
+        assert!(node.remove("sport/tennis/player1/#").is_none());
+        assert!(node.get("sport/tennis/player1").is_some());
+        for pattern in patterns.iter() {
+            node.remove(pattern);
         }
+        assert!(node.is_empty());
     }
 
-    pub async fn get_client<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS) -> Sender<HandleEvent> {
-        self.container.lock().await.get(topic_name.as_ref()).unwrap().senders.get(client_id.as_ref()).unwrap().clone()
+    #[tokio::test]
+    async fn broadcast_wildcard() {
+        let subscript = Subscript::new();
+        let (sender, mut receiver) = mpsc::channel(8);
+        let (other_sender, mut other_receiver) = mpsc::channel(8);
+        subscript.subscript("sensors/+/temp", ClientID::from("a"), sender.clone()).await;
+        subscript.subscript("sensors/#", ClientID::from("a"), sender).await;
+        subscript.subscript("home/#", ClientID::from("b"), other_sender).await;
+        assert_eq!(subscript.len().await, 3);
+
+        let msg = TopicMessage::Content(ClientID::from("c"), PublishMessage::new(MqttQos::Qos0, MqttDup::Disable, MqttRetain::Disable, "sensors/kitchen/temp".to_owned(), 0, "21".to_owned(), None));
+        subscript.broadcast("sensors/kitchen/temp", &msg).await;
+        assert!(matches!(receiver.try_recv(), Ok(HandleEvent::BroadcastEvent(_))));
+        assert!(receiver.try_recv().is_err());
+        assert!(other_receiver.try_recv().is_err());
+
+        subscript.exit(ClientID::from("a")).await;
+        assert_eq!(subscript.topics().await, vec!["home/#".to_owned()]);
+        subscript.unsubscript("home/#", ClientID::from("b")).await;
+        assert!(subscript.is_empty().await);
     }
 }
@@ -1,5 +1,11 @@
-use crate::tools::protocol::{MqttProtocolLevel, MQTT_PROTOCOL_NAME, MqttWillFlag, MqttQos, MqttRetain};
-use crate::hex::Property;
+use crate::tools::protocol::{MqttCleanSession, MqttProtocolLevel, MQTT_PROTOCOL_NAME, MqttWillFlag, MqttQos, MqttRetain};
+use crate::hex::{Property, PropertyItem, PropertyValue};
+use std::sync::Arc;
+use bytes::Bytes;
+use std::time::Duration;
+use crate::auth::{AuthMechanism, Authenticator, Authorizer};
+use crate::tools::framer::MAX_PACKET_SIZE;
+use crate::tools::tls::IdentityAs;
 
 #[derive(Debug, Clone)]
 pub struct Will {
@@ -7,10 +13,90 @@ pub struct Will {
     will_qos: MqttQos,
     will_retain: MqttRetain,
     will_topic: Option<String>,
-    will_message: Option<String>,
+    will_message: Option<Bytes>,
+    properties: Vec<PropertyItem>,
+}
+
+///
+/// 设置属性, 已存在的同名属性被替换
+///
+fn set_property(properties: &mut Vec<PropertyItem>, property: Property, value: PropertyValue) {
+    properties.retain(|item| item.0 != property);
+    properties.push(PropertyItem(property, value));
 }
 
 impl Will {
+    ///
+    /// 遗嘱消息, 连接非正常断开时由服务端发布到 `topic`
+    ///
+    pub fn new<S: Into<String>, B: Into<Bytes>>(topic: S, message: B) -> Will {
+        Will {
+            will_flag: MqttWillFlag::Enable,
+            will_qos: MqttQos::Qos0,
+            will_retain: MqttRetain::Disable,
+            will_topic: Some(topic.into()),
+            will_message: Some(message.into()),
+            properties: vec![],
+        }
+    }
+
+    pub fn qos(mut self, qos: MqttQos) -> Will {
+        self.will_qos = qos;
+        self
+    }
+
+    pub fn retain(mut self, retain: MqttRetain) -> Will {
+        self.will_retain = retain;
+        self
+    }
+
+    ///
+    /// v5 WillDelayInterval: 连接断开后延迟发布遗嘱的秒数
+    ///
+    pub fn delay_interval(mut self, seconds: u32) -> Will {
+        set_property(&mut self.properties, Property::WillDelayInterval, PropertyValue::Long(seconds));
+        self
+    }
+
+    ///
+    /// v5 PayloadFormatIndicator: 为 `true` 时遗嘱消息是 UTF-8 字符串
+    ///
+    pub fn payload_format_indicator(mut self, utf8: bool) -> Will {
+        set_property(&mut self.properties, Property::PayloadFormatIndicator, PropertyValue::Byte(utf8 as u8));
+        self
+    }
+
+    ///
+    /// v5 MessageExpiryInterval: 遗嘱消息的有效秒数
+    ///
+    pub fn message_expiry_interval(mut self, seconds: u32) -> Will {
+        set_property(&mut self.properties, Property::MessageExpiryInterval, PropertyValue::Long(seconds));
+        self
+    }
+
+    pub fn content_type<S: Into<String>>(mut self, content_type: S) -> Will {
+        set_property(&mut self.properties, Property::ContentType, PropertyValue::String(content_type.into()));
+        self
+    }
+
+    pub fn response_topic<S: Into<String>>(mut self, response_topic: S) -> Will {
+        set_property(&mut self.properties, Property::ResponseTopic, PropertyValue::String(response_topic.into()));
+        self
+    }
+
+    pub fn correlation_data<B: Into<Vec<u8>>>(mut self, correlation_data: B) -> Will {
+        set_property(&mut self.properties, Property::CorrelationData, PropertyValue::Binary(correlation_data.into()));
+        self
+    }
+
+    ///
+    /// v5 UserProperty, 可以多次设置, 按顺序发送
+    ///
+    pub fn user_property<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Will {
+        self.properties.push(PropertyItem(Property::UserProperty, PropertyValue::Map(key.into(), value.into())));
+        self
+    }
+
     pub fn will_flag(&self) -> MqttWillFlag {
         self.will_flag
     }
@@ -23,9 +109,34 @@ impl Will {
     pub fn will_topic(&self) -> Option<String> {
         self.will_topic.to_owned()
     }
-    pub fn will_message(&self) -> Option<String> {
+    pub fn will_message(&self) -> Option<Bytes> {
         self.will_message.to_owned()
     }
+    ///
+    /// 以 UTF-8 字符串读取遗嘱消息, 不是合法的 UTF-8 时返回 `None`
+    ///
+    pub fn will_message_str(&self) -> Option<&str> {
+        self.will_message.as_deref().and_then(|message| std::str::from_utf8(message).ok())
+    }
+    ///
+    /// v5 CONNECT 载荷中的遗嘱属性
+    ///
+    pub fn properties(&self) -> &[PropertyItem] {
+        &self.properties
+    }
+}
+
+impl Default for Will {
+    fn default() -> Self {
+        Will {
+            will_flag: MqttWillFlag::Disable,
+            will_qos: MqttQos::Qos0,
+            will_retain: MqttRetain::Disable,
+            will_topic: None,
+            will_message: None,
+            properties: vec![],
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -38,8 +149,9 @@ pub struct Config {
     protocol_level: MqttProtocolLevel,
     delay: u32,
     max_attempts: i32,
+    clean_session: MqttCleanSession,
     will: Will,
-    properties: Option<Property>
+    properties: Vec<PropertyItem>,
 }
 
 impl Config {
@@ -67,9 +179,18 @@ impl Config {
     pub fn max_attempts(&self) -> i32 {
         self.max_attempts
     }
+    pub fn clean_session(&self) -> MqttCleanSession {
+        self.clean_session
+    }
     pub fn will(&self) -> &Will {
         &self.will
     }
+    ///
+    /// v5 CONNECT 属性
+    ///
+    pub fn properties(&self) -> &[PropertyItem] {
+        &self.properties
+    }
 }
 
 #[derive(Debug)]
@@ -82,7 +203,9 @@ pub struct ConfigBuilder {
     protocol_level: Option<MqttProtocolLevel>,
     delay: Option<u32>,
     max_attempts: Option<i32>,
-    will: Option<Will>
+    clean_session: Option<MqttCleanSession>,
+    will: Option<Will>,
+    properties: Vec<PropertyItem>,
 }
 
 impl ConfigBuilder {
@@ -96,7 +219,9 @@ impl ConfigBuilder {
             protocol_level: None,
             delay: None,
             max_attempts: None,
-            will: None
+            clean_session: None,
+            will: None,
+            properties: vec![],
         }
     }
 
@@ -135,11 +260,76 @@ impl ConfigBuilder {
         self
     }
 
+    ///
+    /// v3 CleanSession / v5 CleanStart, 默认为 `Enable`. 为 `Disable` 时重连后继续服务端保留的会话,
+    /// 服务端没有保留会话时不再重发之前的连接上未确认的消息
+    ///
+    pub fn clean_session(mut self, clean_session: MqttCleanSession) -> ConfigBuilder {
+        self.clean_session = Option::from(clean_session);
+        self
+    }
+
     pub fn will(mut self, will: Will) -> ConfigBuilder {
         self.will = Option::from(will);
         self
     }
 
+    ///
+    /// v5 SessionExpiryInterval: 断开连接后服务端保留会话的秒数
+    ///
+    pub fn session_expiry_interval(mut self, seconds: u32) -> ConfigBuilder {
+        set_property(&mut self.properties, Property::SessionExpiryInterval, PropertyValue::Long(seconds));
+        self
+    }
+
+    ///
+    /// v5 ReceiveMaximum: 客户端同时处理的 QoS 1/2 消息数量上限
+    ///
+    pub fn receive_maximum(mut self, receive_maximum: u16) -> ConfigBuilder {
+        set_property(&mut self.properties, Property::ReceiveMaximum, PropertyValue::Short(receive_maximum));
+        self
+    }
+
+    ///
+    /// v5 MaximumPacketSize: 客户端接受的最大报文长度
+    ///
+    pub fn maximum_packet_size(mut self, maximum_packet_size: u32) -> ConfigBuilder {
+        set_property(&mut self.properties, Property::MaximumPacketSize, PropertyValue::Long(maximum_packet_size));
+        self
+    }
+
+    ///
+    /// v5 TopicAliasMaximum: 客户端接受的主题别名数量上限
+    ///
+    pub fn topic_alias_maximum(mut self, topic_alias_maximum: u16) -> ConfigBuilder {
+        set_property(&mut self.properties, Property::TopicAliasMaximum, PropertyValue::Short(topic_alias_maximum));
+        self
+    }
+
+    ///
+    /// v5 RequestResponseInformation, 未设置时客户端请求 ResponseInformation 用于创建响应主题
+    ///
+    pub fn request_response_information(mut self, request: bool) -> ConfigBuilder {
+        set_property(&mut self.properties, Property::RequestResponseInformation, PropertyValue::Byte(request as u8));
+        self
+    }
+
+    ///
+    /// v5 RequestProblemInformation: 为 `false` 时服务端只在 PUBLISH、CONNACK 与 DISCONNECT 中携带 ReasonString 与 UserProperty
+    ///
+    pub fn request_problem_information(mut self, request: bool) -> ConfigBuilder {
+        set_property(&mut self.properties, Property::RequestProblemInformation, PropertyValue::Byte(request as u8));
+        self
+    }
+
+    ///
+    /// v5 UserProperty, 可以多次设置, 按顺序发送
+    ///
+    pub fn user_property<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> ConfigBuilder {
+        self.properties.push(PropertyItem(Property::UserProperty, PropertyValue::Map(key.into(), value.into())));
+        self
+    }
+
     fn check(&self) -> bool {
         self.client_id.is_some() &&
             self.keep_alive.is_some() &&
@@ -158,14 +348,15 @@ impl ConfigBuilder {
             Config {
                 client_id: self.client_id.take().unwrap(),
                 username: self.username.take(),
-                password: self.username.take(),
+                password: self.password.take(),
                 keep_alive: self.keep_alive.take().unwrap(),
                 protocol_name: self.protocol_name.take().unwrap(),
                 protocol_level: self.protocol_level.take().unwrap(),
                 delay: self.delay.take().unwrap(),
                 max_attempts: self.max_attempts.take().unwrap(),
-                will: self.will.take().unwrap(),
-                properties: None
+                clean_session: self.clean_session.take().unwrap_or(MqttCleanSession::Enable),
+                will: self.will.take().unwrap_or_default(),
+                properties: std::mem::take(&mut self.properties),
             }
         )
     }
@@ -183,13 +374,89 @@ impl Default for ConfigBuilder {
             protocol_level: Some(MqttProtocolLevel::Level3_1_1),
             delay: Some(3000),
             max_attempts: Some(-1),
-            will: Option::from(Will {
-                will_flag: MqttWillFlag::Disable,
-                will_qos: MqttQos::Qos0,
-                will_retain: MqttRetain::Disable,
-                will_topic: None,
-                will_message: None,
-            })
+            clean_session: Some(MqttCleanSession::Enable),
+            will: Option::from(Will::default()),
+            properties: vec![],
+        }
+    }
+}
+
+///
+/// 服务端配置, 由所有连接共享
+///
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub(crate) max_packet_size: usize,
+    pub(crate) retain_available: bool,
+    pub(crate) retry_interval: Duration,
+    pub(crate) max_session_expiry_interval: u32,
+    pub(crate) server_keep_alive: Option<u16>,
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    pub(crate) authorizer: Option<Arc<dyn Authorizer>>,
+    pub(crate) auth_mechanisms: Vec<Arc<dyn AuthMechanism>>,
+    pub(crate) identity_as: Option<IdentityAs>,
+    pub(crate) protocol_levels: Option<Vec<MqttProtocolLevel>>,
+    pub(crate) shutdown_timeout: Duration,
+    pub(crate) connect_timeout: Duration,
+}
+
+impl ServerConfig {
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+    pub fn retain_available(&self) -> bool {
+        self.retain_available
+    }
+    pub fn retry_interval(&self) -> Duration {
+        self.retry_interval
+    }
+    pub fn max_session_expiry_interval(&self) -> u32 {
+        self.max_session_expiry_interval
+    }
+    pub fn server_keep_alive(&self) -> Option<u16> {
+        self.server_keep_alive
+    }
+    pub fn authenticator(&self) -> Option<&Arc<dyn Authenticator>> {
+        self.authenticator.as_ref()
+    }
+    pub fn authorizer(&self) -> Option<&Arc<dyn Authorizer>> {
+        self.authorizer.as_ref()
+    }
+    pub fn auth_mechanism(&self, method: &str) -> Option<&Arc<dyn AuthMechanism>> {
+        self.auth_mechanisms.iter().find(|mechanism| mechanism.method() == method)
+    }
+    pub fn identity_as(&self) -> Option<IdentityAs> {
+        self.identity_as
+    }
+    ///
+    /// 是否接受该协议版本的连接, 未限制时接受所有支持的版本
+    ///
+    pub fn allow_protocol_level(&self, level: MqttProtocolLevel) -> bool {
+        self.protocol_levels.as_ref().is_none_or(|levels| levels.contains(&level))
+    }
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_packet_size: MAX_PACKET_SIZE,
+            retain_available: true,
+            retry_interval: Duration::from_secs(20),
+            max_session_expiry_interval: u32::MAX,
+            server_keep_alive: None,
+            authenticator: None,
+            authorizer: None,
+            auth_mechanisms: vec![],
+            identity_as: None,
+            protocol_levels: None,
+            shutdown_timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(10),
         }
     }
 }
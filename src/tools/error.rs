@@ -0,0 +1,108 @@
+use std::fmt;
+use crate::hex::reason_code::ReasonPhrases;
+
+///
+/// 报文解析错误
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    Truncated,
+    InvalidUtf8,
+    MalformedRemainingLength,
+    InvalidQos(u8),
+    InvalidFlags(u8),
+    UnknownPacketType(u8),
+    UnsupportedProtocolLevel(u8),
+    InvalidProperty(u8),
+    InvalidReasonCode(u8),
+    PacketTooLarge(usize),
+    PayloadFormatInvalid,
+}
+
+impl DecodeError {
+    ///
+    /// 对应 v5 CONNACK / DISCONNECT 的原因码
+    ///
+    pub fn reason_code(&self) -> ReasonPhrases {
+        match self {
+            DecodeError::UnsupportedProtocolLevel(_) => ReasonPhrases::UnsupportedProtocolVersion,
+            DecodeError::PacketTooLarge(_) => ReasonPhrases::PacketTooLarge,
+            DecodeError::PayloadFormatInvalid => ReasonPhrases::PayloadFormatInvalid,
+            _ => ReasonPhrases::MalformedPacket
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "packet truncated"),
+            DecodeError::InvalidUtf8 => write!(f, "invalid utf-8 string"),
+            DecodeError::MalformedRemainingLength => write!(f, "malformed remaining length"),
+            DecodeError::InvalidQos(qos) => write!(f, "invalid qos: {}", qos),
+            DecodeError::InvalidFlags(flags) => write!(f, "invalid flags: {:#04x}", flags),
+            DecodeError::UnknownPacketType(kind) => write!(f, "unknown packet type: {}", kind),
+            DecodeError::UnsupportedProtocolLevel(level) => write!(f, "unsupported protocol level: {}", level),
+            DecodeError::InvalidProperty(property) => write!(f, "invalid property: {:#04x}", property),
+            DecodeError::InvalidReasonCode(code) => write!(f, "invalid reason code: {:#04x}", code),
+            DecodeError::PacketTooLarge(size) => write!(f, "packet too large: {} bytes", size),
+            DecodeError::PayloadFormatInvalid => write!(f, "payload is not valid utf-8"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+///
+/// 请求/响应错误
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RequestError {
+    Unsupported,
+    NotConnected,
+    Timeout,
+    Closed,
+    NoResponseTopic,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Unsupported => write!(f, "request/response requires mqtt v5"),
+            RequestError::NotConnected => write!(f, "client not connected"),
+            RequestError::Timeout => write!(f, "request timed out"),
+            RequestError::Closed => write!(f, "connection closed before response"),
+            RequestError::NoResponseTopic => write!(f, "request has no response topic"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+///
+/// 等待 PUBACK / PUBCOMP / SUBACK / UNSUBACK 失败
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AckError {
+    NotConnected,
+    NoPacketIdentifier,
+    Timeout,
+    /// 连接中断, 报文不会在重连后重发
+    ConnectionLost,
+    /// 客户端断开且不再重连
+    Closed,
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckError::NotConnected => write!(f, "client not connected"),
+            AckError::NoPacketIdentifier => write!(f, "no packet identifier available"),
+            AckError::Timeout => write!(f, "acknowledgement timed out"),
+            AckError::ConnectionLost => write!(f, "connection lost before acknowledgement"),
+            AckError::Closed => write!(f, "connection closed before acknowledgement"),
+        }
+    }
+}
+
+impl std::error::Error for AckError {}
@@ -0,0 +1,194 @@
+use crate::tools::error::DecodeError;
+
+///
+/// 剩余长度字段能表示的最大值 (Variable Byte Integer 最多 4 字节)
+///
+pub const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+///
+/// 完整报文的最大长度: 1 字节固定报头 + 4 字节剩余长度 + 剩余长度
+///
+pub const MAX_PACKET_SIZE: usize = MAX_REMAINING_LENGTH + 5;
+
+///
+/// 流式报文分帧
+///
+/// 缓存从连接读取的原始字节, 解析固定报头和剩余长度, 每次只返回一个完整报文,
+/// 用于处理被拆分或被合并的 TCP 读取
+///
+#[derive(Debug)]
+pub struct PacketFramer {
+    buffer: Vec<u8>,
+    max_packet_size: usize,
+}
+
+impl Default for PacketFramer {
+    fn default() -> Self {
+        PacketFramer::new()
+    }
+}
+
+impl PacketFramer {
+    pub fn new() -> PacketFramer {
+        PacketFramer::with_max_packet_size(MAX_PACKET_SIZE)
+    }
+
+    pub fn with_max_packet_size(max_packet_size: usize) -> PacketFramer {
+        PacketFramer { buffer: Vec::default(), max_packet_size }
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    ///
+    /// 取出下一个完整报文, 数据不足时返回 `Ok(None)`
+    ///
+    pub fn next_packet(&mut self) -> Result<Option<Vec<u8>>, DecodeError> {
+        let (remaining_length, head_bytes) = match decode_remaining_length(&self.buffer)? {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+
+        let packet_size = head_bytes + remaining_length;
+        if packet_size > self.max_packet_size {
+            return Err(DecodeError::PacketTooLarge(packet_size));
+        }
+
+        if self.buffer.len() < packet_size {
+            return Ok(None);
+        }
+
+        Ok(Some(self.buffer.drain(..packet_size).collect()))
+    }
+}
+
+///
+/// 解析缓存中的剩余长度, 返回 (剩余长度, 固定报头字节数), 数据不足时返回 `Ok(None)`
+///
+fn decode_remaining_length(data: &[u8]) -> Result<Option<(usize, usize)>, DecodeError> {
+    let (mut multiplier, mut value) = (1_usize, 0_usize);
+
+    for head_index in 1..=4 {
+        let encoded_byte = match data.get(head_index) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        value += (encoded_byte & 127) as usize * multiplier;
+        if encoded_byte & 128 == 0 {
+            return Ok(Some((value, head_index + 1)));
+        }
+        multiplier *= 128;
+    }
+
+    Err(DecodeError::MalformedRemainingLength)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use crate::message::BaseMessage;
+    use crate::message::entity::PublishMessage;
+    use crate::message::v3::MqttMessageV3;
+    use crate::packet::v3_unpacket;
+    use crate::tools::protocol::{MqttDup, MqttQos, MqttRetain};
+
+    fn publish_bytes(topic: &str, body: String, message_id: u16) -> Vec<u8> {
+        let msg = PublishMessage::new(MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable, topic.to_owned(), message_id, body, None);
+        MqttMessageV3::Publish(msg).to_vec().unwrap()
+    }
+
+    #[test]
+    fn split_packet() {
+        let packet = publish_bytes("a/b", "hello".to_owned(), 1);
+        let mut framer = PacketFramer::new();
+
+        framer.extend(&packet[..1]);
+        assert_eq!(framer.next_packet(), Ok(None));
+        framer.extend(&packet[1..4]);
+        assert_eq!(framer.next_packet(), Ok(None));
+        framer.extend(&packet[4..]);
+        assert_eq!(framer.next_packet(), Ok(Some(packet)));
+        assert!(framer.is_empty());
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let packet = publish_bytes("a/b", "x".repeat(300), 7);
+        let mut framer = PacketFramer::new();
+        let mut packets = vec![];
+        for byte in packet.iter() {
+            framer.extend(&[*byte]);
+            if let Some(p) = framer.next_packet().unwrap() {
+                packets.push(p);
+            }
+        }
+        assert_eq!(packets, vec![packet]);
+    }
+
+    #[test]
+    fn coalesced_packets() {
+        let first = publish_bytes("a/b", "first".to_owned(), 1);
+        let second = vec![0xC0, 0x00];
+        let third = publish_bytes("c/d", "third".to_owned(), 2);
+        let mut framer = PacketFramer::new();
+
+        framer.extend(&[first.clone(), second.clone(), third[..3].to_vec()].concat());
+        assert_eq!(framer.next_packet(), Ok(Some(first)));
+        assert_eq!(framer.next_packet(), Ok(Some(second)));
+        assert_eq!(framer.next_packet(), Ok(None));
+
+        framer.extend(&third[3..]);
+        assert_eq!(framer.next_packet(), Ok(Some(third)));
+        assert_eq!(framer.next_packet(), Ok(None));
+    }
+
+    #[test]
+    fn large_publish() {
+        let body = "0123456789".repeat(500);
+        let packet = publish_bytes("large/topic", body.clone(), 42);
+        assert!(packet.len() > 1024);
+
+        let mut framer = PacketFramer::new();
+        for chunk in packet.chunks(1024) {
+            framer.extend(chunk);
+        }
+        let frame = framer.next_packet().unwrap().unwrap();
+        match v3_unpacket::publish(BaseMessage::try_from(frame).unwrap()).unwrap() {
+            MqttMessageV3::Publish(msg) => {
+                assert_eq!(msg.topic, "large/topic");
+                assert_eq!(msg.message_id, 42);
+                assert_eq!(msg.msg_body, body);
+            }
+            _ => panic!("expected publish"),
+        }
+    }
+
+    #[test]
+    fn malformed_remaining_length() {
+        let mut framer = PacketFramer::new();
+        framer.extend(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]);
+        assert_eq!(framer.next_packet(), Err(DecodeError::MalformedRemainingLength));
+    }
+
+    #[test]
+    fn max_packet_size() {
+        let packet = publish_bytes("a/b", "x".repeat(200), 1);
+        let mut framer = PacketFramer::with_max_packet_size(128);
+        framer.extend(&packet[..3]);
+        assert_eq!(framer.next_packet(), Err(DecodeError::PacketTooLarge(packet.len())));
+    }
+}
@@ -4,12 +4,13 @@ pub mod config;
 pub mod protocol;
 pub mod types;
 pub mod tls;
+pub mod framer;
+pub mod error;
+pub mod websocket;
 
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn test() {
 
@@ -1,14 +1,22 @@
+use bytes::Bytes;
 use crate::tools::types::TypeKind;
 use crate::tools::protocol::{MqttCleanSession, MqttWillFlag, MqttQos, MqttDup, MqttRetain};
 use crate::message::WillField;
+use crate::message::entity::PublishMessage;
 
 ///
 /// 包装报文字符串数组
 ///
-pub fn pack_string(str: &String) -> Vec<u8> {
-    let str = str.as_bytes().to_vec();
-    let mut content = pack_short_int((str.len() as u16));
-    content.extend(str);
+pub fn pack_string(str: &str) -> Vec<u8> {
+    pack_binary(str.as_bytes())
+}
+
+///
+/// 包装报文二进制数组
+///
+pub fn pack_binary(data: &[u8]) -> Vec<u8> {
+    let mut content = pack_short_int(data.len() as u16);
+    content.extend(data);
     content
 }
 
@@ -63,15 +71,15 @@ pub fn pack_header(header_type: TypeKind, body_length: usize) -> Vec<u8> {
 
 pub fn pack_publish_header(header_type: TypeKind, body_length: usize, qos: Option<MqttQos>, dup: Option<MqttDup>, retain: Option<MqttRetain>) -> Vec<u8> {
     let mut r#type = header_type.as_header_byte();
-    if dup.is_some() && dup.unwrap() == MqttDup::Enable {
+    if dup == Some(MqttDup::Enable) {
         r#type |= 1 << 3
     }
 
-    if qos.is_some() && qos.unwrap() > MqttQos::Qos0 {
-        r#type |= (qos.unwrap() as u8) << 1;
+    if let Some(qos) = qos.filter(|qos| *qos > MqttQos::Qos0) {
+        r#type |= (qos as u8) << 1;
     }
 
-    if retain.is_some() && retain.unwrap() == MqttRetain::Enable {
+    if retain == Some(MqttRetain::Enable) {
         r#type |= 1;
     }
 
@@ -82,7 +90,25 @@ pub fn pack_publish_header(header_type: TypeKind, body_length: usize, qos: Optio
     header
 }
 
-pub fn pack_protocol_name(name_str: &String) -> Vec<u8> {
+///
+/// 组装 PUBLISH 报文, `body` 为不含报文标识符的可变报头与载荷, 报文标识符插入在主题名之后
+///
+/// 返回固定报头、主题名与报文标识符, 以及与 `body` 共享内存的剩余部分, 不复制属性与载荷
+///
+pub fn pack_publish(msg: &PublishMessage, body: Bytes) -> (Vec<u8>, Bytes) {
+    let split = 2 + msg.topic.len();
+    let message_id = if msg.qos > MqttQos::Qos0 {
+        pack_message_short_id(msg.message_id)
+    } else {
+        vec![]
+    };
+    let mut head = pack_publish_header(TypeKind::PUBLISH, body.len() + message_id.len(), Option::from(msg.qos), Option::from(msg.dup), Option::from(msg.retain));
+    head.extend_from_slice(&body[..split]);
+    head.extend(message_id);
+    (head, body.slice(split..))
+}
+
+pub fn pack_protocol_name(name_str: &str) -> Vec<u8> {
     pack_string(name_str)
 }
 
@@ -118,7 +144,7 @@ pub fn pack_connect_flags(
     Ok(connect_flags)
 }
 
-pub fn pack_client_id(client_id: &String) -> Vec<u8> {
+pub fn pack_client_id(client_id: &str) -> Vec<u8> {
     pack_string(client_id)
 }
 
@@ -130,10 +156,7 @@ pub fn pack_will_topic<T:WillField>(msg: &T) -> Option<Vec<u8>> {
 }
 
 pub fn pack_will_message<T:WillField>(msg: &T) -> Option<Vec<u8>> {
-    if msg.message_str().is_some() {
-        return Some(pack_string(msg.message_str().unwrap()));
-    }
-    None
+    msg.message_bytes().map(|message| pack_binary(message))
 }
 
 pub fn pack_username<T:WillField>(msg: &T) -> Option<Vec<u8>> {
@@ -161,12 +184,12 @@ fn pack_remaining_length(mut length: usize) -> Vec<u8> {
 
     loop {
         let mut digit = length % 128;
-        length = length / 128;
+        length /= 128;
         if length > 0 {
-            digit = (digit | 128);
+            digit |= 128;
         }
         remaining.push(digit as u8);
-        if length <= 0 { break; }
+        if length == 0 { break; }
     }
 
     remaining
@@ -181,4 +204,29 @@ mod tests {
         // println!("{:?}", pack_protocol_name(&String::from("MQTT")))
         // let b = (3600_u32 as u16).to_ne_bytes();
     }
+
+    #[test]
+    fn shared_publish() {
+        use crate::hex::{Property, PropertyItem, PropertyValue};
+        use crate::message::v3::MqttMessageV3;
+        use crate::message::v5::MqttMessageV5;
+
+        let properties = vec![PropertyItem(Property::ContentType, PropertyValue::String("text/plain".to_owned()))];
+        let msg = PublishMessage::new(MqttQos::Qos2, MqttDup::Disable, MqttRetain::Enable, "shared/t".to_owned(), 0, vec![0x00, 0xFF], Some(properties));
+        let shared = msg.shared();
+        for (qos, message_id, dup) in [(MqttQos::Qos0, 0, MqttDup::Disable), (MqttQos::Qos1, 7, MqttDup::Enable), (MqttQos::Qos2, 300, MqttDup::Disable)] {
+            let plain = PublishMessage { qos, message_id, dup, ..msg.clone() };
+            let cached = PublishMessage { qos, message_id, dup, ..shared.clone() };
+            assert_eq!(MqttMessageV3::Publish(cached.clone()).to_vec(), MqttMessageV3::Publish(plain.clone()).to_vec());
+            assert_eq!(MqttMessageV5::Publish(cached).to_vec(), MqttMessageV5::Publish(plain).to_vec());
+        }
+        assert!(shared.cache.is_some());
+        assert!(msg.cache.is_none());
+
+        // 属性与载荷只编码一次, 各订阅者的报文共享同一段内存
+        let (_, first) = crate::packet::v5_packet::publish_chunks(&PublishMessage { qos: MqttQos::Qos1, message_id: 1, ..shared.clone() });
+        let (head, second) = crate::packet::v5_packet::publish_chunks(&PublishMessage { qos: MqttQos::Qos1, message_id: 2, ..shared.clone() });
+        assert_eq!(first.as_ptr(), second.as_ptr());
+        assert_eq!(&head[head.len() - 2..], &[0x00, 0x02]);
+    }
 }
@@ -1,8 +1,8 @@
 use num_enum::TryFromPrimitive;
 
-pub const MQISDP_PROTOCOL_NAME: &'static str = "MQIsdp";
+pub const MQISDP_PROTOCOL_NAME: &str = "MQIsdp";
 
-pub const MQTT_PROTOCOL_NAME: &'static str = "MQTT";
+pub const MQTT_PROTOCOL_NAME: &str = "MQTT";
 
 #[derive(Debug, Copy, Clone, TryFromPrimitive, Ord, PartialOrd, Eq, PartialEq)]
 #[repr(u8)]
@@ -1,23 +1,246 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::io::{BufReader, Error};
-use std::path::Path;
-use rustls_pemfile::{certs, rsa_private_keys};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use rustls_pemfile::{certs, read_all, Item};
+use tokio::task::JoinHandle;
 use tokio_rustls::rustls::{Certificate, PrivateKey};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
 
+fn invalid_input(path: &Path, msg: &str) -> Error {
+    Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", path.display(), msg))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
 
 pub fn client_load_certs(path: &Path) -> Result<Vec<Vec<u8>>, Error> {
-    certs(&mut BufReader::new(File::open(path)?))
+    certs(&mut open(path)?)
 }
 
 pub fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
-    certs(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
+    certs(&mut open(path)?)
+        .map_err(|_| invalid_input(path, "invalid cert"))
         .map(|mut certs| certs.drain(..).map(Certificate).collect())
 }
 
+///
+/// 读取文件中的全部私钥, 支持 PKCS#1 (RSA)、PKCS#8 与 SEC1 (EC) 格式
+///
 pub fn load_keys(path: &Path) -> io::Result<Vec<PrivateKey>> {
-    rsa_private_keys(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
-        .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
+    read_all(&mut open(path)?)
+        .map_err(|_| invalid_input(path, "invalid key"))
+        .map(|items| items.into_iter().filter_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None
+        }).collect())
+}
+
+///
+/// 读取文件中的第一个私钥
+///
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    load_keys(path)?.into_iter().next().ok_or_else(|| invalid_input(path, "no private key found"))
+}
+
+///
+/// 读取证书链与对应的私钥
+///
+pub fn load_certified_key(cert: &Path, key: &Path) -> io::Result<CertifiedKey> {
+    let certs = load_certs(cert)?;
+    if certs.is_empty() {
+        return Err(invalid_input(cert, "no certificate found"));
+    }
+    let signing_key = sign::any_supported_type(&load_private_key(key)?)
+        .map_err(|_| invalid_input(key, "unsupported private key type"))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn modified(cert: &Path, key: &Path) -> [Option<SystemTime>; 2] {
+    [cert, key].map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+}
+
+struct LoadedKey {
+    modified: [Option<SystemTime>; 2],
+    certified_key: Arc<CertifiedKey>,
+}
+
+///
+/// 服务端证书解析器, 证书或私钥文件的修改时间变化后重新加载;
+/// 新证书只用于之后的握手, 已建立的连接不受影响
+///
+pub struct CertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    loaded: RwLock<LoadedKey>,
+}
+
+impl CertResolver {
+    pub fn new(cert: &Path, key: &Path) -> io::Result<CertResolver> {
+        let modified = modified(cert, key);
+        let certified_key = Arc::new(load_certified_key(cert, key)?);
+        Ok(CertResolver {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            loaded: RwLock::new(LoadedKey { modified, certified_key }),
+        })
+    }
+
+    pub fn certified_key(&self) -> Arc<CertifiedKey> {
+        self.loaded.read().unwrap().certified_key.clone()
+    }
+
+    ///
+    /// 文件有变化时重新加载, 返回是否已替换证书; 加载失败时继续使用原证书
+    ///
+    pub fn reload(&self) -> io::Result<bool> {
+        let modified = modified(&self.cert, &self.key);
+        if self.loaded.read().unwrap().modified == modified {
+            return Ok(false);
+        }
+        let certified_key = Arc::new(load_certified_key(&self.cert, &self.key)?);
+        *self.loaded.write().unwrap() = LoadedKey { modified, certified_key };
+        Ok(true)
+    }
+
+    ///
+    /// 每隔 `interval` 检查一次证书文件
+    ///
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(interval);
+            timer.tick().await;
+            loop {
+                timer.tick().await;
+                match resolver.reload() {
+                    Ok(true) => println!("tls certificate reloaded; cert = {}", resolver.cert.display()),
+                    Ok(false) => {}
+                    Err(e) => println!("failed to reload tls certificate; err = {}", e),
+                }
+            }
+        })
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key())
+    }
+}
+
+///
+/// 客户端证书身份的用途
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityAs {
+    Username,
+    ClientId,
+}
+
+///
+/// 证书中的身份信息: subject 的 CN 以及 DNS / email / URI 类型的 SAN
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    common_name: Option<String>,
+    subject_alt_names: Vec<String>,
+}
+
+impl PeerIdentity {
+    pub fn parse(cert: &Certificate) -> Option<PeerIdentity> {
+        let (_, cert) = parse_x509_certificate(&cert.0).ok()?;
+        let common_name = cert.subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_owned);
+        let subject_alt_names = match cert.subject_alternative_name() {
+            Ok(Some(san)) => san.value.general_names.iter().filter_map(|name| match name {
+                GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None
+            }).collect(),
+            _ => vec![]
+        };
+        Some(PeerIdentity { common_name, subject_alt_names })
+    }
+
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    pub fn subject_alt_names(&self) -> &[String] {
+        &self.subject_alt_names
+    }
+
+    ///
+    /// 作为用户名或客户端标识的名称, 优先使用 CN, 没有 CN 时使用第一个 SAN
+    ///
+    pub fn name(&self) -> Option<&str> {
+        self.common_name().or_else(|| self.subject_alt_names.first().map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_identity() {
+        let certs = load_certs(Path::new("config/certs/client-test.crt")).unwrap();
+        let identity = PeerIdentity::parse(&certs[0]).unwrap();
+        assert_eq!(identity.common_name(), Some("device-1"));
+        assert_eq!(identity.subject_alt_names(), ["device-1.mqtt.test", "device-1@mqtt.test"]);
+        assert_eq!(identity.name(), Some("device-1"));
+
+        assert!(PeerIdentity::parse(&Certificate(vec![0x30, 0x00])).is_none());
+    }
+
+    #[test]
+    fn private_key_formats() {
+        for path in ["config/certs/server-test.key", "config/certs/server-test-pkcs8.key", "config/certs/ec-test.key"] {
+            let key = load_private_key(Path::new(path)).unwrap();
+            assert!(sign::any_supported_type(&key).is_ok(), "{}", path);
+        }
+        let err = load_private_key(Path::new("config/certs/ca.crt")).unwrap_err();
+        assert_eq!(err.to_string(), "config/certs/ca.crt: no private key found");
+        let err = load_certified_key(Path::new("config/certs/server-test.crt"), Path::new("config/certs/missing.key")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn reload_certificate() {
+        let dir = std::env::temp_dir().join(format!("mqtt-rs-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("server.crt"), dir.join("server.key"));
+        fs::copy("config/certs/server-test.crt", &cert).unwrap();
+        fs::copy("config/certs/server-test.key", &key).unwrap();
+        let resolver = CertResolver::new(&cert, &key).unwrap();
+        let server_cert = load_certs(Path::new("config/certs/server-test.crt")).unwrap();
+        assert_eq!(resolver.certified_key().cert, server_cert);
+        assert!(!resolver.reload().unwrap());
+
+        fs::write(&key, "").unwrap();
+        File::options().write(true).open(&key).unwrap().set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert!(resolver.reload().is_err());
+        assert_eq!(resolver.certified_key().cert, server_cert);
+
+        fs::copy("config/certs/client-test.crt", &cert).unwrap();
+        fs::copy("config/certs/client-test.key", &key).unwrap();
+        for path in [&cert, &key] {
+            File::options().write(true).open(path).unwrap().set_modified(SystemTime::now() + Duration::from_secs(20)).unwrap();
+        }
+        assert!(resolver.reload().unwrap());
+        assert_eq!(resolver.certified_key().cert, load_certs(Path::new("config/certs/client-test.crt")).unwrap());
+        assert!(!resolver.reload().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -54,11 +54,11 @@ impl TypeKind {
             TypeKind::PUBLISH => { (TypeKind::PUBLISH as u8) << 4 }
             TypeKind::PUBACK => { (TypeKind::PUBACK as u8) << 4 }
             TypeKind::PUBREC => { (TypeKind::PUBREC as u8) << 4 }
-            TypeKind::PUBREL => { (TypeKind::PUBREL as u8) << 4 }
+            TypeKind::PUBREL => { (TypeKind::PUBREL as u8) << 4 | 0b0010 }
             TypeKind::PUBCOMP => { (TypeKind::PUBCOMP as u8) << 4 }
-            TypeKind::SUBSCRIBE => { (TypeKind::SUBSCRIBE as u8) << 4 }
+            TypeKind::SUBSCRIBE => { (TypeKind::SUBSCRIBE as u8) << 4 | 0b0010 }
             TypeKind::SUBACK => { (TypeKind::SUBACK as u8) << 4 }
-            TypeKind::UNSUBSCRIBE => { (TypeKind::UNSUBSCRIBE as u8) << 4 }
+            TypeKind::UNSUBSCRIBE => { (TypeKind::UNSUBSCRIBE as u8) << 4 | 0b0010 }
             TypeKind::UNSUBACK => { (TypeKind::UNSUBACK as u8) << 4 }
             TypeKind::PINGREQ => { (TypeKind::PINGREQ as u8) << 4 }
             TypeKind::PINGRESP => { (TypeKind::PINGRESP as u8) << 4 }
@@ -1,29 +1,40 @@
 use crate::tools::types::TypeKind;
 use std::convert::{TryFrom, TryInto};
 use crate::tools::protocol::{MqttProtocolLevel, MqttCleanSession, MqttWillFlag, MqttUsernameFlag, MqttPasswordFlag, MqttRetain, MqttQos, MqttDup};
+use crate::tools::error::DecodeError;
 use crate::message::{ConnectMessagePayload, VariableHeader};
-use crate::hex::un_pack_property;
+use crate::hex::{find_property, un_pack_property, Property, PropertyItem};
+use bytes::Bytes;
+
+///
+/// 固定报头: (报文种类, retain, qos, dup, 剩余数据)
+///
+pub type FixedHeader<'a> = (TypeKind, Option<MqttRetain>, Option<MqttQos>, Option<MqttDup>, &'a [u8]);
 
 ///
 /// 获取报文种类
 ///
-pub fn get_type(data: &[u8]) -> (Option<TypeKind>, Option<MqttRetain>, Option<MqttQos>, Option<MqttDup>, &[u8]) {
-    let kind = TypeKind::try_from((data[0] >> 4)).ok();
-    if kind.unwrap() == TypeKind::PUBLISH {
-        let (retain, qos, dup) = get_publish_header(data[0]);
-        return (kind, retain, qos, dup, get_remaining_data(data));
+pub fn get_type(data: &[u8]) -> Result<FixedHeader<'_>, DecodeError> {
+    let (header, _) = parse_byte(data)?;
+    let kind = TypeKind::try_from(header >> 4).map_err(|_| DecodeError::UnknownPacketType(header >> 4))?;
+    if kind == TypeKind::PUBLISH {
+        let (retain, qos, dup) = get_publish_header(header);
+        if qos.is_none() {
+            return Err(DecodeError::InvalidQos((header >> 1) & 3));
+        }
+        return Ok((kind, retain, qos, dup, get_remaining_data(data)?));
     }
-    (kind, None, None, None, get_remaining_data(data))
+    Ok((kind, None, None, None, get_remaining_data(data)?))
 }
 
 ///
 /// 获取协议名称和协议版本
 ///
-pub fn get_protocol_name_and_version(data: &[u8]) -> (Option<String>, Option<MqttProtocolLevel>) {
-    let slice = get_remaining_data(data);
-    let protocol_name = Option::from(String::from_utf8_lossy(slice).into_owned());
-    let mqtt_version = MqttProtocolLevel::try_from(data[6]).ok();
-    (protocol_name, mqtt_version)
+pub fn get_protocol_name_and_version(data: &[u8]) -> Result<(String, MqttProtocolLevel), DecodeError> {
+    let (protocol_name, last_data) = parse_string(get_remaining_data(data)?)?;
+    let (level, _) = parse_byte(last_data)?;
+    let mqtt_version = MqttProtocolLevel::try_from(level).map_err(|_| DecodeError::UnsupportedProtocolLevel(level))?;
+    Ok((protocol_name, mqtt_version))
 }
 
 ///
@@ -43,123 +54,153 @@ pub fn get_publish_header(data: u8) -> (Option<MqttRetain>, Option<MqttQos>, Opt
 ///
 /// 获取 初始连接的 负载数据
 ///
-pub fn get_connect_payload_data(protocol_level: MqttProtocolLevel, data: &[u8], will_flag: MqttWillFlag, username_flag: MqttUsernameFlag, password_flag: MqttPasswordFlag) -> ConnectMessagePayload {
-    let (client_id, last_data) = parse_string(data).unwrap();
+pub fn get_connect_payload_data(protocol_level: MqttProtocolLevel, data: &[u8], will_flag: MqttWillFlag, username_flag: MqttUsernameFlag, password_flag: MqttPasswordFlag) -> Result<ConnectMessagePayload, DecodeError> {
+    let (client_id, last_data) = parse_string(data)?;
 
     let (properties, will_topic, will_message, last_data) = if MqttWillFlag::Enable == will_flag {
         let (properties, last_data) = if protocol_level == MqttProtocolLevel::Level5 {
-            let (properties_total_length, last_data) = parse_byte(last_data.unwrap());
-
-            if properties_total_length > 0 {
-                (Some(un_pack_property::will_properties(properties_total_length as u32, last_data)), last_data.get(properties_total_length as usize..))
-            } else {
-                (None, Some(last_data))
-            }
+            let (properties_data, last_data) = parse_properties_data(last_data)?;
+            (Some(un_pack_property::will_properties(properties_data)?), last_data)
         } else {
             (None, last_data)
         };
 
-        let (will_topic, will_last_data) = parse_string(last_data.unwrap()).unwrap();
-        let (will_message, will_last_data) = parse_string(will_last_data.unwrap()).unwrap();
-        (properties, Some(will_topic), Some(will_message), will_last_data)
+        let (will_topic, last_data) = parse_string(last_data)?;
+        let (will_message, last_data) = parse_binary(last_data)?;
+        let will_message = check_payload_format(properties.as_deref(), Bytes::from(will_message))?;
+        (properties, Some(will_topic), Some(will_message), last_data)
     } else {
-        (None, Some("".to_string()), Some("".to_string()), last_data)
+        (None, Some("".to_string()), Some(Bytes::new()), last_data)
     };
 
     let (user_name, last_data) = if MqttUsernameFlag::Enable == username_flag {
-        parse_string(last_data.unwrap()).unwrap()
+        let (user_name, last_data) = parse_string(last_data)?;
+        (Some(user_name), last_data)
     } else {
-        ("".to_string(), last_data)
+        (None, last_data)
     };
 
-    let (password, _) = if MqttPasswordFlag::Enable == password_flag {
-        parse_string(last_data.unwrap()).unwrap()
+    let password = if MqttPasswordFlag::Enable == password_flag {
+        Some(parse_string(last_data)?.0)
     } else {
-        ("".to_string(), last_data)
+        None
     };
     println!("client ID: {}", client_id);
-    ConnectMessagePayload {
+    Ok(ConnectMessagePayload {
         client_id,
         will_topic,
         will_message,
-        user_name: Some(user_name),
-        password: Some(password),
+        user_name,
+        password,
         properties,
+    })
+}
+
+///
+/// PayloadFormatIndicator 为 1 时消息体必须是 UTF-8 字符串, 否则原样作为二进制数据
+///
+pub fn check_payload_format(properties: Option<&[PropertyItem]>, payload: Bytes) -> Result<Bytes, DecodeError> {
+    let utf8 = find_property(properties, Property::PayloadFormatIndicator).and_then(PropertyItem::as_byte) == Some(1);
+    if utf8 && std::str::from_utf8(&payload).is_err() {
+        return Err(DecodeError::PayloadFormatInvalid);
     }
+    Ok(payload)
 }
 
 ///
 /// 获取可变报文头数据
 ///
-pub fn get_connect_variable_header(message_bytes: &[u8]) -> (VariableHeader, &[u8]) {
-    let data = message_bytes.get(2..).unwrap();
-    let slice = get_remaining_data(data);
-    let protocol_name = Option::from(String::from_utf8_lossy(slice).into_owned());
-    let clean_session = (data[7] >> 1) & 1;
-    let will_flag = (data[7] >> 2) & 1;
-    let will_qos = (data[7] >> 3) & 3;
-    let will_retain = (data[7] >> 5) & 1;
-    let password_flag = (data[7] >> 6) & 1;
-    let username_flag = (data[7] >> 7) & 1;
-    let (keep_alive, _) = parse_short_int(data.get(8..10).unwrap());
+pub fn get_connect_variable_header(message_bytes: &[u8]) -> Result<(VariableHeader, &[u8]), DecodeError> {
+    let data = get_remaining_data(message_bytes)?;
+    let (protocol_name, last_data) = parse_string(data)?;
+    let (level, last_data) = parse_byte(last_data)?;
+    let (flags, last_data) = parse_byte(last_data)?;
+    let (keep_alive, last_data) = parse_short_int(last_data)?;
 
-    (
+    let protocol_level = MqttProtocolLevel::try_from(level).map_err(|_| DecodeError::UnsupportedProtocolLevel(level))?;
+    if flags & 1 != 0 {
+        return Err(DecodeError::InvalidFlags(flags));
+    }
+    let clean_session = (flags >> 1) & 1;
+    let will_flag = (flags >> 2) & 1;
+    let will_qos = (flags >> 3) & 3;
+    let will_retain = (flags >> 5) & 1;
+    let password_flag = (flags >> 6) & 1;
+    let username_flag = (flags >> 7) & 1;
+
+    Ok((
         VariableHeader {
-            protocol_name,
+            protocol_name: Some(protocol_name),
             keep_alive: Some(keep_alive),
-            protocol_level: MqttProtocolLevel::try_from(data[6]).ok(),
+            protocol_level: Some(protocol_level),
             clean_session: MqttCleanSession::try_from(clean_session).ok(),
             will_flag: MqttWillFlag::try_from(will_flag).ok(),
-            will_qos: MqttQos::try_from(will_qos).ok(),
+            will_qos: Some(MqttQos::try_from(will_qos).map_err(|_| DecodeError::InvalidQos(will_qos))?),
             will_retain: MqttRetain::try_from(will_retain).ok(),
             password_flag: MqttPasswordFlag::try_from(password_flag).ok(),
             username_flag: MqttUsernameFlag::try_from(username_flag).ok(),
         },
-        data.get(10..).unwrap()
-    )
+        last_data
+    ))
 }
 
 ///
 /// 解析报文 byte 数据
 ///
-pub fn parse_byte(data: &[u8]) -> (u8, &[u8]) {
-    (data[0], data.get(1..).unwrap())
+pub fn parse_byte(data: &[u8]) -> Result<(u8, &[u8]), DecodeError> {
+    match data.split_first() {
+        Some((byte, last_data)) => Ok((*byte, last_data)),
+        None => Err(DecodeError::Truncated)
+    }
 }
 
 ///
 /// 解析报文 short int 数据
 ///
-pub fn parse_short_int(data: &[u8]) -> (u16, &[u8]) {
-    // println!("parse_short_int: {:?}", data.get(..2).unwrap());
-    let bytes = data.get(..2).unwrap();
-    let short_int_bytes = bytes.iter().rev().cloned().collect::<Vec<u8>>();
-    let short_int = u16::from_le_bytes(short_int_bytes.try_into().unwrap());
-    // println!("short int: {}", short_int);
-    (short_int, data.get(2..).unwrap())
+pub fn parse_short_int(data: &[u8]) -> Result<(u16, &[u8]), DecodeError> {
+    let bytes = data.get(..2).ok_or(DecodeError::Truncated)?;
+    let short_int = u16::from_be_bytes(bytes.try_into().unwrap());
+    Ok((short_int, &data[2..]))
 }
 
 ///
 /// 解析报文 long int 数据
 ///
-pub fn parse_long_int(data: &[u8]) -> (u32, &[u8]) {
-    // println!("parse_long_int: {:?}", data.get(..4).unwrap());
-    let bytes = data.get(..4).unwrap();
-    let long_int_bytes = bytes.iter().rev().cloned().collect::<Vec<u8>>();
-    let long_int = u32::from_le_bytes(long_int_bytes.try_into().unwrap());
-    // println!("long int: {}", long_int);
-    (long_int, data.get(4..).unwrap())
+pub fn parse_long_int(data: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
+    let bytes = data.get(..4).ok_or(DecodeError::Truncated)?;
+    let long_int = u32::from_be_bytes(bytes.try_into().unwrap());
+    Ok((long_int, &data[4..]))
+}
+
+///
+/// 解析报文 二进制 数据
+///
+pub fn parse_binary(data: &[u8]) -> Result<(Vec<u8>, &[u8]), DecodeError> {
+    let (length, last_data) = parse_short_int(data)?;
+    let length = length as usize;
+    let value = last_data.get(..length).ok_or(DecodeError::Truncated)?;
+    Ok((value.to_vec(), &last_data[length..]))
 }
 
 ///
 /// 解析报文 string 数据
 ///
-pub fn parse_string(data: &[u8]) -> Result<(String, Option<&[u8]>), &str> {
-    let length = data[1];
-    if length as usize > data.len() {
-        return Err("parse string length error");
+pub fn parse_string(data: &[u8]) -> Result<(String, &[u8]), DecodeError> {
+    let (value, last_data) = parse_binary(data)?;
+    let value = String::from_utf8(value).map_err(|_| DecodeError::InvalidUtf8)?;
+    Ok((value, last_data))
+}
+
+///
+/// 解析 v5 属性数据, 返回 (属性数据, 剩余数据)
+///
+pub fn parse_properties_data(data: &[u8]) -> Result<(&[u8], &[u8]), DecodeError> {
+    let (length, last_data) = unpack_var_int(data)?;
+    let length = length as usize;
+    if length > last_data.len() {
+        return Err(DecodeError::Truncated);
     }
-    let value = get_remaining_data(data);
-    Ok((String::from_utf8(value.to_vec()).expect("parse utf-8 string"), data.get(2 + (length as usize)..)))
+    Ok(last_data.split_at(length))
 }
 
 ///
@@ -168,45 +209,39 @@ pub fn parse_string(data: &[u8]) -> Result<(String, Option<&[u8]>), &str> {
 /// from http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.pdf 第19页
 ///
 ///
-pub fn get_remaining_length(data: &[u8]) -> Result<(usize, usize), &'static str> {
-    let (mut head_index, mut digit, mut multiplier, mut value) = (1_usize, 0, 1, 0);
-
-    loop {
-        digit = data[head_index] & 127;
-        value += digit as usize * multiplier;
-        multiplier *= 128;
-        if multiplier > 128 * 128 * 128 {
-            return Err("Malformed Variable Byte Integer");
-        }
-        head_index += 1;
-        if (digit & 128) == 0 { break; }
-    }
-
-    Ok((value, head_index))
+pub fn get_remaining_length(data: &[u8]) -> Result<(usize, usize), DecodeError> {
+    let (_, last_data) = parse_byte(data)?;
+    let (value, last) = unpack_var_int(last_data)?;
+    Ok((value as usize, data.len() - last.len()))
 }
 
 ///
 /// 后续需要处理的数据
 ///
-pub fn get_remaining_data(data: &[u8]) -> &[u8] {
-    // println!("remaining_data handle data: {:?}", data);
-    let (remaining_length, head_bytes) = get_remaining_length(data).unwrap();
-    // println!("remaining_length: {}", remaining_length);
-    // println!("last_data: {:?}", data.get(head_bytes..(remaining_length + head_bytes)).unwrap());
-    data.get(head_bytes..(remaining_length + head_bytes)).unwrap()
+pub fn get_remaining_data(data: &[u8]) -> Result<&[u8], DecodeError> {
+    let (remaining_length, head_bytes) = get_remaining_length(data)?;
+    data.get(head_bytes..(remaining_length + head_bytes)).ok_or(DecodeError::Truncated)
 }
 
+///
+/// 解析 Variable Byte Integer
+///
+pub fn unpack_var_int(data: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
+    let (mut multiplier, mut value) = (1_u32, 0_u32);
 
-pub fn unpack_var_int(data: &[u8]) -> (String, &[u8]) {
-    let (remaining_length, head_bytes) = get_remaining_length(data).unwrap();
-    let (mut result, mut shift) = (0, 0);
+    for (index, encoded_byte) in data.iter().enumerate().take(4) {
+        value += (encoded_byte & 127) as u32 * multiplier;
+        if encoded_byte & 128 == 0 {
+            return Ok((value, &data[index + 1..]));
+        }
+        multiplier *= 128;
+    }
 
-    for i in 0..head_bytes {
-        shift += 1;
-        result |= (data[i] & 127) << (shift * 7);
+    if data.len() < 4 {
+        Err(DecodeError::Truncated)
+    } else {
+        Err(DecodeError::MalformedRemainingLength)
     }
-    let val = String::from_utf8_lossy(data.get(head_bytes..(remaining_length + head_bytes)).unwrap()).into_owned();
-    (val, data.get((remaining_length + head_bytes)..).unwrap())
 }
 
 #[cfg(test)]
@@ -218,16 +253,68 @@ mod tests {
         // let data = vec![192_u8, 0_u8];
         // assert_eq!(TypeKind::PINGREQ, get_type(&data));
         // println!("{:?}", format!("{:b}", 192));
-        let mut arr = [0, 0, 14, 16];
+        let arr = [0, 0, 14, 16];
         let a = arr.iter().rev();
         println!("{:?}", a.cloned().collect::<Vec<i32>>());
         // println!("{}", u32::from_le_bytes([16, 14, 0, 0]));
         // let a =  3600_u32.to_ne_bytes();
     }
 
-    fn read_be_u16(input: &mut &[u8]) -> u16 {
-        let (int_bytes, rest) = input.split_at(std::mem::size_of::<u16>());
-        *input = rest;
-        u16::from_be_bytes(int_bytes.try_into().unwrap())
+    #[test]
+    fn decode_errors() {
+        assert_eq!(get_type(&[0x00, 0x00]).err(), Some(DecodeError::UnknownPacketType(0)));
+        assert_eq!(get_type(&[0x36, 0x00]).err(), Some(DecodeError::InvalidQos(3)));
+        assert_eq!(get_type(&[0x30, 0x05, 0x00]).err(), Some(DecodeError::Truncated));
+        assert_eq!(get_remaining_length(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF]).err(), Some(DecodeError::MalformedRemainingLength));
+        assert_eq!(parse_string(&[0x00, 0x05, b'a']).err(), Some(DecodeError::Truncated));
+        assert_eq!(parse_string(&[0x00, 0x02, 0xC3, 0x28]).err(), Some(DecodeError::InvalidUtf8));
+        assert_eq!(parse_short_int(&[0x01]).err(), Some(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn payload_format() {
+        use crate::hex::PropertyValue;
+        use crate::message::BaseMessage;
+        use crate::message::entity::PublishMessage;
+        use crate::message::v3::MqttMessageV3;
+        use crate::message::v5::MqttMessageV5;
+        use crate::packet::{v3_unpacket, v5_unpacket};
+
+        let body = vec![0xFF, 0x00, 0xC3, 0x28];
+        let msg = PublishMessage::new(MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable, "binary".to_owned(), 1, body.clone(), None);
+        let packet = MqttMessageV3::Publish(msg.clone()).to_vec().unwrap();
+        match v3_unpacket::publish(BaseMessage::try_from(packet).unwrap()).unwrap() {
+            MqttMessageV3::Publish(msg) => assert_eq!(msg.payload(), body.as_slice()),
+            _ => panic!("expected publish"),
+        }
+
+        let packet = MqttMessageV5::Publish(PublishMessage { properties: Some(vec![]), ..msg.clone() }).to_vec().unwrap();
+        match v5_unpacket::publish(BaseMessage::try_from(packet).unwrap()).unwrap() {
+            MqttMessageV5::Publish(msg) => {
+                assert_eq!(msg.payload(), body.as_slice());
+                assert!(!msg.is_utf8_payload());
+                assert!(msg.payload_str().is_err());
+            }
+            _ => panic!("expected publish"),
+        }
+
+        let utf8 = Some(vec![PropertyItem(Property::PayloadFormatIndicator, PropertyValue::Byte(1))]);
+        let packet = MqttMessageV5::Publish(PublishMessage { properties: utf8.clone(), ..msg.clone() }).to_vec().unwrap();
+        assert_eq!(v5_unpacket::publish(BaseMessage::try_from(packet).unwrap()).err(), Some(DecodeError::PayloadFormatInvalid));
+        let text = PublishMessage::new(MqttQos::Qos0, MqttDup::Disable, MqttRetain::Disable, "text".to_owned(), 0, "温度", utf8);
+        let packet = MqttMessageV5::Publish(text).to_vec().unwrap();
+        match v5_unpacket::publish(BaseMessage::try_from(packet).unwrap()).unwrap() {
+            MqttMessageV5::Publish(msg) => assert_eq!(msg.payload_str(), Ok("温度")),
+            _ => panic!("expected publish"),
+        }
+    }
+
+    #[test]
+    fn var_int() {
+        assert_eq!(unpack_var_int(&[0x00]), Ok((0, &[][..])));
+        assert_eq!(unpack_var_int(&[0x7F, 0x01]), Ok((127, &[0x01][..])));
+        assert_eq!(unpack_var_int(&[0x80, 0x01]), Ok((128, &[][..])));
+        assert_eq!(unpack_var_int(&[0xFF, 0xFF, 0xFF, 0x7F]), Ok((268_435_455, &[][..])));
+        assert_eq!(unpack_var_int(&[0x80]), Err(DecodeError::Truncated));
     }
 }
@@ -0,0 +1,115 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::WebSocketStream;
+
+///
+/// MQTT over WebSocket 使用的子协议
+///
+pub const MQTT_SUBPROTOCOL: &str = "mqtt";
+
+const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+
+///
+/// 把 WebSocket 二进制帧转换为字节流, 交给与 TCP 相同的报文解析流程;
+/// 每次写入作为一个二进制帧发送
+///
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> WsStream<S> {
+        WsStream { inner, buffer: vec![], position: 0 }
+    }
+}
+
+///
+/// 完成 WebSocket 握手, 只接受 `path` 路径上请求了 `mqtt` 子协议的连接
+///
+pub async fn accept<S>(stream: S, path: &str) -> Result<WsStream<S>, WsError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin
+{
+    let path = path.to_owned();
+    // 错误类型由 tungstenite 的握手回调决定
+    #[allow(clippy::result_large_err)]
+    let callback = move |request: &Request, mut response: Response| {
+        if request.uri().path() != path {
+            return Err(error_response(StatusCode::NOT_FOUND));
+        }
+        let offered = request.headers().get_all(SEC_WEBSOCKET_PROTOCOL).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == MQTT_SUBPROTOCOL);
+        if !offered {
+            return Err(error_response(StatusCode::BAD_REQUEST));
+        }
+        response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(MQTT_SUBPROTOCOL));
+        Ok(response)
+    };
+    tokio_tungstenite::accept_hdr_async(stream, callback).await.map(WsStream::new)
+}
+
+fn error_response(status: StatusCode) -> ErrorResponse {
+    let mut response = ErrorResponse::new(status.canonical_reason().map(str::to_owned));
+    *response.status_mut() = status;
+    response
+}
+
+fn io_error(err: WsError) -> io::Error {
+    match err {
+        WsError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.position < this.buffer.len() {
+                let n = buf.remaining().min(this.buffer.len() - this.position);
+                buf.put_slice(&this.buffer[this.position..this.position + n]);
+                this.position += n;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.buffer = data;
+                    this.position = 0;
+                }
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "text frames are not allowed")));
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(io_error(err))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(io_error)?;
+        Pin::new(&mut this.inner).start_send(Message::Binary(buf.to_vec())).map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx).map_err(io_error)
+    }
+}